@@ -0,0 +1,51 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use std::{
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Embeds the git commit and the time of the build, reported by `GET /version`.
+fn main() {
+    println!("cargo:rerun-if-env-changed=CS_BUILD_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    for path in [".git/HEAD", ".git/refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+
+    // docker builds have no git, so the commit can be given explicitly
+    let commit = std::env::var("CS_BUILD_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+    });
+
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=CS_BUILD_COMMIT={}", commit.trim());
+    }
+
+    // honors https://reproducible-builds.org/specs/source-date-epoch/
+    let time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|time| time.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())
+        });
+
+    println!("cargo:rustc-env=CS_BUILD_TIME={time}");
+}
@@ -1,3 +1,3 @@
 #!/bin/sh
 # Builds a docker image for release.
-docker build . -t 'registry.chiya.dev/castella'
+docker build . -t 'registry.chiya.dev/castella' --build-arg CS_BUILD_COMMIT="$(git rev-parse HEAD)"
@@ -0,0 +1,179 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::{
+    db::{MetadataTable, WrappedMetadataKey},
+    keys::MasterKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("not a metadata archive")]
+    Format,
+
+    #[error("failed to encrypt archive record")]
+    Encrypt,
+
+    #[error("failed to decrypt archive; wrong key or corrupted data")]
+    Decrypt,
+
+    #[error("invalid archive record: {0}")]
+    Record(serde_json::Error),
+
+    #[error("archive is truncated or its records were reordered")]
+    Sequence,
+}
+
+/// Identifies metadata archives and the version of their format.
+const MAGIC: &[u8; 8] = b"CSMETA1\n";
+
+/// Maximum size of an encrypted frame, which bounds the memory used for reading untrusted archives.
+const MAX_FRAME_SIZE: u32 = 256 * 1024 * 1024;
+
+/// Record of a metadata archive. Archives start with a header, which is followed by rows and an end record.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Header {
+        /// Migration version of the exported database.
+        migration_version: u32,
+        /// Key encrypting file metadata, if any.
+        metadata_key: Option<WrappedMetadataKey>,
+    },
+    Rows {
+        table: MetadataTable,
+        rows: Vec<Map<String, Value>>,
+    },
+    End {
+        /// Total number of exported rows.
+        rows: u64,
+    },
+}
+
+/// Sequence number of a record, which is encrypted with it so that records can't be dropped or reordered.
+#[derive(Serialize, Deserialize)]
+struct Frame<T> {
+    index: u64,
+    record: T,
+}
+
+/// Writes records to an archive, each encrypted separately with the archive key.
+pub struct ArchiveWriter<W> {
+    writer: W,
+    key: MasterKey,
+    index: u64,
+}
+
+impl<W: AsyncWrite + Unpin> ArchiveWriter<W> {
+    pub async fn new(mut writer: W, key: MasterKey) -> Result<Self, Error> {
+        writer.write_all(MAGIC).await?;
+
+        Ok(Self {
+            writer,
+            key,
+            index: 0,
+        })
+    }
+
+    pub async fn write(&mut self, record: &Record) -> Result<(), Error> {
+        let frame = serde_json::to_vec(&Frame {
+            index: self.index,
+            record,
+        })
+        .map_err(Error::Record)?;
+
+        let frame = self.key.wrap(&frame).map_err(|_| Error::Encrypt)?;
+
+        self.writer
+            .write_all(&(frame.len() as u32).to_be_bytes())
+            .await?;
+
+        self.writer.write_all(&frame).await?;
+        self.index += 1;
+        Ok(())
+    }
+
+    /// Flushes the archive, which must end with [`Record::End`].
+    pub async fn finish(mut self) -> Result<(), Error> {
+        Ok(self.writer.flush().await?)
+    }
+}
+
+/// Reads records from an archive written by [`ArchiveWriter`].
+pub struct ArchiveReader<R> {
+    reader: R,
+    key: MasterKey,
+    index: u64,
+}
+
+impl<R: AsyncRead + Unpin> ArchiveReader<R> {
+    pub async fn new(mut reader: R, key: MasterKey) -> Result<Self, Error> {
+        let mut magic = [0; MAGIC.len()];
+
+        match reader.read_exact(&mut magic).await {
+            Ok(_) if &magic == MAGIC => {}
+            Ok(_) => return Err(Error::Format),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::Format)
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(Self {
+            reader,
+            key,
+            index: 0,
+        })
+    }
+
+    /// Reads the next record, failing if the archive ends before [`Record::End`] was read.
+    pub async fn read(&mut self) -> Result<Record, Error> {
+        let mut len = [0; 4];
+
+        match self.reader.read_exact(&mut len).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::Sequence)
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let len = u32::from_be_bytes(len);
+
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::Format);
+        }
+
+        let mut frame = vec![0; len as usize];
+
+        match self.reader.read_exact(&mut frame).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::Sequence)
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let frame = self.key.unwrap(&frame).map_err(|_| Error::Decrypt)?;
+        let Frame { index, record } =
+            serde_json::from_slice::<Frame<Record>>(&frame).map_err(Error::Record)?;
+
+        if index != self.index {
+            return Err(Error::Sequence);
+        }
+
+        self.index += 1;
+        Ok(record)
+    }
+}
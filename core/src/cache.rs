@@ -0,0 +1,618 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::{
+    db::File,
+    redis::{self, Redis, Subscription, Value},
+};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use lru::LruCache;
+use rand::{thread_rng, Rng};
+use std::{
+    collections::HashSet,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tracing::Instrument;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialize chunk cache: {0}")]
+    Init(std::io::Error),
+}
+
+/// Least recently used cache of stored file chunks on local disk, keyed by remote file ID and chunk index.
+///
+/// Chunks are cached exactly as stored in Drive, so they are still encrypted and verified when read.
+#[derive(Debug)]
+pub struct ChunkCache {
+    path: PathBuf,
+    capacity: AtomicU64,
+    index: Mutex<Index>,
+}
+
+#[derive(Debug)]
+struct Index {
+    // sizes of cached chunks keyed by their file name
+    entries: LruCache<String, u64>,
+    // cached chunks of uploaded files that have not been read since
+    unread: HashSet<String>,
+    size: u64,
+}
+
+impl ChunkCache {
+    /// Opens the cache at the given directory, indexing chunks cached by a previous run.
+    /// `capacity` is the maximum total size of cached chunks in bytes.
+    pub fn new(path: PathBuf, capacity: u64) -> Result<Self, Error> {
+        std::fs::create_dir_all(&path).map_err(Error::Init)?;
+
+        let mut existing = Vec::new();
+
+        for entry in std::fs::read_dir(&path).map_err(Error::Init)? {
+            let entry = entry.map_err(Error::Init)?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata().map_err(Error::Init)?;
+
+            if !metadata.is_file() {
+                continue;
+            }
+
+            // leftover of an interrupted write
+            if name.ends_with(".tmp") {
+                let _ = std::fs::remove_file(entry.path());
+                continue;
+            }
+
+            let time = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            existing.push((time, name, metadata.len()));
+        }
+
+        // oldest chunks are evicted first
+        existing.sort();
+
+        let cache = Self {
+            path,
+            capacity: AtomicU64::new(capacity),
+            index: Mutex::new(Index {
+                entries: LruCache::unbounded(),
+                unread: HashSet::new(),
+                size: 0,
+            }),
+        };
+
+        let evicted = {
+            let mut index = cache.index.lock().unwrap();
+
+            for (_, name, size) in existing {
+                index.entries.put(name, size);
+                index.size += size;
+            }
+
+            cache.evict(&mut index)
+        };
+
+        for name in evicted {
+            let _ = std::fs::remove_file(cache.path.join(name));
+        }
+
+        debug!(
+            "indexed {count} cached chunk(s) in '{path}'",
+            count = cache.index.lock().unwrap().entries.len(),
+            path = cache.path.display()
+        );
+
+        Ok(cache)
+    }
+
+    /// Changes the maximum total size of cached chunks, evicting chunks that no longer fit.
+    pub async fn set_capacity(&self, capacity: u64) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+
+        let evicted = self.evict(&mut self.index.lock().unwrap());
+        Self::remove_chunks(&self.path, evicted).await;
+    }
+
+    fn chunk_name(file_id: &str, chunk_id: u32) -> String {
+        format!("{file_id}.{chunk_id}")
+    }
+
+    /// Removes least recently used chunks from the index until it fits in the capacity,
+    /// returning their file names.
+    fn evict(&self, index: &mut Index) -> Vec<String> {
+        let mut evicted = Vec::new();
+
+        while index.size > self.capacity.load(Ordering::Relaxed) {
+            match index.entries.pop_lru() {
+                Some((name, size)) => {
+                    index.size -= size;
+                    index.unread.remove(&name);
+                    evicted.push(name);
+                }
+                None => break,
+            }
+        }
+
+        evicted
+    }
+
+    async fn remove_chunks(path: &Path, names: Vec<String>) {
+        for name in names {
+            trace!("evicting cached chunk {name}");
+
+            if let Err(err) = tokio::fs::remove_file(path.join(&name)).await {
+                warn!("failed to remove cached chunk {name}: {err}");
+            }
+        }
+    }
+
+    pub fn contains(&self, file_id: &str, chunk_id: u32) -> bool {
+        let name = Self::chunk_name(file_id, chunk_id);
+        self.index.lock().unwrap().entries.contains(&name)
+    }
+
+    pub async fn get(&self, file_id: &str, chunk_id: u32) -> Option<Bytes> {
+        let name = Self::chunk_name(file_id, chunk_id);
+
+        {
+            let mut index = self.index.lock().unwrap();
+            index.entries.get(&name)?;
+            index.unread.remove(&name);
+        }
+
+        match tokio::fs::read(self.path.join(&name)).await {
+            Ok(data) => Some(data.into()),
+            Err(err) => {
+                warn!("failed to read cached chunk {name}: {err}");
+
+                let mut index = self.index.lock().unwrap();
+                if let Some(size) = index.entries.pop(&name) {
+                    index.size -= size;
+                }
+                index.unread.remove(&name);
+
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, file_id: &str, chunk_id: u32, data: &[u8]) {
+        let name = Self::chunk_name(file_id, chunk_id);
+        let size = data.len() as u64;
+
+        if size > self.capacity.load(Ordering::Relaxed)
+            || self.index.lock().unwrap().entries.contains(&name)
+        {
+            return;
+        }
+
+        // write to a temporary file first so that partially written chunks are never read
+        let temp = self
+            .path
+            .join(format!("{name}.{:016x}.tmp", thread_rng().gen::<u64>()));
+
+        let result = match tokio::fs::write(&temp, data).await {
+            Ok(()) => tokio::fs::rename(&temp, self.path.join(&name)).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            warn!("failed to cache chunk {name}: {err}");
+            let _ = tokio::fs::remove_file(&temp).await;
+            return;
+        }
+
+        trace!("cached chunk {name} of size {size}");
+
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+
+            if index.entries.put(name, size).is_none() {
+                index.size += size;
+            }
+
+            self.evict(&mut index)
+        };
+
+        Self::remove_chunks(&self.path, evicted).await;
+    }
+
+    /// Caches a chunk of an uploaded file, which is removed by [`Self::remove_unread`] unless it is read.
+    pub async fn put_unread(&self, file_id: &str, chunk_id: u32, data: &[u8]) {
+        self.put(file_id, chunk_id, data).await;
+
+        let name = Self::chunk_name(file_id, chunk_id);
+        let mut index = self.index.lock().unwrap();
+
+        if index.entries.contains(&name) {
+            index.unread.insert(name);
+        }
+    }
+
+    /// Removes all cached chunks of a remote file.
+    pub async fn remove_file(&self, file_id: &str) {
+        self.remove_matching(file_id, |_, _| true).await;
+    }
+
+    /// Removes cached chunks of an uploaded file that have not been read since they were cached.
+    pub async fn remove_unread(&self, file_id: &str) {
+        self.remove_matching(file_id, |index, name| index.unread.contains(name))
+            .await;
+    }
+
+    async fn remove_matching(&self, file_id: &str, filter: impl Fn(&Index, &String) -> bool) {
+        let prefix = format!("{file_id}.");
+
+        let removed = {
+            let mut index = self.index.lock().unwrap();
+
+            let names: Vec<_> = index
+                .entries
+                .iter()
+                .map(|(name, _)| name)
+                .filter(|name| name.starts_with(&prefix) && filter(&index, name))
+                .cloned()
+                .collect();
+
+            for name in &names {
+                if let Some(size) = index.entries.pop(name) {
+                    index.size -= size;
+                }
+                index.unread.remove(name);
+            }
+
+            names
+        };
+
+        Self::remove_chunks(&self.path, removed).await;
+    }
+}
+
+/// Reads a range of chunks of a remote file from the cache.
+pub fn read_stream(
+    cache: Arc<ChunkCache>,
+    file_id: String,
+    chunk_range: Range<u32>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static {
+    futures::stream::iter(chunk_range).then(move |chunk_id| {
+        let cache = cache.clone();
+        let file_id = file_id.clone();
+
+        async move {
+            cache.get(&file_id, chunk_id).await.ok_or_else(|| {
+                use std::io::{Error, ErrorKind};
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("chunk {chunk_id} was evicted from the cache"),
+                )
+            })
+        }
+    })
+}
+
+/// Writes chunks passing through the stream into the given caches in the background.
+/// The media cache is given with the number of chunks of the file, and only receives chunks at either end.
+pub fn write_stream<S>(
+    stream: S,
+    local: Option<Arc<ChunkCache>>,
+    shared: Option<Arc<SharedCache>>,
+    media: Option<(Arc<MediaCache>, u32)>,
+    file_id: String,
+    chunk_id: u32,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static,
+{
+    stream
+        .zip(futures::stream::iter(chunk_id..))
+        .map(move |(chunk, chunk_id)| {
+            let chunk = chunk?;
+
+            if let Some((ref cache, chunk_count)) = media {
+                if cache.caches_chunk(chunk_id, chunk_count) {
+                    cache.put(&file_id, chunk_id, chunk.clone());
+                }
+            }
+
+            let local = local.clone();
+            let shared = shared.clone();
+            let file_id = file_id.clone();
+            let data = chunk.clone();
+
+            tokio::spawn(
+                async move {
+                    if let Some(cache) = local {
+                        cache.put(&file_id, chunk_id, &data).await;
+                    }
+
+                    if let Some(cache) = shared {
+                        cache.put_chunk(&file_id, chunk_id, &data).await;
+                    }
+                }
+                .in_current_span(),
+            );
+
+            Ok(chunk)
+        })
+}
+
+/// Least recently used cache in memory of the first and last chunks of media files,
+/// where players read headers and indexes such as MP4 `moov` atoms before and while seeking.
+///
+/// Kept apart from [`ChunkCache`] so that streaming large files doesn't evict these chunks.
+/// Chunks are cached exactly as stored in Drive, so they are still encrypted and verified when read.
+#[derive(Debug)]
+pub struct MediaCache {
+    // number of chunks cached at each end of a file
+    edge_chunks: u32,
+    capacity: u64,
+    index: Mutex<MediaIndex>,
+}
+
+#[derive(Debug)]
+struct MediaIndex {
+    entries: LruCache<(String, u32), Bytes>,
+    size: u64,
+}
+
+impl MediaCache {
+    /// `edge_chunks` is the number of chunks cached at each end of a file,
+    /// and `capacity` is the maximum total size of cached chunks in bytes.
+    pub fn new(edge_chunks: u32, capacity: u64) -> Self {
+        Self {
+            edge_chunks,
+            capacity,
+            index: Mutex::new(MediaIndex {
+                entries: LruCache::unbounded(),
+                size: 0,
+            }),
+        }
+    }
+
+    /// Returns true if chunks of files of the content type are cached.
+    pub fn caches_type(content_type: &str) -> bool {
+        content_type.starts_with("video/") || content_type.starts_with("audio/")
+    }
+
+    /// Returns true if the chunk is at either end of a file with the given number of chunks.
+    pub fn caches_chunk(&self, chunk_id: u32, chunk_count: u32) -> bool {
+        chunk_id < self.edge_chunks || chunk_id >= chunk_count.saturating_sub(self.edge_chunks)
+    }
+
+    /// Returns a range of chunks of a remote file if all of them are cached.
+    pub fn get_chunks(&self, file_id: &str, chunk_range: Range<u32>) -> Option<Vec<Bytes>> {
+        let mut index = self.index.lock().unwrap();
+
+        chunk_range
+            .map(|chunk_id| index.entries.get(&(file_id.into(), chunk_id)).cloned())
+            .collect()
+    }
+
+    pub fn put(&self, file_id: &str, chunk_id: u32, data: Bytes) {
+        let size = data.len() as u64;
+
+        if size > self.capacity {
+            return;
+        }
+
+        let mut index = self.index.lock().unwrap();
+
+        if let Some(replaced) = index.entries.put((file_id.into(), chunk_id), data) {
+            index.size -= replaced.len() as u64;
+        }
+
+        index.size += size;
+
+        while index.size > self.capacity {
+            match index.entries.pop_lru() {
+                Some((_, data)) => index.size -= data.len() as u64,
+                None => break,
+            }
+        }
+    }
+
+    /// Removes all cached chunks of a remote file.
+    pub fn remove_file(&self, file_id: &str) {
+        let mut index = self.index.lock().unwrap();
+
+        let keys: Vec<_> = index
+            .entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(id, _)| id == file_id)
+            .cloned()
+            .collect();
+
+        for key in keys {
+            if let Some(data) = index.entries.pop(&key) {
+                index.size -= data.len() as u64;
+            }
+        }
+    }
+}
+
+/// Cache of file metadata and small chunks shared between instances through Redis.
+///
+/// Failures are logged and treated as cache misses so that an unavailable Redis server
+/// only degrades performance.
+#[derive(Debug)]
+pub struct SharedCache {
+    redis: Redis,
+    prefix: String,
+    metadata_ttl: u64,
+    chunk_ttl: u64,
+    max_chunk_size: u64,
+}
+
+impl SharedCache {
+    /// `max_chunk_size` is the maximum stored size of files whose chunks are cached;
+    /// zero disables chunk caching.
+    pub fn new(
+        redis: Redis,
+        prefix: String,
+        metadata_ttl: u64,
+        chunk_ttl: u64,
+        max_chunk_size: u64,
+    ) -> Self {
+        Self {
+            redis,
+            prefix,
+            metadata_ttl,
+            chunk_ttl,
+            max_chunk_size,
+        }
+    }
+
+    fn file_key(&self, key: i64) -> String {
+        format!("{}file:{key}", self.prefix)
+    }
+
+    fn chunk_key(&self, file_id: &str, chunk_id: u32) -> String {
+        format!("{}chunk:{file_id}:{chunk_id}", self.prefix)
+    }
+
+    fn invalidation_channel(&self) -> String {
+        format!("{}invalidate", self.prefix)
+    }
+
+    async fn set(&self, key: &str, value: &[u8], ttl: u64) {
+        let ttl = ttl.to_string();
+
+        if let Err(err) = self
+            .redis
+            .command(&[b"SET", key.as_bytes(), value, b"EX", ttl.as_bytes()])
+            .await
+        {
+            warn!("failed to cache {key}: {err}");
+        }
+    }
+
+    async fn delete(&self, keys: &[String]) {
+        let args: Vec<_> = std::iter::once(&b"DEL"[..])
+            .chain(keys.iter().map(|key| key.as_bytes()))
+            .collect();
+
+        if let Err(err) = self.redis.command(&args).await {
+            warn!("failed to remove {} cached key(s): {err}", keys.len());
+        }
+    }
+
+    /// Returns the cached metadata of a file. Cached files lack their secret and manifest.
+    pub async fn get_file(&self, key: i64) -> Option<File> {
+        let data = match self
+            .redis
+            .command(&[b"GET", self.file_key(key).as_bytes()])
+            .await
+        {
+            Ok(Value::Data(data)) => data,
+            Ok(_) => return None,
+            Err(err) => {
+                warn!("failed to get cached file {key}: {err}");
+                return None;
+            }
+        };
+
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub async fn put_file(&self, file: &File) {
+        // metadata encrypted at rest must not be cached in plaintext
+        if self.metadata_ttl == 0 || file.metadata_encrypted {
+            return;
+        }
+
+        // only metadata is served from the cache
+        let mut value = match serde_json::to_value(file) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+
+        value["secret"] = serde_json::json!([]);
+        value["manifest"] = serde_json::Value::Null;
+
+        if let Ok(data) = serde_json::to_vec(&value) {
+            self.set(&self.file_key(file.key), &data, self.metadata_ttl)
+                .await;
+        }
+    }
+
+    pub async fn remove_file(&self, key: i64) {
+        self.delete(&[self.file_key(key)]).await;
+    }
+
+    /// Whether the chunks of a remote file with the given stored size are cached.
+    pub fn caches_chunks(&self, stored_size: u64) -> bool {
+        stored_size <= self.max_chunk_size
+    }
+
+    /// Returns a range of chunks of a remote file if all of them are cached.
+    pub async fn get_chunks(&self, file_id: &str, chunk_range: Range<u32>) -> Option<Vec<Bytes>> {
+        let keys: Vec<_> = chunk_range
+            .map(|chunk_id| self.chunk_key(file_id, chunk_id))
+            .collect();
+
+        let args: Vec<_> = std::iter::once(&b"MGET"[..])
+            .chain(keys.iter().map(|key| key.as_bytes()))
+            .collect();
+
+        match self.redis.command(&args).await {
+            Ok(Value::Array(values)) => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Data(data) => Some(data.into()),
+                    _ => None,
+                })
+                .collect(),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("failed to get cached chunks of file '{file_id}': {err}");
+                None
+            }
+        }
+    }
+
+    pub async fn put_chunk(&self, file_id: &str, chunk_id: u32, data: &[u8]) {
+        self.set(&self.chunk_key(file_id, chunk_id), data, self.chunk_ttl)
+            .await;
+    }
+
+    /// Removes the cached chunks of a remote file and notifies other instances to do the same.
+    pub async fn remove_chunks(&self, file_id: &str, chunk_count: u32) {
+        if self.max_chunk_size != 0 {
+            let keys: Vec<_> = (0..chunk_count)
+                .map(|chunk_id| self.chunk_key(file_id, chunk_id))
+                .collect();
+
+            self.delete(&keys).await;
+        }
+
+        let channel = self.invalidation_channel();
+
+        match self
+            .redis
+            .command(&[b"PUBLISH", channel.as_bytes(), file_id.as_bytes()])
+            .await
+        {
+            Ok(Value::Int(count)) => {
+                trace!("published invalidation of file '{file_id}' to {count} instance(s)")
+            }
+            Ok(_) => {}
+            Err(err) => warn!("failed to publish invalidation of file '{file_id}': {err}"),
+        }
+    }
+
+    /// Subscribes to IDs of remote files whose chunks were removed by any instance.
+    pub async fn subscribe(&self) -> Result<Subscription, redis::Error> {
+        self.redis.subscribe(&self.invalidation_channel()).await
+    }
+}
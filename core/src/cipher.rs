@@ -0,0 +1,476 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::stream::BufferPool;
+use aes_gcm::Aes256Gcm;
+use bytes::Bytes;
+use chacha20poly1305::{
+    aead::{self, AeadInPlace, NewAead},
+    XChaCha20Poly1305,
+};
+use futures::{Stream, StreamExt};
+use rand::{thread_rng, RngCore};
+use std::{fmt::Display, str::FromStr, sync::Arc};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown cipher '{0}'")]
+    Unknown(String),
+}
+
+/// Algorithm used to encrypt file content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherKind {
+    XChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl CipherKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::XChaCha20Poly1305 => "xchacha20-poly1305",
+            Self::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+
+    fn nonce_size(self) -> usize {
+        match self {
+            Self::XChaCha20Poly1305 => 24,
+            Self::Aes256Gcm => 12,
+        }
+    }
+
+    pub fn secret_size(self) -> usize {
+        ChunkStreamCipher::KEY_SIZE + self.nonce_size()
+    }
+}
+
+impl FromStr for CipherKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xchacha20-poly1305" => Ok(Self::XChaCha20Poly1305),
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            _ => Err(Error::Unknown(s.into())),
+        }
+    }
+}
+
+impl Display for CipherKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Layout of encrypted file content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Chunk nonce is the file nonce with the chunk index added to its last 4 bytes.
+    V1,
+    /// STREAM construction; chunk nonce is a prefix of the file nonce followed by
+    /// the chunk index and a flag marking the last chunk, so truncation is detected.
+    V2,
+    /// Same as [Format::V2], additionally authenticating the chunk index and remote file ID
+    /// as associated data, so chunks cannot be transplanted between files.
+    V3,
+}
+
+impl Format {
+    pub const LATEST: Self = Self::V3;
+
+    pub fn from_version(version: i16) -> Option<Self> {
+        match version {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            3 => Some(Self::V3),
+            _ => None,
+        }
+    }
+
+    pub fn version(self) -> i16 {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+            Self::V3 => 3,
+        }
+    }
+}
+
+enum Algorithm {
+    XChaCha20Poly1305(XChaCha20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+pub struct ChunkStreamCipher {
+    algorithm: Algorithm,
+    format: Format,
+    nonce: Vec<u8>,
+    last_chunk_id: u32,
+    file_id: Vec<u8>,
+}
+
+// aead::Error doesn't seem to implement StdError??
+// this wrapper is a hack
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct CipherError(chacha20poly1305::aead::Error);
+
+impl ChunkStreamCipher {
+    pub const KEY_SIZE: usize = 32;
+    pub const TAG_SIZE: usize = 16;
+
+    pub fn gen_secret(kind: CipherKind) -> Vec<u8> {
+        let mut buffer = vec![0; kind.secret_size()];
+        thread_rng().fill_bytes(&mut buffer);
+        buffer
+    }
+
+    /// Returns `None` if the secret is of an invalid size for the cipher.
+    pub fn new(
+        kind: CipherKind,
+        format: Format,
+        secret: &[u8],
+        last_chunk_id: u32,
+        file_id: &str,
+    ) -> Option<Self> {
+        if secret.len() != kind.secret_size() {
+            return None;
+        }
+
+        let (key, nonce) = secret.split_at(Self::KEY_SIZE);
+        let key = key.into();
+
+        Some(Self {
+            algorithm: match kind {
+                CipherKind::XChaCha20Poly1305 => {
+                    Algorithm::XChaCha20Poly1305(XChaCha20Poly1305::new(key))
+                }
+                CipherKind::Aes256Gcm => Algorithm::Aes256Gcm(Box::new(Aes256Gcm::new(key))),
+            },
+            format,
+            nonce: nonce.to_vec(),
+            last_chunk_id,
+            file_id: file_id.as_bytes().to_vec(),
+        })
+    }
+
+    fn get_chunk_nonce(&self, chunk_id: u32) -> Vec<u8> {
+        let mut buffer = self.nonce.clone();
+
+        match self.format {
+            Format::V1 => {
+                // add chunk index to the last 4 bytes of nonce
+                let (_, suffix) = buffer.split_at_mut(self.nonce.len() - 4);
+                let x = u32::from_be_bytes((&*suffix).try_into().unwrap());
+                suffix.copy_from_slice(&x.wrapping_add(chunk_id).to_be_bytes());
+            }
+            Format::V2 | Format::V3 => {
+                // replace the last 5 bytes of nonce with chunk index and last chunk flag
+                let (_, suffix) = buffer.split_at_mut(self.nonce.len() - 5);
+                suffix[..4].copy_from_slice(&chunk_id.to_be_bytes());
+                suffix[4] = (chunk_id == self.last_chunk_id) as u8;
+            }
+        }
+
+        buffer
+    }
+
+    fn get_chunk_aad(&self, chunk_id: u32) -> Vec<u8> {
+        match self.format {
+            Format::V1 | Format::V2 => Vec::new(),
+            Format::V3 => [&chunk_id.to_be_bytes()[..], &self.file_id].concat(),
+        }
+    }
+
+    /// Encrypts a chunk into a buffer from `pool`, with the tag appended.
+    pub fn encrypt(
+        &self,
+        chunk_id: u32,
+        chunk: &[u8],
+        pool: &BufferPool,
+    ) -> Result<Bytes, CipherError> {
+        let nonce = self.get_chunk_nonce(chunk_id);
+        let aad = self.get_chunk_aad(chunk_id);
+
+        let mut buffer = pool.alloc(chunk.len() + Self::TAG_SIZE);
+        let (data, tag) = buffer.split_at_mut(chunk.len());
+        data.copy_from_slice(chunk);
+
+        tag.copy_from_slice(
+            &match self.algorithm {
+                Algorithm::XChaCha20Poly1305(ref cipher) => {
+                    cipher.encrypt_in_place_detached(nonce[..].into(), &aad, data)
+                }
+                Algorithm::Aes256Gcm(ref cipher) => {
+                    cipher.encrypt_in_place_detached(nonce[..].into(), &aad, data)
+                }
+            }
+            .map_err(CipherError)?,
+        );
+
+        Ok(buffer.freeze())
+    }
+
+    /// Decrypts a chunk with the tag appended into a buffer from `pool`.
+    pub fn decrypt(
+        &self,
+        chunk_id: u32,
+        chunk: &[u8],
+        pool: &BufferPool,
+    ) -> Result<Bytes, CipherError> {
+        let nonce = self.get_chunk_nonce(chunk_id);
+        let aad = self.get_chunk_aad(chunk_id);
+
+        let (data, tag) = chunk
+            .len()
+            .checked_sub(Self::TAG_SIZE)
+            .map(|len| chunk.split_at(len))
+            .ok_or(CipherError(aead::Error))?;
+
+        let mut buffer = pool.alloc(data.len());
+        buffer.copy_from_slice(data);
+
+        match self.algorithm {
+            Algorithm::XChaCha20Poly1305(ref cipher) => {
+                cipher.decrypt_in_place_detached(nonce[..].into(), &aad, &mut buffer, tag.into())
+            }
+            Algorithm::Aes256Gcm(ref cipher) => {
+                cipher.decrypt_in_place_detached(nonce[..].into(), &aad, &mut buffer, tag.into())
+            }
+        }
+        .map_err(CipherError)?;
+
+        Ok(buffer.freeze())
+    }
+}
+
+/// Maximum number of chunks being encrypted or decrypted concurrently per stream.
+const PIPELINE_DEPTH: usize = 4;
+
+pub fn encrypt_stream<S>(
+    stream: S,
+    cipher: ChunkStreamCipher,
+    chunk_id: u32,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static,
+{
+    transform_stream(stream, cipher, chunk_id, |cipher, chunk_id, buf, pool| {
+        let chunk = cipher.encrypt(chunk_id, &buf, pool)?;
+
+        trace!(
+            "encrypted chunk {chunk_id} of size {size}",
+            size = chunk.len() - ChunkStreamCipher::TAG_SIZE
+        );
+
+        Ok(chunk)
+    })
+}
+
+pub fn decrypt_stream<S>(
+    stream: S,
+    cipher: ChunkStreamCipher,
+    chunk_id: u32,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static,
+{
+    transform_stream(stream, cipher, chunk_id, |cipher, chunk_id, buf, pool| {
+        let chunk = cipher.decrypt(chunk_id, &buf, pool)?;
+
+        trace!(
+            "decrypted chunk {chunk_id} of size {size}",
+            size = chunk.len()
+        );
+
+        Ok(chunk)
+    })
+}
+
+/// Applies `f` to each chunk on the blocking thread pool so that the reactor isn't stalled by cipher work,
+/// keeping up to [PIPELINE_DEPTH] chunks in flight while preserving their order.
+fn transform_stream<S>(
+    stream: S,
+    cipher: ChunkStreamCipher,
+    chunk_id: u32,
+    f: fn(&ChunkStreamCipher, u32, Bytes, &BufferPool) -> Result<Bytes, CipherError>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static,
+{
+    use std::io::{Error, ErrorKind};
+
+    let cipher = Arc::new(cipher);
+    let pool = Arc::new(BufferPool::new(BufferPool::STREAM_SIZE));
+
+    stream
+        .zip(futures::stream::iter(chunk_id..))
+        .map(move |(buf, chunk_id)| {
+            let cipher = cipher.clone();
+            let pool = pool.clone();
+
+            async move {
+                let buf = buf?;
+
+                // entered on the blocking thread, which doesn't inherit the span of the stream
+                let span = error_span!("crypto", chunk_id);
+                let task = move || span.in_scope(|| f(&cipher, chunk_id, buf, &pool));
+
+                match tokio::task::spawn_blocking(task).await {
+                    Ok(Ok(chunk)) => Ok(chunk),
+                    Ok(Err(err)) => Err(Error::new(ErrorKind::InvalidData, err)),
+                    Err(err) => Err(Error::other(err)),
+                }
+            }
+        })
+        .buffered(PIPELINE_DEPTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE_ID: &str = "remote-file";
+
+    fn chunks() -> Vec<Bytes> {
+        vec![
+            Bytes::from_static(b"first chunk"),
+            Bytes::from_static(b"second chunk"),
+            Bytes::from_static(b"last"),
+        ]
+    }
+
+    fn new_cipher(
+        kind: CipherKind,
+        format: Format,
+        secret: &[u8],
+        file_id: &str,
+    ) -> ChunkStreamCipher {
+        ChunkStreamCipher::new(kind, format, secret, 2, file_id).unwrap()
+    }
+
+    /// Encrypts the test chunks, returning the secret and the encrypted chunks.
+    fn encrypt_chunks(kind: CipherKind, format: Format) -> (Vec<u8>, Vec<Bytes>) {
+        let secret = ChunkStreamCipher::gen_secret(kind);
+        let cipher = new_cipher(kind, format, &secret, FILE_ID);
+        let pool = BufferPool::new(BufferPool::STREAM_SIZE);
+
+        let encrypted = (0..)
+            .zip(chunks())
+            .map(|(chunk_id, chunk)| cipher.encrypt(chunk_id, &chunk, &pool).unwrap())
+            .collect();
+
+        (secret, encrypted)
+    }
+
+    fn kinds() -> [CipherKind; 2] {
+        [CipherKind::XChaCha20Poly1305, CipherKind::Aes256Gcm]
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for kind in kinds() {
+            for format in [Format::V1, Format::V2, Format::V3] {
+                let secret = ChunkStreamCipher::gen_secret(kind);
+
+                let encrypted: Vec<_> = encrypt_stream(
+                    futures::stream::iter(chunks().into_iter().map(Ok)),
+                    new_cipher(kind, format, &secret, FILE_ID),
+                    0,
+                )
+                .map(Result::unwrap)
+                .collect()
+                .await;
+
+                assert_ne!(encrypted, chunks());
+
+                let decrypted: Vec<_> = decrypt_stream(
+                    futures::stream::iter(encrypted.into_iter().map(Ok)),
+                    new_cipher(kind, format, &secret, FILE_ID),
+                    0,
+                )
+                .map(Result::unwrap)
+                .collect()
+                .await;
+
+                assert_eq!(decrypted, chunks(), "{kind} v{}", format.version());
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let pool = BufferPool::new(BufferPool::STREAM_SIZE);
+
+        for kind in kinds() {
+            for format in [Format::V2, Format::V3] {
+                let (secret, encrypted) = encrypt_chunks(kind, format);
+
+                // the last chunk flag of the file doesn't match a stream ending at the second chunk
+                let truncated = ChunkStreamCipher::new(kind, format, &secret, 1, FILE_ID).unwrap();
+                assert!(truncated.decrypt(0, &encrypted[0], &pool).is_ok());
+                assert!(truncated.decrypt(1, &encrypted[1], &pool).is_err());
+
+                // nor does a chunk cut short match its tag
+                let cipher = new_cipher(kind, format, &secret, FILE_ID);
+                let chunk = &encrypted[2];
+                assert!(cipher.decrypt(2, &chunk[..chunk.len() - 1], &pool).is_err());
+                assert!(cipher
+                    .decrypt(2, &chunk[..ChunkStreamCipher::TAG_SIZE - 1], &pool)
+                    .is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn swapped_chunks() {
+        let pool = BufferPool::new(BufferPool::STREAM_SIZE);
+
+        for kind in kinds() {
+            for format in [Format::V2, Format::V3] {
+                let (secret, encrypted) = encrypt_chunks(kind, format);
+                let cipher = new_cipher(kind, format, &secret, FILE_ID);
+
+                assert!(cipher.decrypt(0, &encrypted[1], &pool).is_err());
+                assert!(cipher.decrypt(1, &encrypted[0], &pool).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_chunk_id() {
+        let pool = BufferPool::new(BufferPool::STREAM_SIZE);
+
+        for kind in kinds() {
+            for format in [Format::V2, Format::V3] {
+                let (secret, encrypted) = encrypt_chunks(kind, format);
+                let cipher = new_cipher(kind, format, &secret, FILE_ID);
+
+                assert!(cipher.decrypt(1, &encrypted[1], &pool).is_ok());
+                assert!(cipher.decrypt(3, &encrypted[1], &pool).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_file_id() {
+        let pool = BufferPool::new(BufferPool::STREAM_SIZE);
+
+        for kind in kinds() {
+            // only authenticated as associated data since v3
+            let (secret, encrypted) = encrypt_chunks(kind, Format::V2);
+            let cipher = new_cipher(kind, Format::V2, &secret, "other-file");
+            assert!(cipher.decrypt(0, &encrypted[0], &pool).is_ok());
+
+            let (secret, encrypted) = encrypt_chunks(kind, Format::V3);
+            let cipher = new_cipher(kind, Format::V3, &secret, "other-file");
+            assert!(cipher.decrypt(0, &encrypted[0], &pool).is_err());
+        }
+    }
+}
@@ -0,0 +1,2951 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use self::config::DbConfigKey;
+use crate::{keys::MasterKey, rate_limit::WaitQueue};
+use chrono::NaiveDateTime;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{
+    postgres::PgPoolOptions, query, query_as, types::Json, Executor, FromRow, PgPool, Postgres,
+    Row, Transaction,
+};
+use std::{fmt, str::FromStr, time::Duration};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialize connection pool: {0}")]
+    PoolInit(sqlx::Error),
+
+    #[error("failed to begin transaction: {0}")]
+    TransactionBegin(sqlx::Error),
+
+    #[error("failed to commit transaction: {0}")]
+    TransactionCommit(sqlx::Error),
+
+    #[error("failed to ping database: {0}")]
+    Ping(sqlx::Error),
+
+    #[error("failed to create config table: {0}")]
+    ConfigTableCreate(sqlx::Error),
+
+    #[error("failed to get config: {0}")]
+    ConfigGet(sqlx::Error),
+
+    #[error("failed to set config: {0}")]
+    ConfigSet(sqlx::Error),
+
+    #[error("failed to de/serialize config value: {0}")]
+    ConfigSerde(serde_json::Error),
+
+    #[error("failed to apply migrations: {0}")]
+    Migration(sqlx::Error),
+
+    #[error("invalid migration '{0}'; not forward compatible with that version")]
+    MigrationVersionInvalid(u32),
+
+    #[error("database is at migration {0} of {MIGRATION_VERSION}")]
+    MigrationPending(u32),
+
+    #[error("failed to add drive: {0}")]
+    DriveAdd(sqlx::Error),
+
+    #[error("failed to get drive: {0}")]
+    DriveGet(sqlx::Error),
+
+    #[error("failed to add file: {0}")]
+    FileAdd(sqlx::Error),
+
+    #[error("failed to get file: {0}")]
+    FileGet(sqlx::Error),
+
+    #[error("failed to delete file: {0}")]
+    FileDelete(sqlx::Error),
+
+    #[error("failed to lock file: {0}")]
+    FileLock(sqlx::Error),
+
+    #[error("failed to add audit log entry: {0}")]
+    AuditAdd(sqlx::Error),
+
+    #[error("failed to get audit log entries: {0}")]
+    AuditGet(sqlx::Error),
+
+    #[error("failed to prune audit log: {0}")]
+    AuditPrune(sqlx::Error),
+
+    #[error("failed to update file statistics: {0}")]
+    FileStatsUpdate(sqlx::Error),
+
+    #[error("failed to update file secret: {0}")]
+    FileSecretUpdate(sqlx::Error),
+
+    #[error("failed to replace remote file: {0}")]
+    FileReplace(sqlx::Error),
+
+    #[error("failed to update file metadata: {0}")]
+    FileMetadataUpdate(sqlx::Error),
+
+    #[error("failed to encrypt file metadata")]
+    FileMetadataEncrypt,
+
+    #[error("failed to decrypt file metadata")]
+    FileMetadataDecrypt,
+
+    #[error("failed to update file upload state: {0}")]
+    FileSpoolUpdate(sqlx::Error),
+
+    #[error("failed to update storage usage: {0}")]
+    StorageUsageUpdate(sqlx::Error),
+
+    #[error("failed to reserve storage: {0}")]
+    StorageReserve(sqlx::Error),
+
+    #[error("failed to release storage: {0}")]
+    StorageRelease(sqlx::Error),
+
+    #[error("failed to add transfer: {0}")]
+    TransferAdd(sqlx::Error),
+
+    #[error("failed to get transfer: {0}")]
+    TransferGet(sqlx::Error),
+
+    #[error("failed to prune transfers: {0}")]
+    TransferPrune(sqlx::Error),
+
+    #[error("failed to update file visibility: {0}")]
+    FileVisibilityUpdate(sqlx::Error),
+
+    #[error("failed to add user: {0}")]
+    UserAdd(sqlx::Error),
+
+    #[error("failed to get user: {0}")]
+    UserGet(sqlx::Error),
+
+    #[error("failed to update user token: {0}")]
+    UserTokenUpdate(sqlx::Error),
+
+    #[error("failed to delete user: {0}")]
+    UserDelete(sqlx::Error),
+
+    #[error("failed to set alias: {0}")]
+    AliasSet(sqlx::Error),
+
+    #[error("failed to get alias: {0}")]
+    AliasGet(sqlx::Error),
+
+    #[error("failed to delete alias: {0}")]
+    AliasDelete(sqlx::Error),
+
+    #[error("failed to set object: {0}")]
+    ObjectSet(sqlx::Error),
+
+    #[error("failed to get object: {0}")]
+    ObjectGet(sqlx::Error),
+
+    #[error("failed to delete object: {0}")]
+    ObjectDelete(sqlx::Error),
+
+    #[error("failed to add collection: {0}")]
+    CollectionAdd(sqlx::Error),
+
+    #[error("failed to get collection: {0}")]
+    CollectionGet(sqlx::Error),
+
+    #[error("failed to delete collection: {0}")]
+    CollectionDelete(sqlx::Error),
+
+    #[error("failed to export rows: {0}")]
+    RowsExport(sqlx::Error),
+
+    #[error("failed to import rows: {0}")]
+    RowsImport(sqlx::Error),
+
+    #[error("invalid column name '{0}'")]
+    ColumnInvalid(String),
+
+    #[error("failed to get queued deletes: {0}")]
+    DeleteQueueGet(sqlx::Error),
+
+    #[error("failed to update delete queue: {0}")]
+    DeleteQueueUpdate(sqlx::Error),
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct Drive {
+    pub key: i32,
+    /// Drive API shared drive resource ID.
+    pub id: String,
+    /// Time of drive creation.
+    pub created_time: NaiveDateTime,
+}
+
+/// Shared drive along with the remote files stored in it.
+#[derive(Debug, FromRow)]
+pub struct DriveUsage {
+    pub key: i32,
+    pub id: String,
+    pub created_time: NaiveDateTime,
+    /// Number of remote files in the drive.
+    pub file_count: i64,
+    /// Total size of the remote files in the drive, including encryption overhead.
+    pub stored_size: i64,
+}
+
+/// Number of files of a content type and the total size of their content.
+#[derive(Debug, FromRow, Serialize)]
+pub struct ContentTypeStats {
+    /// Content type, or `None` for files whose metadata is encrypted.
+    pub content_type: Option<String>,
+    pub file_count: i64,
+    pub size: i64,
+}
+
+/// Number of successful operations recorded in the audit log.
+#[derive(Debug, Clone, Copy, FromRow, Serialize)]
+pub struct OperationCounts {
+    pub uploads: i64,
+    pub downloads: i64,
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct File {
+    pub key: i64,
+    /// Drive API file resource ID.
+    pub id: String,
+    /// Key of the containing drive.
+    pub drive_key: i32,
+    /// Original size before encryption.
+    pub size: i64,
+    /// File content type.
+    pub content_type: String,
+    /// Time of file creation.
+    pub created_time: NaiveDateTime,
+    /// Time of last file access.
+    pub accessed_time: NaiveDateTime,
+    /// Encrypted file secret for decryption.
+    pub secret: Vec<u8>,
+    /// Identifier of the master key wrapping the secret, if wrapped.
+    pub secret_key: Option<String>,
+    /// Number of times the file was downloaded.
+    pub download_count: i64,
+    /// Total number of bytes served from the file.
+    pub bytes_served: i64,
+    /// Number of downloads remaining before the file is deleted, or null if unlimited.
+    pub remaining_downloads: Option<i32>,
+    /// Original name of the file, if provided.
+    pub filename: Option<String>,
+    /// User-defined metadata as a JSON object.
+    pub metadata: Value,
+    /// SHA-256 digest of the original content.
+    pub sha256: Option<Vec<u8>>,
+    /// Algorithm used to encrypt the file.
+    pub cipher: String,
+    /// Version of the encrypted content format.
+    pub format: i16,
+    /// Concatenated SHA-256 hashes of the encrypted chunks.
+    pub manifest: Option<Vec<u8>>,
+    /// Merkle tree root of the chunk hashes.
+    pub manifest_root: Option<Vec<u8>>,
+    /// Time at which the remote file was last encrypted.
+    pub encrypted_time: NaiveDateTime,
+    /// Content type and filename are encrypted with the metadata key.
+    pub metadata_encrypted: bool,
+    /// Content is held in the local upload spool and is yet to be uploaded to Drive.
+    pub spooled: bool,
+    /// Whether the file can be downloaded without credentials, or `None` to follow the server default.
+    pub public: Option<bool>,
+    /// Namespace of the API keys that can access the file.
+    pub namespace: String,
+    /// Collection that the file was uploaded into, if any.
+    pub collection_key: Option<i32>,
+}
+
+impl File {
+    pub fn encryption(&self) -> Encryption {
+        match self.cipher.as_str() {
+            UNENCRYPTED => Encryption::None,
+            CLIENT_ENCRYPTED => Encryption::Client,
+            _ => Encryption::Server,
+        }
+    }
+
+    /// Whether the content is encrypted by the server.
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption() == Encryption::Server
+    }
+}
+
+/// Value of [`File::cipher`] for files whose content is stored as is.
+pub const UNENCRYPTED: &str = "none";
+
+/// Value of [`File::cipher`] for files whose content was encrypted by the client before upload.
+pub const CLIENT_ENCRYPTED: &str = "client";
+
+/// Value of [`File::namespace`] for files uploaded before namespaces or with API keys without a namespace.
+pub const DEFAULT_NAMESPACE: &str = "";
+
+/// Number of migrations applied by [`Db::migrate`], which must be bumped when adding a migration.
+pub const MIGRATION_VERSION: u32 = 28;
+
+/// Time after which space reserved for an upload is released even if the upload never finished.
+pub const STORAGE_RESERVATION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Table of the metadata that makes stored content recoverable, listed in the order in which they must be imported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataTable {
+    Drives,
+    Collections,
+    Files,
+}
+
+impl MetadataTable {
+    pub const ALL: [Self; 3] = [Self::Drives, Self::Collections, Self::Files];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Drives => "drives",
+            Self::Collections => "collections",
+            Self::Files => "files",
+        }
+    }
+}
+
+/// Party that encrypted the content of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encryption {
+    Server,
+    /// The content is not encrypted at all.
+    None,
+    /// The content is stored as uploaded, having been encrypted by the client.
+    Client,
+}
+
+/// File that is yet to be added.
+#[derive(Debug, Clone, Copy)]
+pub struct NewFile<'a> {
+    pub id: &'a str,
+    pub drive_key: i32,
+    pub size: i64,
+    pub content_type: &'a str,
+    pub cipher: &'a str,
+    pub format: i16,
+    pub secret: &'a [u8],
+    pub secret_key: Option<&'a str>,
+    pub remaining_downloads: Option<i32>,
+    pub filename: Option<&'a str>,
+    pub metadata: &'a Map<String, Value>,
+    pub sha256: &'a [u8],
+    pub manifest: Option<&'a [u8]>,
+    pub manifest_root: Option<&'a [u8]>,
+    pub spooled: bool,
+    pub public: Option<bool>,
+    pub namespace: &'a str,
+    pub collection_key: Option<i32>,
+}
+
+/// Re-encrypted remote file that is yet to replace the remote file of existing files.
+#[derive(Debug)]
+pub struct NewRemoteFile<'a> {
+    pub id: &'a str,
+    pub drive_key: i32,
+    pub cipher: &'a str,
+    pub format: i16,
+    pub secret: &'a [u8],
+    pub secret_key: Option<&'a str>,
+    pub manifest: Option<&'a [u8]>,
+    pub manifest_root: Option<&'a [u8]>,
+}
+
+/// Filter for listing files, newest files first unless sorted otherwise.
+#[derive(Debug, Default)]
+pub struct FileQuery {
+    /// Only return files whose metadata contains this JSON value.
+    pub metadata: Option<Value>,
+    /// Only return files with a key less than this.
+    pub before: Option<i64>,
+    pub limit: Option<u32>,
+    /// Only return files in this namespace.
+    pub namespace: Option<String>,
+    /// Only return files in this collection.
+    pub collection_key: Option<i32>,
+    /// Only return files with this content type, or with this prefix if it ends with '/'.
+    pub content_type: Option<String>,
+    /// Only return files whose filename starts with this, ignoring case.
+    pub filename: Option<String>,
+    /// Only return files whose metadata lists all of these tags in `tags`.
+    pub tags: Vec<String>,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub created_after: Option<NaiveDateTime>,
+    pub created_before: Option<NaiveDateTime>,
+    pub accessed_after: Option<NaiveDateTime>,
+    pub accessed_before: Option<NaiveDateTime>,
+    pub sort: FileSort,
+    pub ascending: bool,
+    /// Only return files after the last file of the previous page.
+    pub cursor: Option<FileCursor>,
+}
+
+impl FileQuery {
+    /// Returns the maximum number of files to return.
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(100).min(1000)
+    }
+}
+
+/// Column by which listed files are sorted, along with the key to break ties.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSort {
+    #[default]
+    Key,
+    Size,
+    Created,
+    Accessed,
+}
+
+impl FileSort {
+    fn column(self) -> &'static str {
+        match self {
+            FileSort::Key => "key",
+            FileSort::Size => "size",
+            FileSort::Created => "created_time",
+            FileSort::Accessed => "accessed_time",
+        }
+    }
+
+    fn column_type(self) -> &'static str {
+        match self {
+            FileSort::Key | FileSort::Size => "bigint",
+            FileSort::Created | FileSort::Accessed => "timestamp",
+        }
+    }
+}
+
+/// Position of the last file of a page of listed files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCursor {
+    /// Value of the sort column of the file, formatted as text.
+    value: String,
+    key: i64,
+}
+
+impl FileCursor {
+    pub fn new(file: &File, sort: FileSort) -> Self {
+        let value = match sort {
+            FileSort::Key => file.key.to_string(),
+            FileSort::Size => file.size.to_string(),
+            FileSort::Created => file.created_time.to_string(),
+            FileSort::Accessed => file.accessed_time.to_string(),
+        };
+
+        Self {
+            value,
+            key: file.key,
+        }
+    }
+}
+
+impl fmt::Display for FileCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cursor = format!("{},{}", self.value, self.key);
+        f.write_str(&base64::encode_config(cursor, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+impl FromStr for FileCursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cursor = base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(|_| ())?;
+        let cursor = String::from_utf8(cursor).map_err(|_| ())?;
+        let (value, key) = cursor.rsplit_once(',').ok_or(())?;
+
+        Ok(Self {
+            value: value.into(),
+            key: key.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+/// Download statistics accumulated for a file since the last update.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileStats {
+    pub download_count: i64,
+    pub bytes_served: i64,
+    /// Time of the last download, which becomes the access time of the file.
+    pub accessed_time: Option<NaiveDateTime>,
+}
+
+impl FileStats {
+    /// Adds statistics accumulated separately to these statistics.
+    pub fn merge(&mut self, other: &FileStats) {
+        self.download_count += other.download_count;
+        self.bytes_served += other.bytes_served;
+        self.accessed_time = self.accessed_time.max(other.accessed_time);
+    }
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub key: i64,
+    /// Time of the operation.
+    pub time: NaiveDateTime,
+    /// Kind of operation performed.
+    pub operation: String,
+    /// Key of the file operated on, if any.
+    pub file_key: Option<i64>,
+    /// Drive API file resource ID, if known.
+    pub file_id: Option<String>,
+    /// Address of the requesting client.
+    pub client_addr: Option<String>,
+    /// Subject of the certificate presented by the requesting client.
+    pub client_name: Option<String>,
+    /// Number of bytes transferred.
+    pub size: Option<i64>,
+    /// Start of the requested byte range, inclusive.
+    pub range_start: Option<i64>,
+    /// End of the requested byte range, exclusive.
+    pub range_end: Option<i64>,
+    /// HTTP status code of the response.
+    pub status: i16,
+}
+
+/// Audit log entry that is yet to be recorded.
+#[derive(Debug, Default)]
+pub struct AuditEvent {
+    pub operation: &'static str,
+    pub file_key: Option<i64>,
+    pub file_id: Option<String>,
+    pub client_addr: Option<String>,
+    pub client_name: Option<String>,
+    pub size: Option<i64>,
+    pub range_start: Option<i64>,
+    pub range_end: Option<i64>,
+    pub status: i16,
+}
+
+/// Filter for querying the audit log, newest entries first.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub operation: Option<String>,
+    pub file_key: Option<i64>,
+    /// Only return entries with a key less than this.
+    pub before: Option<i64>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct User {
+    pub key: i32,
+    /// Unique name of the person or application.
+    pub name: String,
+    /// Role determining the requests that the user is authorized for.
+    pub role: String,
+    /// Namespace of the files that the user can access.
+    pub namespace: String,
+    /// SHA-256 digest of the token that the user presents as an API key.
+    pub token_sha256: Vec<u8>,
+    /// Time of user creation.
+    pub created_time: NaiveDateTime,
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct Alias {
+    /// Path by which the file can be downloaded.
+    pub name: String,
+    /// Key of the aliased file.
+    pub file_key: i64,
+    /// Time at which the alias was last pointed to a file.
+    pub created_time: NaiveDateTime,
+}
+
+/// Object stored through the S3-compatible api.
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct S3Object {
+    /// Namespace of the credentials that stored the object.
+    pub namespace: String,
+    pub bucket: String,
+    /// Key of the object in the bucket.
+    pub name: String,
+    /// Key of the file holding the content of the object.
+    pub file_key: i64,
+    /// Time at which the object was last stored.
+    pub created_time: NaiveDateTime,
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct Collection {
+    pub key: i32,
+    /// Name of the collection, unique in its namespace.
+    pub name: String,
+    /// Namespace of the API keys that can access the collection.
+    pub namespace: String,
+    pub created_time: NaiveDateTime,
+}
+
+/// Unreferenced remote file waiting to be deleted from drive.
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct QueuedDelete {
+    /// Drive API file resource ID.
+    pub id: String,
+    /// Time at which the remote file was queued for deletion.
+    pub created_time: NaiveDateTime,
+    /// Number of failed attempts to delete the remote file.
+    pub attempts: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NewUser<'a> {
+    pub name: &'a str,
+    pub role: &'a str,
+    pub namespace: &'a str,
+    pub token_sha256: &'a [u8],
+}
+
+/// Key encrypting file metadata, wrapped by a master key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WrappedMetadataKey {
+    /// Identifier of the wrapping master key.
+    pub key_id: String,
+    /// Base64-encoded wrapped key.
+    pub wrapped: String,
+}
+
+#[derive(Debug)]
+pub struct Db {
+    pool: PgPool,
+    // operations waiting for a connection from the pool
+    queue: WaitQueue,
+    metadata_key: Option<MasterKey>,
+    encrypt_metadata: bool,
+    track_atime: bool,
+}
+
+impl Db {
+    pub fn new(connection: impl AsRef<str>) -> Result<Self, Error> {
+        Ok(Self {
+            pool: PgPoolOptions::new()
+                .max_connections(10)
+                .connect_lazy(connection.as_ref())
+                .map_err(Error::PoolInit)?,
+            queue: WaitQueue::default(),
+            metadata_key: None,
+            encrypt_metadata: false,
+            track_atime: true,
+        })
+    }
+
+    /// Returns the number of operations waiting for a connection, and the average time for which they waited.
+    pub fn connection_queue(&self) -> (usize, Duration) {
+        (self.queue.len(), self.queue.average_wait())
+    }
+
+    /// Sets whether downloads update the access time of files.
+    pub fn set_track_atime(&mut self, track: bool) {
+        self.track_atime = track;
+    }
+
+    /// Sets the key with which file metadata is decrypted, and also encrypted if `encrypt` is true.
+    pub fn set_metadata_key(&mut self, key: MasterKey, encrypt: bool) {
+        self.metadata_key = Some(key);
+        self.encrypt_metadata = encrypt;
+    }
+
+    /// Returns the encrypted content type and filename of a file to insert,
+    /// or `None` if metadata encryption is disabled.
+    fn encrypt_file_metadata(
+        &self,
+        file: &NewFile<'_>,
+    ) -> Result<Option<(String, Option<String>)>, Error> {
+        match self.metadata_key {
+            Some(ref key) if self.encrypt_metadata => Ok(Some((
+                encrypt_text(key, file.content_type)?,
+                file.filename
+                    .map(|filename| encrypt_text(key, filename))
+                    .transpose()?,
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Decrypts file metadata after retrieval.
+    fn decrypt_file_metadata(&self, mut file: File) -> Result<File, Error> {
+        if file.metadata_encrypted {
+            let key = self
+                .metadata_key
+                .as_ref()
+                .ok_or(Error::FileMetadataDecrypt)?;
+
+            file.content_type = decrypt_text(key, &file.content_type)?;
+            file.filename = match file.filename {
+                Some(ref filename) => Some(decrypt_text(key, filename)?),
+                None => None,
+            };
+
+            file.metadata_encrypted = false;
+        }
+
+        Ok(file)
+    }
+
+    fn decrypt_files_metadata(&self, files: Vec<File>) -> Result<Vec<File>, Error> {
+        files
+            .into_iter()
+            .map(|file| self.decrypt_file_metadata(file))
+            .collect()
+    }
+
+    async fn executor(&self) -> Result<DbExecutor<'_>, Error> {
+        let span = error_span!("db");
+
+        Ok(DbExecutor {
+            tx: self
+                .queue
+                .wait(self.pool.begin())
+                .await
+                .map_err(Error::TransactionBegin)?,
+            _span: span,
+        })
+    }
+
+    pub async fn migrate(&self) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.migrate().await?;
+        exec.commit().await
+    }
+
+    /// Checks that the database is reachable.
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.executor().await?.ping().await
+    }
+
+    /// Checks that all migrations known to this version have been applied.
+    pub async fn check_migrated(&self) -> Result<(), Error> {
+        let version = self
+            .executor()
+            .await?
+            .get_config(config::MigrationVersion)
+            .await?
+            .unwrap_or(0);
+
+        if version < MIGRATION_VERSION {
+            Err(Error::MigrationPending(version))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn add_drive(&self, id: impl AsRef<str>) -> Result<Drive, Error> {
+        let mut exec = self.executor().await?;
+        let drive = exec.add_drive(id.as_ref()).await?;
+        exec.commit().await?;
+        Ok(drive)
+    }
+
+    pub async fn get_drive_by_least_files(&self, max_files: u32) -> Result<Option<Drive>, Error> {
+        self.executor()
+            .await?
+            .get_drive_by_least_files(max_files)
+            .await
+    }
+
+    pub async fn add_file(&self, file: &NewFile<'_>) -> Result<File, Error> {
+        let mut exec = self.executor().await?;
+        let encrypted = self.encrypt_file_metadata(file)?;
+        let file = match encrypted {
+            Some((ref content_type, ref filename)) => NewFile {
+                content_type,
+                filename: filename.as_deref(),
+                ..*file
+            },
+            None => *file,
+        };
+
+        let file = exec.add_file(&file, encrypted.is_some()).await?;
+        exec.commit().await?;
+        self.decrypt_file_metadata(file)
+    }
+
+    pub async fn get_file_by_key(&self, key: i64) -> Result<Option<File>, Error> {
+        self.executor()
+            .await?
+            .get_file_by_key(key)
+            .await?
+            .map(|file| self.decrypt_file_metadata(file))
+            .transpose()
+    }
+
+    /// Counts a download of a file towards its download limit and updates its access time,
+    /// returning the updated file.
+    pub async fn count_file_download(&self, key: i64) -> Result<Option<File>, Error> {
+        let mut exec = self.executor().await?;
+        let file = exec.count_file_download(key, self.track_atime).await?;
+        exec.commit().await?;
+        file.map(|file| self.decrypt_file_metadata(file))
+            .transpose()
+    }
+
+    /// Adds a file that references the same remote file as an existing file.
+    /// Returns `None` if the remote file is no longer referenced by any file.
+    pub async fn add_file_reference(&self, file: &NewFile<'_>) -> Result<Option<File>, Error> {
+        let mut exec = self.executor().await?;
+        let encrypted = self.encrypt_file_metadata(file)?;
+        let file = match encrypted {
+            Some((ref content_type, ref filename)) => NewFile {
+                content_type,
+                filename: filename.as_deref(),
+                ..*file
+            },
+            None => *file,
+        };
+
+        let file = exec.add_file_reference(&file, encrypted.is_some()).await?;
+        exec.commit().await?;
+        file.map(|file| self.decrypt_file_metadata(file))
+            .transpose()
+    }
+
+    /// Deletes a file, additionally returning whether its remote file is no longer referenced.
+    /// Unreferenced remote files that aren't spooled are queued for deletion in the same transaction.
+    pub async fn delete_file_by_key(&self, key: i64) -> Result<Option<(File, bool)>, Error> {
+        let mut exec = self.executor().await?;
+        let file = exec.delete_file_by_key(key).await?;
+        exec.commit().await?;
+        file.map(|(file, unreferenced)| Ok((self.decrypt_file_metadata(file)?, unreferenced)))
+            .transpose()
+    }
+
+    pub async fn add_file_stats(&self, stats: &[(i64, FileStats)]) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.add_file_stats(stats, self.track_atime).await?;
+        exec.commit().await
+    }
+
+    /// Reserves space for an upload of `size` bytes into a namespace if the stored and reserved size stays within
+    /// the quota of all namespaces and that of each namespace, returning the key of the reservation,
+    /// or the quota that would be exceeded.
+    pub async fn reserve_storage(
+        &self,
+        namespace: &str,
+        size: u64,
+        quota: Option<u64>,
+        namespace_quota: Option<u64>,
+    ) -> Result<Result<i64, u64>, Error> {
+        let mut exec = self.executor().await?;
+        let reservation = exec
+            .reserve_storage(namespace, size, quota, namespace_quota)
+            .await?;
+        exec.commit().await?;
+        Ok(reservation)
+    }
+
+    /// Releases space reserved by [`Self::reserve_storage`] once the upload was stored or failed.
+    pub async fn release_storage(&self, key: i64) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.release_storage(key).await?;
+        exec.commit().await
+    }
+
+    /// Counts `size` bytes towards the transfers of a namespace on the current UTC day if they stay within
+    /// `allowance`, returning the number of bytes transferred that day, or `None` if it would be exceeded.
+    pub async fn add_transfer(
+        &self,
+        namespace: &str,
+        size: u64,
+        allowance: u64,
+    ) -> Result<Option<u64>, Error> {
+        let mut exec = self.executor().await?;
+        let transferred = exec.add_transfer(namespace, size, allowance).await?;
+        exec.commit().await?;
+        Ok(transferred)
+    }
+
+    /// Returns the number of bytes transferred by a namespace on the current UTC day.
+    pub async fn get_transfer(&self, namespace: &str) -> Result<u64, Error> {
+        self.executor().await?.get_transfer(namespace).await
+    }
+
+    /// Deletes the transfers of days before the current UTC day, returning the number of deleted rows.
+    pub async fn prune_transfers(&self) -> Result<u64, Error> {
+        let mut exec = self.executor().await?;
+        let count = exec.prune_transfers().await?;
+        exec.commit().await?;
+        Ok(count)
+    }
+
+    /// Returns any file with the content digest in a namespace, or in any namespace.
+    pub async fn get_file_by_sha256(
+        &self,
+        sha256: &[u8],
+        namespace: Option<&str>,
+    ) -> Result<Option<File>, Error> {
+        self.executor()
+            .await?
+            .get_file_by_sha256(sha256, namespace)
+            .await?
+            .map(|file| self.decrypt_file_metadata(file))
+            .transpose()
+    }
+
+    pub async fn get_files(&self, query: &FileQuery) -> Result<Vec<File>, Error> {
+        self.decrypt_files_metadata(self.executor().await?.get_files(query).await?)
+    }
+
+    /// Returns files whose secret is not wrapped by the given master key.
+    pub async fn get_files_by_other_secret_key(
+        &self,
+        secret_key: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<File>, Error> {
+        self.decrypt_files_metadata(
+            self.executor()
+                .await?
+                .get_files_by_other_secret_key(secret_key, limit)
+                .await?,
+        )
+    }
+
+    /// Replaces the secret of a file, unless it was changed since `old_secret` was read.
+    pub async fn set_file_secret(
+        &self,
+        key: i64,
+        old_secret: &[u8],
+        secret: &[u8],
+        secret_key: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.set_file_secret(key, old_secret, secret, secret_key)
+            .await?;
+        exec.commit().await
+    }
+
+    /// Returns files whose remote file was last encrypted before the given time, ordered by key.
+    pub async fn get_files_by_encrypted_time(
+        &self,
+        before: NaiveDateTime,
+        after_key: i64,
+        limit: u32,
+    ) -> Result<Vec<File>, Error> {
+        self.decrypt_files_metadata(
+            self.executor()
+                .await?
+                .get_files_by_encrypted_time(before, after_key, limit)
+                .await?,
+        )
+    }
+
+    /// Returns the usage of every drive, given the chunk size and authentication tag size
+    /// with which remote files are encrypted.
+    pub async fn get_drive_usage(
+        &self,
+        chunk_size: u64,
+        tag_size: u64,
+    ) -> Result<Vec<DriveUsage>, Error> {
+        self.executor()
+            .await?
+            .get_drive_usage(chunk_size, tag_size)
+            .await
+    }
+
+    pub async fn get_drive_by_key(&self, key: i32) -> Result<Option<Drive>, Error> {
+        self.executor().await?.get_drive_by_key(key).await
+    }
+
+    pub async fn get_drives(&self) -> Result<Vec<Drive>, Error> {
+        self.executor().await?.get_drives().await
+    }
+
+    /// Returns the IDs of the remote files in a shared drive that are referenced by files and were uploaded.
+    pub async fn get_remote_ids_by_drive(&self, drive_key: i32) -> Result<Vec<String>, Error> {
+        self.executor()
+            .await?
+            .get_remote_ids_by_drive(drive_key)
+            .await
+    }
+
+    /// Returns the IDs among the given remote file IDs that no file references and that aren't queued for deletion.
+    pub async fn get_unknown_remote_ids(&self, ids: &[String]) -> Result<Vec<String>, Error> {
+        self.executor().await?.get_unknown_remote_ids(ids).await
+    }
+
+    /// Returns the keys of the files referencing any of the given remote files.
+    pub async fn get_file_keys_by_remote_ids(&self, ids: &[String]) -> Result<Vec<i64>, Error> {
+        self.executor()
+            .await?
+            .get_file_keys_by_remote_ids(ids)
+            .await
+    }
+
+    /// Returns any file referencing a remote file.
+    pub async fn get_file_by_remote_id(&self, id: &str) -> Result<Option<File>, Error> {
+        self.executor()
+            .await?
+            .get_file_by_remote_id(id)
+            .await?
+            .map(|file| self.decrypt_file_metadata(file))
+            .transpose()
+    }
+
+    /// Marks all files referencing a spooled remote file as uploaded,
+    /// returning whether any file was updated.
+    pub async fn set_file_uploaded(&self, id: &str) -> Result<bool, Error> {
+        let mut exec = self.executor().await?;
+        let updated = exec.set_file_uploaded(id).await?;
+        exec.commit().await?;
+        Ok(updated)
+    }
+
+    /// Sets whether a file can be downloaded without credentials, returning the updated file.
+    pub async fn set_file_public(
+        &self,
+        key: i64,
+        public: Option<bool>,
+    ) -> Result<Option<File>, Error> {
+        let mut exec = self.executor().await?;
+        let file = exec.set_file_public(key, public).await?;
+        exec.commit().await?;
+        file.map(|file| self.decrypt_file_metadata(file))
+            .transpose()
+    }
+
+    /// Points all files referencing a remote file to another remote file, queueing the old one for deletion,
+    /// and returns whether any file was updated.
+    pub async fn replace_remote_file(
+        &self,
+        old_id: &str,
+        file: &NewRemoteFile<'_>,
+    ) -> Result<bool, Error> {
+        let mut exec = self.executor().await?;
+        let replaced = exec.replace_remote_file(old_id, file).await?;
+        exec.commit().await?;
+        Ok(replaced)
+    }
+
+    /// Replaces the content of a file with a new remote file, keeping its key, metadata and statistics,
+    /// unless its remote file is no longer `old_id` if given.
+    /// Returns the file before and after the replacement, and whether its old remote file is no longer referenced,
+    /// in which case it is queued for deletion unless it is spooled.
+    pub async fn replace_file_content(
+        &self,
+        key: i64,
+        old_id: Option<&str>,
+        file: &NewFile<'_>,
+    ) -> Result<Option<(File, File, bool)>, Error> {
+        let mut exec = self.executor().await?;
+        let encrypted = self.encrypt_file_metadata(file)?;
+        let file = match encrypted {
+            Some((ref content_type, ref filename)) => NewFile {
+                content_type,
+                filename: filename.as_deref(),
+                ..*file
+            },
+            None => *file,
+        };
+
+        let result = exec
+            .replace_file_content(key, old_id, &file, encrypted.is_some())
+            .await?;
+
+        exec.commit().await?;
+        result
+            .map(|(old, new, unreferenced)| {
+                Ok((
+                    self.decrypt_file_metadata(old)?,
+                    self.decrypt_file_metadata(new)?,
+                    unreferenced,
+                ))
+            })
+            .transpose()
+    }
+
+    /// Returns the number of files and the total size of their content.
+    pub async fn get_file_totals(&self) -> Result<(u64, u64), Error> {
+        self.executor().await?.get_file_totals().await
+    }
+
+    /// Returns the content types with the most content.
+    pub async fn get_content_type_stats(&self, limit: u32) -> Result<Vec<ContentTypeStats>, Error> {
+        self.executor().await?.get_content_type_stats(limit).await
+    }
+
+    /// Returns the number of successful uploads and downloads since the given time.
+    pub async fn get_operation_counts(
+        &self,
+        since: NaiveDateTime,
+    ) -> Result<OperationCounts, Error> {
+        self.executor().await?.get_operation_counts(since).await
+    }
+
+    pub async fn get_files_by_downloads(
+        &self,
+        ascending: bool,
+        limit: u32,
+        namespace: Option<&str>,
+    ) -> Result<Vec<File>, Error> {
+        self.decrypt_files_metadata(
+            self.executor()
+                .await?
+                .get_files_by_downloads(ascending, limit, namespace)
+                .await?,
+        )
+    }
+
+    pub async fn get_metadata_key(&self) -> Result<Option<WrappedMetadataKey>, Error> {
+        self.executor().await?.get_config(config::MetadataKey).await
+    }
+
+    /// Stores the metadata key unless one is already stored, returning the stored key.
+    pub async fn add_metadata_key(
+        &self,
+        key: &WrappedMetadataKey,
+    ) -> Result<WrappedMetadataKey, Error> {
+        let mut exec = self.executor().await?;
+        exec.add_config(config::MetadataKey, key).await?;
+        let key = exec.get_config(config::MetadataKey).await?;
+        exec.commit().await?;
+        key.ok_or(Error::ConfigGet(sqlx::Error::RowNotFound))
+    }
+
+    pub async fn set_metadata_key_config(&self, key: &WrappedMetadataKey) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.set_config(config::MetadataKey, key).await?;
+        exec.commit().await
+    }
+
+    /// Encrypts the metadata of files added before metadata encryption was enabled,
+    /// returning the number of files updated.
+    pub async fn encrypt_files_metadata(&self) -> Result<u64, Error> {
+        let key = match self.metadata_key {
+            Some(ref key) if self.encrypt_metadata => key,
+            _ => return Ok(0),
+        };
+
+        let mut count = 0;
+
+        loop {
+            let mut exec = self.executor().await?;
+            let files = exec.get_files_by_metadata_encrypted(false, 100).await?;
+
+            if files.is_empty() {
+                return Ok(count);
+            }
+
+            for (key_, content_type, filename) in files {
+                exec.set_file_metadata(
+                    key_,
+                    &encrypt_text(key, &content_type)?,
+                    filename
+                        .map(|filename| encrypt_text(key, &filename))
+                        .transpose()?
+                        .as_deref(),
+                )
+                .await?;
+
+                count += 1;
+            }
+
+            exec.commit().await?;
+        }
+    }
+
+    pub async fn add_audit_entry(&self, event: &AuditEvent) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.add_audit_entry(event).await?;
+        exec.commit().await
+    }
+
+    pub async fn get_audit_entries(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+        self.executor().await?.get_audit_entries(query).await
+    }
+
+    pub async fn prune_audit_log(&self, before: NaiveDateTime) -> Result<u64, Error> {
+        let mut exec = self.executor().await?;
+        let count = exec.prune_audit_log(before).await?;
+        exec.commit().await?;
+        Ok(count)
+    }
+
+    /// Queues a remote file that no file references for deletion.
+    pub async fn queue_delete(&self, id: &str) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.queue_delete(id).await?;
+        exec.commit().await
+    }
+
+    /// Returns remote files in the delete queue whose deletion is due, oldest first.
+    pub async fn get_queued_deletes(&self, limit: u32) -> Result<Vec<QueuedDelete>, Error> {
+        self.executor().await?.get_queued_deletes(limit).await
+    }
+
+    /// Removes a remote file from the delete queue after it was deleted from drive.
+    pub async fn remove_queued_delete(&self, id: &str) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.remove_queued_delete(id).await?;
+        exec.commit().await
+    }
+
+    /// Records a failed attempt to delete a queued remote file, deferring the next attempt.
+    pub async fn defer_queued_delete(&self, id: &str, error: &str) -> Result<(), Error> {
+        let mut exec = self.executor().await?;
+        exec.defer_queued_delete(id, error).await?;
+        exec.commit().await
+    }
+
+    /// Adds a user, or returns `None` if the name or token is taken.
+    pub async fn add_user(&self, user: &NewUser<'_>) -> Result<Option<User>, Error> {
+        let mut exec = self.executor().await?;
+        let user = exec.add_user(user).await?;
+        exec.commit().await?;
+        Ok(user)
+    }
+
+    pub async fn get_users(&self) -> Result<Vec<User>, Error> {
+        self.executor().await?.get_users().await
+    }
+
+    pub async fn get_user_by_token(&self, token_sha256: &[u8]) -> Result<Option<User>, Error> {
+        self.executor().await?.get_user_by_token(token_sha256).await
+    }
+
+    /// Returns whether any users exist.
+    pub async fn has_users(&self) -> Result<bool, Error> {
+        self.executor().await?.has_users().await
+    }
+
+    /// Replaces the token of a user, returning the updated user.
+    pub async fn set_user_token(
+        &self,
+        key: i32,
+        token_sha256: &[u8],
+    ) -> Result<Option<User>, Error> {
+        let mut exec = self.executor().await?;
+        let user = exec.set_user_token(key, token_sha256).await?;
+        exec.commit().await?;
+        Ok(user)
+    }
+
+    pub async fn delete_user(&self, key: i32) -> Result<Option<User>, Error> {
+        let mut exec = self.executor().await?;
+        let user = exec.delete_user(key).await?;
+        exec.commit().await?;
+        Ok(user)
+    }
+
+    /// Points an alias to a file, replacing the file it pointed to if it exists.
+    pub async fn set_alias(&self, name: &str, file_key: i64) -> Result<Alias, Error> {
+        let mut exec = self.executor().await?;
+        let alias = exec.set_alias(name, file_key).await?;
+        exec.commit().await?;
+        Ok(alias)
+    }
+
+    pub async fn get_alias(&self, name: &str) -> Result<Option<Alias>, Error> {
+        self.executor().await?.get_alias(name).await
+    }
+
+    /// Returns the aliases starting with a prefix that point to files in a namespace, ordered by name,
+    /// along with their files.
+    pub async fn get_aliased_files(
+        &self,
+        prefix: &str,
+        namespace: &str,
+        limit: u32,
+    ) -> Result<Vec<(String, File)>, Error> {
+        self.executor()
+            .await?
+            .get_aliased_files(prefix, namespace, limit)
+            .await?
+            .into_iter()
+            .map(|(name, file)| Ok((name, self.decrypt_file_metadata(file)?)))
+            .collect()
+    }
+
+    pub async fn delete_alias(&self, name: &str) -> Result<Option<Alias>, Error> {
+        let mut exec = self.executor().await?;
+        let alias = exec.delete_alias(name).await?;
+        exec.commit().await?;
+        Ok(alias)
+    }
+
+    /// Points an object to a file, returning the key of the file it pointed to if it existed.
+    pub async fn set_s3_object(
+        &self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+        file_key: i64,
+    ) -> Result<Option<i64>, Error> {
+        let mut exec = self.executor().await?;
+        let previous = exec
+            .set_s3_object(namespace, bucket, name, file_key)
+            .await?;
+        exec.commit().await?;
+        Ok(previous)
+    }
+
+    pub async fn get_s3_object(
+        &self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Option<S3Object>, Error> {
+        self.executor()
+            .await?
+            .get_s3_object(namespace, bucket, name)
+            .await
+    }
+
+    pub async fn delete_s3_object(
+        &self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Option<S3Object>, Error> {
+        let mut exec = self.executor().await?;
+        let object = exec.delete_s3_object(namespace, bucket, name).await?;
+        exec.commit().await?;
+        Ok(object)
+    }
+
+    /// Adds a collection, or returns `None` if the name is taken in the namespace.
+    pub async fn add_collection(
+        &self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<Option<Collection>, Error> {
+        let mut exec = self.executor().await?;
+        let collection = exec.add_collection(name, namespace).await?;
+        exec.commit().await?;
+        Ok(collection)
+    }
+
+    pub async fn get_collections(&self, namespace: &str) -> Result<Vec<Collection>, Error> {
+        self.executor().await?.get_collections(namespace).await
+    }
+
+    pub async fn get_collection(
+        &self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<Option<Collection>, Error> {
+        self.executor().await?.get_collection(name, namespace).await
+    }
+
+    /// Deletes a collection, keeping its files outside any collection.
+    pub async fn delete_collection(&self, key: i32) -> Result<Option<Collection>, Error> {
+        let mut exec = self.executor().await?;
+        let collection = exec.delete_collection(key).await?;
+        exec.commit().await?;
+        Ok(collection)
+    }
+
+    /// Returns the rows of a table with a key greater than `after_key` as JSON objects, ordered by key.
+    /// Columns are exported as stored, so file metadata may be encrypted.
+    pub async fn export_rows(
+        &self,
+        table: MetadataTable,
+        after_key: i64,
+        limit: u32,
+    ) -> Result<Vec<Map<String, Value>>, Error> {
+        self.executor()
+            .await?
+            .export_rows(table, after_key, limit)
+            .await
+    }
+
+    /// Inserts rows exported by [`Db::export_rows`], skipping rows that conflict with existing ones,
+    /// and returns the number of rows inserted. Columns missing from the rows take their default values.
+    pub async fn import_rows(
+        &self,
+        table: MetadataTable,
+        rows: &[Map<String, Value>],
+    ) -> Result<u64, Error> {
+        let mut exec = self.executor().await?;
+        let count = exec.import_rows(table, rows).await?;
+        exec.commit().await?;
+        Ok(count)
+    }
+}
+
+#[derive(Debug)]
+struct DbExecutor<'a> {
+    tx: Transaction<'a, Postgres>,
+    // times the transaction for the request until it is committed or dropped
+    _span: tracing::Span,
+}
+
+impl DbExecutor<'_> {
+    async fn commit(self) -> Result<(), Error> {
+        self.tx.commit().await.map_err(Error::TransactionCommit)
+    }
+
+    async fn ping(&mut self) -> Result<(), Error> {
+        query("select 1")
+            .execute(&mut self.tx)
+            .await
+            .map_err(Error::Ping)?;
+
+        Ok(())
+    }
+
+    async fn ensure_config_table(&mut self) -> Result<(), Error> {
+        query(
+            "create table if not exists config (
+                key integer primary key,
+                value text not null
+            )",
+        )
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::ConfigTableCreate)?;
+
+        Ok(())
+    }
+
+    async fn get_config<T: DbConfigKey>(&mut self, key: T) -> Result<Option<T::Type>, Error> {
+        let value: Option<(String,)> = query_as(
+            "select value from config
+            where key = $1",
+        )
+        .bind(key.value())
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::ConfigGet)?;
+
+        match value {
+            None => Ok(None),
+            Some((value,)) => Ok(serde_json::de::from_str(&value).map_err(Error::ConfigSerde)?),
+        }
+    }
+
+    async fn set_config<T: DbConfigKey>(&mut self, key: T, value: &T::Type) -> Result<(), Error> {
+        query(
+            "insert into config (key, value)
+            values ($1, $2)
+            on conflict (key)
+            do
+                update set value = $2",
+        )
+        .bind(key.value())
+        .bind(serde_json::ser::to_string(value).map_err(Error::ConfigSerde)?)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::ConfigSet)?;
+
+        Ok(())
+    }
+
+    async fn add_config<T: DbConfigKey>(&mut self, key: T, value: &T::Type) -> Result<(), Error> {
+        query(
+            "insert into config (key, value)
+            values ($1, $2)
+            on conflict (key)
+            do nothing",
+        )
+        .bind(key.value())
+        .bind(serde_json::ser::to_string(value).map_err(Error::ConfigSerde)?)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::ConfigSet)?;
+
+        Ok(())
+    }
+
+    async fn migrate(&mut self) -> Result<(), Error> {
+        self.ensure_config_table().await?;
+
+        let mut version = self
+            .get_config(config::MigrationVersion)
+            .await?
+            .unwrap_or(0);
+
+        loop {
+            let queries = match version {
+                0 => include_str!("sql/migration1.sql"),
+                1 => include_str!("sql/migration2.sql"),
+                2 => include_str!("sql/migration3.sql"),
+                3 => include_str!("sql/migration4.sql"),
+                4 => include_str!("sql/migration5.sql"),
+                5 => include_str!("sql/migration6.sql"),
+                6 => include_str!("sql/migration7.sql"),
+                7 => include_str!("sql/migration8.sql"),
+                8 => include_str!("sql/migration9.sql"),
+                9 => include_str!("sql/migration10.sql"),
+                10 => include_str!("sql/migration11.sql"),
+                11 => include_str!("sql/migration12.sql"),
+                12 => include_str!("sql/migration13.sql"),
+                13 => include_str!("sql/migration14.sql"),
+                14 => include_str!("sql/migration15.sql"),
+                15 => include_str!("sql/migration16.sql"),
+                16 => include_str!("sql/migration17.sql"),
+                17 => include_str!("sql/migration18.sql"),
+                18 => include_str!("sql/migration19.sql"),
+                19 => include_str!("sql/migration20.sql"),
+                20 => include_str!("sql/migration21.sql"),
+                21 => include_str!("sql/migration22.sql"),
+                22 => include_str!("sql/migration23.sql"),
+                23 => include_str!("sql/migration24.sql"),
+                24 => include_str!("sql/migration25.sql"),
+                25 => include_str!("sql/migration26.sql"),
+                26 => include_str!("sql/migration27.sql"),
+                27 => include_str!("sql/migration28.sql"),
+                MIGRATION_VERSION => break,
+                _ => return Err(Error::MigrationVersionInvalid(version)),
+            };
+
+            version += 1;
+            warn!("applying migration {version}");
+
+            // executed as one batch of statements without arguments, which postgres parses itself,
+            // so semicolons in comments and literals don't split statements
+            (&mut self.tx)
+                .execute(queries)
+                .await
+                .map_err(Error::Migration)?;
+        }
+
+        self.set_config(config::MigrationVersion, &version).await?;
+        Ok(())
+    }
+
+    async fn add_drive(&mut self, id: &str) -> Result<Drive, Error> {
+        query_as::<_, Drive>(
+            "insert into drives (id)
+            values ($1)
+            returning *",
+        )
+        .bind(id)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::DriveAdd)
+    }
+
+    async fn get_drive_by_least_files(&mut self, max_files: u32) -> Result<Option<Drive>, Error> {
+        query_as::<_, Drive>(
+            "with counts as (
+                select drive_key, count(distinct id) as count from files
+                group by drive_key
+                order by count asc
+            )
+            select drive.* from drives drive
+            left join counts count on
+                drive.key = count.drive_key
+            where coalesce(count, 0) <= $1
+            order by coalesce(count, 0) asc
+            limit 1",
+        )
+        .bind(max_files)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::DriveGet)
+    }
+
+    async fn reserve_storage(
+        &mut self,
+        namespace: &str,
+        size: u64,
+        quota: Option<u64>,
+        namespace_quota: Option<u64>,
+    ) -> Result<Result<i64, u64>, Error> {
+        // locking the usage of all namespaces serializes reservations,
+        // so that concurrent uploads can't both fit in the space left for one
+        let (total_stored,): (i64,) = query_as(
+            "select stored_size from storage_usage
+            where namespace is null
+            for update",
+        )
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::StorageReserve)?;
+
+        // uploads that never finished, as when the server stopped, would otherwise hold their space forever
+        query(
+            "delete from storage_reservations
+            where created_time < timezone('utc', now()) - $1 * interval '1 second'",
+        )
+        .bind(STORAGE_RESERVATION_TIMEOUT.as_secs_f64())
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::StorageReserve)?;
+
+        let (namespace_stored, total_reserved, namespace_reserved): (i64, i64, i64) = query_as(
+            "select
+                coalesce((select stored_size from storage_usage where namespace = $1), 0),
+                coalesce(sum(size), 0)::bigint,
+                coalesce(sum(size) filter (where namespace = $1), 0)::bigint
+            from storage_reservations",
+        )
+        .bind(namespace)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::StorageReserve)?;
+
+        let limits = [
+            (quota, total_stored + total_reserved),
+            (namespace_quota, namespace_stored + namespace_reserved),
+        ];
+
+        for (quota, used) in limits {
+            if let Some(quota) = quota {
+                if used as u64 + size > quota {
+                    return Ok(Err(quota));
+                }
+            }
+        }
+
+        let (key,): (i64,) = query_as(
+            "insert into storage_reservations (namespace, size)
+            values ($1, $2)
+            returning key",
+        )
+        .bind(namespace)
+        .bind(size as i64)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::StorageReserve)?;
+
+        Ok(Ok(key))
+    }
+
+    async fn release_storage(&mut self, key: i64) -> Result<(), Error> {
+        query(
+            "delete from storage_reservations
+            where key = $1",
+        )
+        .bind(key)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::StorageRelease)?;
+
+        Ok(())
+    }
+
+    async fn add_transfer(
+        &mut self,
+        namespace: &str,
+        size: u64,
+        allowance: u64,
+    ) -> Result<Option<u64>, Error> {
+        // checked in the same statement, so that concurrent transfers can't exceed the allowance together
+        let transferred: Option<(i64,)> = query_as(
+            "insert into transfer_usage (namespace, day, size)
+            select $1, timezone('utc', now())::date, $2
+            where $2 <= $3
+            on conflict (namespace, day) do update set
+                size = transfer_usage.size + excluded.size
+            where transfer_usage.size + excluded.size <= $3
+            returning size",
+        )
+        .bind(namespace)
+        .bind(size as i64)
+        .bind(allowance as i64)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::TransferAdd)?;
+
+        Ok(transferred.map(|(size,)| size as u64))
+    }
+
+    async fn get_transfer(&mut self, namespace: &str) -> Result<u64, Error> {
+        let transferred: Option<(i64,)> = query_as(
+            "select size from transfer_usage
+            where namespace = $1 and day = timezone('utc', now())::date",
+        )
+        .bind(namespace)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::TransferGet)?;
+
+        Ok(transferred.map_or(0, |(size,)| size as u64))
+    }
+
+    async fn prune_transfers(&mut self) -> Result<u64, Error> {
+        Ok(query(
+            "delete from transfer_usage
+            where day < timezone('utc', now())::date",
+        )
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::TransferPrune)?
+        .rows_affected())
+    }
+
+    /// Updates the storage usage after a reference to a remote file was added or removed,
+    /// so that each remote file is counted once while it is referenced.
+    /// Changes to the references of the remote file must be serialized.
+    async fn update_storage_usage(
+        &mut self,
+        id: &str,
+        namespace: &str,
+        size: i64,
+        added: bool,
+    ) -> Result<(), Error> {
+        let (total, namespaced): (i64, i64) = query_as(
+            "select count(*), count(*) filter (where namespace = $2) from files
+            where id = $1",
+        )
+        .bind(id)
+        .bind(namespace)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::StorageUsageUpdate)?;
+
+        // the remote file starts counting with its first reference and stops with its last
+        let (references, size) = if added { (1, size) } else { (0, -size) };
+
+        if total == references {
+            query(
+                "update storage_usage set
+                    stored_size = stored_size + $1
+                where namespace is null",
+            )
+            .bind(size)
+            .execute(&mut self.tx)
+            .await
+            .map_err(Error::StorageUsageUpdate)?;
+        }
+
+        if namespaced == references {
+            query(
+                "insert into storage_usage (namespace, stored_size)
+                values ($1, $2)
+                on conflict (namespace) where namespace is not null do update set
+                    stored_size = storage_usage.stored_size + excluded.stored_size",
+            )
+            .bind(namespace)
+            .bind(size)
+            .execute(&mut self.tx)
+            .await
+            .map_err(Error::StorageUsageUpdate)?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_drive_usage(
+        &mut self,
+        chunk_size: u64,
+        tag_size: u64,
+    ) -> Result<Vec<DriveUsage>, Error> {
+        // remote files shared by several files are counted once
+        query_as::<_, DriveUsage>(
+            "select
+                drive.key,
+                drive.id,
+                drive.created_time,
+                count(remote.id) as file_count,
+                coalesce(sum(
+                    case when remote.cipher in ($3, $4) then remote.size
+                    else remote.size + (greatest(remote.size - 1, 0) / $1 + 1) * $2 end
+                ), 0)::bigint as stored_size
+            from drives drive
+            left join (
+                select distinct on (id) id, drive_key, size, cipher from files
+            ) remote on
+                drive.key = remote.drive_key
+            group by drive.key
+            order by drive.key",
+        )
+        .bind(chunk_size as i64)
+        .bind(tag_size as i64)
+        .bind(UNENCRYPTED)
+        .bind(CLIENT_ENCRYPTED)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::DriveGet)
+    }
+
+    async fn get_drive_by_key(&mut self, key: i32) -> Result<Option<Drive>, Error> {
+        query_as::<_, Drive>(
+            "select * from drives
+            where key = $1",
+        )
+        .bind(key)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::DriveGet)
+    }
+
+    async fn get_drives(&mut self) -> Result<Vec<Drive>, Error> {
+        query_as::<_, Drive>(
+            "select * from drives
+            order by key",
+        )
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::DriveGet)
+    }
+
+    async fn get_remote_ids_by_drive(&mut self, drive_key: i32) -> Result<Vec<String>, Error> {
+        let ids: Vec<(String,)> = query_as(
+            "select distinct id from files
+            where drive_key = $1 and not spooled",
+        )
+        .bind(drive_key)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)?;
+
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn get_unknown_remote_ids(&mut self, ids: &[String]) -> Result<Vec<String>, Error> {
+        let ids: Vec<(String,)> = query_as(
+            "select id from unnest($1::text[]) as remote (id)
+            where not exists (select 1 from files where files.id = remote.id)
+            and not exists (select 1 from delete_queue queued where queued.id = remote.id)",
+        )
+        .bind(ids)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)?;
+
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn get_file_keys_by_remote_ids(&mut self, ids: &[String]) -> Result<Vec<i64>, Error> {
+        let keys: Vec<(i64,)> = query_as(
+            "select key from files
+            where id = any($1)
+            order by key",
+        )
+        .bind(ids)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)?;
+
+        Ok(keys.into_iter().map(|(key,)| key).collect())
+    }
+
+    async fn add_file(
+        &mut self,
+        file: &NewFile<'_>,
+        metadata_encrypted: bool,
+    ) -> Result<File, Error> {
+        let file = query_as::<_, File>(
+            "insert into files (id, drive_key, size, content_type, cipher, format, secret, secret_key, remaining_downloads, filename, metadata, sha256, manifest, manifest_root, metadata_encrypted, spooled, public, namespace, collection_key)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)
+            returning *",
+        )
+        .bind(file.id)
+        .bind(file.drive_key)
+        .bind(file.size)
+        .bind(file.content_type)
+        .bind(file.cipher)
+        .bind(file.format)
+        .bind(file.secret)
+        .bind(file.secret_key)
+        .bind(file.remaining_downloads)
+        .bind(file.filename)
+        .bind(Json(file.metadata))
+        .bind(file.sha256)
+        .bind(file.manifest)
+        .bind(file.manifest_root)
+        .bind(metadata_encrypted)
+        .bind(file.spooled)
+        .bind(file.public)
+        .bind(file.namespace)
+        .bind(file.collection_key)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::FileAdd)?;
+
+        self.update_storage_usage(&file.id, &file.namespace, file.size, true)
+            .await?;
+
+        Ok(file)
+    }
+
+    async fn get_file_by_key(&mut self, key: i64) -> Result<Option<File>, Error> {
+        query_as::<_, File>(
+            "select * from files
+            where key = $1",
+        )
+        .bind(key)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn count_file_download(
+        &mut self,
+        key: i64,
+        update_atime: bool,
+    ) -> Result<Option<File>, Error> {
+        // remaining_downloads is decremented unconditionally;
+        // a negative count indicates the download limit was exceeded
+        query_as::<_, File>(
+            "update files set
+                accessed_time = case when $2 then timezone('utc', now()) else accessed_time end,
+                remaining_downloads = remaining_downloads - 1
+            where key = $1
+            returning *",
+        )
+        .bind(key)
+        .bind(update_atime)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn delete_file_by_key(&mut self, key: i64) -> Result<Option<(File, bool)>, Error> {
+        let file = match query_as::<_, File>(
+            "delete from files
+            where key = $1
+            returning *",
+        )
+        .bind(key)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::FileDelete)?
+        {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        self.lock_remote_file(&file.id).await?;
+        self.update_storage_usage(&file.id, &file.namespace, file.size, false)
+            .await?;
+
+        let (references,): (i64,) = query_as(
+            "select count(*) from files
+            where id = $1",
+        )
+        .bind(&file.id)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::FileDelete)?;
+
+        // spooled files are removed from the spool instead, which can't fail
+        if references == 0 && !file.spooled {
+            self.queue_delete(&file.id).await?;
+        }
+
+        Ok(Some((file, references == 0)))
+    }
+
+    async fn add_file_stats(
+        &mut self,
+        stats: &[(i64, FileStats)],
+        update_atime: bool,
+    ) -> Result<(), Error> {
+        query(
+            "update files set
+                download_count = files.download_count + stats.download_count,
+                bytes_served = files.bytes_served + stats.bytes_served,
+                accessed_time = greatest(files.accessed_time, stats.accessed_time)
+            from unnest($1::bigint[], $2::bigint[], $3::bigint[], $4::timestamp[])
+                as stats (key, download_count, bytes_served, accessed_time)
+            where files.key = stats.key",
+        )
+        .bind(stats.iter().map(|(key, _)| *key).collect::<Vec<_>>())
+        .bind(
+            stats
+                .iter()
+                .map(|(_, stats)| stats.download_count)
+                .collect::<Vec<_>>(),
+        )
+        .bind(
+            stats
+                .iter()
+                .map(|(_, stats)| stats.bytes_served)
+                .collect::<Vec<_>>(),
+        )
+        .bind(
+            stats
+                .iter()
+                .map(|(_, stats)| stats.accessed_time.filter(|_| update_atime))
+                .collect::<Vec<_>>(),
+        )
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::FileStatsUpdate)?;
+
+        Ok(())
+    }
+
+    async fn lock_remote_file(&mut self, id: &str) -> Result<(), Error> {
+        // serializes reference changes to the same remote file
+        query("select pg_advisory_xact_lock(hashtext($1))")
+            .bind(id)
+            .execute(&mut self.tx)
+            .await
+            .map_err(Error::FileLock)?;
+
+        Ok(())
+    }
+
+    async fn add_file_reference(
+        &mut self,
+        file: &NewFile<'_>,
+        metadata_encrypted: bool,
+    ) -> Result<Option<File>, Error> {
+        self.lock_remote_file(file.id).await?;
+
+        let exists: Option<(bool,)> = query_as(
+            "select spooled from files
+            where id = $1
+            limit 1",
+        )
+        .bind(file.id)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)?;
+
+        match exists {
+            // the remote file may have been uploaded from the spool in the meantime
+            Some((spooled,)) => Ok(Some(
+                self.add_file(&NewFile { spooled, ..*file }, metadata_encrypted)
+                    .await?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_file_by_remote_id(&mut self, id: &str) -> Result<Option<File>, Error> {
+        query_as::<_, File>(
+            "select * from files
+            where id = $1
+            limit 1",
+        )
+        .bind(id)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn set_file_uploaded(&mut self, id: &str) -> Result<bool, Error> {
+        self.lock_remote_file(id).await?;
+
+        let result = query(
+            "update files set
+                spooled = false
+            where id = $1 and spooled",
+        )
+        .bind(id)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::FileSpoolUpdate)?;
+
+        Ok(result.rows_affected() != 0)
+    }
+
+    async fn set_file_public(
+        &mut self,
+        key: i64,
+        public: Option<bool>,
+    ) -> Result<Option<File>, Error> {
+        query_as::<_, File>(
+            "update files set
+                public = $2
+            where key = $1
+            returning *",
+        )
+        .bind(key)
+        .bind(public)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::FileVisibilityUpdate)
+    }
+
+    async fn get_file_by_sha256(
+        &mut self,
+        sha256: &[u8],
+        namespace: Option<&str>,
+    ) -> Result<Option<File>, Error> {
+        query_as::<_, File>(
+            "select * from files
+            where sha256 = $1
+            and ($2::text is null or namespace = $2)
+            order by key desc
+            limit 1",
+        )
+        .bind(sha256)
+        .bind(namespace)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn get_files(&mut self, query: &FileQuery) -> Result<Vec<File>, Error> {
+        // sort column is interpolated from a fixed set of names
+        let column = query.sort.column();
+        let (order, after) = match query.ascending {
+            true => ("asc", ">"),
+            false => ("desc", "<"),
+        };
+
+        query_as::<_, File>(&format!(
+            "select * from files
+            where ($1::jsonb is null or metadata @> $1)
+            and ($2::bigint is null or key < $2)
+            and ($4::text is null or namespace = $4)
+            and ($5::integer is null or collection_key = $5)
+            and ($6::text is null or content_type = $6)
+            and ($15::text is null or content_type like $15)
+            and ($16::text is null or lower(filename) like $16)
+            and ($17::jsonb is null or metadata @> $17)
+            and ($7::bigint is null or size >= $7)
+            and ($8::bigint is null or size <= $8)
+            and ($9::timestamp is null or created_time >= $9)
+            and ($10::timestamp is null or created_time < $10)
+            and ($11::timestamp is null or accessed_time >= $11)
+            and ($12::timestamp is null or accessed_time < $12)
+            and ($13::text is null or ({column}, key) {after} ($13::text::{column_type}, $14))
+            order by {column} {order}, key {order}
+            limit $3",
+            column_type = query.sort.column_type(),
+        ))
+        .bind(query.metadata.as_ref().map(Json))
+        .bind(query.before)
+        .bind(query.limit() as i64)
+        .bind(&query.namespace)
+        .bind(query.collection_key)
+        .bind(
+            query
+                .content_type
+                .as_ref()
+                .filter(|content_type| !content_type.ends_with('/')),
+        )
+        .bind(query.min_size)
+        .bind(query.max_size)
+        .bind(query.created_after)
+        .bind(query.created_before)
+        .bind(query.accessed_after)
+        .bind(query.accessed_before)
+        .bind(query.cursor.as_ref().map(|cursor| &cursor.value))
+        .bind(query.cursor.as_ref().map(|cursor| cursor.key))
+        .bind(
+            query
+                .content_type
+                .as_ref()
+                .filter(|content_type| content_type.ends_with('/'))
+                .map(|prefix| format!("{}%", escape_like(prefix))),
+        )
+        .bind(
+            query
+                .filename
+                .as_ref()
+                .map(|prefix| format!("{}%", escape_like(&prefix.to_lowercase()))),
+        )
+        .bind((!query.tags.is_empty()).then(|| Json(serde_json::json!({ "tags": query.tags }))))
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn get_files_by_other_secret_key(
+        &mut self,
+        secret_key: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<File>, Error> {
+        query_as::<_, File>(
+            "select * from files
+            where secret_key is distinct from $1
+            order by key asc
+            limit $2",
+        )
+        .bind(secret_key)
+        .bind(limit as i64)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn set_file_secret(
+        &mut self,
+        key: i64,
+        old_secret: &[u8],
+        secret: &[u8],
+        secret_key: Option<&str>,
+    ) -> Result<(), Error> {
+        query(
+            "update files set
+                secret = $3,
+                secret_key = $4
+            where key = $1 and secret = $2",
+        )
+        .bind(key)
+        .bind(old_secret)
+        .bind(secret)
+        .bind(secret_key)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::FileSecretUpdate)?;
+
+        Ok(())
+    }
+
+    async fn get_files_by_metadata_encrypted(
+        &mut self,
+        metadata_encrypted: bool,
+        limit: u32,
+    ) -> Result<Vec<(i64, String, Option<String>)>, Error> {
+        query_as(
+            "select key, content_type, filename from files
+            where metadata_encrypted = $1
+            order by key asc
+            limit $2",
+        )
+        .bind(metadata_encrypted)
+        .bind(limit as i64)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn set_file_metadata(
+        &mut self,
+        key: i64,
+        content_type: &str,
+        filename: Option<&str>,
+    ) -> Result<(), Error> {
+        query(
+            "update files set
+                content_type = $2,
+                filename = $3,
+                metadata_encrypted = true
+            where key = $1",
+        )
+        .bind(key)
+        .bind(content_type)
+        .bind(filename)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::FileMetadataUpdate)?;
+
+        Ok(())
+    }
+
+    async fn get_files_by_encrypted_time(
+        &mut self,
+        before: NaiveDateTime,
+        after_key: i64,
+        limit: u32,
+    ) -> Result<Vec<File>, Error> {
+        query_as::<_, File>(
+            "select * from files
+            where encrypted_time < $1 and key > $2
+            order by key asc
+            limit $3",
+        )
+        .bind(before)
+        .bind(after_key)
+        .bind(limit as i64)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn replace_remote_file(
+        &mut self,
+        old_id: &str,
+        file: &NewRemoteFile<'_>,
+    ) -> Result<bool, Error> {
+        self.lock_remote_file(old_id).await?;
+
+        let result = query(
+            "update files set
+                id = $2,
+                drive_key = $3,
+                cipher = $4,
+                format = $5,
+                secret = $6,
+                secret_key = $7,
+                manifest = $8,
+                manifest_root = $9,
+                encrypted_time = timezone('utc', now())
+            where id = $1",
+        )
+        .bind(old_id)
+        .bind(file.id)
+        .bind(file.drive_key)
+        .bind(file.cipher)
+        .bind(file.format)
+        .bind(file.secret)
+        .bind(file.secret_key)
+        .bind(file.manifest)
+        .bind(file.manifest_root)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::FileReplace)?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        self.queue_delete(old_id).await?;
+        Ok(true)
+    }
+
+    async fn replace_file_content(
+        &mut self,
+        key: i64,
+        old_id: Option<&str>,
+        file: &NewFile<'_>,
+        metadata_encrypted: bool,
+    ) -> Result<Option<(File, File, bool)>, Error> {
+        let old = match query_as::<_, File>(
+            "select * from files
+            where key = $1 and ($2::text is null or id = $2)
+            for update",
+        )
+        .bind(key)
+        .bind(old_id)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::FileReplace)?
+        {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        self.lock_remote_file(&old.id).await?;
+
+        let new = query_as::<_, File>(
+            "update files set
+                id = $2,
+                drive_key = $3,
+                size = $4,
+                content_type = $5,
+                cipher = $6,
+                format = $7,
+                secret = $8,
+                secret_key = $9,
+                filename = $10,
+                sha256 = $11,
+                manifest = $12,
+                manifest_root = $13,
+                metadata_encrypted = $14,
+                spooled = $15,
+                encrypted_time = timezone('utc', now())
+            where key = $1
+            returning *",
+        )
+        .bind(key)
+        .bind(file.id)
+        .bind(file.drive_key)
+        .bind(file.size)
+        .bind(file.content_type)
+        .bind(file.cipher)
+        .bind(file.format)
+        .bind(file.secret)
+        .bind(file.secret_key)
+        .bind(file.filename)
+        .bind(file.sha256)
+        .bind(file.manifest)
+        .bind(file.manifest_root)
+        .bind(metadata_encrypted)
+        .bind(file.spooled)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::FileReplace)?;
+
+        self.update_storage_usage(&old.id, &old.namespace, old.size, false)
+            .await?;
+        self.update_storage_usage(&new.id, &new.namespace, new.size, true)
+            .await?;
+
+        let (references,): (i64,) = query_as(
+            "select count(*) from files
+            where id = $1",
+        )
+        .bind(&old.id)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::FileReplace)?;
+
+        if references == 0 && !old.spooled {
+            self.queue_delete(&old.id).await?;
+        }
+
+        Ok(Some((old, new, references == 0)))
+    }
+
+    async fn get_files_by_downloads(
+        &mut self,
+        ascending: bool,
+        limit: u32,
+        namespace: Option<&str>,
+    ) -> Result<Vec<File>, Error> {
+        query_as::<_, File>(if ascending {
+            "select * from files
+            where $2::text is null or namespace = $2
+            order by download_count asc, key asc
+            limit $1"
+        } else {
+            "select * from files
+            where $2::text is null or namespace = $2
+            order by download_count desc, key asc
+            limit $1"
+        })
+        .bind(limit as i64)
+        .bind(namespace)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn get_file_totals(&mut self) -> Result<(u64, u64), Error> {
+        let (count, size): (i64, i64) =
+            query_as("select count(*), coalesce(sum(size), 0)::bigint from files")
+                .fetch_one(&mut self.tx)
+                .await
+                .map_err(Error::FileGet)?;
+
+        Ok((count as u64, size as u64))
+    }
+
+    async fn get_content_type_stats(&mut self, limit: u32) -> Result<Vec<ContentTypeStats>, Error> {
+        // encrypted content types differ for every file
+        query_as::<_, ContentTypeStats>(
+            "select
+                case when metadata_encrypted then null else content_type end as content_type,
+                count(*) as file_count,
+                coalesce(sum(size), 0)::bigint as size
+            from files
+            group by 1
+            order by size desc
+            limit $1",
+        )
+        .bind(limit as i64)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::FileGet)
+    }
+
+    async fn get_operation_counts(
+        &mut self,
+        since: NaiveDateTime,
+    ) -> Result<OperationCounts, Error> {
+        query_as::<_, OperationCounts>(
+            "select
+                count(*) filter (where operation = 'upload') as uploads,
+                count(*) filter (where operation = 'download') as downloads
+            from audit_log
+            where time >= $1 and status < 400",
+        )
+        .bind(since)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::AuditGet)
+    }
+
+    async fn add_audit_entry(&mut self, event: &AuditEvent) -> Result<(), Error> {
+        query(
+            "insert into audit_log (operation, file_key, file_id, client_addr, size, range_start, range_end, status, client_name)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(event.operation)
+        .bind(event.file_key)
+        .bind(&event.file_id)
+        .bind(&event.client_addr)
+        .bind(event.size)
+        .bind(event.range_start)
+        .bind(event.range_end)
+        .bind(event.status)
+        .bind(&event.client_name)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::AuditAdd)?;
+
+        Ok(())
+    }
+
+    async fn get_audit_entries(&mut self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+        query_as::<_, AuditEntry>(
+            "select * from audit_log
+            where ($1::text is null or operation = $1)
+            and ($2::bigint is null or file_key = $2)
+            and ($3::bigint is null or key < $3)
+            order by key desc
+            limit $4",
+        )
+        .bind(&query.operation)
+        .bind(query.file_key)
+        .bind(query.before)
+        .bind(query.limit.unwrap_or(100).min(1000) as i64)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::AuditGet)
+    }
+
+    async fn prune_audit_log(&mut self, before: NaiveDateTime) -> Result<u64, Error> {
+        Ok(query(
+            "delete from audit_log
+            where time < $1",
+        )
+        .bind(before)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::AuditPrune)?
+        .rows_affected())
+    }
+
+    async fn queue_delete(&mut self, id: &str) -> Result<(), Error> {
+        query(
+            "insert into delete_queue (id)
+            values ($1)
+            on conflict do nothing",
+        )
+        .bind(id)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::DeleteQueueUpdate)?;
+
+        Ok(())
+    }
+
+    async fn get_queued_deletes(&mut self, limit: u32) -> Result<Vec<QueuedDelete>, Error> {
+        query_as::<_, QueuedDelete>(
+            "select id, created_time, attempts from delete_queue
+            where retry_time <= timezone('utc', now())
+            order by retry_time
+            limit $1",
+        )
+        .bind(limit as i64)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::DeleteQueueGet)
+    }
+
+    async fn remove_queued_delete(&mut self, id: &str) -> Result<(), Error> {
+        query(
+            "delete from delete_queue
+            where id = $1",
+        )
+        .bind(id)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::DeleteQueueUpdate)?;
+
+        Ok(())
+    }
+
+    async fn defer_queued_delete(&mut self, id: &str, error: &str) -> Result<(), Error> {
+        // back off exponentially from a minute up to a day between attempts
+        query(
+            "update delete_queue set
+                attempts = attempts + 1,
+                retry_time = timezone('utc', now())
+                    + least(interval '1 minute' * power(2, least(attempts, 20)), interval '1 day'),
+                last_error = $2
+            where id = $1",
+        )
+        .bind(id)
+        .bind(error)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::DeleteQueueUpdate)?;
+
+        Ok(())
+    }
+
+    async fn add_user(&mut self, user: &NewUser<'_>) -> Result<Option<User>, Error> {
+        query_as::<_, User>(
+            "insert into users (name, role, namespace, token_sha256)
+            values ($1, $2, $3, $4)
+            on conflict do nothing
+            returning *",
+        )
+        .bind(user.name)
+        .bind(user.role)
+        .bind(user.namespace)
+        .bind(user.token_sha256)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::UserAdd)
+    }
+
+    async fn get_users(&mut self) -> Result<Vec<User>, Error> {
+        query_as::<_, User>(
+            "select * from users
+            order by key",
+        )
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::UserGet)
+    }
+
+    async fn has_users(&mut self) -> Result<bool, Error> {
+        let (exists,): (bool,) = query_as("select exists (select from users)")
+            .fetch_one(&mut self.tx)
+            .await
+            .map_err(Error::UserGet)?;
+
+        Ok(exists)
+    }
+
+    async fn get_user_by_token(&mut self, token_sha256: &[u8]) -> Result<Option<User>, Error> {
+        query_as::<_, User>(
+            "select * from users
+            where token_sha256 = $1",
+        )
+        .bind(token_sha256)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::UserGet)
+    }
+
+    async fn set_user_token(
+        &mut self,
+        key: i32,
+        token_sha256: &[u8],
+    ) -> Result<Option<User>, Error> {
+        query_as::<_, User>(
+            "update users set
+                token_sha256 = $2
+            where key = $1
+            returning *",
+        )
+        .bind(key)
+        .bind(token_sha256)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::UserTokenUpdate)
+    }
+
+    async fn delete_user(&mut self, key: i32) -> Result<Option<User>, Error> {
+        query_as::<_, User>(
+            "delete from users
+            where key = $1
+            returning *",
+        )
+        .bind(key)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::UserDelete)
+    }
+
+    async fn set_alias(&mut self, name: &str, file_key: i64) -> Result<Alias, Error> {
+        query_as::<_, Alias>(
+            "insert into aliases (name, file_key)
+            values ($1, $2)
+            on conflict (name) do update set
+                file_key = excluded.file_key,
+                created_time = excluded.created_time
+            returning *",
+        )
+        .bind(name)
+        .bind(file_key)
+        .fetch_one(&mut self.tx)
+        .await
+        .map_err(Error::AliasSet)
+    }
+
+    async fn get_alias(&mut self, name: &str) -> Result<Option<Alias>, Error> {
+        query_as::<_, Alias>(
+            "select * from aliases
+            where name = $1",
+        )
+        .bind(name)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::AliasGet)
+    }
+
+    async fn get_aliased_files(
+        &mut self,
+        prefix: &str,
+        namespace: &str,
+        limit: u32,
+    ) -> Result<Vec<(String, File)>, Error> {
+        let rows = query(
+            "select aliases.name as alias_name, files.* from aliases
+            join files on files.key = aliases.file_key
+            where aliases.name like $1 and files.namespace = $2
+            order by aliases.name asc
+            limit $3",
+        )
+        .bind(format!("{}%", escape_like(prefix)))
+        .bind(namespace)
+        .bind(limit as i64)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::AliasGet)?;
+
+        rows.iter()
+            .map(|row| Ok((row.try_get("alias_name")?, File::from_row(row)?)))
+            .collect::<Result<_, sqlx::Error>>()
+            .map_err(Error::AliasGet)
+    }
+
+    async fn delete_alias(&mut self, name: &str) -> Result<Option<Alias>, Error> {
+        query_as::<_, Alias>(
+            "delete from aliases
+            where name = $1
+            returning *",
+        )
+        .bind(name)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::AliasDelete)
+    }
+
+    async fn set_s3_object(
+        &mut self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+        file_key: i64,
+    ) -> Result<Option<i64>, Error> {
+        // the previous file is locked so that concurrent replacements each see the file they replaced
+        let previous: Option<(i64,)> = query_as(
+            "select file_key from s3_objects
+            where namespace = $1 and bucket = $2 and name = $3
+            for update",
+        )
+        .bind(namespace)
+        .bind(bucket)
+        .bind(name)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::ObjectSet)?;
+
+        query(
+            "insert into s3_objects (namespace, bucket, name, file_key)
+            values ($1, $2, $3, $4)
+            on conflict (namespace, bucket, name) do update set
+                file_key = excluded.file_key,
+                created_time = excluded.created_time",
+        )
+        .bind(namespace)
+        .bind(bucket)
+        .bind(name)
+        .bind(file_key)
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::ObjectSet)?;
+
+        Ok(previous.map(|(key,)| key))
+    }
+
+    async fn get_s3_object(
+        &mut self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Option<S3Object>, Error> {
+        query_as::<_, S3Object>(
+            "select * from s3_objects
+            where namespace = $1 and bucket = $2 and name = $3",
+        )
+        .bind(namespace)
+        .bind(bucket)
+        .bind(name)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::ObjectGet)
+    }
+
+    async fn delete_s3_object(
+        &mut self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Option<S3Object>, Error> {
+        query_as::<_, S3Object>(
+            "delete from s3_objects
+            where namespace = $1 and bucket = $2 and name = $3
+            returning *",
+        )
+        .bind(namespace)
+        .bind(bucket)
+        .bind(name)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::ObjectDelete)
+    }
+
+    async fn add_collection(
+        &mut self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<Option<Collection>, Error> {
+        query_as::<_, Collection>(
+            "insert into collections (name, namespace)
+            values ($1, $2)
+            on conflict do nothing
+            returning *",
+        )
+        .bind(name)
+        .bind(namespace)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::CollectionAdd)
+    }
+
+    async fn get_collections(&mut self, namespace: &str) -> Result<Vec<Collection>, Error> {
+        query_as::<_, Collection>(
+            "select * from collections
+            where namespace = $1
+            order by name",
+        )
+        .bind(namespace)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::CollectionGet)
+    }
+
+    async fn get_collection(
+        &mut self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<Option<Collection>, Error> {
+        query_as::<_, Collection>(
+            "select * from collections
+            where namespace = $2 and name = $1",
+        )
+        .bind(name)
+        .bind(namespace)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::CollectionGet)
+    }
+
+    async fn delete_collection(&mut self, key: i32) -> Result<Option<Collection>, Error> {
+        query_as::<_, Collection>(
+            "delete from collections
+            where key = $1
+            returning *",
+        )
+        .bind(key)
+        .fetch_optional(&mut self.tx)
+        .await
+        .map_err(Error::CollectionDelete)
+    }
+
+    async fn export_rows(
+        &mut self,
+        table: MetadataTable,
+        after_key: i64,
+        limit: u32,
+    ) -> Result<Vec<Map<String, Value>>, Error> {
+        let rows: Vec<(Json<Map<String, Value>>,)> = query_as(&format!(
+            "select to_jsonb(t) from {table} t
+            where key > $1
+            order by key asc
+            limit $2",
+            table = table.name()
+        ))
+        .bind(after_key)
+        .bind(limit as i64)
+        .fetch_all(&mut self.tx)
+        .await
+        .map_err(Error::RowsExport)?;
+
+        Ok(rows.into_iter().map(|(row,)| row.0).collect())
+    }
+
+    async fn import_rows(
+        &mut self,
+        table: MetadataTable,
+        rows: &[Map<String, Value>],
+    ) -> Result<u64, Error> {
+        let columns = match rows.first() {
+            Some(row) => row.keys().cloned().collect::<Vec<_>>(),
+            None => return Ok(0),
+        };
+
+        // column names can't be bound as parameters
+        if let Some(column) = columns.iter().find(|column| {
+            !column
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        }) {
+            return Err(Error::ColumnInvalid(column.clone()));
+        }
+
+        let columns = columns.join(", ");
+
+        let count = query(&format!(
+            "insert into {table} ({columns})
+            select {columns} from jsonb_populate_recordset(null::{table}, $1)
+            on conflict do nothing",
+            table = table.name()
+        ))
+        .bind(Json(rows))
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::RowsImport)?
+        .rows_affected();
+
+        // keys were inserted explicitly, so the sequence must be advanced past them
+        query(&format!(
+            "select setval(pg_get_serial_sequence('{table}', 'key'), max(key))
+            from {table}
+            having max(key) is not null",
+            table = table.name()
+        ))
+        .execute(&mut self.tx)
+        .await
+        .map_err(Error::RowsImport)?;
+
+        // imported files are counted all at once rather than as they are added
+        if table == MetadataTable::Files {
+            query("select storage_usage_refresh()")
+                .execute(&mut self.tx)
+                .await
+                .map_err(Error::RowsImport)?;
+        }
+
+        Ok(count)
+    }
+}
+
+fn encrypt_text(key: &MasterKey, text: &str) -> Result<String, Error> {
+    key.wrap(text.as_bytes())
+        .map(base64::encode)
+        .map_err(|_| Error::FileMetadataEncrypt)
+}
+
+fn decrypt_text(key: &MasterKey, text: &str) -> Result<String, Error> {
+    base64::decode(text)
+        .ok()
+        .and_then(|wrapped| key.unwrap(&wrapped).ok())
+        .and_then(|text| String::from_utf8(text).ok())
+        .ok_or(Error::FileMetadataDecrypt)
+}
+
+/// Escapes the wildcards of a `like` pattern.
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+mod config {
+    use super::*;
+
+    pub trait DbConfigKey {
+        type Type: Serialize + DeserializeOwned;
+        fn value(&self) -> u32;
+    }
+
+    macro_rules! define_key {
+        ($value:expr, $name:ident, $type:ty) => {
+            #[derive(Debug)]
+            pub struct $name;
+
+            impl DbConfigKey for $name {
+                type Type = $type;
+
+                fn value(&self) -> u32 {
+                    $value
+                }
+            }
+        };
+    }
+
+    define_key!(1, MigrationVersion, u32);
+    define_key!(2, MetadataKey, WrappedMetadataKey);
+}
@@ -0,0 +1,684 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::{
+    auth::Authenticator,
+    http::HttpConfig,
+    metrics::Metrics,
+    rate_limit::{RateLimit, WaitQueue},
+    stream::{throttle_stream, BandwidthLimiter},
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream::StreamExt, Stream, TryStreamExt};
+use governor::{
+    clock::QuantaClock,
+    state::{InMemoryState, NotKeyed},
+    RateLimiter,
+};
+use headers::{ContentLength, ContentRange, HeaderMapExt};
+use http::StatusCode;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use reqwest::{Body, Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    ops::Range,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialize http client: {0}")]
+    ClientInit(reqwest::Error),
+
+    #[error("failed to generate file id: {0}")]
+    FileIdGenerate(reqwest::Error),
+
+    #[error("no file id was generated")]
+    FileIdMissing,
+
+    #[error("failed to create file: {0}")]
+    FileCreate(reqwest::Error),
+
+    #[error("failed to serialize file metadata: {0}")]
+    FileMetaSerde(serde_json::Error),
+
+    #[error("failed to download file: {0}")]
+    FileGet(reqwest::Error),
+
+    #[error("requested file range [{0}, {1}), but response is out of bounds")]
+    FileRangeResponseInvalid(u64, u64),
+
+    #[error("failed to delete file: {0}")]
+    FileDelete(reqwest::Error),
+
+    #[error("failed to list files: {0}")]
+    FileList(reqwest::Error),
+
+    #[error("failed to get file: {0}")]
+    FileCheck(reqwest::Error),
+
+    #[error("failed to create shared drive: {0}")]
+    DriveCreate(reqwest::Error),
+
+    #[error("failed to get shared drive: {0}")]
+    DriveGet(reqwest::Error),
+
+    #[error("failed to get storage quota: {0}")]
+    QuotaGet(reqwest::Error),
+
+    #[error("{0}")]
+    Auth(crate::auth::Error),
+}
+
+#[derive(Debug)]
+pub struct Drive {
+    http: Client,
+    auth: Authenticator,
+    // limiters are replaced along with their limits when the limits change
+    request_limiter: RwLock<(RateLimit, Arc<DirectRateLimiter>)>,
+    upload_limiter: RwLock<(RateLimit, Arc<BandwidthLimiter>)>,
+    // requests waiting for the request limiter
+    queue: WaitQueue,
+    metrics: Metrics,
+}
+
+type DirectRateLimiter = RateLimiter<NotKeyed, InMemoryState, QuantaClock>;
+
+#[derive(Debug, Clone)]
+pub struct FolderHandle {
+    pub id: String,
+}
+
+impl FolderHandle {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self { id: id.into() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileHandle {
+    pub id: String,
+}
+
+impl FileHandle {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self { id: id.into() }
+    }
+}
+
+/// Storage quota of the account that owns the refresh token.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StorageQuota {
+    /// Storage limit in bytes, or `None` if storage is unlimited.
+    pub limit: Option<u64>,
+    /// Storage used in bytes.
+    pub usage: u64,
+    /// Storage remaining in bytes, or `None` if storage is unlimited.
+    pub remaining: Option<u64>,
+}
+
+/// File listed in a shared drive.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListedFile {
+    pub id: String,
+    pub created_time: DateTime<Utc>,
+}
+
+/// Page of files listed in a shared drive.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileList {
+    pub files: Vec<ListedFile>,
+    /// Token with which the next page is requested, or `None` if this is the last page.
+    pub next_page_token: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct FileResponse<S: Stream<Item = Result<Bytes, Error>>> {
+    pub stream: S,
+    pub range: Range<u64>,
+}
+
+impl Drive {
+    pub fn new(
+        http: HttpConfig,
+        auth: Authenticator,
+        request_limit: RateLimit,
+        upload_limit: RateLimit, // MiB/s
+        metrics: Metrics,
+    ) -> Result<Self, Error> {
+        let http = http.create_client().map_err(Error::ClientInit)?;
+        let request_limiter = Arc::new(RateLimiter::direct(request_limit.into()));
+        let upload_limiter = Arc::new(BandwidthLimiter::new(upload_limit, 1024 * 1024));
+
+        Ok(Self {
+            http,
+            auth,
+            request_limiter: RwLock::new((request_limit, request_limiter)),
+            upload_limiter: RwLock::new((upload_limit, upload_limiter)),
+            queue: WaitQueue::default(),
+            metrics,
+        })
+    }
+
+    /// Replaces the limits of requests and upload bandwidth if they changed.
+    /// Uploads in progress continue at the previous bandwidth limit.
+    pub fn set_limits(&self, request_limit: RateLimit, upload_limit: RateLimit) {
+        let mut request_limiter = self.request_limiter.write().unwrap();
+
+        if request_limiter.0 != request_limit {
+            *request_limiter = (
+                request_limit,
+                Arc::new(RateLimiter::direct(request_limit.into())),
+            );
+        }
+
+        let mut upload_limiter = self.upload_limiter.write().unwrap();
+
+        if upload_limiter.0 != upload_limit {
+            *upload_limiter = (
+                upload_limit,
+                Arc::new(BandwidthLimiter::new(upload_limit, 1024 * 1024)),
+            );
+        }
+    }
+
+    fn request_limiter(&self) -> Arc<DirectRateLimiter> {
+        self.request_limiter.read().unwrap().1.clone()
+    }
+
+    /// Waits until a request can be sent without exceeding the request limit.
+    async fn wait_for_request(&self) {
+        let start = Instant::now();
+        self.queue.wait(self.request_limiter().until_ready()).await;
+        self.metrics.time("drive.request_wait", start.elapsed());
+    }
+
+    /// Returns the number of requests waiting for the request limit,
+    /// and the estimated time until all of them are sent.
+    pub fn request_queue(&self) -> (usize, Duration) {
+        let len = self.queue.len();
+        let interval = self.request_limiter.read().unwrap().0.interval();
+
+        (len, self.queue.average_wait().max(interval * len as u32))
+    }
+
+    /// Sends a request to the Drive API, recording its latency, status and failures under the given operation name.
+    async fn send(
+        &self,
+        operation: &str,
+        request: RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        self.send_expecting(operation, request, None).await
+    }
+
+    /// Sends a request like [`Self::send`], but doesn't record an `expected` error status as a failure,
+    /// such as when a missing file is an answer rather than an error. The status is still returned as an error.
+    async fn send_expecting(
+        &self,
+        operation: &str,
+        request: RequestBuilder,
+        expected: Option<StatusCode>,
+    ) -> reqwest::Result<reqwest::Response> {
+        let _span = error_span!("drive");
+        let start = Instant::now();
+        let result = request.send().await;
+
+        self.metrics
+            .time(&format!("drive.{operation}.time"), start.elapsed());
+
+        // requests that failed without a response, such as on timeouts, have no status
+        if let Ok(ref response) = result {
+            let class = response.status().as_u16() / 100;
+            self.metrics
+                .count(&format!("drive.{operation}.responses.{class}xx"), 1);
+        }
+
+        let result = result.and_then(|response| response.error_for_status());
+
+        if let Err(ref err) = result {
+            if expected.is_none() || err.status() != expected {
+                self.metrics.count(&format!("drive.{operation}.errors"), 1);
+            }
+        }
+
+        result
+    }
+
+    /// Counts a request that is sent again after it failed, under the operation name given to [`Drive::send`].
+    pub fn record_retry(&self, operation: &str) {
+        self.metrics.count(&format!("drive.{operation}.retries"), 1);
+    }
+
+    /// Checks that an access token can be obtained, reusing the current token until it expires.
+    pub async fn check_auth(&self) -> Result<(), Error> {
+        self.auth.access_token().await.map_err(Error::Auth)?;
+        Ok(())
+    }
+
+    /// Reserves a file ID that can be used to create a file later.
+    pub async fn generate_file_id(&self) -> Result<FileHandle, Error> {
+        #[derive(Deserialize)]
+        struct Response {
+            ids: Vec<String>,
+        }
+
+        self.wait_for_request().await;
+
+        let Response { ids } = self
+            .send(
+                "generate_ids",
+                self.http
+                    .get("https://www.googleapis.com/drive/v3/files/generateIds")
+                    .query(&[("count", "1"), ("space", "drive"), ("type", "files")])
+                    .header(
+                        "authorization",
+                        self.auth.header().await.map_err(Error::Auth)?,
+                    ),
+            )
+            .await
+            .map_err(Error::FileIdGenerate)?
+            .json()
+            .await
+            .map_err(Error::FileIdGenerate)?;
+
+        ids.into_iter()
+            .next()
+            .map(FileHandle::new)
+            .ok_or(Error::FileIdMissing)
+    }
+
+    pub async fn create_file<S, E>(
+        &self,
+        file: &FileHandle,
+        name: impl AsRef<str>,
+        parent: FolderHandle,
+        size: u64,
+        content_type: impl AsRef<str>,
+        content: S,
+    ) -> Result<FileHandle, Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let name = name.as_ref();
+
+        // reqwest doesn't support 'multipart/related' so let's build it ourselves
+        // multipart boundary
+        let boundary = format!(
+            "----------{}",
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(50)
+                .map(char::from)
+                .collect::<String>()
+        );
+
+        let (body, length) = {
+            #[derive(Serialize)]
+            struct Request<'a, 'b, 'c> {
+                id: &'c str,
+                name: &'a str,
+                parents: [String; 1],
+                #[serde(rename = "mimeType")]
+                mime_type: &'b str,
+            }
+
+            // first part: json-serialized file metadata
+            // second part: media content
+            let meta = serde_json::ser::to_string(&Request {
+                id: &file.id,
+                name,
+                parents: [parent.id],
+                mime_type: content_type.as_ref(),
+            })
+            .map_err(Error::FileMetaSerde)?;
+
+            // RFC2112
+            let prefix: Bytes = format!(
+                "--{boundary}\r\n{meta_header}\r\n{meta}\r\n--{boundary}\r\n{media_header}\r\n",
+                meta_header = "content-type: application/json; charset=utf-8\r\n",
+                media_header = "content-type: application/octet-stream\r\n"
+            )
+            .into();
+            let suffix: Bytes = format!("\r\n--{boundary}--").into();
+
+            // construct complete body stream: prefix + media + suffix
+            let concat_len = prefix.len() as u64 + size + suffix.len() as u64;
+            let concat = futures::stream::once(async move { Ok(prefix) })
+                .chain(content)
+                .chain(futures::stream::once(async move { Ok(suffix) }));
+
+            (concat, concat_len)
+        };
+
+        #[derive(Deserialize)]
+        struct Response {
+            id: String,
+        }
+
+        let body = throttle_stream(body, self.upload_limiter.read().unwrap().1.clone());
+        self.wait_for_request().await;
+
+        info!("uploading new file '{name}', total size {length}");
+
+        let Response { id } = self
+            .send(
+                "files.create",
+                self.http
+                    .post("https://www.googleapis.com/upload/drive/v3/files")
+                    .query(&[("uploadType", "multipart"), ("supportsAllDrives", "true")])
+                    .header(
+                        "authorization",
+                        self.auth.header().await.map_err(Error::Auth)?,
+                    )
+                    .header(
+                        "content-type",
+                        format!("multipart/related; boundary={boundary}"),
+                    )
+                    .header("content-length", length)
+                    .body(Body::wrap_stream(body)),
+            )
+            .await
+            .map_err(Error::FileCreate)?
+            .json()
+            .await
+            .map_err(Error::FileCreate)?;
+
+        info!("file '{name}' upload complete");
+        self.metrics.count("drive.bytes_uploaded", size);
+
+        Ok(FileHandle::new(id))
+    }
+
+    pub async fn get_file(
+        &self,
+        file: &FileHandle,
+        range: Range<u64>,
+    ) -> Result<FileResponse<impl Stream<Item = Result<Bytes, Error>>>, Error> {
+        let FileHandle { ref id } = file;
+
+        self.wait_for_request().await;
+
+        debug!(
+            "downloading file '{id}', range {start}-{end}",
+            start = range.start,
+            end = range.end
+        );
+
+        let response = self
+            .send(
+                "files.get",
+                self.http
+                    .get(format!("https://www.googleapis.com/drive/v3/files/{id}"))
+                    .query(&[
+                        ("alt", "media"),
+                        //("acknowledgeAbuse", "true"),
+                        ("supportsAllDrives", "true"),
+                    ])
+                    .header(
+                        "authorization",
+                        self.auth.header().await.map_err(Error::Auth)?,
+                    )
+                    .header(
+                        "range",
+                        format!(
+                            "bytes={start}-{end}",
+                            start = range.start,
+                            end = range.end.saturating_sub(1),
+                        ),
+                    ),
+            )
+            .await
+            .map_err(Error::FileGet)?;
+
+        let response_range = if response.status() == StatusCode::PARTIAL_CONTENT {
+            // parse content-range for partial response
+            response
+                .headers()
+                .typed_get()
+                .and_then(|range: ContentRange| range.bytes_range())
+                .map(|(start, end)| start..end.saturating_add(1)) // make end exclusive
+                .unwrap_or(range.clone())
+        } else {
+            // parse content-length and assume full response
+            response
+                .headers()
+                .typed_get()
+                .map(|len: ContentLength| 0..len.0)
+                .unwrap_or(range.clone())
+        };
+
+        // ensure response range is valid
+        if response_range.start > range.start || response_range.end < range.end {
+            return Err(Error::FileRangeResponseInvalid(range.start, range.end));
+        }
+
+        Ok(FileResponse {
+            stream: response.bytes_stream().map_err(Error::FileGet).inspect_ok({
+                let metrics = self.metrics.clone();
+                move |chunk| metrics.count("drive.bytes_downloaded", chunk.len() as u64)
+            }),
+            range: response_range,
+        })
+    }
+
+    pub async fn delete_file(&self, file: &FileHandle) -> Result<(), Error> {
+        let FileHandle { ref id } = file;
+
+        self.wait_for_request().await;
+        info!("deleting file '{id}'");
+
+        let result = self
+            .send_expecting(
+                "files.delete",
+                self.http
+                    .delete(format!("https://www.googleapis.com/drive/v3/files/{id}"))
+                    .query(&[("supportsAllDrives", "true")])
+                    .header(
+                        "authorization",
+                        self.auth.header().await.map_err(Error::Auth)?,
+                    ),
+                Some(StatusCode::NOT_FOUND),
+            )
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            // already deleted, such as by an earlier attempt whose response was lost
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(()),
+            Err(err) => Err(Error::FileDelete(err)),
+        }
+    }
+
+    /// Checks whether a file exists, without downloading it.
+    pub async fn file_exists(&self, file: &FileHandle) -> Result<bool, Error> {
+        let FileHandle { ref id } = file;
+
+        self.wait_for_request().await;
+
+        let result = self
+            .send_expecting(
+                "files.get",
+                self.http
+                    .get(format!("https://www.googleapis.com/drive/v3/files/{id}"))
+                    .query(&[("supportsAllDrives", "true"), ("fields", "id")])
+                    .header(
+                        "authorization",
+                        self.auth.header().await.map_err(Error::Auth)?,
+                    ),
+                Some(StatusCode::NOT_FOUND),
+            )
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(false),
+            Err(err) => Err(Error::FileCheck(err)),
+        }
+    }
+
+    /// Lists a page of the files in a shared drive, continuing from the page token of the previous page if given.
+    pub async fn list_files(
+        &self,
+        drive: &FolderHandle,
+        page_token: Option<&str>,
+    ) -> Result<FileList, Error> {
+        let FolderHandle { ref id } = drive;
+
+        self.wait_for_request().await;
+
+        let mut query = vec![
+            ("corpora", "drive"),
+            ("driveId", id.as_str()),
+            ("includeItemsFromAllDrives", "true"),
+            ("supportsAllDrives", "true"),
+            ("q", "trashed = false"),
+            ("fields", "nextPageToken, files(id, createdTime)"),
+            ("pageSize", "1000"),
+        ];
+
+        if let Some(token) = page_token {
+            query.push(("pageToken", token));
+        }
+
+        self.send(
+            "files.list",
+            self.http
+                .get("https://www.googleapis.com/drive/v3/files")
+                .query(&query)
+                .header(
+                    "authorization",
+                    self.auth.header().await.map_err(Error::Auth)?,
+                ),
+        )
+        .await
+        .map_err(Error::FileList)?
+        .json()
+        .await
+        .map_err(Error::FileList)
+    }
+
+    pub async fn create_drive(&self, name: impl AsRef<str>) -> Result<FolderHandle, Error> {
+        let name = name.as_ref();
+
+        // required by drive api, we don't use it
+        let request_id = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(20)
+            .map(char::from)
+            .collect::<String>();
+
+        #[derive(Serialize)]
+        struct Request<'a> {
+            name: &'a str,
+            hidden: bool,
+        }
+
+        #[derive(Deserialize)]
+        struct Response {
+            id: String,
+        }
+
+        self.wait_for_request().await;
+
+        info!("creating new shared drive '{name}'");
+
+        let Response { id } = self
+            .send(
+                "drives.create",
+                self.http
+                    .post("https://www.googleapis.com/drive/v3/drives")
+                    .query(&[("requestId", &request_id)])
+                    .header(
+                        "authorization",
+                        self.auth.header().await.map_err(Error::Auth)?,
+                    )
+                    .json(&Request { name, hidden: true }),
+            )
+            .await
+            .map_err(Error::DriveCreate)?
+            .json()
+            .await
+            .map_err(Error::DriveCreate)?;
+
+        Ok(FolderHandle::new(id))
+    }
+
+    pub async fn get_storage_quota(&self) -> Result<StorageQuota, Error> {
+        // int64 values are formatted as strings
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Quota {
+            limit: Option<String>,
+            usage: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Response {
+            storage_quota: Quota,
+        }
+
+        self.wait_for_request().await;
+
+        let Response { storage_quota } = self
+            .send(
+                "about.get",
+                self.http
+                    .get("https://www.googleapis.com/drive/v3/about")
+                    .query(&[("fields", "storageQuota")])
+                    .header(
+                        "authorization",
+                        self.auth.header().await.map_err(Error::Auth)?,
+                    ),
+            )
+            .await
+            .map_err(Error::QuotaGet)?
+            .json()
+            .await
+            .map_err(Error::QuotaGet)?;
+
+        let limit: Option<u64> = storage_quota.limit.and_then(|limit| limit.parse().ok());
+        let usage = storage_quota
+            .usage
+            .and_then(|usage| usage.parse().ok())
+            .unwrap_or(0);
+
+        Ok(StorageQuota {
+            limit,
+            usage,
+            remaining: limit.map(|limit| limit.saturating_sub(usage)),
+        })
+    }
+
+    /// Checks that a shared drive exists and is accessible.
+    pub async fn get_drive(&self, drive: &FolderHandle) -> Result<(), Error> {
+        let FolderHandle { ref id } = drive;
+
+        self.wait_for_request().await;
+
+        self.send(
+            "drives.get",
+            self.http
+                .get(format!("https://www.googleapis.com/drive/v3/drives/{id}"))
+                .query(&[("fields", "id")])
+                .header(
+                    "authorization",
+                    self.auth.header().await.map_err(Error::Auth)?,
+                ),
+        )
+        .await
+        .map_err(Error::DriveGet)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use std::ops::Range;
+
+/// Byte range requested in a `Range` header, with inclusive offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// Range from an offset to another offset, or to the end of the content if unbounded.
+    From(u64, Option<u64>),
+    /// Last bytes of the content of the given length.
+    Suffix(u64),
+}
+
+/// Parses a `Range` header of one or more byte ranges, e.g. `bytes=0-99,200-,-50`.
+pub fn parse_range_header(s: impl AsRef<str>) -> Option<Vec<ByteRange>> {
+    s.as_ref()
+        .strip_prefix("bytes=")?
+        .split(',')
+        .map(|range| parse_byte_range(range.trim()))
+        .collect()
+}
+
+fn parse_byte_range(s: &str) -> Option<ByteRange> {
+    let (start, end) = s.split_once('-')?;
+
+    if start.is_empty() {
+        return Some(ByteRange::Suffix(end.parse().ok()?));
+    }
+
+    let end = if end.is_empty() {
+        None
+    } else {
+        Some(end.parse().ok()?)
+    };
+
+    Some(ByteRange::From(start.parse().ok()?, end))
+}
+
+/// Parses the `Content-Range` header of a request body, e.g. `bytes 100-199/200`,
+/// into the range it covers and the complete length if known.
+pub fn parse_content_range_header(s: impl AsRef<str>) -> Option<(Range<u64>, Option<u64>)> {
+    let s = s.as_ref().strip_prefix("bytes ")?;
+    let (range, length) = s.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+
+    if end < start {
+        return None;
+    }
+
+    let length = match length {
+        "*" => None,
+        length => Some(length.parse().ok()?),
+    };
+
+    // the exclusive end of a range ending at the last representable offset would overflow
+    Some((start..end.checked_add(1)?, length))
+}
+
+/// Serializes a JSON value for use in a header, escaping non-ASCII characters.
+pub fn format_json_header(value: &serde_json::Value) -> String {
+    let mut s = String::new();
+
+    for c in value.to_string().chars() {
+        if c.is_ascii() {
+            s.push(c);
+        } else {
+            // non-ascii characters can only appear in json strings, so escaping is safe
+            let mut buffer = [0; 2];
+            for unit in c.encode_utf16(&mut buffer) {
+                s.push_str(&format!("\\u{unit:04x}"));
+            }
+        }
+    }
+
+    s
+}
+
+/// Decodes a case-insensitive hexadecimal string.
+pub fn parse_hex(s: impl AsRef<str>) -> Option<Vec<u8>> {
+    let s = s.as_ref();
+    if s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Encodes bytes as a lowercase hexadecimal string.
+pub fn format_hex(bytes: impl AsRef<[u8]>) -> String {
+    bytes
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Parses an RFC 9530 `Repr-Digest` header into pairs of algorithm names and digests.
+pub fn parse_repr_digest(s: impl AsRef<str>) -> Option<Vec<(String, Vec<u8>)>> {
+    s.as_ref()
+        .split(',')
+        .map(|member| {
+            let (algorithm, value) = member.trim().split_once('=')?;
+            let value = value.strip_prefix(':')?.strip_suffix(':')?;
+
+            Some((algorithm.to_ascii_lowercase(), base64::decode(value).ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_header() {
+        assert_eq!(
+            parse_range_header("bytes=0-99"),
+            Some(vec![ByteRange::From(0, Some(99))])
+        );
+
+        // open-ended
+        assert_eq!(
+            parse_range_header("bytes=100-"),
+            Some(vec![ByteRange::From(100, None)])
+        );
+
+        // suffix
+        assert_eq!(
+            parse_range_header("bytes=-50"),
+            Some(vec![ByteRange::Suffix(50)])
+        );
+    }
+
+    #[test]
+    fn range_header_multiple() {
+        assert_eq!(
+            parse_range_header("bytes=0-99, 200-,-50"),
+            Some(vec![
+                ByteRange::From(0, Some(99)),
+                ByteRange::From(200, None),
+                ByteRange::Suffix(50),
+            ])
+        );
+    }
+
+    #[test]
+    fn range_header_invalid() {
+        assert_eq!(parse_range_header("items=0-99"), None);
+        assert_eq!(parse_range_header("bytes=0-99,"), None);
+        assert_eq!(parse_range_header("bytes=-"), None);
+        assert_eq!(parse_range_header("bytes=a-b"), None);
+        assert_eq!(parse_range_header("bytes=18446744073709551616-"), None);
+    }
+
+    #[test]
+    fn content_range_header() {
+        assert_eq!(
+            parse_content_range_header("bytes 100-199/200"),
+            Some((100..200, Some(200)))
+        );
+
+        assert_eq!(
+            parse_content_range_header("bytes 0-99/*"),
+            Some((0..100, None))
+        );
+
+        assert_eq!(parse_content_range_header("bytes 100-99/200"), None);
+        assert_eq!(parse_content_range_header("bytes */200"), None);
+    }
+
+    #[test]
+    fn content_range_header_overflow() {
+        assert_eq!(
+            parse_content_range_header(format!("bytes 0-{}/*", u64::MAX - 1)),
+            Some((0..u64::MAX, None))
+        );
+
+        assert_eq!(
+            parse_content_range_header(format!("bytes 0-{}/*", u64::MAX)),
+            None
+        );
+    }
+}
@@ -6,9 +6,9 @@
 //
 //   https://opensource.org/licenses/MIT
 //
-use reqwest::{Client, Proxy};
+use reqwest::{Client, ClientBuilder, Proxy};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HttpConfig {
     pub user_agent: Option<String>,
     pub proxy: Option<String>,
@@ -29,14 +29,19 @@ impl Default for HttpConfig {
 
 impl HttpConfig {
     pub fn create_client(self) -> Result<Client, reqwest::Error> {
+        self.client_builder()?.build()
+    }
+
+    /// Returns a client builder with this configuration applied, for callers that need to adjust it further.
+    pub fn client_builder(self) -> Result<ClientBuilder, reqwest::Error> {
         let mut http = Client::builder();
 
         if let Some(user_agent) = self.user_agent {
             http = http.user_agent(user_agent);
         } else {
+            // named after the server rather than this library, which is versioned along with it
             http = http.user_agent(format!(
-                "{name}/{major}.{minor}",
-                name = env!("CARGO_PKG_NAME"),
+                "castella/{major}.{minor}",
                 major = env!("CARGO_PKG_VERSION_MAJOR"),
                 minor = env!("CARGO_PKG_VERSION_MINOR")
             ));
@@ -46,11 +51,11 @@ impl HttpConfig {
             http = http.proxy(Proxy::all(proxy)?);
         }
 
-        http.gzip(self.compression)
+        Ok(http
+            .gzip(self.compression)
             .deflate(self.compression)
             .brotli(self.compression)
             .https_only(!self.allow_insecure)
-            .referer(false)
-            .build()
+            .referer(false))
     }
 }
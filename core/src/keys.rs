@@ -0,0 +1,135 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::{
+    header::{format_hex, parse_hex},
+    kms::Kms,
+};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    XChaCha20Poly1305,
+};
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("master key must be {} hex-encoded bytes", MasterKey::SIZE)]
+    Format,
+
+    #[error("failed to wrap file secret")]
+    Wrap,
+
+    #[error("failed to unwrap file secret")]
+    Unwrap,
+
+    #[error("{0}")]
+    Kms(#[from] crate::kms::Error),
+}
+
+/// Key used to wrap file secrets, either held in memory or by a key management service.
+#[derive(Debug)]
+pub enum WrappingKey {
+    Local(MasterKey),
+    Kms(Kms),
+}
+
+impl WrappingKey {
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Local(key) => key.id(),
+            Self::Kms(kms) => kms.id(),
+        }
+    }
+
+    pub async fn wrap(&self, secret: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Local(key) => key.wrap(secret),
+            Self::Kms(kms) => Ok(kms.encrypt(secret).await?),
+        }
+    }
+
+    pub async fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Local(key) => key.unwrap(wrapped),
+            Self::Kms(kms) => Ok(kms.decrypt(wrapped).await?),
+        }
+    }
+}
+
+/// Key used to wrap file secrets before they are stored in the database.
+pub struct MasterKey {
+    id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl MasterKey {
+    pub const SIZE: usize = 32;
+    const NONCE_SIZE: usize = 24;
+
+    pub fn new(key: &[u8]) -> Option<Self> {
+        if key.len() != Self::SIZE {
+            return None;
+        }
+
+        // identify the key by its fingerprint so that the wrapping key of a secret can be told apart
+        let id = format_hex(&Sha256::digest(key)[..8]);
+
+        Some(Self {
+            id,
+            cipher: XChaCha20Poly1305::new(key.into()),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Encrypts a secret, returning the nonce followed by the ciphertext.
+    pub fn wrap(&self, secret: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0; Self::NONCE_SIZE];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let wrapped = self
+            .cipher
+            .encrypt(&nonce.into(), secret)
+            .map_err(|_| Error::Wrap)?;
+
+        Ok([&nonce[..], &wrapped].concat())
+    }
+
+    pub fn unwrap(&self, wrapped: &[u8]) -> Result<Vec<u8>, Error> {
+        if wrapped.len() < Self::NONCE_SIZE {
+            return Err(Error::Unwrap);
+        }
+
+        let (nonce, wrapped) = wrapped.split_at(Self::NONCE_SIZE);
+
+        self.cipher
+            .decrypt(nonce.into(), wrapped)
+            .map_err(|_| Error::Unwrap)
+    }
+}
+
+impl FromStr for MasterKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex(s.trim())
+            .and_then(|key| Self::new(&key))
+            .ok_or(Error::Format)
+    }
+}
+
+// never print the key itself
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MasterKey").field("id", &self.id).finish()
+    }
+}
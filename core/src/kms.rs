@@ -0,0 +1,342 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::{auth::Authenticator, header::format_hex, http::HttpConfig};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialize http client: {0}")]
+    ClientInit(reqwest::Error),
+
+    #[error("kms key must be either \"gcp:<key name>\" or \"aws:<region>:<key id>\"")]
+    KeyFormat,
+
+    #[error("aws kms requires an access key id and secret access key")]
+    AwsCredentialsMissing,
+
+    #[error("failed to encrypt with kms: {0}")]
+    Encrypt(reqwest::Error),
+
+    #[error("failed to decrypt with kms: {0}")]
+    Decrypt(reqwest::Error),
+
+    #[error("failed to decode kms response: {0}")]
+    ResponseDecode(base64::DecodeError),
+
+    #[error("{0}")]
+    Auth(crate::auth::Error),
+}
+
+/// Key held by a key management service, given as `gcp:<key name>` or `aws:<region>:<key id>`.
+#[derive(Debug, Clone)]
+pub enum KmsKey {
+    /// Resource name of a Cloud KMS key, `projects/*/locations/*/keyRings/*/cryptoKeys/*`.
+    Gcp(String),
+    /// Region and ID or ARN of an AWS KMS key.
+    Aws(String, String),
+}
+
+impl FromStr for KmsKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("gcp", name)) if !name.is_empty() => Ok(Self::Gcp(name.into())),
+            Some(("aws", key)) => match key.split_once(':') {
+                Some((region, id)) if !region.is_empty() && !id.is_empty() => {
+                    Ok(Self::Aws(region.into(), id.into()))
+                }
+                _ => Err(Error::KeyFormat),
+            },
+            _ => Err(Error::KeyFormat),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+/// Client that encrypts and decrypts small secrets using a key management service.
+#[derive(Debug)]
+pub struct Kms {
+    http: Client,
+    id: String,
+    backend: Backend,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Gcp {
+        auth: Authenticator,
+        name: String,
+    },
+    Aws {
+        credentials: AwsCredentials,
+        region: String,
+        key_id: String,
+    },
+}
+
+impl Kms {
+    /// `auth` is used for Cloud KMS and `aws_credentials` for AWS KMS.
+    pub fn new(
+        http: HttpConfig,
+        key: KmsKey,
+        auth: Authenticator,
+        aws_credentials: Option<AwsCredentials>,
+    ) -> Result<Self, Error> {
+        let http = http.create_client().map_err(Error::ClientInit)?;
+
+        let (id, backend) = match key {
+            KmsKey::Gcp(name) => (format!("gcp-kms:{name}"), Backend::Gcp { auth, name }),
+            KmsKey::Aws(region, key_id) => (
+                format!("aws-kms:{region}:{key_id}"),
+                Backend::Aws {
+                    credentials: aws_credentials.ok_or(Error::AwsCredentialsMissing)?,
+                    region,
+                    key_id,
+                },
+            ),
+        };
+
+        Ok(Self { http, id, backend })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub async fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.backend {
+            Backend::Gcp { ref auth, ref name } => {
+                #[derive(Serialize)]
+                struct Request {
+                    plaintext: String,
+                }
+
+                #[derive(Deserialize)]
+                struct Response {
+                    ciphertext: String,
+                }
+
+                let Response { ciphertext } = self
+                    .http
+                    .post(format!("https://cloudkms.googleapis.com/v1/{name}:encrypt"))
+                    .header("authorization", auth.header().await.map_err(Error::Auth)?)
+                    .json(&Request {
+                        plaintext: base64::encode(plaintext),
+                    })
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(Error::Encrypt)?
+                    .json()
+                    .await
+                    .map_err(Error::Encrypt)?;
+
+                base64::decode(ciphertext).map_err(Error::ResponseDecode)
+            }
+
+            Backend::Aws { ref key_id, .. } => {
+                #[derive(Serialize)]
+                #[serde(rename_all = "PascalCase")]
+                struct Request<'a> {
+                    key_id: &'a str,
+                    plaintext: String,
+                }
+
+                #[derive(Deserialize)]
+                #[serde(rename_all = "PascalCase")]
+                struct Response {
+                    ciphertext_blob: String,
+                }
+
+                let Response { ciphertext_blob } = self
+                    .aws_request(
+                        "TrentService.Encrypt",
+                        &Request {
+                            key_id,
+                            plaintext: base64::encode(plaintext),
+                        },
+                    )
+                    .await
+                    .map_err(Error::Encrypt)?;
+
+                base64::decode(ciphertext_blob).map_err(Error::ResponseDecode)
+            }
+        }
+    }
+
+    pub async fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.backend {
+            Backend::Gcp { ref auth, ref name } => {
+                #[derive(Serialize)]
+                struct Request {
+                    ciphertext: String,
+                }
+
+                #[derive(Deserialize)]
+                struct Response {
+                    plaintext: String,
+                }
+
+                let Response { plaintext } = self
+                    .http
+                    .post(format!("https://cloudkms.googleapis.com/v1/{name}:decrypt"))
+                    .header("authorization", auth.header().await.map_err(Error::Auth)?)
+                    .json(&Request {
+                        ciphertext: base64::encode(ciphertext),
+                    })
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(Error::Decrypt)?
+                    .json()
+                    .await
+                    .map_err(Error::Decrypt)?;
+
+                base64::decode(plaintext).map_err(Error::ResponseDecode)
+            }
+
+            Backend::Aws { ref key_id, .. } => {
+                #[derive(Serialize)]
+                #[serde(rename_all = "PascalCase")]
+                struct Request<'a> {
+                    key_id: &'a str,
+                    ciphertext_blob: String,
+                }
+
+                #[derive(Deserialize)]
+                #[serde(rename_all = "PascalCase")]
+                struct Response {
+                    plaintext: String,
+                }
+
+                let Response { plaintext } = self
+                    .aws_request(
+                        "TrentService.Decrypt",
+                        &Request {
+                            key_id,
+                            ciphertext_blob: base64::encode(ciphertext),
+                        },
+                    )
+                    .await
+                    .map_err(Error::Decrypt)?;
+
+                base64::decode(plaintext).map_err(Error::ResponseDecode)
+            }
+        }
+    }
+
+    /// Sends an AWS KMS request signed with Signature Version 4.
+    async fn aws_request<T: Serialize, R: for<'de> Deserialize<'de>>(
+        &self,
+        target: &str,
+        request: &T,
+    ) -> Result<R, reqwest::Error> {
+        let (credentials, region) = match self.backend {
+            Backend::Aws {
+                ref credentials,
+                ref region,
+                ..
+            } => (credentials, region),
+            Backend::Gcp { .. } => unreachable!(),
+        };
+
+        // serialization of plain structs can't fail
+        let body = serde_json::to_vec(request).unwrap();
+
+        let host = format!("kms.{region}.amazonaws.com");
+        let now = Utc::now();
+        let time = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let content_type = "application/x-amz-json-1.1";
+
+        // headers must be sorted by name
+        let mut headers = vec![
+            ("content-type", content_type),
+            ("host", &host),
+            ("x-amz-date", &time),
+        ];
+
+        if let Some(ref token) = credentials.session_token {
+            headers.push(("x-amz-security-token", token));
+        }
+
+        headers.push(("x-amz-target", target));
+
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "POST\n/\n\n{headers}\n{signed_headers}\n{hash}",
+            headers = headers
+                .iter()
+                .map(|(name, value)| format!("{name}:{value}\n"))
+                .collect::<String>(),
+            hash = format_hex(Sha256::digest(&body))
+        );
+
+        let scope = format!("{date}/{region}/kms/aws4_request");
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{time}\n{scope}\n{hash}",
+            hash = format_hex(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signature = {
+            let key = format!("AWS4{}", credentials.secret_access_key);
+            let key = hmac_sha256(key.as_bytes(), date.as_bytes());
+            let key = hmac_sha256(&key, region.as_bytes());
+            let key = hmac_sha256(&key, b"kms");
+            let key = hmac_sha256(&key, b"aws4_request");
+            format_hex(hmac_sha256(&key, string_to_sign.as_bytes()))
+        };
+
+        let mut builder = self.http.post(format!("https://{host}/"));
+
+        for (name, value) in headers {
+            if name != "host" {
+                builder = builder.header(name, value);
+            }
+        }
+
+        builder
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={access_key_id}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                    access_key_id = credentials.access_key_id
+                ),
+            )
+            .body(body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())?
+            .json()
+            .await
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
@@ -0,0 +1,59 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+
+//! Storage engine of castella, which stores files encrypted in chunks across Google Drive shared drives and keeps
+//! their metadata and keys in PostgreSQL.
+//!
+//! [`Store`](store::Store) is the entry point for uploading, downloading and managing files. It is created from a
+//! [`StoreConfig`](store::StoreConfig) holding a [`Db`](db::Db) connected to a migrated database and a
+//! [`Drive`](drive::Drive) client authorized by an [`Authenticator`](auth::Authenticator), along with optional
+//! caches, a spool and webhooks. The castella server is built on this crate, so anything it does with files can be
+//! done by embedding the store without running the server.
+
+#[macro_use]
+extern crate tracing;
+
+/// Encrypted archives of file metadata for exporting and importing stores.
+pub mod archive;
+/// OAuth access tokens for the Google APIs.
+pub mod auth;
+/// Local and shared caches of file metadata and chunks.
+pub mod cache;
+/// Encryption of file content in chunks.
+pub mod cipher;
+/// PostgreSQL database of files, drives, aliases, collections, users and the audit log.
+pub mod db;
+/// Google Drive client for storing chunks in shared drives.
+pub mod drive;
+/// Parsing and formatting of HTTP header values.
+pub mod header;
+/// Configuration of outgoing HTTP clients.
+pub mod http;
+/// Master keys that wrap file secrets.
+pub mod keys;
+/// Keys held by cloud key management services.
+pub mod kms;
+/// Merkle tree manifests of encrypted chunks.
+pub mod manifest;
+/// StatsD metrics.
+pub mod metrics;
+/// Rate and concurrency limiters.
+pub mod rate_limit;
+/// Minimal Redis client.
+pub mod redis;
+/// Detection of content types from file content.
+pub mod sniff;
+/// Local spool of uploads waiting to be uploaded to Drive.
+pub mod spool;
+/// Uploading, downloading and managing stored files.
+pub mod store;
+/// Stream utilities for chunking, slicing and throttling content.
+pub mod stream;
+/// Signed webhook notifications of file events.
+pub mod webhook;
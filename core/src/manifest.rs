@@ -0,0 +1,85 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use sha2::{Digest, Sha256};
+
+const HASH_SIZE: usize = 32;
+
+/// SHA-256 hashes of the encrypted chunks of a file, forming the leaves of a Merkle tree.
+#[derive(Debug, Default, Clone)]
+pub struct Manifest {
+    hashes: Vec<[u8; HASH_SIZE]>,
+}
+
+impl Manifest {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if !bytes.len().is_multiple_of(HASH_SIZE) {
+            return None;
+        }
+
+        Some(Self {
+            hashes: bytes
+                .chunks_exact(HASH_SIZE)
+                .map(|hash| hash.try_into().unwrap())
+                .collect(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.hashes.concat()
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.hashes.push(Self::hash_leaf(chunk));
+    }
+
+    /// Returns whether the encrypted chunk matches its hash in the manifest.
+    pub fn verify(&self, chunk_id: u32, chunk: &[u8]) -> bool {
+        self.hashes.get(chunk_id as usize) == Some(&Self::hash_leaf(chunk))
+    }
+
+    /// Computes the Merkle tree root of the manifest.
+    pub fn root(&self) -> [u8; HASH_SIZE] {
+        if self.hashes.is_empty() {
+            return Sha256::digest([]).into();
+        }
+
+        let mut level = self.hashes.clone();
+
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => Self::hash_node(left, right),
+                    [single] => *single, // odd node is promoted to the next level
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+
+        level[0]
+    }
+
+    // leaves and nodes are domain-separated to prevent second preimage attacks
+    fn hash_leaf(chunk: &[u8]) -> [u8; HASH_SIZE] {
+        Sha256::new()
+            .chain_update([0])
+            .chain_update(chunk)
+            .finalize()
+            .into()
+    }
+
+    fn hash_node(left: &[u8], right: &[u8]) -> [u8; HASH_SIZE] {
+        Sha256::new()
+            .chain_update([1])
+            .chain_update(left)
+            .chain_update(right)
+            .finalize()
+            .into()
+    }
+}
@@ -0,0 +1,136 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use std::{
+    fmt::{Display, Write},
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to resolve statsd address '{0}': {1}")]
+    AddressResolve(String, std::io::Error),
+
+    #[error("statsd address '{0}' did not resolve to any address")]
+    AddressMissing(String),
+
+    #[error("failed to open statsd socket: {0}")]
+    Socket(std::io::Error),
+}
+
+/// Maximum size of a datagram of metrics, which fits in the MTU of most networks.
+const MAX_PACKET_SIZE: usize = 1400;
+
+/// Emits counters, gauges and timers to a StatsD server over UDP.
+///
+/// Metrics are buffered and sent in batches of lines when the buffer is full or when [`Metrics::flush`] is called.
+/// Without a server, recording metrics does nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    sink: Option<Arc<StatsdSink>>,
+}
+
+#[derive(Debug)]
+struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    buffer: Mutex<String>,
+}
+
+impl Metrics {
+    /// Returns metrics that are discarded.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Returns metrics that are sent to the StatsD server at the given address, e.g. "localhost:8125",
+    /// with the names of all metrics prefixed by the given prefix.
+    pub fn statsd(address: &str, prefix: impl Into<String>) -> Result<Self, Error> {
+        let target = address
+            .to_socket_addrs()
+            .map_err(|err| Error::AddressResolve(address.into(), err))?
+            .next()
+            .ok_or_else(|| Error::AddressMissing(address.into()))?;
+
+        let local = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+
+        let socket = UdpSocket::bind(local).map_err(Error::Socket)?;
+        socket.connect(target).map_err(Error::Socket)?;
+
+        // never block the caller when the socket buffer is full
+        socket.set_nonblocking(true).map_err(Error::Socket)?;
+
+        Ok(Self {
+            sink: Some(Arc::new(StatsdSink {
+                socket,
+                prefix: prefix.into(),
+                buffer: Mutex::new(String::new()),
+            })),
+        })
+    }
+
+    /// Increments a counter.
+    pub fn count(&self, name: &str, value: u64) {
+        self.record(name, value, "c");
+    }
+
+    /// Sets a gauge to the current value of a quantity.
+    pub fn gauge(&self, name: &str, value: u64) {
+        self.record(name, value, "g");
+    }
+
+    /// Records the duration of an operation in milliseconds.
+    pub fn time(&self, name: &str, duration: Duration) {
+        self.record(name, duration.as_millis(), "ms");
+    }
+
+    fn record(&self, name: &str, value: impl Display, kind: &str) {
+        if let Some(ref sink) = self.sink {
+            let line = format!("{}{name}:{value}|{kind}", sink.prefix);
+            let mut buffer = sink.buffer.lock().unwrap();
+
+            if !buffer.is_empty() && buffer.len() + 1 + line.len() > MAX_PACKET_SIZE {
+                sink.send(&mut buffer);
+            }
+
+            if !buffer.is_empty() {
+                buffer.push('\n');
+            }
+
+            let _ = buffer.write_str(&line);
+        }
+    }
+
+    /// Sends all buffered metrics.
+    pub fn flush(&self) {
+        if let Some(ref sink) = self.sink {
+            sink.send(&mut sink.buffer.lock().unwrap());
+        }
+    }
+}
+
+impl StatsdSink {
+    fn send(&self, buffer: &mut String) {
+        if buffer.is_empty() {
+            return;
+        }
+
+        // metrics are best-effort, so lost packets are only logged
+        if let Err(err) = self.socket.send(buffer.as_bytes()) {
+            debug!("failed to send metrics: {err}");
+        }
+
+        buffer.clear();
+    }
+}
@@ -0,0 +1,237 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use governor::{
+    clock::{Clock, QuantaClock},
+    state::keyed::DefaultKeyedStateStore,
+    Quota, RateLimiter,
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    hash::Hash,
+    num::NonZeroU32,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("value must be positive and follow the format \"burst/period\"")]
+    Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    quota: Quota,
+}
+
+impl RateLimit {
+    /// Interval at which a unit of the limit is replenished.
+    pub fn interval(&self) -> Duration {
+        self.quota.replenish_interval()
+    }
+}
+
+impl From<RateLimit> for Quota {
+    fn from(limit: RateLimit) -> Self {
+        limit.quota
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        let burst = parts.next().ok_or(Error::Format)?;
+        let period = parts.next().ok_or(Error::Format)?;
+
+        parts.next().map_or(Ok(()), |_| Err(Error::Format))?;
+
+        let burst: NonZeroU32 = burst.trim_end().parse().map_err(|_| Error::Format)?;
+        let period: u64 = period.trim_start().parse().map_err(|_| Error::Format)?;
+
+        Ok(Self {
+            quota: Quota::with_period(Duration::from_secs(period) / burst.get())
+                .ok_or(Error::Format)?
+                .allow_burst(burst),
+        })
+    }
+}
+
+impl Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{burst}/{period}",
+            burst = self.quota.burst_size(),
+            // the replenish interval is truncated to nanoseconds when the quota is parsed
+            period = self.quota.burst_size_replenished_in().as_secs_f64().round()
+        )
+    }
+}
+
+/// Rate limiter that limits each key separately, such as the address of a client.
+#[derive(Debug)]
+pub struct KeyedRateLimiter<K: Hash + Eq + Clone> {
+    limiter: RateLimiter<K, DefaultKeyedStateStore<K>, QuantaClock>,
+    clock: QuantaClock,
+    checks: AtomicU32,
+}
+
+impl<K: Hash + Eq + Clone> KeyedRateLimiter<K> {
+    /// Number of checks after which keys that are no longer limited are forgotten.
+    const RETAIN_INTERVAL: u32 = 4096;
+
+    pub fn new(limit: RateLimit) -> Self {
+        let clock = QuantaClock::default();
+
+        Self {
+            limiter: RateLimiter::new(limit.into(), Default::default(), &clock),
+            clock,
+            checks: AtomicU32::new(0),
+        }
+    }
+
+    /// Counts a request of a key, returning the time to wait before retrying if the key is over the limit.
+    pub fn check(&self, key: &K) -> Result<(), Duration> {
+        if self
+            .checks
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(Self::RETAIN_INTERVAL)
+        {
+            self.limiter.retain_recent();
+        }
+
+        self.limiter
+            .check_key(key)
+            .map_err(|not_until| not_until.wait_time_from(self.clock.now()))
+    }
+}
+
+/// Limits the number of concurrent operations of each key, such as the address of a client.
+#[derive(Debug)]
+pub struct KeyedConcurrencyLimiter<K: Hash + Eq> {
+    limit: usize,
+    counts: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+/// Operation counted by a [`KeyedConcurrencyLimiter`] until it is dropped.
+#[derive(Debug)]
+pub struct ConcurrencyPermit<K: Hash + Eq> {
+    key: K,
+    counts: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+impl<K: Hash + Eq + Clone> KeyedConcurrencyLimiter<K> {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            counts: Default::default(),
+        }
+    }
+
+    /// Starts an operation of a key, or returns `None` if the key has reached the limit.
+    pub fn acquire(&self, key: K) -> Option<ConcurrencyPermit<K>> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key.clone()).or_default();
+
+        if *count >= self.limit {
+            return None;
+        }
+
+        *count += 1;
+
+        Some(ConcurrencyPermit {
+            key,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+impl<K: Hash + Eq> Drop for ConcurrencyPermit<K> {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+
+            // don't keep idle keys around
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Counts the tasks that are waiting for a shared resource, such as a connection or a permit,
+/// and the average time for which they waited.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    len: AtomicUsize,
+    // exponentially weighted moving average in microseconds
+    average_wait: AtomicU64,
+}
+
+/// Task counted by a [`WaitQueue`] until it is dropped.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl WaitQueue {
+    /// Weight of each wait in the average, as a power of two.
+    const AVERAGE_SHIFT: u32 = 3;
+
+    /// Waits for a future to complete while counting the task as waiting.
+    pub async fn wait<F: Future>(&self, future: F) -> F::Output {
+        self.len.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.len);
+
+        let start = Instant::now();
+        let output = future.await;
+        self.record(start.elapsed());
+
+        output
+    }
+
+    /// Adds the time for which a task waited to the average.
+    pub fn record(&self, wait: Duration) {
+        let wait = wait.as_micros().min(i64::MAX as u128) as i64;
+
+        let _ = self
+            .average_wait
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                let delta = (wait - average as i64) >> Self::AVERAGE_SHIFT;
+                Some((average as i64 + delta) as u64)
+            });
+    }
+
+    /// Number of tasks that are waiting.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Average time for which recent tasks waited.
+    pub fn average_wait(&self) -> Duration {
+        Duration::from_micros(self.average_wait.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,253 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use futures::{future::BoxFuture, FutureExt};
+use reqwest::Url;
+use std::{sync::Mutex, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("redis url must be of the form \"redis://[[user]:password@]host[:port][/db]\"")]
+    UrlFormat,
+
+    #[error("failed to connect to redis: {0}")]
+    Connect(std::io::Error),
+
+    #[error("redis i/o error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("redis request timed out")]
+    Timeout,
+
+    #[error("redis protocol error: {0}")]
+    Protocol(String),
+
+    #[error("redis error: {0}")]
+    Server(String),
+}
+
+/// Reply to a Redis command.
+#[derive(Debug)]
+pub enum Value {
+    Nil,
+    Int(i64),
+    Data(Vec<u8>),
+    Status(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn expect_ok(self) -> Result<(), Error> {
+        match self {
+            Self::Status(status) if status == "OK" => Ok(()),
+            value => Err(Error::Protocol(format!("unexpected reply {value:?}"))),
+        }
+    }
+}
+
+/// Minimal RESP client for a single Redis server with a pool of idle connections.
+#[derive(Debug)]
+pub struct Redis {
+    url: Url,
+    connections: Mutex<Vec<Connection>>,
+}
+
+impl Redis {
+    const MAX_IDLE_CONNECTIONS: usize = 16;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let url = Url::parse(url).map_err(|_| Error::UrlFormat)?;
+
+        if url.scheme() != "redis" || url.host_str().is_none() {
+            return Err(Error::UrlFormat);
+        }
+
+        Ok(Self {
+            url,
+            connections: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub async fn command(&self, args: &[&[u8]]) -> Result<Value, Error> {
+        tokio::time::timeout(Self::TIMEOUT, async {
+            let idle = self.connections.lock().unwrap().pop();
+            let mut connection = match idle {
+                Some(connection) => connection,
+                None => Connection::open(&self.url).await?,
+            };
+
+            let value = connection.command(args).await?;
+
+            // connections are only reused after a complete exchange
+            let mut connections = self.connections.lock().unwrap();
+            if connections.len() < Self::MAX_IDLE_CONNECTIONS {
+                connections.push(connection);
+            }
+
+            Ok(value)
+        })
+        .await
+        .map_err(|_| Error::Timeout)?
+    }
+
+    /// Subscribes to a channel on a dedicated connection.
+    pub async fn subscribe(&self, channel: &str) -> Result<Subscription, Error> {
+        let mut connection = Connection::open(&self.url).await?;
+        connection
+            .command(&[b"SUBSCRIBE", channel.as_bytes()])
+            .await?;
+
+        Ok(Subscription { connection })
+    }
+}
+
+#[derive(Debug)]
+pub struct Subscription {
+    connection: Connection,
+}
+
+impl Subscription {
+    /// Waits for the next message published to the channel.
+    pub async fn next_message(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Value::Array(values) = self.connection.read_value().await? {
+                let mut values = values.into_iter();
+
+                if let (Some(Value::Data(kind)), Some(_), Some(Value::Data(message))) =
+                    (values.next(), values.next(), values.next())
+                {
+                    if kind == b"message" {
+                        return Ok(message);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    stream: BufStream<TcpStream>,
+}
+
+impl Connection {
+    async fn open(url: &Url) -> Result<Self, Error> {
+        let host = url.host_str().ok_or(Error::UrlFormat)?;
+        let port = url.port().unwrap_or(6379);
+
+        trace!("connecting to redis at {host}:{port}");
+
+        let mut connection = Self {
+            stream: BufStream::new(
+                TcpStream::connect((host, port))
+                    .await
+                    .map_err(Error::Connect)?,
+            ),
+        };
+
+        if let Some(password) = url.password() {
+            let reply = match url.username() {
+                "" => connection.command(&[b"AUTH", password.as_bytes()]).await?,
+                user => {
+                    connection
+                        .command(&[b"AUTH", user.as_bytes(), password.as_bytes()])
+                        .await?
+                }
+            };
+
+            reply.expect_ok()?;
+        }
+
+        match url.path().trim_start_matches('/') {
+            "" => {}
+            db => {
+                db.parse::<u32>().map_err(|_| Error::UrlFormat)?;
+                connection
+                    .command(&[b"SELECT", db.as_bytes()])
+                    .await?
+                    .expect_ok()?;
+            }
+        }
+
+        Ok(connection)
+    }
+
+    async fn command(&mut self, args: &[&[u8]]) -> Result<Value, Error> {
+        let mut buffer = format!("*{}\r\n", args.len()).into_bytes();
+
+        for arg in args {
+            buffer.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            buffer.extend_from_slice(arg);
+            buffer.extend_from_slice(b"\r\n");
+        }
+
+        self.stream.write_all(&buffer).await?;
+        self.stream.flush().await?;
+        self.read_value().await
+    }
+
+    async fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = Vec::new();
+
+        if self.stream.read_until(b'\n', &mut line).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        match line.strip_suffix(b"\r\n") {
+            Some(line) => String::from_utf8(line.to_vec())
+                .map_err(|_| Error::Protocol("line is not valid utf-8".into())),
+            None => Err(Error::Protocol("unterminated line".into())),
+        }
+    }
+
+    fn read_value(&mut self) -> BoxFuture<'_, Result<Value, Error>> {
+        async move {
+            let line = self.read_line().await?;
+            let (kind, rest) = (line.get(..1).unwrap_or(""), line.get(1..).unwrap_or(""));
+
+            let parse_int = |s: &str| {
+                s.parse::<i64>()
+                    .map_err(|_| Error::Protocol(format!("invalid integer '{s}'")))
+            };
+
+            match kind {
+                "+" => Ok(Value::Status(rest.into())),
+                "-" => Err(Error::Server(rest.into())),
+                ":" => Ok(Value::Int(parse_int(rest)?)),
+                "$" => match parse_int(rest)? {
+                    len if len < 0 => Ok(Value::Nil),
+                    len => {
+                        let mut data = vec![0; len as usize + 2];
+                        self.stream.read_exact(&mut data).await?;
+                        data.truncate(len as usize);
+                        Ok(Value::Data(data))
+                    }
+                },
+                "*" => match parse_int(rest)? {
+                    len if len < 0 => Ok(Value::Nil),
+                    len => {
+                        let mut values = Vec::with_capacity(len as usize);
+
+                        for _ in 0..len {
+                            values.push(self.read_value().await?);
+                        }
+
+                        Ok(Value::Array(values))
+                    }
+                },
+                _ => Err(Error::Protocol(format!("unknown reply type '{kind}'"))),
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,78 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown content sniffing mode '{0}'")]
+    Unknown(String),
+}
+
+/// Number of leading bytes of content from which its type is detected.
+pub const SNIFF_SIZE: usize = 8192;
+
+/// How the content type declared by an upload is checked against the type detected from its content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniffMode {
+    /// Store the declared type as is.
+    Off,
+    /// Store the detected type instead of a mismatching declared type.
+    Correct,
+    /// Reject uploads whose declared type mismatches the detected type,
+    /// unless the declared type is generic such as `application/octet-stream`.
+    Reject,
+}
+
+impl SniffMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Correct => "correct",
+            Self::Reject => "reject",
+        }
+    }
+}
+
+impl FromStr for SniffMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "correct" => Ok(Self::Correct),
+            "reject" => Ok(Self::Reject),
+            _ => Err(Error::Unknown(s.into())),
+        }
+    }
+}
+
+impl Display for SniffMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Returns the content type without parameters such as charset.
+pub fn essence(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+/// Returns whether a content type says nothing about the content.
+pub fn is_generic(content_type: &str) -> bool {
+    let essence = essence(content_type);
+
+    essence.is_empty()
+        || essence.eq_ignore_ascii_case("application/octet-stream")
+        || essence.eq_ignore_ascii_case("binary/octet-stream")
+}
+
+/// Detects the content type of content from its leading bytes.
+pub fn detect(head: &[u8]) -> Option<&'static str> {
+    infer::get(head).map(|kind| kind.mime_type())
+}
@@ -0,0 +1,126 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use std::{io::SeekFrom, ops::Range, path::PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialize upload spool: {0}")]
+    Init(std::io::Error),
+
+    #[error("failed to write to upload spool: {0}")]
+    Write(std::io::Error),
+
+    #[error("failed to read from upload spool: {0}")]
+    Read(std::io::Error),
+}
+
+/// Local directory holding uploaded content until it is uploaded to Drive, keyed by remote file ID.
+///
+/// Content is spooled exactly as it is to be stored in Drive, so it is already encrypted.
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+}
+
+impl Spool {
+    pub fn new(path: PathBuf) -> Result<Self, Error> {
+        std::fs::create_dir_all(&path).map_err(Error::Init)?;
+
+        for entry in std::fs::read_dir(&path).map_err(Error::Init)? {
+            let entry = entry.map_err(Error::Init)?;
+
+            // leftover of an interrupted upload that was never acknowledged
+            if entry.file_name().to_string_lossy().ends_with(".tmp") {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+
+        Ok(Self { path })
+    }
+
+    /// Writes content to the spool, returning once it is durably stored.
+    pub async fn write<S, E>(&self, id: &str, content: S) -> Result<(), Error>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let temp = self.path.join(format!("{id}.tmp"));
+        let result = async {
+            let mut file = tokio::fs::File::create(&temp).await?;
+            let mut content = Box::pin(content);
+
+            while let Some(buffer) = content.next().await {
+                file.write_all(&buffer.map_err(std::io::Error::other)?)
+                    .await?;
+            }
+
+            file.sync_all().await?;
+            tokio::fs::rename(&temp, self.path.join(id)).await
+        }
+        .await;
+
+        if let Err(err) = result {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(Error::Write(err));
+        }
+
+        trace!("spooled file '{id}'");
+        Ok(())
+    }
+
+    /// Reads a range of spooled content, or returns `None` if the content isn't in the spool.
+    pub async fn read(
+        &self,
+        id: &str,
+        range: Range<u64>,
+    ) -> Result<
+        Option<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static>,
+        Error,
+    > {
+        let mut file = match tokio::fs::File::open(self.path.join(id)).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Error::Read(err)),
+        };
+
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .map_err(Error::Read)?;
+
+        Ok(Some(ReaderStream::new(file.take(range.end - range.start))))
+    }
+
+    /// Returns the IDs of all spooled files.
+    pub async fn list(&self) -> Result<Vec<String>, Error> {
+        let mut ids = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.path).await.map_err(Error::Read)?;
+
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Read)? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            if !name.ends_with(".tmp") {
+                ids.push(name);
+            }
+        }
+
+        Ok(ids)
+    }
+
+    pub async fn remove(&self, id: &str) {
+        match tokio::fs::remove_file(self.path.join(id)).await {
+            Ok(()) => trace!("removed spooled file '{id}'"),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => warn!("failed to remove spooled file '{id}': {err}"),
+        }
+    }
+}
@@ -0,0 +1,4 @@
+-- Encryption format versions
+alter table files
+  -- Version of the encrypted content format.
+  add column format smallint not null default 1;
@@ -0,0 +1,6 @@
+-- Chunk manifests
+alter table files
+  -- Concatenated SHA-256 hashes of the encrypted chunks.
+  add column manifest bytea
+  -- Merkle tree root of the chunk hashes.
+, add column manifest_root bytea;
@@ -0,0 +1,4 @@
+-- Envelope encryption
+alter table files
+  -- Identifier of the master key wrapping the secret, or null if the secret is stored unwrapped.
+  add column secret_key text;
@@ -0,0 +1,10 @@
+-- Re-encryption tracking
+alter table files
+  -- Time at which the remote file was last encrypted.
+  add column encrypted_time timestamp;
+
+update files set encrypted_time = created_time;
+
+alter table files
+  alter column encrypted_time set not null
+, alter column encrypted_time set default (timezone('utc', now()));
@@ -0,0 +1,4 @@
+-- Metadata encryption
+alter table files
+  -- Content type and filename are encrypted with the metadata key.
+  add column metadata_encrypted boolean not null default false;
@@ -0,0 +1,4 @@
+-- Write-back upload spool
+alter table files
+  -- Content is held in the local upload spool of an instance and is yet to be uploaded to Drive.
+  add column spooled boolean not null default false;
@@ -0,0 +1,4 @@
+-- File visibility
+alter table files
+  -- Whether the file can be downloaded without credentials, or null to follow the server default.
+  add column public boolean;
@@ -0,0 +1,6 @@
+-- Namespaces
+alter table files
+  -- Namespace of the API keys that can access the file, or empty for the default namespace.
+  add column namespace text not null default '';
+
+create index ix_files_namespace_key on files (namespace, key);
@@ -0,0 +1,10 @@
+-- Bytes uploaded and downloaded by each namespace per day, counted against the daily transfer allowance
+create table transfer_usage (
+  namespace     text        not null
+  -- UTC date of the transfers.
+, day           date        not null
+, size          bigint      not null default 0
+, primary key (namespace, day)
+);
+
+create index ix_transfer_usage_day on transfer_usage (day);
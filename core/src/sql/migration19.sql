@@ -0,0 +1,14 @@
+-- User accounts
+create table users (
+  key           serial      primary key
+  -- Unique name of the person or application.
+, name          text        not null unique
+  -- Role determining the requests that the user is authorized for.
+, role          text        not null
+  -- Namespace of the files that the user can access.
+, namespace     text        not null default ''
+  -- SHA-256 digest of the token that the user presents as an API key.
+, token_sha256  bytea       not null unique
+  -- Time of user creation.
+, created_time  timestamp   not null default (timezone('utc', now()))
+);
@@ -0,0 +1,26 @@
+-- Audit log of file operations
+create table audit_log (
+  key           bigserial   primary key
+  -- Time of the operation.
+, time          timestamp   not null default (timezone('utc', now()))
+  -- Kind of operation performed.
+, operation     text        not null
+  -- Key of the file operated on, if any.
+, file_key      integer
+  -- Drive API file resource ID, if known.
+, file_id       text
+  -- Address of the requesting client.
+, client_addr   text
+  -- Number of bytes transferred.
+, size          bigint
+  -- Start of the requested byte range, inclusive.
+, range_start   bigint
+  -- End of the requested byte range, exclusive.
+, range_end     bigint
+  -- HTTP status code of the response.
+, status        smallint    not null
+);
+
+create index ix_audit_log_time on audit_log (time);
+create index ix_audit_log_operation on audit_log (operation);
+create index ix_audit_log_file_key on audit_log (file_key);
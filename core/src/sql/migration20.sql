@@ -0,0 +1,11 @@
+-- Named aliases of files
+create table aliases (
+  -- Path by which the file can be downloaded.
+  name          text        primary key
+  -- Key of the aliased file.
+, file_key      integer     not null references files on delete cascade
+  -- Time at which the alias was last pointed to a file.
+, created_time  timestamp   not null default (timezone('utc', now()))
+);
+
+create index ix_aliases_file_key on aliases (file_key);
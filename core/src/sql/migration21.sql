@@ -0,0 +1,17 @@
+-- Collections of files
+create table collections (
+  key           serial      primary key
+  -- Name of the collection, unique in its namespace.
+, name          text        not null
+  -- Namespace of the API keys that can access the collection.
+, namespace     text        not null default ''
+, created_time  timestamp   not null default (timezone('utc', now()))
+, unique (namespace, name)
+);
+
+-- Collection of each file
+alter table files
+  -- Collection that the file was uploaded into, if any.
+  add column collection_key integer references collections on delete set null;
+
+create index ix_files_collection_key on files (collection_key);
@@ -0,0 +1,3 @@
+-- Listing files by size, with the key breaking ties between pages
+drop index ix_files_size;
+create index ix_files_size on files (size, key);
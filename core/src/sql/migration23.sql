@@ -0,0 +1,3 @@
+-- Searching files by content type and filename prefix
+create index ix_files_content_type_pattern on files (content_type text_pattern_ops);
+create index ix_files_filename_pattern on files (lower(filename) text_pattern_ops);
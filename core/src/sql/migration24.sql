@@ -0,0 +1,4 @@
+-- Client certificates in the audit log
+alter table audit_log
+  -- Subject of the certificate presented by the requesting client.
+  add column client_name text;
@@ -0,0 +1,14 @@
+-- Remote files waiting to be deleted from drive
+create table delete_queue (
+  -- Drive API file resource ID of the unreferenced remote file.
+  id            text        primary key
+, created_time  timestamp   not null default (timezone('utc', now()))
+  -- Number of failed attempts to delete the remote file.
+, attempts      integer     not null default 0
+  -- Time after which the deletion is attempted again.
+, retry_time    timestamp   not null default (timezone('utc', now()))
+  -- Error of the last failed attempt.
+, last_error    text
+);
+
+create index ix_delete_queue_retry_time on delete_queue (retry_time);
@@ -0,0 +1,6 @@
+-- 64-bit file keys, as a 32-bit serial can be exhausted by deployments that delete files as often as they add them
+alter table files alter column key type bigint;
+alter sequence files_key_seq as bigint;
+
+alter table aliases alter column file_key type bigint;
+alter table audit_log alter column file_key type bigint;
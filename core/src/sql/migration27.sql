@@ -0,0 +1,15 @@
+-- Objects of the S3-compatible api
+create table s3_objects (
+  -- Namespace of the credentials that stored the object, each of which has its own buckets.
+  namespace     text        not null
+, bucket        text        not null
+  -- Key of the object in the bucket.
+, name          text        not null
+  -- Key of the file holding the content of the object.
+, file_key      bigint      not null references files on delete cascade
+  -- Time at which the object was last stored.
+, created_time  timestamp   not null default (timezone('utc', now()))
+, primary key (namespace, bucket, name)
+);
+
+create index ix_s3_objects_file_key on s3_objects (file_key);
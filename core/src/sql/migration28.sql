@@ -0,0 +1,43 @@
+-- Size of the stored content, updated along with files so that quotas don't scan every file
+create table storage_usage (
+  -- Namespace of the files, or null for the files of all namespaces.
+  namespace     text
+  -- Total size of the remote files, counting those referenced by several files once.
+, stored_size   bigint      not null default 0
+);
+
+create unique index ux_storage_usage_namespace on storage_usage (namespace) where namespace is not null;
+create unique index ux_storage_usage_total on storage_usage ((namespace is null)) where namespace is null;
+
+-- Space reserved for uploads in progress, which counts towards quotas until the upload is stored or fails
+create table storage_reservations (
+  key           bigserial   primary key
+  -- Namespace of the upload.
+, namespace     text        not null
+, size          bigint      not null
+  -- Time of reservation, after which it expires in case the upload was never finished.
+, created_time  timestamp   not null default (timezone('utc', now()))
+);
+
+create index ix_storage_reservations_namespace on storage_reservations (namespace);
+create index ix_storage_reservations_created_time on storage_reservations (created_time);
+
+-- Recomputes the storage usage from scratch, as after importing files in bulk
+create function storage_usage_refresh() returns void as $$
+begin
+  delete from storage_usage;
+
+  insert into storage_usage (namespace, stored_size)
+  select null, coalesce(sum(size), 0) from (
+    select distinct on (id) size from files
+  ) remote;
+
+  insert into storage_usage (namespace, stored_size)
+  select namespace, sum(size) from (
+    select distinct on (namespace, id) namespace, size from files
+  ) remote
+  group by namespace;
+end;
+$$ language plpgsql;
+
+select storage_usage_refresh();
@@ -0,0 +1,8 @@
+-- Download statistics
+alter table files
+  -- Number of times the file was downloaded.
+  add column download_count bigint not null default 0
+  -- Total number of bytes served from the file.
+, add column bytes_served bigint not null default 0;
+
+create index ix_files_download_count on files (download_count);
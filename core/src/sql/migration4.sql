@@ -0,0 +1,4 @@
+-- Download limits
+alter table files
+  -- Number of downloads remaining before the file is deleted, or null if unlimited.
+  add column remaining_downloads integer;
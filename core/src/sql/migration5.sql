@@ -0,0 +1,4 @@
+-- Original file names
+alter table files
+  -- Original name of the file, if provided.
+  add column filename text;
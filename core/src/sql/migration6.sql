@@ -0,0 +1,6 @@
+-- User metadata
+alter table files
+  -- User-defined metadata as a JSON object.
+  add column metadata jsonb not null default '{}';
+
+create index ix_files_metadata on files using gin (metadata);
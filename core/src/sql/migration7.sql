@@ -0,0 +1,6 @@
+-- Content hashes
+alter table files
+  -- SHA-256 digest of the original content.
+  add column sha256 bytea;
+
+create index ix_files_sha256 on files (sha256);
@@ -0,0 +1,2 @@
+-- Content deduplication, files with identical content may reference the same remote file
+alter table files drop constraint files_id_key;
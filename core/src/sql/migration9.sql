@@ -0,0 +1,4 @@
+-- Cipher selection
+alter table files
+  -- Algorithm used to encrypt the file.
+  add column cipher text not null default 'xchacha20-poly1305';
@@ -0,0 +1,3334 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::{
+    cache::{self, ChunkCache, MediaCache, SharedCache},
+    cipher::{decrypt_stream, encrypt_stream, ChunkStreamCipher, CipherKind, Format},
+    db::{
+        Alias, AuditEntry, AuditEvent, AuditQuery, Collection, ContentTypeStats, Db, Encryption,
+        File, FileQuery, FileStats, MetadataTable, NewFile, NewRemoteFile, NewUser,
+        OperationCounts, S3Object, User, WrappedMetadataKey, CLIENT_ENCRYPTED, DEFAULT_NAMESPACE,
+        UNENCRYPTED,
+    },
+    drive::{self, Drive, FileHandle, FileResponse, FolderHandle, StorageQuota},
+    header::{format_hex, parse_hex, ByteRange},
+    keys::{MasterKey, WrappingKey},
+    manifest::Manifest,
+    rate_limit::RateLimit,
+    sniff::{self, SniffMode, SNIFF_SIZE},
+    spool::Spool,
+    stream::{
+        chunk_stream, hash_stream, peek_stream, readahead_stream, slice_stream, throttle_stream,
+        BandwidthLimiter, BufferPool,
+    },
+    webhook::{Event, EventKind, Webhooks},
+};
+use bytes::{Buf, Bytes};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures::{Stream, StreamExt, TryStreamExt};
+use lru::LruCache;
+use md5::Md5;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{digest::Update, Digest, Sha256, Sha512};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::sync::{Mutex, Notify};
+use tracing::Instrument;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Db(#[from] crate::db::Error),
+
+    #[error("{0}")]
+    Drive(#[from] crate::drive::Error),
+
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("{0}")]
+    Cipher(#[from] crate::cipher::Error),
+
+    #[error("invalid encryption key")]
+    SecretInvalid,
+
+    #[error("file secret is wrapped by unknown master key '{0}'")]
+    MasterKeyUnknown(String),
+
+    #[error("metadata encryption requires a master key")]
+    MasterKeyMissing,
+
+    #[error("invalid metadata key")]
+    MetadataKeyInvalid,
+
+    #[error("{0}")]
+    Redis(#[from] crate::redis::Error),
+
+    #[error("{0}")]
+    Spool(#[from] crate::spool::Error),
+
+    #[error("file has not been uploaded yet")]
+    FileSpooled,
+
+    #[error("content type '{0}' is not allowed")]
+    ContentTypeNotAllowed(String),
+
+    #[error("declared content type '{0}' does not match detected content type '{1}'")]
+    ContentTypeMismatch(String, &'static str),
+
+    #[error("drive {0} does not exist")]
+    DriveNotExists(i32),
+
+    #[error("appended content must start at the end of the file at offset {0}")]
+    AppendOffsetMismatch(u64),
+
+    #[error("file was changed or deleted while appending")]
+    FileChanged,
+
+    #[error("{0}")]
+    MasterKey(#[from] crate::keys::Error),
+
+    #[error("unsupported encryption format version {0}")]
+    FormatInvalid(i16),
+
+    #[error("invalid chunk manifest")]
+    ManifestInvalid,
+
+    #[error("file has reached its download limit")]
+    DownloadLimitExceeded,
+
+    #[error("{0} digest of the uploaded content does not match")]
+    DigestMismatch(&'static str),
+
+    #[error("duplicate content was deleted during upload")]
+    DuplicateDeleted,
+
+    #[error("storage quota of {0} bytes would be exceeded")]
+    QuotaExceeded(u64),
+
+    #[error("daily transfer allowance of {0} bytes would be exceeded")]
+    TransferAllowanceExceeded(u64),
+
+    #[error("none of the requested ranges are within the content of {0} bytes")]
+    RangeNotSatisfiable(u64),
+
+    #[error("no such collection '{0}'")]
+    CollectionNotExists(String),
+
+    #[error("database already has files and a different metadata key")]
+    MetadataKeyConflict,
+
+    #[error("prune policy must have at least one criterion")]
+    PrunePolicyEmpty,
+}
+
+const CHUNK_SIZE: usize = 1024 * 1024; // 1 MiB
+const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + ChunkStreamCipher::TAG_SIZE;
+const DRIVE_MAX_FILE_LIMIT: u32 = 350000; // conservative
+const SECRET_CACHE_SIZE: usize = 10000;
+const DOWNLOAD_SEGMENT_CHUNKS: u32 = 16;
+const DOWNLOAD_RESUME_ATTEMPTS: u32 = 3;
+const DRIVE_CHECK_CONCURRENCY: usize = 4;
+const STATS_CONTENT_TYPES: u32 = 100;
+const DELETE_QUEUE_BATCH_SIZE: u32 = 100;
+const RECONCILE_GRACE_HOURS: i64 = 24; // longer than any upload
+
+#[derive(Debug)]
+pub struct Store {
+    db: Db,
+    drive: Arc<Drive>,
+    deduplicate: bool,
+    cipher: CipherKind,
+    master_key: Option<WrappingKey>,
+    previous_master_keys: Vec<WrappingKey>,
+    chunk_cache: Option<Arc<ChunkCache>>,
+    media_cache: Option<Arc<MediaCache>>,
+    shared_cache: Option<Arc<SharedCache>>,
+    cache_upload_window: Option<std::time::Duration>,
+    readahead: usize,
+    download_parallelism: usize,
+    spool: Option<Spool>,
+    allowed_content_types: Vec<String>,
+    denied_content_types: Vec<String>,
+    sniff: SniffMode,
+    quota: Option<u64>,
+    namespace_quota: Option<u64>,
+    transfer_allowance: Option<u64>,
+    webhooks: Option<Webhooks>,
+    // wakes the spool worker when a file is spooled
+    spool_notify: Notify,
+    // progress of spooled files that the worker has attempted to upload, keyed by remote file id
+    spool_status: std::sync::Mutex<HashMap<String, UploadStatus>>,
+    // unwrapped file secrets keyed by their wrapped form
+    secret_cache: std::sync::Mutex<LruCache<Vec<u8>, Vec<u8>>>,
+    file_alloc_mutex: Mutex<()>,
+    // download statistics pending to be written to the database
+    file_stats: std::sync::Mutex<HashMap<i64, FileStats>>,
+}
+
+#[derive(Debug)]
+pub struct StoreConfig {
+    pub db: Db,
+    pub drive: Drive,
+    /// Reference existing remote files when uploading identical content.
+    pub deduplicate: bool,
+    /// Algorithm used to encrypt newly uploaded files.
+    pub cipher: CipherKind,
+    /// Key used to wrap file secrets, or `None` to store them unwrapped.
+    pub master_key: Option<WrappingKey>,
+    /// Keys that previously wrapped file secrets, used until the secrets are rewrapped.
+    pub previous_master_keys: Vec<WrappingKey>,
+    /// Local disk cache of stored chunks consulted before downloading from Drive.
+    pub chunk_cache: Option<ChunkCache>,
+    /// Memory cache of the first and last chunks of media files consulted before any other cache.
+    pub media_cache: Option<MediaCache>,
+    /// Cache of file metadata and small chunks shared with other instances.
+    pub shared_cache: Option<SharedCache>,
+    /// Duration for which chunks of uploaded files are kept in the local chunk cache unless they are read,
+    /// or `None` to cache only downloaded chunks.
+    pub cache_upload_window: Option<std::time::Duration>,
+    /// Number of chunks to download ahead of the client, or zero to download only as fast as the client reads.
+    pub readahead: usize,
+    /// Number of connections over which large ranges are downloaded concurrently.
+    pub download_parallelism: usize,
+    /// Local spool to which uploads are written before they are uploaded to Drive in the background.
+    pub spool: Option<Spool>,
+    /// Content types that uploads may declare, or empty to allow all types that aren't denied.
+    /// A type may be given as e.g. `image/*` to match all of its subtypes.
+    pub allowed_content_types: Vec<String>,
+    /// Content types that uploads may not declare, matched like `allowed_content_types`.
+    pub denied_content_types: Vec<String>,
+    /// How the content type declared by uploads is checked against the type detected from their content.
+    pub sniff: SniffMode,
+    /// Maximum total size of stored content in bytes, beyond which uploads are rejected.
+    pub quota: Option<u64>,
+    /// Maximum total size of content stored in each namespace in bytes.
+    pub namespace_quota: Option<u64>,
+    /// Maximum number of bytes that each namespace can upload and download per UTC day.
+    pub transfer_allowance: Option<u64>,
+    /// Webhooks notified of uploaded, deleted, expired and corrupted files.
+    pub webhooks: Option<Webhooks>,
+}
+
+#[derive(Debug)]
+pub struct UploadOptions {
+    pub content_type: String,
+    /// Original name of the file.
+    pub filename: Option<String>,
+    /// Number of downloads after which the file is deleted.
+    pub max_downloads: Option<u32>,
+    /// User-defined metadata.
+    pub metadata: Map<String, Value>,
+    /// Digests that the uploaded content must match.
+    pub digests: Vec<ExpectedDigest>,
+    /// Party that encrypts the content.
+    pub encryption: Encryption,
+    /// Whether the file can be downloaded without credentials, or `None` to follow the server default.
+    pub public: Option<bool>,
+    /// Namespace of the API keys that can access the file.
+    pub namespace: String,
+    /// Name of the collection in the namespace to upload the file into.
+    pub collection: Option<String>,
+}
+
+/// Digest of the original content as supplied by the client.
+#[derive(Debug, Clone)]
+pub enum ExpectedDigest {
+    Md5(Vec<u8>),
+    Sha256(Vec<u8>),
+    Sha512(Vec<u8>),
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            content_type: "application/octet-stream".into(),
+            filename: None,
+            max_downloads: None,
+            metadata: Map::new(),
+            digests: Vec::new(),
+            encryption: Encryption::Server,
+            public: None,
+            namespace: DEFAULT_NAMESPACE.into(),
+            collection: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FileData<S: Stream<Item = Result<Bytes, Error>>> {
+    pub info: File,
+    pub content: S,
+    pub range: Range<u64>,
+    /// This is the last permitted download, and the file should be deleted once it is served.
+    pub last_download: bool,
+    /// Transfer allowance left to the namespace of the file today, if there is one.
+    pub transfer_remaining: Option<u64>,
+}
+
+/// Entire content of a file read for exporting using [`Store::export`].
+#[derive(Debug)]
+pub struct ExportData<S: Stream<Item = Result<Bytes, Error>>> {
+    pub info: File,
+    pub content: S,
+    /// Unwrapped secret that decrypts the content if it is read as stored and was encrypted by the server.
+    pub secret: Option<Vec<u8>>,
+}
+
+/// File to be read in several ranges using [`Store::read_range`].
+#[derive(Debug)]
+pub struct RangesData {
+    pub info: File,
+    /// Sorted, non-overlapping ranges to read.
+    pub ranges: Vec<Range<u64>>,
+    /// This is the last permitted download, and the file should be deleted once it is served.
+    pub last_download: bool,
+    /// Transfer allowance left to the namespace of the file today, if there is one.
+    pub transfer_remaining: Option<u64>,
+}
+
+/// Criteria of the files deleted by [`Store::prune`], which must match all of the given criteria.
+#[derive(Debug, Default)]
+pub struct PrunePolicy {
+    /// Files created more than this many days ago.
+    pub older_than: Option<u32>,
+    /// Files not downloaded for this many days.
+    pub not_accessed_for: Option<u32>,
+    /// Files larger than this many bytes.
+    pub larger_than: Option<u64>,
+    /// Files with this content type, or with this prefix if it ends with '/'.
+    /// Files whose metadata is encrypted never match.
+    pub content_type: Option<String>,
+    /// Files in this namespace, or in any namespace if `None`.
+    pub namespace: Option<String>,
+}
+
+/// Files deleted by [`Store::prune`].
+#[derive(Debug, Default, Serialize)]
+pub struct PruneReport {
+    /// Keys of the deleted files, or of the files that would be deleted in a dry run.
+    pub keys: Vec<i64>,
+    /// Total size of the deleted files.
+    pub size: u64,
+    /// Keys of the files that failed to be deleted.
+    pub failed: Vec<i64>,
+}
+
+/// Discrepancies between the database and drive found by [`Store::reconcile`].
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    /// Number of remote files listed in drive.
+    pub listed: u64,
+    /// IDs of remote files in drive that no file references.
+    pub orphaned: Vec<String>,
+    /// Keys of files whose remote file is missing from drive.
+    pub dangling: Vec<i64>,
+}
+
+/// Aggregate statistics of all stored files.
+#[derive(Debug, Serialize)]
+pub struct StorageStats {
+    pub file_count: u64,
+    /// Total size of the content of all files.
+    pub size: u64,
+    /// Total size of the remote files, counting shared remote files once and including encryption overhead.
+    pub stored_size: u64,
+    /// Content types with the most content.
+    pub content_types: Vec<ContentTypeStats>,
+    /// Successful operations in recent windows, as far as the audit log is retained.
+    pub last_hour: OperationCounts,
+    pub last_day: OperationCounts,
+    pub last_week: OperationCounts,
+    /// Storage quota of the Drive account, or `None` if it couldn't be retrieved.
+    pub drive_quota: Option<StorageQuota>,
+}
+
+/// Operations waiting for the resources that all requests share, which grow when the store is overloaded.
+#[derive(Debug, Clone, Copy)]
+pub struct Load {
+    /// Number of database operations waiting for a connection.
+    pub db_waiting: usize,
+    /// Average time for which database operations waited for a connection.
+    pub db_wait: std::time::Duration,
+    /// Number of Drive API requests waiting for the request limit.
+    pub drive_waiting: usize,
+    /// Estimated time until the waiting Drive API requests are sent.
+    pub drive_wait: std::time::Duration,
+}
+
+/// Usage and health of a shared drive.
+#[derive(Debug)]
+pub struct DriveReport {
+    pub key: i32,
+    /// Drive API shared drive resource ID.
+    pub id: String,
+    pub created_time: NaiveDateTime,
+    /// Number of remote files in the drive.
+    pub file_count: u64,
+    /// Total size of the remote files in the drive, including encryption overhead.
+    pub stored_size: u64,
+    pub health: DriveHealth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DriveHealth {
+    Ok,
+    /// Drive holds as many files as new files are allocated to it.
+    Full,
+    /// Drive couldn't be accessed through the Drive API.
+    Unreachable,
+}
+
+/// Result of checking the integrity of a stored file.
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub key: i64,
+    /// Number of chunks in the file.
+    pub chunks: u32,
+    /// Ids of the chunks that failed authentication or did not match the manifest.
+    pub corrupted: Vec<u32>,
+    /// The remote file ended before all chunks could be read.
+    pub truncated: bool,
+}
+
+impl VerifyReport {
+    pub fn is_valid(&self) -> bool {
+        self.corrupted.is_empty() && !self.truncated
+    }
+}
+
+/// Progress of uploading a file to drive.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase", tag = "status", content = "error")]
+pub enum UploadStatus {
+    /// File is in the upload spool and waiting to be uploaded.
+    Pending,
+    Uploading,
+    /// File is durably stored in drive.
+    Complete,
+    /// Last attempt to upload the file failed, and it will be retried.
+    Failed(String),
+}
+
+/// Remote file that is yet to be added to the database.
+struct RemoteUpload<'a> {
+    drive_key: i32,
+    handle: FileHandle,
+    cipher: &'static str,
+    /// File secret, wrapped if a master key is configured.
+    secret: Vec<u8>,
+    secret_key: Option<&'a str>,
+    manifest: Manifest,
+    /// Content is in the upload spool rather than Drive.
+    spooled: bool,
+}
+
+impl Store {
+    pub fn new(config: StoreConfig) -> Self {
+        let StoreConfig {
+            db,
+            drive,
+            deduplicate,
+            cipher,
+            master_key,
+            previous_master_keys,
+            chunk_cache,
+            media_cache,
+            shared_cache,
+            cache_upload_window,
+            readahead,
+            download_parallelism,
+            spool,
+            allowed_content_types,
+            denied_content_types,
+            sniff,
+            quota,
+            namespace_quota,
+            transfer_allowance,
+            webhooks,
+        } = config;
+
+        Self {
+            db,
+            drive: Arc::new(drive),
+            deduplicate,
+            cipher,
+            master_key,
+            previous_master_keys,
+            chunk_cache: chunk_cache.map(Arc::new),
+            media_cache: media_cache.map(Arc::new),
+            shared_cache: shared_cache.map(Arc::new),
+            cache_upload_window,
+            readahead,
+            download_parallelism,
+            spool,
+            allowed_content_types,
+            denied_content_types,
+            sniff,
+            quota,
+            namespace_quota,
+            transfer_allowance,
+            webhooks,
+            spool_notify: Notify::new(),
+            spool_status: Default::default(),
+            secret_cache: std::sync::Mutex::new(LruCache::new(SECRET_CACHE_SIZE)),
+            file_alloc_mutex: Mutex::new(()),
+            file_stats: Default::default(),
+        }
+    }
+
+    /// Queues an event about a file for delivery to the webhooks, if any.
+    fn notify(&self, event: EventKind, file: &File, details: Option<Value>) {
+        if let Some(ref webhooks) = self.webhooks {
+            webhooks.send(&Event::new(event, file, details));
+        }
+    }
+
+    /// Detects the type of uploaded content from its leading bytes, and replaces the declared type with it
+    /// or rejects the upload if they mismatch.
+    async fn sniff_content_type<S, B, E>(
+        &self,
+        options: &mut UploadOptions,
+        content: S,
+    ) -> Result<impl Stream<Item = Result<Bytes, E>> + Send + Sync + 'static, Error>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Buf + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let size = if self.sniffs(options) { SNIFF_SIZE } else { 0 };
+
+        let (head, content) = peek_stream(content, size).await;
+
+        if let Some(detected) = sniff::detect(&head) {
+            let declared = sniff::essence(&options.content_type);
+
+            if !declared.eq_ignore_ascii_case(detected) {
+                if self.sniff == SniffMode::Reject && !sniff::is_generic(declared) {
+                    return Err(Error::ContentTypeMismatch(declared.into(), detected));
+                }
+
+                trace!("correcting declared content type '{declared}' to '{detected}'");
+                options.content_type = detected.into();
+            }
+        }
+
+        Ok(content)
+    }
+
+    /// Returns true if the content of an upload is sniffed, in which case its declared content type may change.
+    fn sniffs(&self, options: &UploadOptions) -> bool {
+        // client-encrypted content is indistinguishable from random bytes
+        self.sniff != SniffMode::Off && options.encryption != Encryption::Client
+    }
+
+    /// Reserves space for storing `size` more bytes in a namespace without exceeding the global or namespace quota,
+    /// returning the reservation to release once the content is stored, or `None` if there are no quotas.
+    async fn reserve_storage(&self, size: u64, namespace: &str) -> Result<Option<i64>, Error> {
+        if self.quota.is_none() && self.namespace_quota.is_none() {
+            return Ok(None);
+        }
+
+        match self
+            .db
+            .reserve_storage(namespace, size, self.quota, self.namespace_quota)
+            .await?
+        {
+            Ok(key) => Ok(Some(key)),
+            Err(quota) => Err(Error::QuotaExceeded(quota)),
+        }
+    }
+
+    /// Counts `size` bytes towards the daily transfer allowance of a namespace,
+    /// returning the allowance left today, or `None` if there is no allowance.
+    async fn count_transfer(&self, namespace: &str, size: u64) -> Result<Option<u64>, Error> {
+        let allowance = match self.transfer_allowance {
+            Some(allowance) => allowance,
+            None => return Ok(None),
+        };
+
+        match self.db.add_transfer(namespace, size, allowance).await? {
+            Some(transferred) => Ok(Some(allowance.saturating_sub(transferred))),
+            None => Err(Error::TransferAllowanceExceeded(allowance)),
+        }
+    }
+
+    /// Returns the daily transfer allowance left to a namespace today, or `None` if there is no allowance.
+    pub async fn get_transfer_remaining(&self, namespace: &str) -> Result<Option<u64>, Error> {
+        match self.transfer_allowance {
+            Some(allowance) => Ok(Some(
+                allowance.saturating_sub(self.db.get_transfer(namespace).await?),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Releases space reserved by [`Self::reserve_storage`], which expires on its own if this fails.
+    async fn release_storage(&self, reservation: Option<i64>) {
+        if let Some(key) = reservation {
+            if let Err(err) = self.db.release_storage(key).await {
+                warn!("failed to release storage reservation {key}: {err}");
+            }
+        }
+    }
+
+    fn check_content_type(&self, content_type: &str) -> Result<(), Error> {
+        let essence = sniff::essence(content_type);
+        let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(kind) => essence
+                .split_once('/')
+                .is_some_and(|(essence_kind, _)| essence_kind.eq_ignore_ascii_case(kind)),
+            None => essence.eq_ignore_ascii_case(pattern),
+        };
+
+        if self.denied_content_types.iter().any(matches)
+            || !(self.allowed_content_types.is_empty()
+                || self.allowed_content_types.iter().any(matches))
+        {
+            return Err(Error::ContentTypeNotAllowed(essence.into()));
+        }
+
+        Ok(())
+    }
+
+    fn rand_drive_name() -> String {
+        format!(
+            "castella-{}",
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(char::from)
+                .collect::<String>()
+        )
+    }
+
+    fn rand_file_name() -> String {
+        thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(20)
+            .map(char::from)
+            .collect()
+    }
+
+    async fn allocate_file(&self) -> Result<crate::db::Drive, Error> {
+        // don't create multiple drives in race condition
+        let _lock = self.file_alloc_mutex.lock().await;
+
+        // find a drive with the least number of files and less than the limit
+        match self
+            .db
+            .get_drive_by_least_files(DRIVE_MAX_FILE_LIMIT)
+            .await?
+        {
+            Some(drive) => Ok(drive),
+            None => {
+                // such a drive doesn't exist; create a new one and add to database
+                let folder = self.drive.create_drive(Self::rand_drive_name()).await?;
+                Ok(self.db.add_drive(folder.id).await?)
+            }
+        }
+    }
+
+    pub async fn upload<S, B, E>(
+        &self,
+        size: u64,
+        options: UploadOptions,
+        content: S,
+    ) -> Result<File, Error>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Buf + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        // reject the upload before reading any content if possible,
+        // so that clients expecting 100-continue don't send the body for nothing
+        let sniffs = self.sniffs(&options);
+        if !sniffs {
+            self.check_content_type(&options.content_type)?;
+        }
+
+        let collection_key = match options.collection {
+            Some(ref name) => Some(
+                self.db
+                    .get_collection(name, &options.namespace)
+                    .await?
+                    .ok_or_else(|| Error::CollectionNotExists(name.clone()))?
+                    .key,
+            ),
+            None => None,
+        };
+
+        let reservation = self.reserve_storage(size, &options.namespace).await?;
+        let result = self
+            .upload_reserved(size, options, sniffs, collection_key, content)
+            .await;
+
+        // the stored file counts towards the quota in its place by now
+        self.release_storage(reservation).await;
+        result
+    }
+
+    /// Uploads content for which space was reserved after the checks that don't need the content.
+    async fn upload_reserved<S, B, E>(
+        &self,
+        size: u64,
+        mut options: UploadOptions,
+        sniffs: bool,
+        collection_key: Option<i32>,
+        content: S,
+    ) -> Result<File, Error>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Buf + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.count_transfer(&options.namespace, size).await?;
+
+        let content = self.sniff_content_type(&mut options, content).await?;
+        if sniffs {
+            self.check_content_type(&options.content_type)?;
+        }
+
+        // skip uploading entirely if the client told us the digest of existing content
+        if self.deduplicate {
+            let sha256 = options.digests.iter().find_map(|digest| match digest {
+                ExpectedDigest::Sha256(sha256) => Some(sha256),
+                _ => None,
+            });
+
+            if let Some(sha256) = sha256 {
+                if let Some(existing) = self.db.get_file_by_sha256(sha256, None).await? {
+                    // don't let unencrypted uploads reference encrypted content or vice versa
+                    if existing.size as u64 == size && existing.encryption() == options.encryption {
+                        let file = self
+                            .upload_duplicate(existing, size, options, collection_key, content)
+                            .await?;
+
+                        self.notify(EventKind::Upload, &file, None);
+                        return Ok(file);
+                    }
+                }
+            }
+        }
+
+        // upload file and add to database
+        let (
+            RemoteUpload {
+                drive_key,
+                handle,
+                cipher,
+                secret,
+                secret_key,
+                manifest,
+                spooled,
+            },
+            sha256,
+        ) = self.upload_verified(size, &options, content).await?;
+
+        let file = NewFile {
+            id: &handle.id,
+            drive_key,
+            size: size as i64,
+            content_type: &options.content_type,
+            cipher,
+            format: Format::LATEST.version(),
+            secret: &secret,
+            secret_key,
+            remaining_downloads: options.max_downloads.map(|n| n.min(i32::MAX as u32) as i32),
+            filename: options.filename.as_deref(),
+            metadata: &options.metadata,
+            sha256: &sha256,
+            manifest: Some(&manifest.to_bytes()),
+            manifest_root: Some(&manifest.root()),
+            spooled,
+            public: options.public,
+            namespace: &options.namespace,
+            collection_key,
+        };
+
+        match self.add_uploaded_file(&file, &handle, &options).await {
+            Ok(file) => {
+                if spooled {
+                    self.spool_notify.notify_one();
+                }
+
+                self.notify(EventKind::Upload, &file, None);
+                Ok(file)
+            }
+            Err(err) => {
+                // nothing references the upload, which would otherwise be leaked
+                self.discard_upload(&handle, spooled).await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Adds a file for a new remote file to the database, or a reference to existing content if it is a duplicate,
+    /// in which case the new remote file is deleted.
+    async fn add_uploaded_file(
+        &self,
+        file: &NewFile<'_>,
+        handle: &FileHandle,
+        options: &UploadOptions,
+    ) -> Result<File, Error> {
+        if self.deduplicate {
+            // identical content may have been uploaded without the client knowing its digest
+            let existing = self
+                .db
+                .get_file_by_sha256(file.sha256, None)
+                .await?
+                .filter(|existing| existing.encryption() == options.encryption);
+
+            if let Some(existing) = existing {
+                let reference = self
+                    .db
+                    .add_file_reference(&NewFile {
+                        id: &existing.id,
+                        drive_key: existing.drive_key,
+                        cipher: &existing.cipher,
+                        format: existing.format,
+                        secret: &existing.secret,
+                        secret_key: existing.secret_key.as_deref(),
+                        manifest: existing.manifest.as_deref(),
+                        manifest_root: existing.manifest_root.as_deref(),
+                        ..*file
+                    })
+                    .await?;
+
+                if let Some(reference) = reference {
+                    trace!("content matches file {}; deleting upload", existing.key);
+                    self.discard_upload(handle, file.spooled).await;
+                    return Ok(reference);
+                }
+            }
+        }
+
+        Ok(self.db.add_file(file).await?)
+    }
+
+    /// Deletes a remote file that no file references after a failed or redundant upload,
+    /// queueing its deletion if it can't be deleted right away.
+    async fn discard_upload(&self, handle: &FileHandle, spooled: bool) {
+        if let Err(err) = self.delete_remote_file(handle, spooled).await {
+            warn!(
+                "failed to delete upload '{}'; retrying later: {err}",
+                handle.id
+            );
+
+            if let Err(err) = self.db.queue_delete(&handle.id).await {
+                error!("failed to queue deletion of upload '{}': {err}", handle.id);
+            }
+        }
+    }
+
+    /// Uploads content to a new remote file and checks it against the digests expected by the client,
+    /// returning the remote file and the SHA-256 digest of the content.
+    async fn upload_verified<S, B, E>(
+        &self,
+        size: u64,
+        options: &UploadOptions,
+        content: S,
+    ) -> Result<(RemoteUpload<'_>, Vec<u8>), Error>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Buf + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        // chain processing streams
+        let hasher = Arc::new(std::sync::Mutex::new(ContentHasher::new(&options.digests)));
+        let hashed = hash_stream(
+            chunk_stream(size, content, CHUNK_SIZE as u64),
+            hasher.clone(),
+        );
+
+        let upload = self
+            .upload_content(size, hashed, options.encryption, self.spool.is_some())
+            .await?;
+
+        // stream is fully consumed by now
+        let hasher = std::mem::take(&mut *hasher.lock().unwrap());
+        let sha256 = hasher.sha256.clone().finalize().to_vec();
+
+        if let Err(err) = hasher.verify(&options.digests) {
+            // don't leave the mismatched upload dangling in drive
+            self.discard_upload(&upload.handle, upload.spooled).await;
+
+            return Err(err);
+        }
+
+        Ok((upload, sha256))
+    }
+
+    /// Replaces the content of an existing file with newly uploaded content, keeping its key.
+    /// The content is always uploaded to a new remote file with a new secret.
+    pub async fn replace<S, B, E>(
+        &self,
+        key: i64,
+        size: u64,
+        mut options: UploadOptions,
+        content: S,
+    ) -> Result<Option<File>, Error>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Buf + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let sniffs = self.sniffs(&options);
+        if !sniffs {
+            self.check_content_type(&options.content_type)?;
+        }
+
+        // don't read the content at all for a nonexistent file
+        if self.db.get_file_by_key(key).await?.is_none() {
+            return Ok(None);
+        }
+
+        let reservation = self.reserve_storage(size, &options.namespace).await?;
+
+        let result = async {
+            self.count_transfer(&options.namespace, size).await?;
+
+            let content = self.sniff_content_type(&mut options, content).await?;
+            if sniffs {
+                self.check_content_type(&options.content_type)?;
+            }
+
+            self.replace_content(key, None, size, options, content)
+                .await
+        }
+        .await;
+
+        self.release_storage(reservation).await;
+        result
+    }
+
+    /// Appends content to the end of an existing file at `offset`, keeping its key.
+    ///
+    /// Drive files can't be appended to, and re-encrypting the partial last chunk under the same secret
+    /// would reuse its nonce, so the existing content is streamed into a new remote file followed by
+    /// the appended content, encrypted with a new secret.
+    pub async fn append<S, B, E>(
+        &self,
+        key: i64,
+        offset: u64,
+        size: u64,
+        content: S,
+    ) -> Result<Option<File>, Error>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Buf + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let file = match self.db.get_file_by_key(key).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let existing_size = file.size as u64;
+        if offset != existing_size {
+            return Err(Error::AppendOffsetMismatch(existing_size));
+        }
+
+        let reservation = self
+            .reserve_storage(existing_size + size, &file.namespace)
+            .await?;
+
+        let result = async {
+            // only the appended content is transferred
+            self.count_transfer(&file.namespace, size).await?;
+
+            let existing = self.read_content(&file).await?;
+            let content: ContentStream = Box::pin(
+                existing.chain(
+                    content
+                        .map_ok(|mut buf| buf.copy_to_bytes(buf.remaining()))
+                        .map_err(std::io::Error::other),
+                ),
+            );
+
+            let options = UploadOptions {
+                content_type: file.content_type.clone(),
+                filename: file.filename.clone(),
+                encryption: file.encryption(),
+                namespace: file.namespace.clone(),
+                ..Default::default()
+            };
+
+            self.replace_content(key, Some(&file.id), existing_size + size, options, content)
+                .await?
+                .map(Some)
+                .ok_or(Error::FileChanged)
+        }
+        .await;
+
+        self.release_storage(reservation).await;
+        result
+    }
+
+    /// Opens the entire content of a file from the spool or drive, decrypted if it was encrypted by the server.
+    async fn read_content(&self, file: &File) -> Result<ContentStream, Error> {
+        let cipher = self.file_cipher(file).await?;
+        let manifest = Self::file_manifest(file)?;
+        let stored_size = Self::stored_size(file);
+
+        let spooled = match self.spool {
+            Some(ref spool) if file.spooled => spool.read(&file.id, 0..stored_size).await?,
+            _ => None,
+        };
+
+        let stream = match spooled {
+            Some(stream) => stream.left_stream(),
+            None => {
+                let FileResponse { stream, .. } = self
+                    .drive
+                    .get_file(&FileHandle::new(file.id.clone()), 0..stored_size)
+                    .await?;
+
+                slice_stream(stream, 0..stored_size)
+                    .map_err(std::io::Error::other)
+                    .right_stream()
+            }
+        };
+
+        let chunked = chunk_stream(stored_size, stream, Self::stored_chunk_size(file));
+        let verified = verify_stream(chunked, manifest, 0);
+
+        // boxed to keep the type of the streams uploaded from it manageable
+        Ok(match cipher {
+            Some(cipher) => Box::pin(decrypt_stream(verified, cipher, 0)),
+            None => Box::pin(verified),
+        })
+    }
+
+    /// Uploads content to a new remote file that replaces the content of a file,
+    /// unless its remote file is no longer `old_id` if given.
+    async fn replace_content<S, B, E>(
+        &self,
+        key: i64,
+        old_id: Option<&str>,
+        size: u64,
+        options: UploadOptions,
+        content: S,
+    ) -> Result<Option<File>, Error>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Buf + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let (
+            RemoteUpload {
+                drive_key,
+                handle,
+                cipher,
+                secret,
+                secret_key,
+                manifest,
+                spooled,
+            },
+            sha256,
+        ) = self.upload_verified(size, &options, content).await?;
+
+        let replaced = self
+            .db
+            .replace_file_content(
+                key,
+                old_id,
+                &NewFile {
+                    id: &handle.id,
+                    drive_key,
+                    size: size as i64,
+                    content_type: &options.content_type,
+                    cipher,
+                    format: Format::LATEST.version(),
+                    secret: &secret,
+                    secret_key,
+                    remaining_downloads: None,
+                    filename: options.filename.as_deref(),
+                    metadata: &options.metadata,
+                    sha256: &sha256,
+                    manifest: Some(&manifest.to_bytes()),
+                    manifest_root: Some(&manifest.root()),
+                    spooled,
+                    public: None,
+                    namespace: &options.namespace,
+                    collection_key: None,
+                },
+            )
+            .await;
+
+        let (old, file, unreferenced) = match replaced {
+            Ok(Some(result)) => result,
+            result => {
+                // file was deleted while uploading, or the replacement failed
+                self.discard_upload(&handle, spooled).await;
+
+                return Ok(result?.map(|(_, file, _)| file));
+            }
+        };
+
+        if spooled {
+            self.spool_notify.notify_one();
+        }
+
+        if let Some(ref cache) = self.shared_cache {
+            cache.remove_file(key).await;
+        }
+
+        // old remote file may still be referenced by other files with identical content
+        if unreferenced {
+            let deleted = if old.spooled {
+                self.delete_remote_file(&FileHandle::new(old.id.clone()), true)
+                    .await
+            } else {
+                self.delete_queued_file(&old.id).await.map(|_| ())
+            };
+
+            if let Err(err) = deleted {
+                warn!("failed to delete replaced file '{}': {err}", old.id);
+            }
+
+            self.uncache_remote_file(&old.id, old.size as u64).await;
+        }
+
+        Ok(Some(file))
+    }
+
+    /// Encrypts content chunked into messages of [`CHUNK_SIZE`] if it is to be encrypted by the server,
+    /// and uploads it to a new remote file, or writes it to the upload spool if `use_spool` is true.
+    async fn upload_content<S>(
+        &self,
+        size: u64,
+        content: S,
+        encryption: Encryption,
+        use_spool: bool,
+    ) -> Result<RemoteUpload<'_>, Error>
+    where
+        S: Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static,
+    {
+        // allocate file to a drive
+        let drive = self.allocate_file().await?;
+
+        trace!("allocating a new file to drive '{}'", drive.id);
+
+        // reserve remote file id, which the cipher authenticates
+        let handle = self.drive.generate_file_id().await?;
+
+        // initialize cipher
+        let encrypt = encryption == Encryption::Server;
+        let (cipher, secret) = if encrypt {
+            let secret = ChunkStreamCipher::gen_secret(self.cipher);
+            let cipher = ChunkStreamCipher::new(
+                self.cipher,
+                Format::LATEST,
+                &secret,
+                Self::last_chunk_id(size),
+                &handle.id,
+            )
+            .ok_or(Error::SecretInvalid)?;
+
+            (Some(cipher), secret)
+        } else {
+            (None, Vec::new())
+        };
+
+        // chain processing streams
+        let manifest = Arc::new(std::sync::Mutex::new(Manifest::default()));
+        let stream = {
+            let encrypted = match cipher {
+                Some(cipher) => encrypt_stream(content, cipher, 0).left_stream(),
+                None => content.right_stream(),
+            };
+
+            let manifest = manifest.clone();
+            encrypted.map_ok(move |chunk| {
+                manifest.lock().unwrap().push(&chunk);
+                chunk
+            })
+        };
+
+        // write-through to the local cache; spooled files are read from the spool anyway
+        let write_through = match (&self.chunk_cache, self.cache_upload_window) {
+            (Some(cache), Some(window)) if !use_spool => Some((cache.clone(), window)),
+            _ => None,
+        };
+
+        let stream = match write_through {
+            Some((ref cache, _)) => {
+                let cache = cache.clone();
+                let id = handle.id.clone();
+
+                stream
+                    .zip(futures::stream::iter(0..))
+                    .map(move |(chunk, chunk_id)| {
+                        if let Ok(ref chunk) = chunk {
+                            let cache = cache.clone();
+                            let id = id.clone();
+                            let chunk = chunk.clone();
+
+                            tokio::spawn(
+                                async move { cache.put_unread(&id, chunk_id, &chunk).await }
+                                    .in_current_span(),
+                            );
+                        }
+
+                        chunk
+                    })
+                    .left_stream()
+            }
+            None => stream.right_stream(),
+        };
+
+        // ciphertext expansion; one tag for each encrypted chunk
+        let stored_size = if encrypt {
+            Self::encrypted_size(size)
+        } else {
+            size
+        };
+
+        trace!("original size {size}, stored size {stored_size}");
+
+        let spooled = match self.spool {
+            Some(ref spool) if use_spool => {
+                spool.write(&handle.id, stream).await?;
+                true
+            }
+            _ => {
+                let result = self
+                    .drive
+                    .create_file(
+                        &handle,
+                        Self::rand_file_name(),
+                        FolderHandle::new(drive.id),
+                        stored_size,
+                        "application/octet-stream",
+                        stream,
+                    )
+                    .await;
+
+                // chunks that are not read back within the window are removed, including those of failed uploads
+                if let Some((cache, window)) = write_through {
+                    let id = handle.id.clone();
+
+                    tokio::spawn(async move {
+                        tokio::time::sleep(window).await;
+                        cache.remove_unread(&id).await;
+                    });
+                }
+
+                result?;
+                false
+            }
+        };
+
+        let manifest = std::mem::take(&mut *manifest.lock().unwrap());
+
+        if !encrypt {
+            return Ok(RemoteUpload {
+                drive_key: drive.key,
+                handle,
+                cipher: match encryption {
+                    Encryption::Client => CLIENT_ENCRYPTED,
+                    _ => UNENCRYPTED,
+                },
+                secret,
+                secret_key: None,
+                manifest,
+                spooled,
+            });
+        }
+
+        let (secret, secret_key) = match self.master_key {
+            Some(ref key) => (key.wrap(&secret).await?, Some(key.id())),
+            None => (secret, None),
+        };
+
+        Ok(RemoteUpload {
+            drive_key: drive.key,
+            handle,
+            cipher: self.cipher.name(),
+            secret,
+            secret_key,
+            manifest,
+            spooled,
+        })
+    }
+
+    /// Adds a file referencing the remote file of existing identical content.
+    async fn upload_duplicate<S, B, E>(
+        &self,
+        existing: File,
+        size: u64,
+        options: UploadOptions,
+        collection_key: Option<i32>,
+        content: S,
+    ) -> Result<File, Error>
+    where
+        S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+        B: Buf + Send + Sync + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        trace!("content matches file {}; skipping upload", existing.key);
+
+        // the content is still verified so that a digest alone doesn't grant access to existing content
+        let hasher = Arc::new(std::sync::Mutex::new(ContentHasher::new(&options.digests)));
+
+        hash_stream(
+            chunk_stream(size, content, CHUNK_SIZE as u64),
+            hasher.clone(),
+        )
+        .try_for_each(|_| async { Ok(()) })
+        .await?;
+
+        let hasher = std::mem::take(&mut *hasher.lock().unwrap());
+        let sha256 = hasher.sha256.clone().finalize();
+        hasher.verify(&options.digests)?;
+
+        self.db
+            .add_file_reference(&NewFile {
+                id: &existing.id,
+                drive_key: existing.drive_key,
+                size: size as i64,
+                content_type: &options.content_type,
+                cipher: &existing.cipher,
+                format: existing.format,
+                secret: &existing.secret,
+                secret_key: existing.secret_key.as_deref(),
+                remaining_downloads: options.max_downloads.map(|n| n.min(i32::MAX as u32) as i32),
+                filename: options.filename.as_deref(),
+                metadata: &options.metadata,
+                sha256: &sha256,
+                manifest: existing.manifest.as_deref(),
+                manifest_root: existing.manifest_root.as_deref(),
+                spooled: existing.spooled,
+                public: options.public,
+                namespace: &options.namespace,
+                collection_key,
+            })
+            .await?
+            .ok_or(Error::DuplicateDeleted)
+    }
+
+    fn find_master_key(&self, id: &str) -> Result<&WrappingKey, Error> {
+        self.master_key
+            .iter()
+            .chain(&self.previous_master_keys)
+            .find(|key| key.id() == id)
+            .ok_or_else(|| Error::MasterKeyUnknown(id.into()))
+    }
+
+    /// Loads the key with which file metadata is encrypted,
+    /// generating one if `encrypt` is true and none exists yet.
+    pub async fn load_metadata_key(&mut self, encrypt: bool) -> Result<(), Error> {
+        let wrapped = match self.db.get_metadata_key().await? {
+            Some(wrapped) => wrapped,
+            None if encrypt => {
+                let master_key = self.master_key.as_ref().ok_or(Error::MasterKeyMissing)?;
+                let mut key = vec![0; MasterKey::SIZE];
+                thread_rng().fill(&mut key[..]);
+
+                // another instance may have generated a key in the meantime
+                self.db
+                    .add_metadata_key(&WrappedMetadataKey {
+                        key_id: master_key.id().into(),
+                        wrapped: base64::encode(master_key.wrap(&key).await?),
+                    })
+                    .await?
+            }
+            None => return Ok(()),
+        };
+
+        let key = self.unwrap_metadata_key(&wrapped).await?;
+
+        // rewrap the key with the current master key after rotation
+        if let Some(ref master_key) = self.master_key {
+            if master_key.id() != wrapped.key_id {
+                self.db
+                    .set_metadata_key_config(&WrappedMetadataKey {
+                        key_id: master_key.id().into(),
+                        wrapped: base64::encode(master_key.wrap(&key).await?),
+                    })
+                    .await?;
+            }
+        }
+
+        self.db.set_metadata_key(
+            MasterKey::new(&key).ok_or(Error::MetadataKeyInvalid)?,
+            encrypt,
+        );
+
+        Ok(())
+    }
+
+    async fn unwrap_metadata_key(&self, wrapped: &WrappedMetadataKey) -> Result<Vec<u8>, Error> {
+        Ok(self
+            .find_master_key(&wrapped.key_id)?
+            .unwrap(&base64::decode(&wrapped.wrapped).map_err(|_| Error::MetadataKeyInvalid)?)
+            .await?)
+    }
+
+    /// Returns the wrapped metadata key for exporting, rewrapped with `rewrap_key` if given.
+    pub async fn export_metadata_key(
+        &self,
+        rewrap_key: Option<&MasterKey>,
+    ) -> Result<Option<WrappedMetadataKey>, Error> {
+        let (wrapped, rewrap_key) = match (self.db.get_metadata_key().await?, rewrap_key) {
+            (Some(wrapped), Some(rewrap_key)) => (wrapped, rewrap_key),
+            (wrapped, _) => return Ok(wrapped),
+        };
+
+        let key = self.unwrap_metadata_key(&wrapped).await?;
+
+        Ok(Some(WrappedMetadataKey {
+            key_id: rewrap_key.id().into(),
+            wrapped: base64::encode(rewrap_key.wrap(&key)?),
+        }))
+    }
+
+    /// Returns the rows of a metadata table with a key greater than `after_key` for exporting,
+    /// with file secrets rewrapped with `rewrap_key` if given.
+    pub async fn export_metadata(
+        &self,
+        table: MetadataTable,
+        after_key: i64,
+        limit: u32,
+        rewrap_key: Option<&MasterKey>,
+    ) -> Result<Vec<Map<String, Value>>, Error> {
+        let mut rows = self.db.export_rows(table, after_key, limit).await?;
+
+        let rewrap_key = match rewrap_key {
+            Some(key) if table == MetadataTable::Files => key,
+            _ => return Ok(rows),
+        };
+
+        for row in &mut rows {
+            // bytea columns are exported in hex format
+            let secret = row
+                .get("secret")
+                .and_then(Value::as_str)
+                .and_then(|secret| secret.strip_prefix("\\x"))
+                .and_then(parse_hex)
+                .ok_or(Error::SecretInvalid)?;
+
+            let key_id = row.get("secret_key").and_then(Value::as_str);
+            let secret = self.unwrap_secret_with(&secret, key_id).await?;
+
+            row.insert(
+                "secret".into(),
+                format!("\\x{}", format_hex(rewrap_key.wrap(&secret)?)).into(),
+            );
+            row.insert("secret_key".into(), rewrap_key.id().into());
+        }
+
+        Ok(rows)
+    }
+
+    /// Stores an exported metadata key unless the database has its own. A different key is only replaced
+    /// while there are no files, as it may have been generated on startup.
+    pub async fn import_metadata_key(&self, key: &WrappedMetadataKey) -> Result<(), Error> {
+        let stored = self.db.add_metadata_key(key).await?;
+
+        if stored.key_id == key.key_id && stored.wrapped == key.wrapped {
+            return Ok(());
+        }
+
+        // the same key may have been rewrapped with another master key since it was exported
+        if let (Ok(stored), Ok(key)) = (
+            self.unwrap_metadata_key(&stored).await,
+            self.unwrap_metadata_key(key).await,
+        ) {
+            if stored == key {
+                return Ok(());
+            }
+        }
+
+        if !self
+            .db
+            .export_rows(MetadataTable::Files, 0, 1)
+            .await?
+            .is_empty()
+        {
+            return Err(Error::MetadataKeyConflict);
+        }
+
+        Ok(self.db.set_metadata_key_config(key).await?)
+    }
+
+    /// Inserts exported rows of a metadata table, skipping rows that already exist,
+    /// and returns the number of rows inserted.
+    pub async fn import_metadata(
+        &self,
+        table: MetadataTable,
+        rows: &[Map<String, Value>],
+    ) -> Result<u64, Error> {
+        Ok(self.db.import_rows(table, rows).await?)
+    }
+
+    /// Encrypts the metadata of files added before metadata encryption was enabled,
+    /// returning the number of files updated.
+    pub async fn encrypt_files_metadata(&self) -> Result<u64, Error> {
+        Ok(self.db.encrypt_files_metadata().await?)
+    }
+
+    /// Returns the plaintext secret of a file, unwrapping it with the master key if necessary.
+    async fn unwrap_secret(&self, file: &File) -> Result<Vec<u8>, Error> {
+        self.unwrap_secret_with(&file.secret, file.secret_key.as_deref())
+            .await
+    }
+
+    /// Unwraps a secret with the master key of the given ID, or returns it as is if it isn't wrapped.
+    async fn unwrap_secret_with(
+        &self,
+        secret: &[u8],
+        key_id: Option<&str>,
+    ) -> Result<Vec<u8>, Error> {
+        let id = match key_id {
+            Some(id) => id,
+            None => return Ok(secret.to_vec()),
+        };
+
+        // avoid calling the key management service for every download
+        if let Some(secret) = self.secret_cache.lock().unwrap().get(secret) {
+            return Ok(secret.clone());
+        }
+
+        let key = self.find_master_key(id)?;
+        let unwrapped = key.unwrap(secret).await?;
+
+        self.secret_cache
+            .lock()
+            .unwrap()
+            .put(secret.to_vec(), unwrapped.clone());
+
+        Ok(unwrapped)
+    }
+
+    /// Initializes the cipher with which the content of a file was encrypted.
+    /// Returns `None` if the file is not encrypted.
+    async fn file_cipher(&self, file: &File) -> Result<Option<ChunkStreamCipher>, Error> {
+        if !file.is_encrypted() {
+            return Ok(None);
+        }
+
+        ChunkStreamCipher::new(
+            file.cipher.parse()?,
+            Format::from_version(file.format).ok_or(Error::FormatInvalid(file.format))?,
+            &self.unwrap_secret(file).await?,
+            Self::last_chunk_id(file.size as u64),
+            &file.id,
+        )
+        .map(Some)
+        .ok_or(Error::SecretInvalid)
+    }
+
+    pub async fn get_storage_stats(&self) -> Result<StorageStats, Error> {
+        let (file_count, size) = self.db.get_file_totals().await?;
+        let stored_size = self
+            .db
+            .get_drive_usage(CHUNK_SIZE as u64, ChunkStreamCipher::TAG_SIZE as u64)
+            .await?
+            .iter()
+            .map(|usage| usage.stored_size as u64)
+            .sum();
+
+        let now = Utc::now().naive_utc();
+        let drive_quota = match self.drive.get_storage_quota().await {
+            Ok(quota) => Some(quota),
+            Err(err) => {
+                warn!("{err}");
+                None
+            }
+        };
+
+        Ok(StorageStats {
+            file_count,
+            size,
+            stored_size,
+            content_types: self.db.get_content_type_stats(STATS_CONTENT_TYPES).await?,
+            last_hour: self
+                .db
+                .get_operation_counts(now - Duration::hours(1))
+                .await?,
+            last_day: self
+                .db
+                .get_operation_counts(now - Duration::days(1))
+                .await?,
+            last_week: self
+                .db
+                .get_operation_counts(now - Duration::weeks(1))
+                .await?,
+            drive_quota,
+        })
+    }
+
+    /// Algorithm with which newly uploaded files are encrypted.
+    pub fn cipher(&self) -> CipherKind {
+        self.cipher
+    }
+
+    /// Returns the number of operations waiting for a database connection or the Drive API request limit.
+    pub fn load(&self) -> Load {
+        let (db_waiting, db_wait) = self.db.connection_queue();
+        let (drive_waiting, drive_wait) = self.drive.request_queue();
+
+        Load {
+            db_waiting,
+            db_wait,
+            drive_waiting,
+            drive_wait,
+        }
+    }
+
+    /// Checks that the database is reachable.
+    pub async fn ping_db(&self) -> Result<(), Error> {
+        Ok(self.db.ping().await?)
+    }
+
+    /// Checks that all database migrations have been applied.
+    pub async fn check_migrated(&self) -> Result<(), Error> {
+        Ok(self.db.check_migrated().await?)
+    }
+
+    /// Checks that an access token for the Drive API can be obtained.
+    pub async fn check_drive_auth(&self) -> Result<(), Error> {
+        Ok(self.drive.check_auth().await?)
+    }
+
+    /// Checks that the Drive API accepts the credentials by requesting information about the user.
+    pub async fn check_drive_access(&self) -> Result<(), Error> {
+        self.drive.get_storage_quota().await?;
+        Ok(())
+    }
+
+    /// Replaces the limits of Drive API requests and upload bandwidth.
+    pub fn set_drive_limits(&self, request_limit: RateLimit, upload_limit: RateLimit) {
+        self.drive.set_limits(request_limit, upload_limit);
+    }
+
+    /// Changes the maximum total size of the chunk cache in bytes, if any.
+    pub async fn set_cache_capacity(&self, capacity: u64) {
+        if let Some(ref cache) = self.chunk_cache {
+            cache.set_capacity(capacity).await;
+        }
+    }
+
+    /// Returns the usage of every shared drive, checking whether each is accessible.
+    pub async fn get_drive_reports(&self) -> Result<Vec<DriveReport>, Error> {
+        let usage = self
+            .db
+            .get_drive_usage(CHUNK_SIZE as u64, ChunkStreamCipher::TAG_SIZE as u64)
+            .await?;
+
+        Ok(futures::stream::iter(usage)
+            .map(|usage| async move {
+                let file_count = usage.file_count as u64;
+                let health = match self.drive.get_drive(&FolderHandle::new(&usage.id)).await {
+                    Err(err) => {
+                        warn!("shared drive '{}' is unreachable: {err}", usage.id);
+                        DriveHealth::Unreachable
+                    }
+                    Ok(()) if file_count >= DRIVE_MAX_FILE_LIMIT as u64 => DriveHealth::Full,
+                    Ok(()) => DriveHealth::Ok,
+                };
+
+                DriveReport {
+                    key: usage.key,
+                    id: usage.id,
+                    created_time: usage.created_time,
+                    file_count,
+                    stored_size: usage.stored_size as u64,
+                    health,
+                }
+            })
+            .buffered(DRIVE_CHECK_CONCURRENCY)
+            .collect()
+            .await)
+    }
+
+    /// Lists every shared drive and compares its remote files with the files in the database.
+    ///
+    /// Remote files uploaded within the last day are skipped, as they may belong to uploads that are in progress.
+    /// If `repair` is set, orphaned remote files are deleted and files whose remote file is missing are deleted.
+    pub async fn reconcile(&self, repair: bool) -> Result<ReconcileReport, Error> {
+        let grace_time = Utc::now() - Duration::hours(RECONCILE_GRACE_HOURS);
+        let mut report = ReconcileReport::default();
+        let mut missing = Vec::new();
+
+        for drive in self.db.get_drives().await? {
+            let folder = FolderHandle::new(drive.id);
+
+            // taken before listing, so that files uploaded meanwhile aren't considered missing
+            let mut known: HashSet<_> = self
+                .db
+                .get_remote_ids_by_drive(drive.key)
+                .await?
+                .into_iter()
+                .collect();
+
+            let mut page_token = None;
+
+            loop {
+                let list = self
+                    .drive
+                    .list_files(&folder, page_token.as_deref())
+                    .await?;
+
+                report.listed += list.files.len() as u64;
+
+                let mut candidates = Vec::new();
+
+                for file in list.files {
+                    if !known.remove(&file.id) && file.created_time < grace_time {
+                        candidates.push(file.id);
+                    }
+                }
+
+                // checked again, as files may have been added since the known ids were taken
+                if !candidates.is_empty() {
+                    report
+                        .orphaned
+                        .extend(self.db.get_unknown_remote_ids(&candidates).await?);
+                }
+
+                page_token = match list.next_page_token {
+                    Some(token) => Some(token),
+                    None => break,
+                };
+            }
+
+            // confirmed individually, in case the listing was stale
+            for id in known {
+                if !self.drive.file_exists(&FileHandle::new(&id)).await? {
+                    missing.push(id);
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            // files deleted meanwhile no longer reference the missing remote files
+            report.dangling = self.db.get_file_keys_by_remote_ids(&missing).await?;
+        }
+
+        if !report.orphaned.is_empty() || !report.dangling.is_empty() {
+            warn!(
+                "found {} orphaned remote file(s) and {} file(s) missing from drive",
+                report.orphaned.len(),
+                report.dangling.len()
+            );
+        }
+
+        if repair {
+            for id in &report.orphaned {
+                self.db.queue_delete(id).await?;
+                self.delete_queued_file(id).await?;
+            }
+
+            for &key in &report.dangling {
+                if let Err(err) = self.delete(key).await {
+                    warn!("failed to delete file {key} missing from drive: {err}");
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Size of the remote file.
+    fn stored_size(file: &File) -> u64 {
+        if file.is_encrypted() {
+            Self::encrypted_size(file.size as u64)
+        } else {
+            file.size as u64
+        }
+    }
+
+    /// Size of the chunks within the remote file.
+    fn stored_chunk_size(file: &File) -> u64 {
+        if file.is_encrypted() {
+            ENCRYPTED_CHUNK_SIZE as u64
+        } else {
+            CHUNK_SIZE as u64
+        }
+    }
+
+    fn file_manifest(file: &File) -> Result<Option<Manifest>, Error> {
+        // files uploaded before manifests were introduced can't be verified
+        match file.manifest {
+            Some(ref manifest) => Ok(Some(
+                Manifest::from_bytes(manifest).ok_or(Error::ManifestInvalid)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Wraps file secrets that are not yet wrapped by the master key, returning the number of files updated.
+    pub async fn wrap_secrets(&self) -> Result<u64, Error> {
+        let key = match self.master_key {
+            Some(ref key) => key,
+            None => return Ok(0),
+        };
+
+        let mut count = 0;
+
+        loop {
+            let files = self
+                .db
+                .get_files_by_other_secret_key(Some(key.id()), 100)
+                .await?;
+
+            if files.is_empty() {
+                return Ok(count);
+            }
+
+            for file in files {
+                let secret = key.wrap(&self.unwrap_secret(&file).await?).await?;
+
+                self.db
+                    .set_file_secret(file.key, &file.secret, &secret, Some(key.id()))
+                    .await?;
+
+                count += 1;
+            }
+        }
+    }
+
+    fn last_chunk_id(size: u64) -> u32 {
+        (size.saturating_sub(1) / (CHUNK_SIZE as u64)) as u32
+    }
+
+    fn encrypted_size(size: u64) -> u64 {
+        size + (Self::last_chunk_id(size) as u64 + 1) * (ChunkStreamCipher::TAG_SIZE as u64)
+    }
+
+    fn resolve_range(range: ByteRange, size: u64) -> Option<Range<u64>> {
+        let (start, end) = match range {
+            ByteRange::From(start, Some(end)) => (start, end.saturating_add(1).min(size)),
+            ByteRange::From(start, None) => (start, size),
+            // the last bytes of the file, or the entire file if it is shorter
+            ByteRange::Suffix(length) => (size.saturating_sub(length), size),
+        };
+
+        if start < end {
+            Some(start..end)
+        } else {
+            None
+        }
+    }
+
+    /// Downloads a range of a remote file, split into chunks of the given size.
+    /// The download is resumed from where it failed if the connection is interrupted.
+    async fn download_chunks(
+        drive: Arc<Drive>,
+        id: String,
+        range: Range<u64>,
+        chunk_size: u64,
+    ) -> Result<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static, Error>
+    {
+        let stream = open_range(&drive, &id, range.clone())
+            .await
+            .map_err(Error::Drive)?;
+
+        let length = range.end - range.start;
+        let resumed = resume_stream(drive, id, range, stream);
+
+        Ok(chunk_stream(length, resumed, chunk_size))
+    }
+
+    pub async fn get(
+        &self,
+        key: i64,
+        range: Option<ByteRange>,
+    ) -> Result<Option<FileData<impl Stream<Item = Result<Bytes, Error>>>>, Error> {
+        let resolve = |size| match range {
+            Some(range) => Self::resolve_range(range, size).ok_or(Error::RangeNotSatisfiable(size)),
+            None => Ok(0..size),
+        };
+
+        let (file, last_download, transfer_remaining) = match self
+            .get_for_download(key, |size| {
+                let range = resolve(size)?;
+                Ok(range.end - range.start)
+            })
+            .await?
+        {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        let range = resolve(file.size as u64)?;
+
+        let content = self.read_range(&file, range.clone()).await?;
+        self.add_download_stats(file.key, range.end - range.start);
+
+        Ok(Some(FileData {
+            info: file,
+            content,
+            range,
+            last_download,
+            transfer_remaining,
+        }))
+    }
+
+    /// Counts a download of an entire file like [`Self::get`] without reading its content,
+    /// for downloads that may be served from elsewhere, such as a cache of derived content.
+    /// Returns the file, whether this is its last permitted download, and the transfer allowance left.
+    pub async fn count_download(
+        &self,
+        key: i64,
+    ) -> Result<Option<(File, bool, Option<u64>)>, Error> {
+        let download = self.get_for_download(key, Ok).await?;
+
+        if let Some((ref file, ..)) = download {
+            self.add_download_stats(file.key, file.size as u64);
+        }
+
+        Ok(download)
+    }
+
+    /// Gets a file to be downloaded in several ranges, which are sorted and coalesced where they overlap.
+    /// Fails with [`Error::RangeNotSatisfiable`] if none of the ranges are within the file.
+    pub async fn get_ranges(
+        &self,
+        key: i64,
+        ranges: Vec<ByteRange>,
+    ) -> Result<Option<RangesData>, Error> {
+        let length =
+            |ranges: &[Range<u64>]| ranges.iter().map(|range| range.end - range.start).sum();
+
+        let (file, last_download, transfer_remaining) = match self
+            .get_for_download(key, |size| {
+                Ok(length(&Self::coalesce_ranges(&ranges, size)?))
+            })
+            .await?
+        {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        let coalesced = Self::coalesce_ranges(&ranges, file.size as u64)?;
+        self.add_download_stats(file.key, length(&coalesced));
+
+        Ok(Some(RangesData {
+            info: file,
+            ranges: coalesced,
+            last_download,
+            transfer_remaining,
+        }))
+    }
+
+    /// Resolves ranges within a file of `size` bytes, sorted and coalesced where they overlap.
+    fn coalesce_ranges(ranges: &[ByteRange], size: u64) -> Result<Vec<Range<u64>>, Error> {
+        let mut resolved: Vec<_> = ranges
+            .iter()
+            .filter_map(|&range| Self::resolve_range(range, size))
+            .collect();
+
+        resolved.sort_by_key(|range| range.start);
+
+        let mut coalesced: Vec<Range<u64>> = Vec::with_capacity(resolved.len());
+
+        for range in resolved {
+            match coalesced.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => coalesced.push(range),
+            }
+        }
+
+        if coalesced.is_empty() {
+            return Err(Error::RangeNotSatisfiable(size));
+        }
+
+        Ok(coalesced)
+    }
+
+    /// Gets a file and counts a download of `length(size)` bytes towards its download limit and the transfer
+    /// allowance of its namespace. Returns the file, whether this is its last permitted download,
+    /// and the transfer allowance left.
+    async fn get_for_download(
+        &self,
+        key: i64,
+        length: impl FnOnce(u64) -> Result<u64, Error>,
+    ) -> Result<Option<(File, bool, Option<u64>)>, Error> {
+        let file = match self.get_cached_file(key).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        // counted before the download, so that a download rejected by the allowance doesn't use up the limit
+        let transfer_remaining = self
+            .count_transfer(&file.namespace, length(file.size as u64)?)
+            .await?;
+
+        let file = match file.remaining_downloads {
+            // counted in the database, so that concurrent downloads can't exceed the limit
+            Some(_) => self.db.count_file_download(key).await?,
+            None => Some(file),
+        };
+
+        let file = match file {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let last_download = match file.remaining_downloads {
+            Some(remaining) if remaining < 0 => {
+                // the last download may not have deleted the file if it was interrupted
+                self.expire(key).await?;
+                return Err(Error::DownloadLimitExceeded);
+            }
+            Some(remaining) => remaining == 0,
+            None => false,
+        };
+
+        Ok(Some((file, last_download, transfer_remaining)))
+    }
+
+    /// Records a download in the statistics that are flushed to the database in batches,
+    /// including the access time of the file.
+    fn add_download_stats(&self, key: i64, length: u64) {
+        let mut stats = self.file_stats.lock().unwrap();
+        let stats = stats.entry(key).or_default();
+        stats.download_count += 1;
+        stats.bytes_served += length as i64;
+        stats.accessed_time = Some(Utc::now().naive_utc());
+    }
+
+    /// Reads a range of the content of a file, which must be within the file.
+    pub async fn read_range(
+        &self,
+        file: &File,
+        range: Range<u64>,
+    ) -> Result<impl Stream<Item = Result<Bytes, Error>> + Send + Sync + 'static, Error> {
+        // initialize cipher
+        // compute ranges for decryption
+        let size = file.size as u64;
+
+        let cipher = self.file_cipher(file).await?;
+        let encrypted_size = Self::stored_size(file);
+        let encrypted_chunk_size = Self::stored_chunk_size(file);
+
+        trace!("original size {size}, stored size {encrypted_size}");
+
+        trace!(
+            "resolved absolute range {start}-{end}",
+            start = range.start,
+            end = range.end
+        );
+
+        let chunk_range = {
+            // ids of the chunks containing the requested range
+            let start = (range.start / (CHUNK_SIZE as u64)) as u32;
+            let end = (range.end.saturating_sub(1) / (CHUNK_SIZE as u64) + 1) as u32;
+
+            start..end
+        };
+
+        trace!(
+            "chunk range {start}-{end}",
+            start = chunk_range.start,
+            end = chunk_range.end
+        );
+
+        let encrypted_range = {
+            // range to request within the encrypted file in drive
+            let start = (chunk_range.start as u64) * encrypted_chunk_size;
+            let end = (chunk_range.end as u64) * encrypted_chunk_size;
+
+            start..end.min(encrypted_size)
+        };
+
+        let content_range = {
+            // range within the decrypted stream
+            // since the file is retrieved in chunks, there may be some excess unrequested
+            // data at the range start and end; trim those off using this range
+            let start = range.start - (chunk_range.start as u64) * (CHUNK_SIZE as u64);
+            let end = start + (range.end - range.start);
+
+            start..end
+        };
+
+        trace!(
+            "content range within stream {start}-{end}",
+            start = content_range.start,
+            end = content_range.end
+        );
+
+        let manifest = Self::file_manifest(file)?;
+
+        // chunks at either end of media files are kept in memory if every requested chunk is one of them
+        let chunk_count = Self::last_chunk_id(size) + 1;
+        let media_cache = self
+            .media_cache
+            .clone()
+            .filter(|_| MediaCache::caches_type(&file.content_type));
+
+        let media_chunks = match media_cache {
+            Some(ref cache)
+                if chunk_range
+                    .clone()
+                    .all(|chunk_id| cache.caches_chunk(chunk_id, chunk_count)) =>
+            {
+                cache.get_chunks(&file.id, chunk_range.clone())
+            }
+            _ => None,
+        };
+
+        let media_cached = media_chunks.is_some();
+
+        // serve from the local chunk cache if every requested chunk is cached,
+        // then from the shared cache, and only then download from drive
+        let local_cached = match self.chunk_cache {
+            Some(ref cache) if !media_cached => chunk_range
+                .clone()
+                .all(|chunk_id| cache.contains(&file.id, chunk_id)),
+            _ => false,
+        };
+
+        let shared_cache = self
+            .shared_cache
+            .clone()
+            .filter(|cache| cache.caches_chunks(encrypted_size));
+
+        let shared_chunks = match shared_cache {
+            Some(ref cache) if !media_cached && !local_cached => {
+                cache.get_chunks(&file.id, chunk_range.clone()).await
+            }
+            _ => None,
+        };
+
+        let shared_cached = shared_chunks.is_some();
+
+        // serve from the upload spool until the file is uploaded to drive
+        let spooled = match self.spool {
+            Some(ref spool) if file.spooled && !media_cached => {
+                spool.read(&file.id, encrypted_range.clone()).await?
+            }
+            _ => None,
+        };
+
+        let chunked = if let Some(chunks) = media_chunks {
+            trace!("serving chunks from media cache");
+            futures::stream::iter(chunks.into_iter().map(Ok))
+                .right_stream()
+                .left_stream()
+        } else if local_cached {
+            trace!("serving chunks from local cache");
+            cache::read_stream(
+                self.chunk_cache.clone().unwrap(),
+                file.id.clone(),
+                chunk_range.clone(),
+            )
+            .left_stream()
+            .left_stream()
+        } else if let Some(chunks) = shared_chunks {
+            trace!("serving chunks from shared cache");
+            futures::stream::iter(chunks.into_iter().map(Ok))
+                .right_stream()
+                .left_stream()
+        } else if let Some(stream) = spooled {
+            trace!("serving chunks from upload spool");
+            chunk_stream(
+                encrypted_range.end - encrypted_range.start,
+                stream,
+                encrypted_chunk_size,
+            )
+            .left_stream()
+            .right_stream()
+        } else {
+            // download file from drive
+            if self.download_parallelism > 1 && chunk_range.len() > DOWNLOAD_SEGMENT_CHUNKS as usize
+            {
+                // download consecutive segments of the range concurrently over separate connections
+                let segments: Vec<_> = chunk_range
+                    .clone()
+                    .step_by(DOWNLOAD_SEGMENT_CHUNKS as usize)
+                    .map(|chunk_id| {
+                        let start = (chunk_id as u64) * encrypted_chunk_size;
+                        let end =
+                            ((chunk_id + DOWNLOAD_SEGMENT_CHUNKS) as u64) * encrypted_chunk_size;
+
+                        start..end.min(encrypted_range.end)
+                    })
+                    .collect();
+
+                trace!("downloading {} segment(s) concurrently", segments.len());
+
+                let drive = self.drive.clone();
+                let id = file.id.clone();
+
+                futures::stream::iter(segments)
+                    .map(move |range| {
+                        let drive = drive.clone();
+                        let id = id.clone();
+
+                        // spawned so that the segment downloads while preceding segments are consumed
+                        let task = tokio::spawn(
+                            async move {
+                                let chunked =
+                                    Self::download_chunks(drive, id, range, encrypted_chunk_size)
+                                        .await?;
+
+                                Ok::<_, Error>(readahead_stream(
+                                    chunked,
+                                    DOWNLOAD_SEGMENT_CHUNKS as usize,
+                                ))
+                            }
+                            .in_current_span(),
+                        );
+
+                        async move {
+                            match task.await {
+                                Ok(result) => result.map_err(std::io::Error::other),
+                                Err(err) => Err(std::io::Error::other(err)),
+                            }
+                        }
+                    })
+                    .buffered(self.download_parallelism)
+                    .try_flatten()
+                    .left_stream()
+                    .right_stream()
+                    .right_stream()
+            } else {
+                let chunked = Self::download_chunks(
+                    self.drive.clone(),
+                    file.id.clone(),
+                    encrypted_range.clone(),
+                    encrypted_chunk_size,
+                )
+                .await?;
+
+                if self.readahead != 0 {
+                    readahead_stream(chunked, self.readahead)
+                        .left_stream()
+                        .right_stream()
+                        .right_stream()
+                        .right_stream()
+                } else {
+                    chunked
+                        .right_stream()
+                        .right_stream()
+                        .right_stream()
+                        .right_stream()
+                }
+            }
+        };
+
+        // chain processing streams
+        let content = {
+            let verified = verify_stream(chunked, manifest, chunk_range.start);
+
+            // only chunks that passed verification are cached
+            let local = self
+                .chunk_cache
+                .clone()
+                .filter(|_| !media_cached && !local_cached);
+            let shared = shared_cache.filter(|_| !media_cached && !local_cached && !shared_cached);
+            let media = media_cache
+                .filter(|_| !media_cached)
+                .map(|cache| (cache, chunk_count));
+
+            let verified = if local.is_some() || shared.is_some() || media.is_some() {
+                cache::write_stream(
+                    verified,
+                    local,
+                    shared,
+                    media,
+                    file.id.clone(),
+                    chunk_range.start,
+                )
+                .left_stream()
+            } else {
+                verified.right_stream()
+            };
+            let decrypted = match cipher {
+                Some(cipher) => decrypt_stream(verified, cipher, chunk_range.start).left_stream(),
+                None => verified.right_stream(),
+            };
+            let view = slice_stream(decrypted, content_range);
+            view.map_err(Error::Io)
+        };
+
+        Ok(content)
+    }
+
+    /// Downloads the entire remote file and checks the authentication tag of every chunk if encrypted,
+    /// as well as its hash if the file has a manifest.
+    pub async fn verify(
+        &self,
+        key: i64,
+        limiter: Option<Arc<BandwidthLimiter>>,
+    ) -> Result<Option<VerifyReport>, Error> {
+        let file = match self.db.get_file_by_key(key).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        if file.spooled {
+            return Err(Error::FileSpooled);
+        }
+
+        let cipher = self.file_cipher(&file).await?;
+        let manifest = Self::file_manifest(&file)?;
+        let encrypted_size = Self::stored_size(&file);
+        let encrypted_chunk_size = Self::stored_chunk_size(&file);
+
+        let FileResponse { stream, .. } = self
+            .drive
+            .get_file(&FileHandle::new(file.id.clone()), 0..encrypted_size)
+            .await?;
+
+        let view = slice_stream(stream, 0..encrypted_size);
+
+        let view = match limiter {
+            Some(limiter) => throttle_stream(view, limiter).left_stream(),
+            None => view.right_stream(),
+        };
+
+        let mut chunks = Box::pin(chunk_stream(encrypted_size, view, encrypted_chunk_size));
+
+        let mut report = VerifyReport {
+            key,
+            chunks: encrypted_size.div_ceil(encrypted_chunk_size) as u32,
+            corrupted: Vec::new(),
+            truncated: false,
+        };
+
+        let pool = BufferPool::new(1);
+        let mut chunk_id = 0;
+
+        loop {
+            let chunk = match chunks.try_next().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    report.truncated = true;
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            let valid = manifest
+                .as_ref()
+                .is_none_or(|manifest| manifest.verify(chunk_id, &chunk))
+                && cipher
+                    .as_ref()
+                    .is_none_or(|cipher| cipher.decrypt(chunk_id, &chunk, &pool).is_ok());
+
+            if !valid {
+                warn!("chunk {chunk_id} of file {key} is corrupted");
+                report.corrupted.push(chunk_id);
+            }
+
+            chunk_id += 1;
+        }
+
+        if !report.is_valid() {
+            self.notify(
+                EventKind::VerifyFailed,
+                &file,
+                serde_json::to_value(&report).ok(),
+            );
+        }
+
+        Ok(Some(report))
+    }
+
+    /// Reads the entire content of a file for exporting, without counting it as a download.
+    /// If `raw` is set, the content is read from Drive as stored and returned with its unwrapped secret.
+    pub async fn export(
+        &self,
+        key: i64,
+        raw: bool,
+        limiter: Option<Arc<BandwidthLimiter>>,
+    ) -> Result<Option<ExportData<impl Stream<Item = Result<Bytes, Error>>>>, Error> {
+        let file = match self.db.get_file_by_key(key).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let (content, secret) = if raw {
+            if file.spooled {
+                return Err(Error::FileSpooled);
+            }
+
+            let secret = if file.is_encrypted() {
+                Some(self.unwrap_secret(&file).await?)
+            } else {
+                None
+            };
+
+            let stored_size = Self::stored_size(&file);
+            let FileResponse { stream, .. } = self
+                .drive
+                .get_file(&FileHandle::new(file.id.clone()), 0..stored_size)
+                .await?;
+
+            let content = slice_stream(stream, 0..stored_size).map_err(Error::from);
+            (content.left_stream(), secret)
+        } else {
+            let content = self.read_range(&file, 0..file.size as u64).await?;
+            (content.right_stream(), None)
+        };
+
+        let content = match limiter {
+            Some(limiter) => throttle_stream(content, limiter).left_stream(),
+            None => content.right_stream(),
+        };
+
+        Ok(Some(ExportData {
+            info: file,
+            content,
+            secret,
+        }))
+    }
+
+    /// Re-encrypts the content of a file with a new secret into a new remote file,
+    /// which replaces the remote file of all files referencing the same content.
+    pub async fn reencrypt(
+        &self,
+        file: &File,
+        limiter: Option<Arc<BandwidthLimiter>>,
+    ) -> Result<(), Error> {
+        // spooled content was encrypted with the current cipher and can't be replaced in drive yet
+        if file.spooled {
+            return Ok(());
+        }
+
+        let size = file.size as u64;
+        let cipher = match self.file_cipher(file).await? {
+            Some(cipher) => cipher,
+            None => return Ok(()), // unencrypted files are left as is
+        };
+
+        let manifest = Self::file_manifest(file)?;
+        let encrypted_size = Self::encrypted_size(size);
+
+        let FileResponse { stream, .. } = self
+            .drive
+            .get_file(&FileHandle::new(file.id.clone()), 0..encrypted_size)
+            .await?;
+
+        let content = {
+            let view = slice_stream(stream, 0..encrypted_size);
+            let view = match limiter {
+                Some(limiter) => throttle_stream(view, limiter).left_stream(),
+                None => view.right_stream(),
+            };
+
+            let chunked = chunk_stream(encrypted_size, view, ENCRYPTED_CHUNK_SIZE as u64);
+            let verified = verify_stream(chunked, manifest, 0);
+            decrypt_stream(verified, cipher, 0)
+        };
+
+        let RemoteUpload {
+            drive_key,
+            handle,
+            cipher,
+            secret,
+            secret_key,
+            manifest,
+            ..
+        } = self
+            .upload_content(size, content, Encryption::Server, false)
+            .await?;
+
+        let replaced = self
+            .db
+            .replace_remote_file(
+                &file.id,
+                &NewRemoteFile {
+                    id: &handle.id,
+                    drive_key,
+                    cipher,
+                    format: Format::LATEST.version(),
+                    secret: &secret,
+                    secret_key,
+                    manifest: Some(&manifest.to_bytes()),
+                    manifest_root: Some(&manifest.root()),
+                },
+            )
+            .await;
+
+        // delete whichever remote file is left unreferenced
+        let unreferenced = match replaced {
+            Ok(true) => {
+                self.delete_queued_file(&file.id).await?;
+                FileHandle::new(file.id.clone())
+            }
+            Ok(false) => {
+                self.discard_upload(&handle, false).await;
+                handle
+            }
+            Err(err) => {
+                self.discard_upload(&handle, false).await;
+                return Err(err.into());
+            }
+        };
+
+        if let Some(ref cache) = self.shared_cache {
+            cache.remove_file(file.key).await;
+        }
+
+        self.uncache_remote_file(&unreferenced.id, size).await;
+
+        Ok(())
+    }
+
+    /// Deletes a remote file from drive, or from the upload spool if it is yet to be uploaded.
+    async fn delete_remote_file(&self, handle: &FileHandle, spooled: bool) -> Result<(), Error> {
+        match self.spool {
+            Some(ref spool) if spooled => {
+                // if the spool worker is uploading it right now, it deletes the upload
+                // after finding that no file references it anymore
+                spool.remove(&handle.id).await;
+                Ok(())
+            }
+            _ => Ok(self.drive.delete_file(handle).await?),
+        }
+    }
+
+    /// Deletes a remote file in the delete queue from drive, returning whether it was deleted.
+    /// Failures are logged and left to [`Store::process_delete_queue`].
+    async fn delete_queued_file(&self, id: &str) -> Result<bool, Error> {
+        match self.drive.delete_file(&FileHandle::new(id)).await {
+            Ok(()) => {
+                self.db.remove_queued_delete(id).await?;
+                Ok(true)
+            }
+            Err(err) => {
+                warn!("failed to delete file '{id}'; retrying later: {err}");
+                self.db.defer_queued_delete(id, &err.to_string()).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Retries deleting the remote files in the delete queue whose deletion is due,
+    /// returning the number of files deleted.
+    pub async fn process_delete_queue(&self) -> Result<u64, Error> {
+        let mut count = 0;
+
+        loop {
+            // failed deletions are deferred, so they aren't returned again
+            let queued = self.db.get_queued_deletes(DELETE_QUEUE_BATCH_SIZE).await?;
+
+            for entry in &queued {
+                // entries never attempted, such as when the server stopped before deleting them, aren't retries
+                if entry.attempts > 0 {
+                    self.drive.record_retry("files.delete");
+                }
+
+                if self.delete_queued_file(&entry.id).await? {
+                    count += 1;
+                }
+            }
+
+            if queued.len() < DELETE_QUEUE_BATCH_SIZE as usize {
+                return Ok(count);
+            }
+        }
+    }
+
+    /// Uploads all files in the upload spool to drive, returning the number of files uploaded.
+    pub async fn upload_spooled(&self) -> Result<u64, Error> {
+        let spool = match self.spool {
+            Some(ref spool) => spool,
+            None => return Ok(0),
+        };
+
+        let mut count = 0;
+
+        for id in spool.list().await? {
+            let retried = matches!(
+                self.spool_status.lock().unwrap().get(&id),
+                Some(UploadStatus::Failed(_))
+            );
+
+            if retried {
+                self.drive.record_retry("files.create");
+            }
+
+            self.set_spool_status(&id, Some(UploadStatus::Uploading));
+
+            match self.upload_spooled_file(spool, &id).await {
+                Ok(uploaded) => {
+                    self.set_spool_status(&id, None);
+
+                    if uploaded {
+                        count += 1;
+                    }
+                }
+                // retried on the next run
+                Err(err) => {
+                    warn!("failed to upload spooled file '{id}': {err}");
+                    self.set_spool_status(&id, Some(UploadStatus::Failed(err.to_string())));
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    async fn upload_spooled_file(&self, spool: &Spool, id: &str) -> Result<bool, Error> {
+        let file = match self.db.get_file_by_remote_id(id).await? {
+            Some(file) if file.spooled => file,
+            _ => {
+                // upload was already completed, never added to the database, or has since been deleted
+                trace!("removing stale spooled file '{id}'");
+                spool.remove(id).await;
+                return Ok(false);
+            }
+        };
+
+        let drive = self
+            .db
+            .get_drive_by_key(file.drive_key)
+            .await?
+            .ok_or(Error::DriveNotExists(file.drive_key))?;
+
+        let size = Self::stored_size(&file);
+        let content = match spool.read(id, 0..size).await? {
+            Some(content) => content,
+            None => return Ok(false), // deleted in the meantime
+        };
+
+        let handle = self
+            .drive
+            .create_file(
+                &FileHandle::new(id),
+                Self::rand_file_name(),
+                FolderHandle::new(drive.id),
+                size,
+                "application/octet-stream",
+                content,
+            )
+            .await?;
+
+        if !self.db.set_file_uploaded(id).await? {
+            // file was deleted while uploading
+            self.discard_upload(&handle, false).await;
+        }
+
+        // cached metadata still says the file is spooled
+        if let Some(ref cache) = self.shared_cache {
+            cache.remove_file(file.key).await;
+        }
+
+        spool.remove(id).await;
+
+        trace!("uploaded spooled file '{id}'");
+        Ok(true)
+    }
+
+    fn set_spool_status(&self, id: &str, status: Option<UploadStatus>) {
+        let mut statuses = self.spool_status.lock().unwrap();
+
+        match status {
+            Some(status) => statuses.insert(id.into(), status),
+            None => statuses.remove(id),
+        };
+    }
+
+    /// Returns the progress of uploading a file to drive.
+    ///
+    /// Spooled files are only uploaded by the instance that spooled them,
+    /// so other instances report them as pending.
+    pub async fn get_upload_status(&self, key: i64) -> Result<Option<UploadStatus>, Error> {
+        let file = match self.db.get_file_by_key(key).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        if !file.spooled {
+            return Ok(Some(UploadStatus::Complete));
+        }
+
+        Ok(Some(
+            self.spool_status
+                .lock()
+                .unwrap()
+                .get(&file.id)
+                .cloned()
+                .unwrap_or(UploadStatus::Pending),
+        ))
+    }
+
+    /// Waits until a file is spooled or the timeout elapses.
+    pub async fn wait_spooled(&self, timeout: std::time::Duration) {
+        let _ = tokio::time::timeout(timeout, self.spool_notify.notified()).await;
+    }
+
+    /// Removes cached chunks of a deleted remote file from all instances.
+    async fn uncache_remote_file(&self, id: &str, size: u64) {
+        if let Some(ref cache) = self.chunk_cache {
+            cache.remove_file(id).await;
+        }
+
+        if let Some(ref cache) = self.media_cache {
+            cache.remove_file(id);
+        }
+
+        if let Some(ref cache) = self.shared_cache {
+            cache.remove_chunks(id, Self::last_chunk_id(size) + 1).await;
+        }
+    }
+
+    /// Removes chunks that other instances remove from the local chunk and media caches,
+    /// until the subscription fails.
+    pub async fn watch_cache_invalidations(&self) -> Result<(), Error> {
+        let shared = match self.shared_cache {
+            Some(ref shared) if self.chunk_cache.is_some() || self.media_cache.is_some() => shared,
+            _ => return Ok(()),
+        };
+
+        let mut subscription = shared.subscribe().await?;
+
+        loop {
+            let id = subscription.next_message().await?;
+            let id = String::from_utf8_lossy(&id);
+
+            if let Some(ref cache) = self.chunk_cache {
+                cache.remove_file(&id).await;
+            }
+
+            if let Some(ref cache) = self.media_cache {
+                cache.remove_file(&id);
+            }
+        }
+    }
+
+    /// Returns files whose remote file was last encrypted before the given time, ordered by key.
+    pub async fn get_files_by_encrypted_time(
+        &self,
+        before: NaiveDateTime,
+        after_key: i64,
+        limit: u32,
+    ) -> Result<Vec<File>, Error> {
+        Ok(self
+            .db
+            .get_files_by_encrypted_time(before, after_key, limit)
+            .await?)
+    }
+
+    /// Sets whether a file can be downloaded without credentials, returning the updated file.
+    pub async fn set_public(&self, key: i64, public: Option<bool>) -> Result<Option<File>, Error> {
+        let file = self.db.set_file_public(key, public).await?;
+
+        if let Some(ref cache) = self.shared_cache {
+            cache.remove_file(key).await;
+        }
+
+        Ok(file)
+    }
+
+    pub async fn get_info(&self, key: i64) -> Result<Option<File>, Error> {
+        match self.get_cached_file(key).await? {
+            Some(file) if matches!(file.remaining_downloads, Some(n) if n <= 0) => {
+                Err(Error::DownloadLimitExceeded)
+            }
+            file => Ok(file),
+        }
+    }
+
+    /// Gets a file from the shared cache, or from the database and caches it.
+    async fn get_cached_file(&self, key: i64) -> Result<Option<File>, Error> {
+        let cached = match self.shared_cache {
+            Some(ref cache) => cache.get_file(key).await,
+            None => None,
+        };
+
+        let file = match cached {
+            Some(file) => Some(file),
+            None => {
+                let file = self.db.get_file_by_key(key).await?;
+
+                if let (Some(ref cache), Some(ref file)) = (&self.shared_cache, &file) {
+                    cache.put_file(file).await;
+                }
+
+                file
+            }
+        };
+
+        Ok(file)
+    }
+
+    pub async fn delete(&self, key: i64) -> Result<Option<File>, Error> {
+        self.delete_file(key, EventKind::Delete).await
+    }
+
+    /// Deletes a file after its last permitted download.
+    pub async fn expire(&self, key: i64) -> Result<Option<File>, Error> {
+        self.delete_file(key, EventKind::Expire).await
+    }
+
+    async fn delete_file(&self, key: i64, event: EventKind) -> Result<Option<File>, Error> {
+        let (file, unreferenced) = match self.db.delete_file_by_key(key).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        if let Some(ref cache) = self.shared_cache {
+            cache.remove_file(key).await;
+        }
+
+        self.notify(event, &file, None);
+
+        // remote file may still be referenced by other files with identical content
+        if unreferenced {
+            if file.spooled {
+                self.delete_remote_file(&FileHandle::new(file.id.clone()), true)
+                    .await?;
+            } else {
+                self.delete_queued_file(&file.id).await?;
+            }
+
+            self.uncache_remote_file(&file.id, file.size as u64).await;
+        }
+
+        Ok(Some(file))
+    }
+
+    /// Deletes the files matching a policy, or only finds them if `dry_run` is set.
+    /// Files that fail to be deleted are logged and reported, and don't stop the others from being deleted.
+    pub async fn prune(&self, policy: &PrunePolicy, dry_run: bool) -> Result<PruneReport, Error> {
+        if policy.older_than.is_none()
+            && policy.not_accessed_for.is_none()
+            && policy.larger_than.is_none()
+            && policy.content_type.is_none()
+        {
+            return Err(Error::PrunePolicyEmpty);
+        }
+
+        let now = Utc::now().naive_utc();
+        let mut report = PruneReport::default();
+        let mut before = None;
+
+        loop {
+            let files = self
+                .db
+                .get_files(&FileQuery {
+                    before,
+                    limit: Some(1000),
+                    namespace: policy.namespace.clone(),
+                    content_type: policy.content_type.clone(),
+                    min_size: policy
+                        .larger_than
+                        .map(|size| size.saturating_add(1).min(i64::MAX as u64) as i64),
+                    created_before: policy
+                        .older_than
+                        .map(|days| now - Duration::days(days.into())),
+                    accessed_before: policy
+                        .not_accessed_for
+                        .map(|days| now - Duration::days(days.into())),
+                    ..Default::default()
+                })
+                .await?;
+
+            match files.last() {
+                Some(file) => before = Some(file.key),
+                None => break,
+            }
+
+            for file in files {
+                if !dry_run {
+                    if let Err(err) = self.delete(file.key).await {
+                        warn!("failed to prune file {key}: {err}", key = file.key);
+                        report.failed.push(file.key);
+                        continue;
+                    }
+                }
+
+                report.keys.push(file.key);
+                report.size += file.size as u64;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Writes download statistics accumulated since the last flush to the database.
+    pub async fn flush_file_stats(&self) -> Result<(), Error> {
+        let stats: Vec<_> = std::mem::take(&mut *self.file_stats.lock().unwrap())
+            .into_iter()
+            .collect();
+
+        if stats.is_empty() {
+            return Ok(());
+        }
+
+        trace!("flushing download statistics of {} file(s)", stats.len());
+
+        if let Err(err) = self.db.add_file_stats(&stats).await {
+            // merge back so that statistics are retried in the next flush
+            let mut pending = self.file_stats.lock().unwrap();
+
+            for (key, stats) in stats {
+                pending.entry(key).or_default().merge(&stats);
+            }
+
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns any file with the content digest in a namespace.
+    pub async fn get_info_by_sha256(
+        &self,
+        sha256: &[u8],
+        namespace: &str,
+    ) -> Result<Option<File>, Error> {
+        Ok(self.db.get_file_by_sha256(sha256, Some(namespace)).await?)
+    }
+
+    pub async fn get_files(&self, query: &FileQuery) -> Result<Vec<File>, Error> {
+        Ok(self.db.get_files(query).await?)
+    }
+
+    pub async fn get_files_by_downloads(
+        &self,
+        ascending: bool,
+        limit: u32,
+        namespace: Option<&str>,
+    ) -> Result<Vec<File>, Error> {
+        Ok(self
+            .db
+            .get_files_by_downloads(ascending, limit, namespace)
+            .await?)
+    }
+
+    pub async fn audit(&self, event: &AuditEvent) -> Result<(), Error> {
+        Ok(self.db.add_audit_entry(event).await?)
+    }
+
+    pub async fn get_audit_log(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+        Ok(self.db.get_audit_entries(query).await?)
+    }
+
+    /// Adds a user, or returns `None` if the name or token is taken.
+    pub async fn add_user(&self, user: &NewUser<'_>) -> Result<Option<User>, Error> {
+        Ok(self.db.add_user(user).await?)
+    }
+
+    pub async fn get_users(&self) -> Result<Vec<User>, Error> {
+        Ok(self.db.get_users().await?)
+    }
+
+    pub async fn get_user_by_token(&self, token_sha256: &[u8]) -> Result<Option<User>, Error> {
+        Ok(self.db.get_user_by_token(token_sha256).await?)
+    }
+
+    pub async fn has_users(&self) -> Result<bool, Error> {
+        Ok(self.db.has_users().await?)
+    }
+
+    pub async fn set_user_token(
+        &self,
+        key: i32,
+        token_sha256: &[u8],
+    ) -> Result<Option<User>, Error> {
+        Ok(self.db.set_user_token(key, token_sha256).await?)
+    }
+
+    pub async fn delete_user(&self, key: i32) -> Result<Option<User>, Error> {
+        Ok(self.db.delete_user(key).await?)
+    }
+
+    /// Points an alias to a file, replacing the file it pointed to if it exists.
+    pub async fn set_alias(&self, name: &str, file_key: i64) -> Result<Alias, Error> {
+        Ok(self.db.set_alias(name, file_key).await?)
+    }
+
+    pub async fn get_alias(&self, name: &str) -> Result<Option<Alias>, Error> {
+        Ok(self.db.get_alias(name).await?)
+    }
+
+    pub async fn delete_alias(&self, name: &str) -> Result<Option<Alias>, Error> {
+        Ok(self.db.delete_alias(name).await?)
+    }
+
+    /// Returns the aliases starting with a prefix that point to files in a namespace, ordered by name,
+    /// along with their files.
+    pub async fn get_aliased_files(
+        &self,
+        prefix: &str,
+        namespace: &str,
+        limit: u32,
+    ) -> Result<Vec<(String, File)>, Error> {
+        Ok(self.db.get_aliased_files(prefix, namespace, limit).await?)
+    }
+
+    /// Points an object to a file, deleting the file that held its previous content if any.
+    pub async fn set_s3_object(
+        &self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+        file_key: i64,
+    ) -> Result<(), Error> {
+        let previous = self
+            .db
+            .set_s3_object(namespace, bucket, name, file_key)
+            .await?;
+
+        if let Some(previous) = previous.filter(|&previous| previous != file_key) {
+            self.delete(previous).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_s3_object(
+        &self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Option<S3Object>, Error> {
+        Ok(self.db.get_s3_object(namespace, bucket, name).await?)
+    }
+
+    /// Deletes an object along with the file holding its content, returning the deleted file.
+    pub async fn delete_s3_object(
+        &self,
+        namespace: &str,
+        bucket: &str,
+        name: &str,
+    ) -> Result<Option<File>, Error> {
+        match self.db.delete_s3_object(namespace, bucket, name).await? {
+            Some(object) => self.delete(object.file_key).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Adds a collection, or returns `None` if the name is taken in the namespace.
+    pub async fn add_collection(
+        &self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<Option<Collection>, Error> {
+        Ok(self.db.add_collection(name, namespace).await?)
+    }
+
+    pub async fn get_collections(&self, namespace: &str) -> Result<Vec<Collection>, Error> {
+        Ok(self.db.get_collections(namespace).await?)
+    }
+
+    pub async fn get_collection(
+        &self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<Option<Collection>, Error> {
+        Ok(self.db.get_collection(name, namespace).await?)
+    }
+
+    /// Deletes a collection, keeping its files outside any collection.
+    pub async fn delete_collection(&self, key: i32) -> Result<Option<Collection>, Error> {
+        Ok(self.db.delete_collection(key).await?)
+    }
+
+    /// Deletes the transfers counted towards the allowance on previous days.
+    pub async fn prune_transfers(&self) -> Result<u64, Error> {
+        Ok(self.db.prune_transfers().await?)
+    }
+
+    /// Deletes audit log entries older than the given retention period.
+    pub async fn prune_audit_log(&self, retention: Duration) -> Result<u64, Error> {
+        Ok(self
+            .db
+            .prune_audit_log(Utc::now().naive_utc() - retention)
+            .await?)
+    }
+}
+
+/// Hashes the original content of an upload.
+#[derive(Default)]
+struct ContentHasher {
+    sha256: Sha256,
+    md5: Option<Md5>,
+    sha512: Option<Sha512>,
+}
+
+impl ContentHasher {
+    fn new(digests: &[ExpectedDigest]) -> Self {
+        let mut hasher = Self::default();
+
+        // only compute the additional digests that will be verified
+        for digest in digests {
+            match digest {
+                ExpectedDigest::Md5(_) => hasher.md5 = Some(Md5::new()),
+                ExpectedDigest::Sha256(_) => {}
+                ExpectedDigest::Sha512(_) => hasher.sha512 = Some(Sha512::new()),
+            }
+        }
+
+        hasher
+    }
+
+    fn verify(self, digests: &[ExpectedDigest]) -> Result<(), Error> {
+        let sha256 = self.sha256.finalize();
+        let md5 = self.md5.map(Md5::finalize);
+        let sha512 = self.sha512.map(Sha512::finalize);
+
+        for digest in digests {
+            let (name, expected, actual) = match digest {
+                ExpectedDigest::Md5(expected) => ("md5", expected, md5.as_deref()),
+                ExpectedDigest::Sha256(expected) => ("sha-256", expected, Some(&*sha256)),
+                ExpectedDigest::Sha512(expected) => ("sha-512", expected, sha512.as_deref()),
+            };
+
+            if actual != Some(expected.as_slice()) {
+                return Err(Error::DigestMismatch(name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Update for ContentHasher {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.sha256, data);
+
+        if let Some(ref mut md5) = self.md5 {
+            Digest::update(md5, data);
+        }
+
+        if let Some(ref mut sha512) = self.sha512 {
+            Digest::update(sha512, data);
+        }
+    }
+}
+
+type RangeStream = Pin<Box<dyn Stream<Item = Result<Bytes, drive::Error>> + Send + Sync>>;
+type ContentStream = Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync>>;
+
+/// Requests a range of a remote file, trimming any excess content in the response.
+async fn open_range(
+    drive: &Drive,
+    id: &str,
+    range: Range<u64>,
+) -> Result<RangeStream, drive::Error> {
+    let FileResponse {
+        stream,
+        range: response_range,
+    } = drive.get_file(&FileHandle::new(id), range.clone()).await?;
+
+    let start = range.start - response_range.start;
+    let end = start + (range.end - range.start);
+
+    Ok(Box::pin(slice_stream(stream, start..end)))
+}
+
+/// Re-requests the remainder of the range when the download fails partway,
+/// up to [DOWNLOAD_RESUME_ATTEMPTS] consecutive times.
+fn resume_stream(
+    drive: Arc<Drive>,
+    id: String,
+    range: Range<u64>,
+    stream: RangeStream,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static {
+    use std::io::Error;
+
+    struct State {
+        drive: Arc<Drive>,
+        id: String,
+        // remaining range
+        range: Range<u64>,
+        stream: Option<RangeStream>,
+        attempts: u32,
+    }
+
+    futures::stream::try_unfold(
+        State {
+            drive,
+            id,
+            range,
+            stream: Some(stream),
+            attempts: 0,
+        },
+        |mut state| async move {
+            loop {
+                if state.range.is_empty() {
+                    return Ok(None);
+                }
+
+                let error = match state.stream {
+                    Some(ref mut stream) => match stream.next().await {
+                        Some(Ok(buffer)) => {
+                            state.range.start += buffer.len() as u64;
+                            state.attempts = 0;
+                            return Ok(Some((buffer, state)));
+                        }
+                        Some(Err(err)) => Error::other(err),
+                        None => Error::other(drive::Error::FileRangeResponseInvalid(
+                            state.range.start,
+                            state.range.end,
+                        )),
+                    },
+                    None => {
+                        // spawned because the request future isn't sync
+                        let task = tokio::spawn({
+                            let drive = state.drive.clone();
+                            let id = state.id.clone();
+                            let range = state.range.clone();
+                            async move { open_range(&drive, &id, range).await }.in_current_span()
+                        });
+
+                        match task.await {
+                            Ok(Ok(stream)) => {
+                                state.stream = Some(stream);
+                                continue;
+                            }
+                            Ok(Err(err)) => Error::other(err),
+                            Err(err) => Error::other(err),
+                        }
+                    }
+                };
+
+                if state.attempts >= DOWNLOAD_RESUME_ATTEMPTS {
+                    return Err(error);
+                }
+
+                state.attempts += 1;
+                state.stream = None;
+                state.drive.record_retry("files.get");
+
+                warn!(
+                    "download of file '{id}' failed at offset {offset}, resuming (attempt {attempt}): {error}",
+                    id = state.id,
+                    offset = state.range.start,
+                    attempt = state.attempts
+                );
+
+                tokio::time::sleep(std::time::Duration::from_secs(state.attempts.into())).await;
+            }
+        },
+    )
+}
+
+/// Checks encrypted chunks against the manifest so that corrupted chunks can be pinpointed.
+fn verify_stream<S>(
+    stream: S,
+    manifest: Option<Manifest>,
+    chunk_id: u32,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + 'static,
+{
+    stream
+        .zip(futures::stream::iter(chunk_id..))
+        .map(move |(chunk, chunk_id)| {
+            let chunk = chunk?;
+
+            match manifest {
+                Some(ref manifest) if !manifest.verify(chunk_id, &chunk) => {
+                    use std::io::{Error, ErrorKind};
+                    Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("chunk {chunk_id} does not match manifest"),
+                    ))
+                }
+                _ => Ok(chunk),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_range() {
+        assert_eq!(
+            Store::resolve_range(ByteRange::From(0, Some(99)), 1000),
+            Some(0..100)
+        );
+
+        // open-ended, and ending past the end of the file
+        assert_eq!(
+            Store::resolve_range(ByteRange::From(900, None), 1000),
+            Some(900..1000)
+        );
+        assert_eq!(
+            Store::resolve_range(ByteRange::From(900, Some(1999)), 1000),
+            Some(900..1000)
+        );
+
+        // suffix, and longer than the file
+        assert_eq!(
+            Store::resolve_range(ByteRange::Suffix(100), 1000),
+            Some(900..1000)
+        );
+        assert_eq!(
+            Store::resolve_range(ByteRange::Suffix(2000), 1000),
+            Some(0..1000)
+        );
+    }
+
+    #[test]
+    fn resolve_range_unsatisfiable() {
+        assert_eq!(
+            Store::resolve_range(ByteRange::From(1000, None), 1000),
+            None
+        );
+        assert_eq!(
+            Store::resolve_range(ByteRange::From(1000, Some(1999)), 1000),
+            None
+        );
+        assert_eq!(Store::resolve_range(ByteRange::Suffix(0), 1000), None);
+        assert_eq!(Store::resolve_range(ByteRange::From(0, None), 0), None);
+    }
+
+    #[test]
+    fn resolve_range_overflow() {
+        assert_eq!(
+            Store::resolve_range(ByteRange::From(0, Some(u64::MAX)), 1000),
+            Some(0..1000)
+        );
+
+        assert_eq!(
+            Store::resolve_range(ByteRange::From(u64::MAX, Some(u64::MAX)), 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn coalesce_ranges() {
+        let ranges = [
+            ByteRange::Suffix(100),
+            ByteRange::From(0, Some(99)),
+            ByteRange::From(50, Some(149)),
+            ByteRange::From(150, Some(199)),
+            ByteRange::From(300, Some(399)),
+            ByteRange::From(2000, None),
+        ];
+
+        // overlapping and adjacent ranges are merged, and ranges past the end are left out
+        assert_eq!(
+            Store::coalesce_ranges(&ranges, 1000).unwrap(),
+            vec![0..200, 300..400, 900..1000]
+        );
+    }
+
+    #[test]
+    fn coalesce_ranges_unsatisfiable() {
+        let ranges = [ByteRange::From(1000, None), ByteRange::Suffix(0)];
+
+        assert!(matches!(
+            Store::coalesce_ranges(&ranges, 1000),
+            Err(Error::RangeNotSatisfiable(1000))
+        ));
+    }
+}
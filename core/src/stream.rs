@@ -7,22 +7,25 @@
 //   https://opensource.org/licenses/MIT
 //
 use crate::rate_limit::RateLimit;
-use bytes::{Buf, Bytes};
+use bytes::{Buf, Bytes, BytesMut};
 use futures::{Stream, StreamExt, TryStreamExt};
 use governor::{
     clock::QuantaClock,
     state::{InMemoryState, NotKeyed},
     RateLimiter,
 };
+use sha2::digest::Update;
 use std::{
+    collections::VecDeque,
     ops::Range,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
 };
 use tokio::io::AsyncReadExt;
 use tokio_util::io::StreamReader;
+use tracing::Instrument;
 
 pub fn slice_stream<S, E>(
     stream: S,
@@ -94,6 +97,48 @@ where
     )
 }
 
+/// Hands out chunk buffers from a ring of reusable allocations.
+///
+/// Each buffer is split off an allocation that is reclaimed once the chunk previously split from it is dropped,
+/// so a stream whose chunks are consumed promptly doesn't allocate at all in the steady state.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<VecDeque<BytesMut>>,
+    size: usize,
+}
+
+impl BufferPool {
+    /// Number of buffers each stream keeps for reuse.
+    pub const STREAM_SIZE: usize = 8;
+
+    pub fn new(size: usize) -> Self {
+        Self {
+            buffers: Mutex::new(VecDeque::with_capacity(size)),
+            size,
+        }
+    }
+
+    /// Returns a zeroed buffer of the given length.
+    pub fn alloc(&self, len: usize) -> BytesMut {
+        let mut buffers = self.buffers.lock().unwrap();
+
+        let mut buffer = if buffers.len() < self.size {
+            BytesMut::new()
+        } else {
+            // oldest buffer is the most likely to have been dropped
+            buffers.pop_front().unwrap()
+        };
+
+        // reclaims the allocation if it's no longer shared, otherwise allocates a new one
+        buffer.reserve(len);
+        buffer.resize(len, 0);
+
+        let chunk = buffer.split_to(len);
+        buffers.push_back(buffer);
+        chunk
+    }
+}
+
 pub fn chunk_stream<S, B, E>(
     size: u64,
     stream: S,
@@ -106,13 +151,11 @@ where
 {
     // futures IntoAsyncRead doesn't support reading from Buf,
     // so let's use tokio-util StreamReader instead.
-    let reader = StreamReader::new(stream.map_err(|err| {
-        use std::io::{Error, ErrorKind};
-        Error::new(ErrorKind::Other, err)
-    }));
+    let reader = StreamReader::new(stream.map_err(std::io::Error::other));
 
     struct State<R> {
         reader: R,
+        pool: BufferPool,
         chunk_size: u64,
         remaining: u64,
     }
@@ -120,11 +163,13 @@ where
     futures::stream::try_unfold(
         State {
             reader: Box::pin(reader),
+            pool: BufferPool::new(BufferPool::STREAM_SIZE),
             chunk_size,
             remaining: size,
         },
         |State {
              mut reader,
+             pool,
              chunk_size,
              remaining,
          }| async move {
@@ -134,13 +179,14 @@ where
             if read == 0 {
                 Ok(None)
             } else {
-                let mut buffer = vec![0; read];
+                let mut buffer = pool.alloc(read);
                 reader.read_exact(&mut buffer).await?;
 
                 Ok(Some((
-                    buffer.into(),
+                    buffer.freeze(),
                     State {
                         reader,
+                        pool,
                         chunk_size,
                         remaining: remaining - read as u64,
                     },
@@ -150,6 +196,87 @@ where
     )
 }
 
+/// Reads items until at least `size` bytes are buffered or the stream ends, returning the buffered bytes
+/// and a stream that yields all data of the original stream including that already read.
+pub async fn peek_stream<S, B, E>(
+    stream: S,
+    size: usize,
+) -> (
+    Bytes,
+    impl Stream<Item = Result<Bytes, E>> + Send + Sync + 'static,
+)
+where
+    S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+    E: Send + Sync + 'static,
+{
+    let mut stream = Box::pin(stream.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining())));
+    let mut head = BytesMut::new();
+    let mut peeked = Vec::new();
+
+    while head.len() < size {
+        match stream.next().await {
+            Some(Ok(buf)) => {
+                head.extend_from_slice(&buf);
+                peeked.push(Ok(buf));
+            }
+            Some(Err(err)) => {
+                peeked.push(Err(err));
+                break;
+            }
+            None => break,
+        }
+    }
+
+    (head.freeze(), futures::stream::iter(peeked).chain(stream))
+}
+
+/// Reads up to `count` items ahead of the consumer in a background task,
+/// so that a slow consumer doesn't stall the source at every item.
+pub fn readahead_stream<S, T>(
+    stream: S,
+    count: usize,
+) -> impl Stream<Item = T> + Send + Sync + 'static
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = tokio::sync::mpsc::channel(count.max(1));
+
+    tokio::spawn(
+        async move {
+            let mut stream = Box::pin(stream);
+
+            while let Some(item) = stream.next().await {
+                // consumer was dropped
+                if sender.send(item).await.is_err() {
+                    break;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+}
+
+/// Feeds all data passing through the stream into a shared hasher.
+pub fn hash_stream<S, E, D>(
+    stream: S,
+    hasher: Arc<Mutex<D>>,
+) -> impl Stream<Item = Result<Bytes, E>> + Send + Sync + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + Sync + 'static,
+    D: Update + Send + 'static,
+{
+    stream.map_ok(move |buffer| {
+        hasher.lock().unwrap().update(&buffer);
+        buffer
+    })
+}
+
 #[derive(Debug)]
 pub struct BandwidthLimiter {
     // unit of measurement for the limiter.
@@ -0,0 +1,254 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::{db::File, header::format_hex, http::HttpConfig};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use reqwest::{Client, StatusCode, Url};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialize http client: {0}")]
+    ClientInit(reqwest::Error),
+
+    #[error("webhook url '{0}' must be an absolute http or https url")]
+    UrlInvalid(String),
+}
+
+/// Maximum number of events waiting to be delivered to a webhook, beyond which new events are dropped.
+const QUEUE_SIZE: usize = 1000;
+
+/// Maximum number of attempts to deliver an event, waiting twice as long after each failed attempt.
+const MAX_ATTEMPTS: u32 = 8;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// File was uploaded.
+    Upload,
+    /// File was deleted.
+    Delete,
+    /// File was deleted after its last permitted download.
+    Expire,
+    /// Content of the file failed integrity verification.
+    VerifyFailed,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Upload => "upload",
+            Self::Delete => "delete",
+            Self::Expire => "expire",
+            Self::VerifyFailed => "verify_failed",
+        }
+    }
+}
+
+/// Event delivered to webhooks as the JSON body of a POST request.
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    /// Random identifier of the event, which is the same for retried deliveries.
+    pub id: String,
+    pub event: EventKind,
+    pub time: DateTime<Utc>,
+    pub file: EventFile<'a>,
+    /// Additional details of the event, such as the verification report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+/// File that an event is about, without its secrets.
+#[derive(Debug, Serialize)]
+pub struct EventFile<'a> {
+    pub key: i64,
+    pub namespace: &'a str,
+    pub size: i64,
+    pub content_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<&'a str>,
+    pub metadata: &'a Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub created_time: DateTime<Utc>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(event: EventKind, file: &'a File, details: Option<Value>) -> Self {
+        Self {
+            id: format_hex(thread_rng().gen::<[u8; 16]>()),
+            event,
+            time: Utc::now(),
+            file: EventFile {
+                key: file.key,
+                namespace: &file.namespace,
+                size: file.size,
+                content_type: &file.content_type,
+                filename: file.filename.as_deref(),
+                metadata: &file.metadata,
+                sha256: file.sha256.as_ref().map(format_hex),
+                created_time: DateTime::from_utc(file.created_time, Utc),
+            },
+            details,
+        }
+    }
+}
+
+/// Delivers events to webhook urls in the background, retrying failed deliveries with exponential backoff.
+///
+/// Each url has its own queue, so that an unreachable webhook delays only its own events, which are delivered in
+/// order. Events that are still queued when the server stops are lost.
+#[derive(Debug)]
+pub struct Webhooks {
+    queues: Vec<mpsc::Sender<Arc<Delivery>>>,
+}
+
+#[derive(Debug)]
+struct Delivery {
+    event: &'static str,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    http: Client,
+    url: Url,
+    secret: Option<Arc<[u8]>>,
+}
+
+impl Webhooks {
+    /// Starts delivering events to the urls, signing them with the secret if given.
+    pub fn new(http: HttpConfig, urls: &[String], secret: Option<&str>) -> Result<Self, Error> {
+        let client = http.create_client().map_err(Error::ClientInit)?;
+        let secret: Option<Arc<[u8]>> = secret.map(|secret| secret.as_bytes().into());
+        let mut queues = Vec::new();
+
+        for url in urls {
+            let parsed = Url::parse(url).map_err(|_| Error::UrlInvalid(url.clone()))?;
+
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(Error::UrlInvalid(url.clone()));
+            }
+
+            let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+
+            tokio::spawn(
+                Endpoint {
+                    http: client.clone(),
+                    url: parsed,
+                    secret: secret.clone(),
+                }
+                .run(receiver),
+            );
+
+            queues.push(sender);
+        }
+
+        Ok(Self { queues })
+    }
+
+    /// Queues an event for delivery to every webhook.
+    pub fn send(&self, event: &Event) {
+        let delivery = match serde_json::to_vec(event) {
+            Ok(body) => Arc::new(Delivery {
+                event: event.event.name(),
+                body,
+            }),
+            Err(err) => {
+                warn!("failed to serialize webhook event: {err}");
+                return;
+            }
+        };
+
+        for queue in &self.queues {
+            if queue.try_send(delivery.clone()).is_err() {
+                warn!("webhook queue is full; dropping {} event", delivery.event);
+            }
+        }
+    }
+}
+
+impl Endpoint {
+    async fn run(self, mut receiver: mpsc::Receiver<Arc<Delivery>>) {
+        while let Some(delivery) = receiver.recv().await {
+            let mut delay = INITIAL_RETRY_DELAY;
+
+            for attempt in 1..=MAX_ATTEMPTS {
+                match self.deliver(&delivery).await {
+                    Ok(()) => break,
+                    Err((err, retry)) if retry && attempt < MAX_ATTEMPTS => {
+                        debug!(
+                            "failed to deliver webhook to '{}', retrying: {err}",
+                            self.url
+                        );
+                        tokio::time::sleep(delay).await;
+                        delay *= 2;
+                    }
+                    Err((err, _)) => {
+                        warn!("failed to deliver webhook to '{}': {err}", self.url);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the event once, returning the error and whether the delivery should be retried on failure.
+    async fn deliver(&self, delivery: &Delivery) -> Result<(), (reqwest::Error, bool)> {
+        let mut request = self
+            .http
+            .post(self.url.clone())
+            .timeout(REQUEST_TIMEOUT)
+            .header("content-type", "application/json")
+            .header("x-castella-event", delivery.event);
+
+        if let Some(ref secret) = self.secret {
+            // the timestamp is signed along with the body so that receivers can reject replayed events
+            let timestamp = Utc::now().timestamp().to_string();
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts keys of any size");
+            mac.update(timestamp.as_bytes());
+            mac.update(b".");
+            mac.update(&delivery.body);
+
+            request = request.header("x-castella-timestamp", &timestamp).header(
+                "x-castella-signature",
+                format!("sha256={}", format_hex(mac.finalize().into_bytes())),
+            );
+        }
+
+        match request
+            .body(delivery.body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                // other client errors won't succeed when retried
+                let retry = match err.status() {
+                    Some(status) if status.is_client_error() => {
+                        status == StatusCode::REQUEST_TIMEOUT
+                            || status == StatusCode::TOO_MANY_REQUESTS
+                    }
+                    _ => true,
+                };
+
+                Err((err, retry))
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use castella_core::db::DEFAULT_NAMESPACE;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, sync::Arc};
+
+/// Requests that an API key authorizes, where each scope includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Scope {
+    /// Download files.
+    #[serde(rename = "reader")]
+    Read,
+    /// Upload, modify and delete files.
+    #[serde(rename = "writer")]
+    Write,
+    /// Use admin endpoints and manage users.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    /// Name of the user role granted the scope.
+    pub fn role(&self) -> &'static str {
+        match self {
+            Scope::Read => "reader",
+            Scope::Write => "writer",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn from_role(role: &str) -> Option<Self> {
+        match role {
+            "reader" => Some(Scope::Read),
+            "writer" => Some(Scope::Write),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Client authorized by an API key.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub scope: Scope,
+    /// Namespace of the files that the client can access.
+    pub namespace: Arc<str>,
+}
+
+/// API keys that clients present to be authorized for restricted requests.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    // keys are looked up by digest so that the lookup doesn't leak their contents through timing
+    digests: HashMap<Vec<u8>, Client>,
+}
+
+impl ApiKeys {
+    pub fn new(keys: impl IntoIterator<Item = (impl AsRef<[u8]>, Client)>) -> Self {
+        Self {
+            digests: keys
+                .into_iter()
+                .map(|(key, client)| (key_digest(key), client))
+                .collect(),
+        }
+    }
+
+    /// Returns true if no keys are configured.
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+
+    /// Returns the client authorized by a key, or `None` if it is not a valid key.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<Client> {
+        self.digests.get(&key_digest(key)).cloned()
+    }
+}
+
+/// Returns the digest by which a key is stored and looked up.
+pub fn key_digest(key: impl AsRef<[u8]>) -> Vec<u8> {
+    Sha256::digest(key.as_ref()).to_vec()
+}
+
+/// Returns a new random key to be handed out to a user.
+pub fn generate_key() -> String {
+    base64::encode_config(thread_rng().gen::<[u8; 32]>(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Maximum length of a namespace.
+const MAX_NAMESPACE_LEN: usize = 64;
+
+/// Returns true if a namespace is short and consists of lowercase letters, digits, '-' and '_'.
+pub fn is_valid_namespace(namespace: &str) -> bool {
+    namespace.len() <= MAX_NAMESPACE_LEN
+        && namespace
+            .bytes()
+            .all(|c| matches!(c, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'))
+}
+
+/// Parses credentials for HTTP basic authentication given as `user:password`.
+pub fn parse_basic_auth(s: &str) -> Result<String, &'static str> {
+    match s.split_once(':') {
+        Some((user, password)) if !user.is_empty() && !password.is_empty() => Ok(s.into()),
+        _ => Err("credentials must be given as 'user:password'"),
+    }
+}
+
+/// Parses an API key given as `namespace:key`, or as `key` in the default namespace.
+pub fn parse_api_key(s: &str) -> Result<(String, String), &'static str> {
+    let (namespace, key) = match s.split_once(':') {
+        Some((namespace, key)) => (namespace, key),
+        None => (DEFAULT_NAMESPACE, s),
+    };
+
+    if !is_valid_namespace(namespace) {
+        return Err("namespace must consist of lowercase letters, digits, '-' and '_'");
+    }
+
+    if key.is_empty() {
+        return Err("key must not be empty");
+    }
+
+    Ok((namespace.into(), key.into()))
+}
+
+/// Parses a role granted to clients presenting a certificate with the given subject,
+/// given as `role=subject` or `namespace:role=subject`, such as `writer=CN=uploader,O=chiya`.
+pub fn parse_cert_client(s: &str) -> Result<(String, Scope, String), &'static str> {
+    let (role, subject) = s
+        .split_once('=')
+        .ok_or("certificate clients must be given as 'role=subject'")?;
+
+    let (namespace, role) = match role.split_once(':') {
+        Some((namespace, role)) => (namespace, role),
+        None => (DEFAULT_NAMESPACE, role),
+    };
+
+    if !is_valid_namespace(namespace) {
+        return Err("namespace must consist of lowercase letters, digits, '-' and '_'");
+    }
+
+    let scope = Scope::from_role(role).ok_or("role must be reader, writer or admin")?;
+
+    if subject.trim().is_empty() {
+        return Err("subject must not be empty");
+    }
+
+    Ok((namespace.into(), scope, subject.into()))
+}
+
+/// Parses credentials of the S3-compatible api given as `role=access_key_id:secret_access_key`
+/// or `namespace:role=access_key_id:secret_access_key`.
+pub fn parse_s3_credential(s: &str) -> Result<(String, Scope, String, String), &'static str> {
+    let (role, credentials) = s
+        .split_once('=')
+        .ok_or("s3 credentials must be given as 'role=access_key_id:secret_access_key'")?;
+
+    let (namespace, role) = match role.split_once(':') {
+        Some((namespace, role)) => (namespace, role),
+        None => (DEFAULT_NAMESPACE, role),
+    };
+
+    if !is_valid_namespace(namespace) {
+        return Err("namespace must consist of lowercase letters, digits, '-' and '_'");
+    }
+
+    let scope = Scope::from_role(role).ok_or("role must be reader, writer or admin")?;
+
+    match credentials.split_once(':') {
+        Some((id, secret)) if !id.is_empty() && !secret.is_empty() => {
+            Ok((namespace.into(), scope, id.into(), secret.into()))
+        }
+        _ => Err("s3 credentials must be given as 'role=access_key_id:secret_access_key'"),
+    }
+}
+
+/// Signs urls that authorize downloading a file until they expire, without presenting an API key.
+#[derive(Debug)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    fn mac(&self, file_key: i64, expires: i64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts keys of any size");
+        mac.update(format!("{file_key}:{expires}").as_bytes());
+        mac
+    }
+
+    /// Returns the signature that authorizes downloading a file until `expires`, given as a unix timestamp.
+    pub fn sign(&self, file_key: i64, expires: i64) -> String {
+        base64::encode_config(
+            self.mac(file_key, expires).finalize().into_bytes(),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    /// Returns true if the signature authorizes downloading a file and hasn't expired.
+    pub fn verify(&self, file_key: i64, expires: i64, signature: &str) -> bool {
+        if expires < Utc::now().timestamp() {
+            return false;
+        }
+
+        match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(signature) => self.mac(file_key, expires).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expires() -> i64 {
+        Utc::now().timestamp() + 3600
+    }
+
+    #[test]
+    fn verify_signed_url() {
+        let signer = UrlSigner::new("secret");
+        let expires = expires();
+        let signature = signer.sign(42, expires);
+
+        assert!(signer.verify(42, expires, &signature));
+    }
+
+    #[test]
+    fn verify_tampered_url() {
+        let signer = UrlSigner::new("secret");
+        let expires = expires();
+        let signature = signer.sign(42, expires);
+
+        assert!(!signer.verify(43, expires, &signature));
+        assert!(!signer.verify(42, expires + 1, &signature));
+        assert!(!signer.verify(42, expires, &signature[1..]));
+        assert!(!signer.verify(42, expires, "not base64!"));
+        assert!(!UrlSigner::new("other secret").verify(42, expires, &signature));
+    }
+
+    #[test]
+    fn verify_expired_url() {
+        let signer = UrlSigner::new("secret");
+        let expires = Utc::now().timestamp() - 1;
+        let signature = signer.sign(42, expires);
+
+        assert!(!signer.verify(42, expires, &signature));
+    }
+}
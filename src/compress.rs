@@ -0,0 +1,123 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use async_compression::{
+    tokio::bufread::{BrotliEncoder, GzipEncoder},
+    Level,
+};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Quality of brotli compression from 0 to 11, which is kept low because responses are compressed as they stream.
+const BROTLI_QUALITY: u32 = 4;
+
+/// Encoding of a response body negotiated from the `Accept-Encoding` header of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// Value of the `Content-Encoding` header, or `None` if the body is not encoded.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Self::Identity => None,
+            Self::Gzip => Some("gzip"),
+            Self::Brotli => Some("br"),
+        }
+    }
+}
+
+/// Parses an `Accept-Encoding` header, returning the most preferred of the supported encodings.
+/// Brotli is chosen over gzip if the client prefers them equally.
+pub fn parse_accept_encoding(s: &str) -> Encoding {
+    let mut best = (Encoding::Identity, 0.0);
+
+    for item in s.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        let encoding = match name.to_ascii_lowercase().as_str() {
+            "br" | "*" => Encoding::Brotli,
+            "gzip" | "x-gzip" => Encoding::Gzip,
+            _ => continue,
+        };
+
+        let preferred = quality > best.1
+            || (quality == best.1 && encoding == Encoding::Brotli && best.0 == Encoding::Gzip);
+
+        if quality > 0.0 && preferred {
+            best = (encoding, quality);
+        }
+    }
+
+    best.0
+}
+
+/// Returns true if content of the type is worth compressing, such as text, JSON, XML and scripts.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/ecmascript"
+                | "application/xml"
+                | "application/wasm"
+                | "application/x-ndjson"
+                | "application/x-sh"
+                | "application/x-tar"
+                | "application/sql"
+                | "application/graphql"
+                | "image/bmp"
+                | "image/x-icon"
+                | "image/vnd.microsoft.icon"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+/// Compresses a stream of content as it is read.
+pub fn compress_stream<S>(
+    stream: S,
+    encoding: Encoding,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Send + Unpin + 'static,
+{
+    let reader = StreamReader::new(stream);
+
+    match encoding {
+        Encoding::Identity => ReaderStream::new(reader).left_stream().left_stream(),
+        Encoding::Gzip => ReaderStream::new(GzipEncoder::new(reader))
+            .right_stream()
+            .left_stream(),
+        Encoding::Brotli => ReaderStream::new(BrotliEncoder::with_quality(
+            reader,
+            Level::Precise(BROTLI_QUALITY),
+        ))
+        .right_stream(),
+    }
+}
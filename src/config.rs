@@ -0,0 +1,91 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use clap::{ArgMatches, Command, ValueSource};
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read config file '{0}': {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("line {1} of config file '{0}' is not of the form NAME=value")]
+    Syntax(PathBuf, usize),
+
+    #[error("unknown option '{1}' in config file '{0}'")]
+    UnknownOption(PathBuf, String),
+}
+
+/// Values of flags that turn them off, as recognized by clap for environment variables.
+const FALSE_VALUES: [&str; 6] = ["n", "no", "f", "false", "off", "0"];
+
+/// Reads a config file of `NAME=value` lines, where names are the environment variables of the options of the
+/// command, into arguments that give the options on the command line.
+///
+/// Options that were already given on the command line according to `matches` are skipped, so that the command line
+/// takes precedence over the config file, and the config file over the environment. Options that accept several
+/// values can be given on several lines. Empty lines and lines starting with `#` are ignored.
+pub fn read_args(
+    path: &Path,
+    command: &Command,
+    matches: &ArgMatches,
+) -> Result<Vec<OsString>, Error> {
+    let content = std::fs::read_to_string(path).map_err(|err| Error::Read(path.into(), err))?;
+    let mut args = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| Error::Syntax(path.into(), index + 1))?;
+
+        let (name, value) = (name.trim(), unquote(value.trim()));
+
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_env() == Some(OsStr::new(name)))
+            .ok_or_else(|| Error::UnknownOption(path.into(), name.into()))?;
+
+        // the option is known to be in the matches, as it has an environment variable
+        if matches.value_source(arg.get_id()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        let long = arg.get_long().unwrap_or_else(|| arg.get_id());
+
+        if arg.is_takes_value_set() {
+            args.push(format!("--{long}={value}").into());
+        } else if !FALSE_VALUES.contains(&value.to_lowercase().as_str()) {
+            args.push(format!("--{long}").into());
+        }
+    }
+
+    Ok(args)
+}
+
+/// Removes the quotes around a value, which are allowed for compatibility with env files.
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(value) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            return value;
+        }
+    }
+
+    value
+}
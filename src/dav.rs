@@ -0,0 +1,91 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::header::escape_xml;
+use chrono::NaiveDateTime;
+
+/// Depth of a `PROPFIND` request, which lists a resource alone or along with its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    Zero,
+    One,
+}
+
+/// Parses a `Depth` header, returning `None` for infinite depth which isn't supported.
+///
+/// A missing header means infinite depth.
+pub fn parse_depth(s: Option<&str>) -> Option<Depth> {
+    match s?.trim() {
+        "0" => Some(Depth::Zero),
+        "1" => Some(Depth::One),
+        _ => None,
+    }
+}
+
+/// Resource listed in the response of a `PROPFIND` request.
+#[derive(Debug)]
+pub struct Resource {
+    /// Path of the resource, ending with '/' if it is a collection.
+    pub href: String,
+    pub created_time: Option<NaiveDateTime>,
+    /// Properties of the file, or `None` if the resource is a collection.
+    pub file: Option<FileProps>,
+}
+
+#[derive(Debug)]
+pub struct FileProps {
+    pub length: i64,
+    pub content_type: String,
+    pub etag: String,
+}
+
+impl Resource {
+    fn display_name(&self) -> &str {
+        let path = self.href.trim_end_matches('/');
+        path.rsplit('/').next().unwrap_or(path)
+    }
+}
+
+/// Returns the XML body of a multi-status response listing the properties of resources.
+pub fn multistatus(resources: &[Resource]) -> String {
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">",
+    );
+
+    for resource in resources {
+        body.push_str("<D:response><D:href>");
+        body.push_str(&escape_xml(&resource.href));
+        body.push_str("</D:href><D:propstat><D:prop><D:displayname>");
+        body.push_str(&escape_xml(resource.display_name()));
+        body.push_str("</D:displayname>");
+
+        if let Some(time) = resource.created_time {
+            body.push_str(&format!(
+                "<D:creationdate>{}</D:creationdate><D:getlastmodified>{}</D:getlastmodified>",
+                time.format("%Y-%m-%dT%H:%M:%SZ"),
+                time.format("%a, %d %b %Y %H:%M:%S GMT"),
+            ));
+        }
+
+        match resource.file {
+            Some(ref file) => body.push_str(&format!(
+                "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+                 <D:getcontenttype>{}</D:getcontenttype><D:getetag>\"{}\"</D:getetag>",
+                file.length,
+                escape_xml(&file.content_type),
+                escape_xml(&file.etag),
+            )),
+            None => body.push_str("<D:resourcetype><D:collection/></D:resourcetype>"),
+        }
+
+        body.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>");
+    }
+
+    body.push_str("</D:multistatus>");
+    body
+}
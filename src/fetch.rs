@@ -0,0 +1,285 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use bytes::Bytes;
+use castella_core::http::HttpConfig;
+use futures::{Stream, StreamExt};
+use reqwest::{header::LOCATION, redirect::Policy, Url};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Maximum number of redirects followed before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialize http client: {0}")]
+    ClientInit(reqwest::Error),
+
+    #[error("source url must be an absolute http or https url")]
+    UrlInvalid,
+
+    #[error("failed to resolve source host: {0}")]
+    Resolve(std::io::Error),
+
+    #[error("source address {0} is not publicly routable")]
+    AddressForbidden(IpAddr),
+
+    #[error("source redirected too many times")]
+    TooManyRedirects,
+
+    #[error("failed to fetch source: {0}")]
+    Request(reqwest::Error),
+
+    #[error("source did not report its content length")]
+    LengthMissing,
+
+    #[error("source is empty")]
+    Empty,
+
+    #[error("source size {0} exceeds the maximum upload size")]
+    TooLarge(u64),
+
+    #[error("source sent more than its declared length of {0} bytes")]
+    LengthExceeded(u64),
+}
+
+/// Client that downloads remote content on behalf of clients to be stored as an upload.
+///
+/// Only publicly routable addresses are fetched from, and each redirect is checked the same way before it is followed.
+#[derive(Debug)]
+pub struct Fetcher {
+    http: HttpConfig,
+    max_size: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct Source<S: Stream<Item = Result<Bytes, Error>>> {
+    pub size: u64,
+    pub content_type: Option<String>,
+    /// Last segment of the url path, if any.
+    pub filename: Option<String>,
+    pub content: S,
+}
+
+impl Fetcher {
+    /// `max_size` is the maximum size of fetched content in bytes.
+    pub fn new(http: HttpConfig, max_size: u64) -> Result<Self, Error> {
+        // content length must be that of the stored content
+        let http = HttpConfig {
+            compression: false,
+            ..http
+        };
+
+        // fail early on an invalid configuration rather than on the first fetch
+        http.clone().create_client().map_err(Error::ClientInit)?;
+
+        Ok(Self {
+            http,
+            max_size: AtomicU64::new(max_size),
+        })
+    }
+
+    /// Changes the maximum size of fetched content in bytes.
+    pub fn set_max_size(&self, max_size: u64) {
+        self.max_size.store(max_size, Ordering::Relaxed);
+    }
+
+    pub async fn fetch(
+        &self,
+        url: &str,
+    ) -> Result<Source<impl Stream<Item = Result<Bytes, Error>>>, Error> {
+        let mut url = Url::parse(url).map_err(|_| Error::UrlInvalid)?;
+        let mut redirects = 0;
+
+        let response = loop {
+            debug!("fetching '{url}'");
+
+            let response = self
+                .connect(&url)
+                .await?
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(Error::Request)?;
+
+            let location = response
+                .status()
+                .is_redirection()
+                .then(|| response.headers().get(LOCATION))
+                .flatten();
+
+            match location {
+                Some(location) => {
+                    if redirects == MAX_REDIRECTS {
+                        return Err(Error::TooManyRedirects);
+                    }
+
+                    redirects += 1;
+
+                    url = location
+                        .to_str()
+                        .ok()
+                        .and_then(|location| url.join(location).ok())
+                        .ok_or(Error::UrlInvalid)?;
+                }
+
+                None => break response.error_for_status().map_err(Error::Request)?,
+            }
+        };
+
+        let size = response.content_length().ok_or(Error::LengthMissing)?;
+
+        if size == 0 {
+            return Err(Error::Empty);
+        } else if size > self.max_size.load(Ordering::Relaxed) {
+            return Err(Error::TooLarge(size));
+        }
+
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .map(Into::into);
+
+        let filename = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|segment| !segment.is_empty())
+            .map(Into::into);
+
+        // the source decides how much it sends, so the declared length is enforced on the stream itself
+        let mut remaining = size;
+
+        let content = response.bytes_stream().map(move |chunk| {
+            let chunk = chunk.map_err(Error::Request)?;
+
+            remaining = remaining
+                .checked_sub(chunk.len() as u64)
+                .ok_or(Error::LengthExceeded(size))?;
+
+            Ok(chunk)
+        });
+
+        Ok(Source {
+            size,
+            content_type,
+            filename,
+            content,
+        })
+    }
+
+    /// Returns a client that connects to the host of `url` only at an address that was checked to be publicly routable.
+    ///
+    /// Redirects are not followed by the client so that they can be checked before following them.
+    async fn connect(&self, url: &Url) -> Result<reqwest::Client, Error> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(Error::UrlInvalid);
+        }
+
+        let port = url.port_or_known_default().ok_or(Error::UrlInvalid)?;
+        let http = self
+            .http
+            .clone()
+            .client_builder()
+            .map_err(Error::ClientInit)?
+            .redirect(Policy::none());
+
+        let host = url.host_str().ok_or(Error::UrlInvalid)?;
+
+        // ipv6 hosts are bracketed in urls
+        let http = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(addr) => check_address(addr).map(|_| http)?,
+            Err(_) => {
+                let mut addrs = tokio::net::lookup_host((host, port))
+                    .await
+                    .map_err(Error::Resolve)?
+                    .peekable();
+
+                let addr = *addrs
+                    .peek()
+                    .ok_or_else(|| Error::Resolve(std::io::ErrorKind::NotFound.into()))?;
+
+                for addr in addrs {
+                    check_address(addr.ip())?;
+                }
+
+                // pinned so that the host cannot resolve to a different address when connecting
+                http.resolve(host, addr)
+            }
+        };
+
+        http.build().map_err(Error::ClientInit)
+    }
+}
+
+fn check_address(addr: IpAddr) -> Result<(), Error> {
+    if is_public(addr) {
+        Ok(())
+    } else {
+        Err(Error::AddressForbidden(addr))
+    }
+}
+
+/// Returns whether `addr` is a globally routable unicast address.
+fn is_public(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => is_public_v4(addr),
+        IpAddr::V6(addr) => is_public_v6(addr),
+    }
+}
+
+fn is_public_v4(addr: Ipv4Addr) -> bool {
+    let [a, b, ..] = addr.octets();
+
+    !(addr.is_unspecified()
+        || addr.is_loopback()
+        || addr.is_private()
+        || addr.is_link_local()
+        || addr.is_broadcast()
+        || addr.is_documentation()
+        || addr.is_multicast()
+        // "this network"
+        || a == 0
+        // shared address space
+        || (a == 100 && (64..128).contains(&b))
+        // ietf protocol assignments
+        || (a == 192 && b == 0 && addr.octets()[2] == 0)
+        // benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // reserved
+        || a >= 240)
+}
+
+fn is_public_v6(addr: Ipv6Addr) -> bool {
+    let segments = addr.segments();
+
+    // ipv4-mapped and nat64 addresses reach the embedded ipv4 address
+    if let Some(mapped) = addr.to_ipv4_mapped() {
+        return is_public_v4(mapped);
+    } else if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., c, d] = segments;
+        return is_public_v4(Ipv4Addr::from(((c as u32) << 16) | d as u32));
+    }
+
+    !(addr.is_unspecified()
+        || addr.is_loopback()
+        || addr.is_multicast()
+        // ipv4-compatible
+        || segments[..6] == [0; 6]
+        // unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // link local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // site local
+        || (segments[0] & 0xffc0) == 0xfec0
+        // documentation
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+}
@@ -6,54 +6,75 @@
 //
 //   https://opensource.org/licenses/MIT
 //
-use std::ops::{Bound, RangeBounds};
-
-// nightly only:
-/// https://doc.rust-lang.org/std/ops/enum.Bound.html#method.as_ref
-fn bound_as_ref<T>(bound: &Bound<T>) -> Bound<&T> {
-    match bound {
-        Bound::Included(ref x) => Bound::Included(x),
-        Bound::Excluded(ref x) => Bound::Excluded(x),
-        Bound::Unbounded => Bound::Unbounded,
-    }
-}
+use http::{header::HeaderName, HeaderValue};
+
+/// Parses a header given as `name:value`, e.g. `x-served-by: castella`.
+pub fn parse_header_pair(s: &str) -> Result<(HeaderName, HeaderValue), &'static str> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or("header must be given as name:value")?;
 
-#[derive(Debug, Clone)]
-struct RangeCustom<T> {
-    start: Bound<T>,
-    end: Bound<T>,
+    Ok((
+        name.trim().parse().map_err(|_| "invalid header name")?,
+        value.trim().parse().map_err(|_| "invalid header value")?,
+    ))
 }
 
-impl<T> RangeBounds<T> for RangeCustom<T> {
-    fn start_bound(&self) -> Bound<&T> {
-        bound_as_ref(&self.start)
-    }
+/// Parses the decoded `user:password` credentials of an `Authorization` header with the basic scheme.
+pub fn parse_basic_credentials(s: &str) -> Option<String> {
+    let (scheme, credentials) = s.trim().split_once(' ')?;
 
-    fn end_bound(&self) -> Bound<&T> {
-        bound_as_ref(&self.end)
+    if scheme.eq_ignore_ascii_case("basic") {
+        String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()
+    } else {
+        None
     }
 }
 
-pub fn parse_single_range_header(s: impl AsRef<str>) -> Option<impl RangeBounds<u64>> {
-    let s = s.as_ref();
-    if !s.starts_with("bytes=") {
-        return None;
+/// Parses the token of an `Authorization` header with the bearer scheme.
+pub fn parse_bearer_token(s: &str) -> Option<&str> {
+    let (scheme, token) = s.trim().split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
     }
+}
 
-    let s = &s["bytes=".len()..];
-    let (start, end) = s.split_once('-')?;
+/// Formats a `Content-Disposition` header value with an RFC 5987 encoded filename.
+pub fn format_content_disposition(disposition: &str, filename: impl AsRef<str>) -> String {
+    let mut value = format!("{disposition}; filename*=UTF-8''");
 
-    let start = if start == "" {
-        Bound::Unbounded
-    } else {
-        Bound::Included(start.parse().ok()?)
-    };
+    for byte in filename.as_ref().bytes() {
+        match byte {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => value.push(byte as char),
+            _ => value.push_str(&format!("%{byte:02X}")),
+        }
+    }
 
-    let end = if end == "" {
-        Bound::Unbounded
-    } else {
-        Bound::Included(end.parse().ok()?)
-    };
+    value
+}
 
-    Some(RangeCustom { start, end })
+/// Escapes a string for use in XML text or attribute values.
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
 }
@@ -0,0 +1,248 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use std::{
+    fmt::{self, Display, Write},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Span, Subscriber,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields},
+    layer::{Context, Layer},
+    registry::LookupSpan,
+    Registry,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unknown log format '{0}'")]
+    Unknown(String),
+}
+
+/// Format of printed log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl LogFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(Error::Unknown(s.into())),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Formats the fields of spans as JSON objects, so that they can be merged into JSON log lines.
+#[derive(Debug, Default)]
+pub struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &span::Record<'_>,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut visitor);
+
+        current.fields.clear();
+        write!(current.fields, "{}", Value::Object(visitor.0))
+    }
+}
+
+/// Formats events as JSON objects with the fields `timestamp`, `level`, `target` and `message`,
+/// along with the fields of the event and of its enclosing spans such as `request_id` and `key`.
+#[derive(Debug, Default)]
+pub struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut line = Map::new();
+
+        line.insert(
+            "timestamp".into(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Micros, true)
+                .into(),
+        );
+        line.insert("level".into(), metadata.level().as_str().into());
+        line.insert("target".into(), metadata.target().into());
+
+        // inner spans take precedence over outer spans, and the event over all spans
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<FormattedFields<JsonFields>>() {
+                    if let Ok(Value::Object(fields)) = serde_json::from_str(&fields.fields) {
+                        line.extend(fields);
+                    }
+                }
+            }
+        }
+
+        let mut visitor = JsonVisitor(line);
+        event.record(&mut visitor);
+
+        writeln!(writer, "{}", Value::Object(visitor.0))
+    }
+}
+
+#[derive(Debug, Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+/// Time spent by a request in each kind of operation, summed over operations that may have run concurrently.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    /// Database transactions, spans named "db".
+    pub db: Duration,
+    /// Requests to the Drive API until their response headers, spans named "drive".
+    pub drive: Duration,
+    /// Encryption and decryption of chunks, spans named "crypto".
+    pub crypto: Duration,
+}
+
+impl Timings {
+    /// Returns the timings collected for a request span so far.
+    pub fn of(span: &Span) -> Self {
+        span.with_subscriber(|(id, dispatch)| {
+            dispatch
+                .downcast_ref::<Registry>()
+                .and_then(|registry| registry.span(id))
+                .and_then(|span| span.extensions().get::<Self>().copied())
+        })
+        .flatten()
+        .unwrap_or_default()
+    }
+}
+
+/// Adds the lifetime of the spans of timed operations to the [`Timings`] of their enclosing request span.
+#[derive(Debug, Default)]
+pub struct TimingLayer;
+
+/// Creation time of a span of a timed operation.
+struct Created(Instant);
+
+impl<S> Layer<S> for TimingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let metadata = attrs.metadata();
+
+        if metadata.target().starts_with(env!("CARGO_PKG_NAME"))
+            && matches!(metadata.name(), "db" | "drive" | "crypto")
+        {
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(Created(Instant::now()));
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let elapsed = match span.extensions().get::<Created>() {
+            Some(Created(created)) => created.elapsed(),
+            None => return,
+        };
+
+        if let Some(request) = span.scope().skip(1).find(|span| span.name() == "request") {
+            let mut extensions = request.extensions_mut();
+
+            if extensions.get_mut::<Timings>().is_none() {
+                extensions.insert(Timings::default());
+            }
+
+            let timings = extensions.get_mut::<Timings>().unwrap();
+
+            match span.name() {
+                "db" => timings.db += elapsed,
+                "drive" => timings.drive += elapsed,
+                _ => timings.crypto += elapsed,
+            }
+        }
+    }
+}
@@ -6,46 +6,135 @@
 //
 //   https://opensource.org/licenses/MIT
 //
-use crate::{http::HttpConfig, server::ServerConfig};
-use auth::Authenticator;
-use clap::Parser;
-use db::Db;
-use drive::Drive;
-use rate_limit::RateLimit;
-use server::routes;
-use std::net::SocketAddr;
-use store::Store;
-use warp::Filter;
+use crate::server::{ClientCache, ServerConfig, ServerSettings, Settings};
+use access::{
+    parse_api_key, parse_basic_auth, parse_cert_client, parse_s3_credential, ApiKeys, Client,
+    Scope, UrlSigner,
+};
+use castella_core::{
+    archive::{ArchiveReader, ArchiveWriter, Record},
+    auth::Authenticator,
+    cache::{ChunkCache, MediaCache, SharedCache},
+    cipher::CipherKind,
+    db::{self, Db, Encryption, FileQuery, MetadataTable, DEFAULT_NAMESPACE, MIGRATION_VERSION},
+    drive::Drive,
+    header::format_hex,
+    http::HttpConfig,
+    keys::{MasterKey, WrappingKey},
+    kms::{AwsCredentials, Kms, KmsKey},
+    metrics::Metrics,
+    rate_limit::RateLimit,
+    redis::Redis,
+    sniff::SniffMode,
+    spool::Spool,
+    store::{ExportData, FileData, PrunePolicy, Store, StoreConfig, UploadOptions},
+    stream::BandwidthLimiter,
+    webhook::Webhooks,
+};
+use chrono::{DateTime, Utc};
+use clap::{Args, CommandFactory, ErrorKind, Parser, Subcommand};
+use fetch::Fetcher;
+use futures::{future::BoxFuture, Future, FutureExt, StreamExt};
+use header::parse_header_pair;
+use log::{JsonFields, JsonFormat, LogFormat, TimingLayer};
+use oidc::{parse_role_mapping, OidcConfig, OidcValidator};
+use serde_json::{json, Value};
+use server::{routes, startup_routes};
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    ffi::OsString,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tls::{CertResolver, RemoteAddr};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tokio_rustls::rustls;
+use tokio_util::io::ReaderStream;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use transform::ImageTransformer;
+use warp::{
+    filters::BoxedFilter,
+    http::{header::HeaderName, HeaderMap, HeaderValue},
+    hyper::{
+        self,
+        server::conn::AddrStream,
+        service::{make_service_fn, service_fn, Service},
+    },
+    Reply,
+};
 
 #[macro_use]
 extern crate tracing;
 
-mod auth;
-mod db;
-mod drive;
+mod access;
+mod compress;
+mod config;
+mod dav;
+mod fetch;
 mod header;
-mod http;
-mod rate_limit;
+mod log;
+mod oidc;
+mod s3;
 mod server;
-mod store;
-mod stream;
+mod systemd;
+mod tls;
+mod transform;
 
 #[tokio::main]
 async fn main() {
-    AppOptions::parse().run().await;
+    AppOptions::load()
+        .unwrap_or_else(|err| err.exit())
+        .run()
+        .await;
 }
 
 #[derive(Debug, Parser)]
 #[clap(about)]
 struct AppOptions {
+    /// File of "NAME=value" lines that give options like environment variables, such as "CS_SERVER_API_KEYS=key".
+    /// Options given on the command line take precedence over the file, which takes precedence over the environment.
+    /// Some options are reloaded from the file on SIGHUP or through "POST /admin/reload".
+    #[clap(long, env = "CS_CONFIG_FILE")]
+    config_file: Option<PathBuf>,
+
+    /// Validate the options, the database connection and the OAuth credentials, then exit with a report
+    /// instead of starting the server.
+    #[clap(long)]
+    check: bool,
+
     /// Minimum level of logs to print.
     #[clap(long, default_value = "warn", env = "CS_LOG_LEVEL")]
     log_level: String,
 
+    /// Format of printed logs, either "text" or "json" with one object per line.
+    #[clap(long, default_value = "text", env = "CS_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Print an access log line with the target "access" for every request, regardless of the log level.
+    #[clap(long, env = "CS_LOG_ACCESS")]
+    log_access: bool,
+
     /// PostgreSQL database connection string.
     #[clap(long, env = "CS_DB_CONNECTION")]
     db_connection: String,
 
+    /// Update the access time of files when they are downloaded. Disabling it turns downloads of files without a
+    /// download limit into reads, but leaves the access time of files at their creation time.
+    #[clap(
+        long,
+        default_value = "true",
+        env = "CS_DB_TRACK_ATIME",
+        parse(try_from_str)
+    )]
+    db_track_atime: bool,
+
     /// User agent string for all HTTP requests.
     #[clap(long, env = "CS_CLIENT_USER_AGENT")]
     client_user_agent: Option<String>,
@@ -82,23 +171,700 @@ struct AppOptions {
     #[clap(long, default_value = "127.0.0.1:1707", env = "CS_SERVER_ENDPOINT")]
     server_endpoint: SocketAddr,
 
+    /// PEM file with the certificate chain with which requests are served over TLS instead of plain HTTP.
+    /// The certificate is reloaded when the file changes.
+    #[clap(long, env = "CS_SERVER_TLS_CERT", requires = "server-tls-key")]
+    server_tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate, reloaded when the file changes.
+    #[clap(long, env = "CS_SERVER_TLS_KEY", requires = "server-tls-cert")]
+    server_tls_key: Option<PathBuf>,
+
+    /// PEM file with CA certificates that issue client certificates. Once given, clients must present a certificate
+    /// issued by one of them to connect.
+    #[clap(long, env = "CS_SERVER_TLS_CLIENT_CA", requires = "server-tls-cert")]
+    server_tls_client_ca: Option<PathBuf>,
+
+    /// Allow clients without a certificate to connect when "CS_SERVER_TLS_CLIENT_CA" is given,
+    /// authorizing them by other means.
+    #[clap(
+        long,
+        env = "CS_SERVER_TLS_CLIENT_OPTIONAL",
+        requires = "server-tls-client-ca"
+    )]
+    server_tls_client_optional: bool,
+
+    /// Roles of clients presenting a certificate with the given subject, given as "role=subject" such as
+    /// "writer=CN=uploader,O=chiya", or as "namespace:role=subject". Can be given multiple times,
+    /// or separated by newlines in the environment variable.
+    #[clap(
+        long = "server-tls-client",
+        env = "CS_SERVER_TLS_CLIENTS",
+        value_delimiter = '\n',
+        requires = "server-tls-client-ca",
+        parse(try_from_str = parse_cert_client)
+    )]
+    server_tls_clients: Vec<(String, Scope, String)>,
+
     /// Maximum body size of a single upload request, measured in MiB.
     #[clap(long, default_value = "102400", env = "CS_SERVER_MAX_UPLOAD_SIZE")]
     server_max_upload_size: u64,
+
+    /// Maximum body size of a multipart/form-data upload request, measured in MiB.
+    /// Forms are buffered in memory before they are uploaded.
+    #[clap(long, default_value = "64", env = "CS_SERVER_MAX_FORM_UPLOAD_SIZE")]
+    server_max_form_upload_size: u64,
+
+    /// Directory in which upload bodies without a content-length, such as those with chunked transfer encoding,
+    /// are buffered to learn their size. Such uploads are rejected if unspecified.
+    #[clap(long, env = "CS_SERVER_UPLOAD_BUFFER_PATH")]
+    server_upload_buffer_path: Option<PathBuf>,
+
+    /// Allow clients to store content downloaded by the server from a url through "POST /fetch".
+    /// Only publicly routable addresses are requested, including when following redirects.
+    #[clap(long, env = "CS_SERVER_ALLOW_FETCH")]
+    server_allow_fetch: bool,
+
+    /// Allow clients to resize, crop and convert images by adding transformation parameters to downloads.
+    #[clap(long, env = "CS_SERVER_IMAGE_TRANSFORMS")]
+    server_image_transforms: bool,
+
+    /// Directory in which transformed images are cached. Transformed images are not cached if unspecified.
+    #[clap(long, env = "CS_SERVER_IMAGE_CACHE_PATH")]
+    server_image_cache_path: Option<PathBuf>,
+
+    /// Maximum total size of cached transformed images, measured in MiB.
+    #[clap(long, default_value = "1024", env = "CS_SERVER_IMAGE_CACHE_SIZE")]
+    server_image_cache_size: u64,
+
+    /// Maximum size of images that can be transformed, measured in MiB.
+    #[clap(long, default_value = "20", env = "CS_SERVER_IMAGE_MAX_SIZE")]
+    server_image_max_size: u64,
+
+    /// Compress downloads of text, JSON, XML and other compressible content types with gzip or brotli
+    /// if the client accepts either. Range requests are never compressed.
+    #[clap(long, env = "CS_SERVER_COMPRESSION")]
+    server_compression: bool,
+
+    /// Add "X-Content-Type-Options", "Content-Security-Policy" and "Referrer-Policy" headers to all responses,
+    /// for deployments that serve files directly to browsers.
+    #[clap(long, env = "CS_SERVER_SECURITY_HEADERS")]
+    server_security_headers: bool,
+
+    /// Value of the "Content-Security-Policy" header added by "--server-security-headers".
+    #[clap(
+        long,
+        default_value = "default-src 'none'; sandbox",
+        env = "CS_SERVER_CONTENT_SECURITY_POLICY"
+    )]
+    server_content_security_policy: HeaderValue,
+
+    /// Value of the "Referrer-Policy" header added by "--server-security-headers".
+    #[clap(long, default_value = "no-referrer", env = "CS_SERVER_REFERRER_POLICY")]
+    server_referrer_policy: HeaderValue,
+
+    /// Add a "Strict-Transport-Security" header with the given max-age in seconds to all responses.
+    /// Only set this if the server is exclusively reachable over HTTPS.
+    #[clap(long, env = "CS_SERVER_HSTS_MAX_AGE")]
+    server_hsts_max_age: Option<u64>,
+
+    /// Extra header given as "name:value" added to all responses. Can be given multiple times,
+    /// or separated by newlines in the environment variable.
+    #[clap(
+        long = "server-header",
+        env = "CS_SERVER_HEADERS",
+        value_delimiter = '\n',
+        parse(try_from_str = parse_header_pair)
+    )]
+    server_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Limit of GET, HEAD and OPTIONS requests of each client address, given as "burst/period" in seconds.
+    /// Requests over the limit are rejected with 429.
+    #[clap(long, env = "CS_SERVER_CLIENT_READ_LIMIT")]
+    server_client_read_limit: Option<RateLimit>,
+
+    /// Limit of requests with any other method of each client address, given as "burst/period" in seconds.
+    #[clap(long, env = "CS_SERVER_CLIENT_WRITE_LIMIT")]
+    server_client_write_limit: Option<RateLimit>,
+
+    /// Maximum number of files that each client address can download concurrently.
+    /// Downloads over the limit are rejected with 429.
+    #[clap(long, env = "CS_SERVER_CLIENT_MAX_DOWNLOADS")]
+    server_client_max_downloads: Option<usize>,
+
+    /// Maximum number of uploads handled concurrently. Further uploads wait for one to complete
+    /// before their body is read, and are rejected with 503 if none completes in time.
+    #[clap(long, env = "CS_SERVER_MAX_UPLOADS")]
+    server_max_uploads: Option<usize>,
+
+    /// Number of seconds for which uploads over "--server-max-uploads" wait for a slot before they're rejected.
+    #[clap(long, default_value = "30", env = "CS_SERVER_UPLOAD_QUEUE_TIMEOUT")]
+    server_upload_queue_timeout: u64,
+
+    /// Number of requests waiting for a database connection, the Drive API request limit or an upload slot
+    /// beyond which further requests are rejected with 503 instead of waiting.
+    #[clap(long, env = "CS_SERVER_SHED_THRESHOLD")]
+    server_shed_threshold: Option<usize>,
+
+    /// Bandwidth limit of each download, measured in MiB/s.
+    #[clap(long, env = "CS_SERVER_DOWNLOAD_LIMIT")]
+    server_download_limit: Option<RateLimit>,
+
+    /// Bandwidth limit shared by all downloads, measured in MiB/s.
+    #[clap(long, env = "CS_SERVER_GLOBAL_DOWNLOAD_LIMIT")]
+    server_global_download_limit: Option<RateLimit>,
+
+    /// Number of milliseconds beyond which requests are logged as slow with the time they spent in the database,
+    /// Drive API requests and encryption. Zero disables slow request logging.
+    #[clap(long, default_value = "0", env = "CS_SERVER_SLOW_REQUEST_THRESHOLD")]
+    server_slow_request_threshold: u64,
+
+    /// Add a "Server-Timing" header to all responses with the time spent in the database, Drive API requests
+    /// and encryption until the response was sent.
+    #[clap(long, env = "CS_SERVER_TIMING")]
+    server_timing: bool,
+
+    /// Start in maintenance mode, in which uploads, modifications and deletions are rejected while downloads
+    /// continue. Maintenance mode can be toggled at runtime through "PUT /admin/maintenance".
+    #[clap(long, env = "CS_SERVER_MAINTENANCE")]
+    server_maintenance: bool,
+
+    /// Query the database and the Drive API on startup and exit if either fails, instead of reporting
+    /// invalid credentials as errors of the first requests that need them.
+    #[clap(long, env = "CS_SERVER_VERIFY_CREDENTIALS")]
+    server_verify_credentials: bool,
+
+    /// Comma-separated API keys, one of which clients must present as a bearer token or in the "X-Api-Key" header
+    /// to upload, modify or delete files and to use admin endpoints. All requests are allowed if no keys are given.
+    /// A key given as "namespace:key" can only access files uploaded with keys of the same namespace.
+    #[clap(
+        long,
+        env = "CS_SERVER_API_KEYS",
+        use_value_delimiter = true,
+        parse(try_from_str = parse_api_key)
+    )]
+    server_api_keys: Vec<(String, String)>,
+
+    /// Comma-separated API keys that only authorize clients to download files, given like "CS_SERVER_API_KEYS".
+    #[clap(
+        long,
+        env = "CS_SERVER_READ_API_KEYS",
+        use_value_delimiter = true,
+        parse(try_from_str = parse_api_key)
+    )]
+    server_read_api_keys: Vec<(String, String)>,
+
+    /// Credentials given as "user:password" that authorize clients like "CS_SERVER_API_KEYS" through
+    /// HTTP basic authentication. Can be given multiple times, or separated by newlines in the environment variable.
+    #[clap(
+        long = "server-basic-auth",
+        env = "CS_SERVER_BASIC_AUTH",
+        value_delimiter = '\n',
+        parse(try_from_str = parse_basic_auth)
+    )]
+    server_basic_auth: Vec<String>,
+
+    /// Credentials of the S3-compatible api under "/s3", given as "role=access_key_id:secret_access_key"
+    /// or as "namespace:role=access_key_id:secret_access_key". The api is disabled if no credentials are given.
+    /// Can be given multiple times, or separated by newlines in the environment variable.
+    #[clap(
+        long = "server-s3-credential",
+        env = "CS_SERVER_S3_CREDENTIALS",
+        value_delimiter = '\n',
+        parse(try_from_str = parse_s3_credential)
+    )]
+    server_s3_credentials: Vec<(String, Scope, String, String)>,
+
+    /// Require an API key to download files that aren't explicitly public.
+    #[clap(long, env = "CS_SERVER_AUTHENTICATE_READS")]
+    server_authenticate_reads: bool,
+
+    /// Secret key with which urls that authorize downloading a file until they expire are signed
+    /// through "POST /$id/sign". Signed urls are disabled if unspecified.
+    #[clap(long, env = "CS_SERVER_URL_SIGNING_KEY")]
+    server_url_signing_key: Option<String>,
+
+    /// Issuer url of an OpenID Connect provider whose tokens are accepted like API keys,
+    /// e.g. "https://accounts.google.com". Tokens are only accepted if this is specified.
+    #[clap(long, env = "CS_OIDC_ISSUER")]
+    oidc_issuer: Option<String>,
+
+    /// Audience that accepted tokens must be issued for, usually the OAuth client ID.
+    #[clap(long, env = "CS_OIDC_AUDIENCE")]
+    oidc_audience: Option<String>,
+
+    /// Claim of accepted tokens listing the roles or groups of the subject.
+    #[clap(long, env = "CS_OIDC_ROLE_CLAIM", default_value = "roles")]
+    oidc_role_claim: String,
+
+    /// Comma-separated mappings of claimed roles to the reader, writer or admin role, given as "claim=role".
+    /// Tokens that claim no mapped role are rejected.
+    #[clap(
+        long,
+        env = "CS_OIDC_ROLES",
+        use_value_delimiter = true,
+        parse(try_from_str = parse_role_mapping)
+    )]
+    oidc_roles: Vec<(String, Scope)>,
+
+    /// Reference existing files when uploading identical content instead of uploading it again.
+    #[clap(long, env = "CS_STORE_DEDUPLICATE")]
+    store_deduplicate: bool,
+
+    /// Cipher used to encrypt newly uploaded files, either "xchacha20-poly1305" or "aes-256-gcm".
+    #[clap(long, default_value = "xchacha20-poly1305", env = "CS_STORE_CIPHER")]
+    store_cipher: CipherKind,
+
+    /// Encrypt the content type and filename of files in the database using a key wrapped by the master key.
+    #[clap(long, env = "CS_STORE_ENCRYPT_METADATA")]
+    store_encrypt_metadata: bool,
+
+    /// Number of chunks downloaded from Drive ahead of the client while streaming a file.
+    #[clap(long, default_value = "2", env = "CS_STORE_READAHEAD")]
+    store_readahead: usize,
+
+    /// Number of connections over which large ranges are downloaded from Drive concurrently.
+    #[clap(long, default_value = "1", env = "CS_STORE_DOWNLOAD_PARALLELISM")]
+    store_download_parallelism: usize,
+
+    /// Directory in which uploads are spooled and acknowledged before they are uploaded to Drive in the background.
+    /// Uploads are acknowledged only after they are uploaded to Drive if unspecified.
+    #[clap(long, env = "CS_STORE_SPOOL_PATH")]
+    store_spool_path: Option<PathBuf>,
+
+    /// Comma-separated content types that uploads may declare, e.g. "image/*,video/mp4".
+    /// All content types that aren't denied are allowed if unspecified.
+    #[clap(
+        long,
+        env = "CS_STORE_ALLOWED_CONTENT_TYPES",
+        use_value_delimiter = true
+    )]
+    store_allowed_content_types: Vec<String>,
+
+    /// Comma-separated content types that uploads may not declare, e.g. "text/html,image/svg+xml".
+    #[clap(
+        long,
+        env = "CS_STORE_DENIED_CONTENT_TYPES",
+        use_value_delimiter = true
+    )]
+    store_denied_content_types: Vec<String>,
+
+    /// Detect the type of uploaded content from its leading bytes, and either store the detected type
+    /// instead of a mismatching declared type ("correct") or reject the upload ("reject").
+    /// Generic declared types such as "application/octet-stream" are always replaced unless "off".
+    #[clap(long, default_value = "off", env = "CS_STORE_SNIFF_CONTENT_TYPE")]
+    store_sniff_content_type: SniffMode,
+
+    /// Maximum total size of stored content, measured in MiB. Uploads that would exceed it are rejected with 507.
+    #[clap(long, env = "CS_STORE_QUOTA")]
+    store_quota: Option<u64>,
+
+    /// Maximum total size of content stored in each namespace, measured in MiB.
+    #[clap(long, env = "CS_STORE_NAMESPACE_QUOTA")]
+    store_namespace_quota: Option<u64>,
+
+    /// Maximum size of content that each namespace can upload and download per UTC day, measured in MiB.
+    /// Transfers that would exceed it are rejected with 429.
+    #[clap(long, env = "CS_STORE_NAMESPACE_TRANSFER_ALLOWANCE")]
+    store_namespace_transfer_allowance: Option<u64>,
+
+    /// Directory in which downloaded chunks are cached. Chunks are not cached if unspecified.
+    #[clap(long, env = "CS_CACHE_PATH")]
+    cache_path: Option<PathBuf>,
+
+    /// Maximum total size of cached chunks, measured in MiB.
+    #[clap(long, default_value = "1024", env = "CS_CACHE_SIZE")]
+    cache_size: u64,
+
+    /// Number of seconds for which chunks of uploaded files are kept in the chunk cache unless they are read.
+    /// Zero disables caching of uploaded files.
+    #[clap(long, default_value = "0", env = "CS_CACHE_UPLOAD_WINDOW")]
+    cache_upload_window: u64,
+
+    /// Maximum total size of the first and last chunks of video and audio files cached in memory, measured in MiB.
+    /// Zero disables caching of media chunks.
+    #[clap(long, default_value = "0", env = "CS_CACHE_MEDIA_SIZE")]
+    cache_media_size: u64,
+
+    /// Number of chunks cached at each end of video and audio files.
+    #[clap(long, default_value = "2", env = "CS_CACHE_MEDIA_CHUNKS")]
+    cache_media_chunks: u32,
+
+    /// Redis server used as a cache shared between instances, e.g. "redis://localhost:6379/0".
+    #[clap(long, env = "CS_REDIS_URL")]
+    redis_url: Option<String>,
+
+    /// Prefix of all Redis keys and channels.
+    #[clap(long, default_value = "castella:", env = "CS_REDIS_PREFIX")]
+    redis_prefix: String,
+
+    /// Number of seconds for which file metadata is cached in Redis. Zero disables metadata caching.
+    #[clap(long, default_value = "60", env = "CS_REDIS_METADATA_TTL")]
+    redis_metadata_ttl: u64,
+
+    /// Number of seconds for which chunks are cached in Redis.
+    #[clap(long, default_value = "3600", env = "CS_REDIS_CHUNK_TTL")]
+    redis_chunk_ttl: u64,
+
+    /// Maximum stored size of files whose chunks are cached in Redis, measured in KiB. Zero disables chunk caching.
+    #[clap(long, default_value = "0", env = "CS_REDIS_MAX_CHUNK_SIZE")]
+    redis_max_chunk_size: u64,
+
+    /// StatsD server to which metrics are sent over UDP, e.g. "localhost:8125".
+    #[clap(long, env = "CS_STATSD_ADDRESS")]
+    statsd_address: Option<String>,
+
+    /// Prefix of the names of all metrics sent to the StatsD server.
+    #[clap(long, default_value = "castella.", env = "CS_STATSD_PREFIX")]
+    statsd_prefix: String,
+
+    /// Comma-separated urls to which file events are posted as JSON, e.g. "https://example.com/castella".
+    #[clap(long, env = "CS_WEBHOOK_URLS", use_value_delimiter = true)]
+    webhook_urls: Vec<String>,
+
+    /// Secret with which webhook events are signed using HMAC-SHA256.
+    #[clap(long, env = "CS_WEBHOOK_SECRET")]
+    webhook_secret: Option<String>,
+
+    /// Hex-encoded 256-bit key used to wrap file secrets stored in the database.
+    #[clap(long, env = "CS_MASTER_KEY", conflicts_with = "master-key-file")]
+    master_key: Option<MasterKey>,
+
+    /// Path to a file containing the hex-encoded master key.
+    #[clap(long, env = "CS_MASTER_KEY_FILE")]
+    master_key_file: Option<PathBuf>,
+
+    /// Comma-separated hex-encoded keys that previously wrapped file secrets, used until the secrets are rewrapped.
+    #[clap(long, env = "CS_PREVIOUS_MASTER_KEYS", use_value_delimiter = true)]
+    previous_master_keys: Vec<MasterKey>,
+
+    /// Key management service key used to wrap file secrets instead of a local master key,
+    /// either "gcp:<key name>" for Cloud KMS or "aws:<region>:<key id>" for AWS KMS.
+    #[clap(long, env = "CS_KMS_KEY", conflicts_with_all = &["master-key", "master-key-file"])]
+    kms_key: Option<KmsKey>,
+
+    /// AWS access key ID for AWS KMS.
+    #[clap(long, env = "CS_AWS_ACCESS_KEY_ID")]
+    aws_access_key_id: Option<String>,
+
+    /// AWS secret access key for AWS KMS.
+    #[clap(long, env = "CS_AWS_SECRET_ACCESS_KEY")]
+    aws_secret_access_key: Option<String>,
+
+    /// AWS session token for AWS KMS, if using temporary credentials.
+    #[clap(long, env = "CS_AWS_SESSION_TOKEN")]
+    aws_session_token: Option<String>,
+
+    /// Number of hours between comparisons of the files in Drive with the database. Zero disables them.
+    #[clap(long, default_value = "0", env = "CS_STORE_RECONCILE_INTERVAL")]
+    store_reconcile_interval: u64,
+
+    /// Delete orphaned remote files and files missing from Drive found by periodic comparisons.
+    #[clap(long, env = "CS_STORE_RECONCILE_REPAIR")]
+    store_reconcile_repair: bool,
+
+    /// Number of days for which audit log entries are retained. Zero retains entries indefinitely.
+    #[clap(long, default_value = "90", env = "CS_AUDIT_RETENTION")]
+    audit_retention: u32,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Verify the integrity of stored files instead of starting the server.
+    Verify(VerifyOptions),
+
+    /// Rewrap all file secrets with the master key, and optionally re-encrypt file contents.
+    Rekey(RekeyOptions),
+
+    /// Upload a file, printing its key.
+    Put(PutOptions),
+
+    /// Download a file to a path or to standard output.
+    Get(GetOptions),
+
+    /// Export the metadata of drives, collections and files including their secrets to an encrypted archive.
+    ExportMetadata(ExportMetadataOptions),
+
+    /// Import metadata from an archive written by "export-metadata", skipping rows that already exist.
+    ImportMetadata(ImportMetadataOptions),
+
+    /// Write the content of stored files to a directory, either decrypted or as stored along with their keys.
+    Export(ExportOptions),
+
+    /// Delete the files matching all of the given criteria.
+    Prune(PruneOptions),
+
+    /// Compare the files in Drive with the database, reporting orphaned remote files and missing files.
+    Reconcile(ReconcileOptions),
+}
+
+#[derive(Debug, Args)]
+struct VerifyOptions {
+    /// Verify all stored files, newest first.
+    #[clap(long)]
+    all: bool,
+
+    /// Keys of the files to verify.
+    #[clap(required_unless_present = "all", conflicts_with = "all")]
+    keys: Vec<i64>,
+
+    /// Bandwidth limit for reading files, measured in MiB/s.
+    #[clap(long)]
+    limit: Option<RateLimit>,
 }
 
 impl AppOptions {
+    /// Parses options from the command line, the config file and the environment.
+    fn load() -> Result<Self, clap::Error> {
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let command = Self::command();
+
+        // options required by the command may be given in the config file
+        let matches = match command
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(&args)
+        {
+            Ok(matches) => matches,
+            Err(_) => return Self::try_parse_from(args),
+        };
+
+        let path = match matches.value_of("config-file") {
+            Some(path) => PathBuf::from(path),
+            None => return Self::try_parse_from(args),
+        };
+
+        let file_args = config::read_args(&path, &command, &matches)
+            .map_err(|err| clap::Error::raw(ErrorKind::Io, err))?;
+
+        // file arguments precede the subcommand, as they are options of the application
+        Self::try_parse_from(
+            args.iter()
+                .take(1)
+                .chain(&file_args)
+                .chain(args.iter().skip(1)),
+        )
+    }
+
+    /// Checks the options and the services they point to without starting the server,
+    /// printing a report and returning whether all checks passed.
+    async fn check(&self) -> bool {
+        let mut valid = true;
+        let mut report = |name: &str, result: Result<String, String>| match result {
+            Ok(details) => println!("{name}: ok{details}"),
+            Err(err) => {
+                valid = false;
+                println!("{name}: {err}");
+            }
+        };
+
+        // options were already parsed, including the syntax of rate limits and keys
+        report(
+            "options",
+            Ok(match self.config_file {
+                Some(ref path) => format!(" (read from '{}')", path.display()),
+                None => String::new(),
+            }),
+        );
+
+        let limits = [
+            ("drive request", Some(self.drive_request_limit)),
+            ("drive upload", Some(self.drive_upload_limit)),
+            ("client read", self.server_client_read_limit),
+            ("client write", self.server_client_write_limit),
+            ("download", self.server_download_limit),
+            ("global download", self.server_global_download_limit),
+        ];
+
+        report(
+            "rate limits",
+            Ok(format!(
+                " ({})",
+                limits
+                    .iter()
+                    .filter_map(|(name, limit)| limit.map(|limit| format!("{name} {limit}")))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        );
+
+        let db = async {
+            let db = Db::new(&self.db_connection).map_err(|err| err.to_string())?;
+            db.ping().await.map_err(|err| err.to_string())?;
+
+            // pending migrations are applied when the server starts
+            Ok(match db.check_migrated().await {
+                Ok(()) => String::new(),
+                Err(db::Error::MigrationPending(version)) => {
+                    format!(" (migrations pending from {version})")
+                }
+                Err(err) => return Err(err.to_string()),
+            })
+        };
+
+        report("database", db.await);
+
+        let oauth = async {
+            let auth = Authenticator::new(
+                HttpConfig {
+                    user_agent: self.client_user_agent.clone(),
+                    proxy: self.client_proxy.clone(),
+                    compression: true,
+                    allow_insecure: self.client_allow_insecure,
+                },
+                self.oauth_client_id.clone(),
+                self.oauth_client_secret.clone(),
+                self.oauth_refresh_token.clone(),
+            )
+            .map_err(|err| err.to_string())?;
+
+            auth.access_token()
+                .await
+                .map(|_| String::new())
+                .map_err(|err| err.to_string())
+        };
+
+        report("oauth", oauth.await);
+
+        if let (Some(cert_path), Some(key_path)) = (&self.server_tls_cert, &self.server_tls_key) {
+            report(
+                "tls certificate",
+                CertResolver::new(cert_path.clone(), key_path.clone())
+                    .map(|_| String::new())
+                    .map_err(|err| err.to_string()),
+            );
+        }
+
+        if let Some(ref path) = self.server_tls_client_ca {
+            report(
+                "tls client ca",
+                tls::client_verifier(path, self.server_tls_client_optional)
+                    .map(|_| String::new())
+                    .map_err(|err| err.to_string()),
+            );
+        }
+
+        if let Some(ref path) = self.master_key_file {
+            report(
+                "master key",
+                std::fs::read_to_string(path)
+                    .map_err(|err| format!("failed to read master key: {err}"))
+                    .and_then(|key| {
+                        key.parse::<MasterKey>()
+                            .map(|_| String::new())
+                            .map_err(|err| format!("failed to parse master key: {err}"))
+                    }),
+            );
+        }
+
+        valid
+    }
+
+    /// Returns the settings of the server that are reloaded while it is running.
+    fn server_settings(&self) -> ServerSettings {
+        let api_keys = ApiKeys::new(
+            self.server_api_keys
+                .iter()
+                .map(|key| (key, Scope::Admin))
+                .chain(
+                    self.server_read_api_keys
+                        .iter()
+                        .map(|key| (key, Scope::Read)),
+                )
+                .map(|((namespace, key), scope)| {
+                    let client = Client {
+                        scope,
+                        namespace: namespace.as_str().into(),
+                    };
+
+                    (key, client)
+                }),
+        );
+
+        let basic_auth = ApiKeys::new(self.server_basic_auth.iter().map(|credentials| {
+            let client = Client {
+                scope: Scope::Admin,
+                namespace: DEFAULT_NAMESPACE.into(),
+            };
+
+            (credentials, client)
+        }));
+
+        let cert_clients = ApiKeys::new(self.server_tls_clients.iter().map(
+            |(namespace, scope, subject)| {
+                let client = Client {
+                    scope: *scope,
+                    namespace: namespace.as_str().into(),
+                };
+
+                (tls::normalize_subject(subject), client)
+            },
+        ));
+
+        let s3_credentials = s3::Credentials::new(self.server_s3_credentials.iter().map(
+            |(namespace, scope, id, secret)| {
+                let client = Client {
+                    scope: *scope,
+                    namespace: namespace.as_str().into(),
+                };
+
+                (id.clone(), secret.clone(), client)
+            },
+        ));
+
+        ServerSettings {
+            max_upload_size: self.server_max_upload_size * 1024 * 1024, // MiB to B
+            client_read_limit: self.server_client_read_limit,
+            client_write_limit: self.server_client_write_limit,
+            api_keys,
+            basic_auth,
+            cert_clients,
+            s3_credentials,
+        }
+    }
+
     pub async fn run(self) {
         // initialize logger
-        tracing_subscriber::fmt()
-            .with_env_filter(&self.log_level)
-            .init();
+        let log_filter = format!(
+            "{},access={}",
+            self.log_level,
+            if self.log_access { "info" } else { "off" }
+        );
+
+        match self.log_format {
+            LogFormat::Text => tracing_subscriber::fmt()
+                .with_env_filter(&log_filter)
+                .finish()
+                .with(TimingLayer)
+                .init(),
+            LogFormat::Json => tracing_subscriber::fmt()
+                .with_env_filter(&log_filter)
+                .fmt_fields(JsonFields)
+                .event_format(JsonFormat)
+                .finish()
+                .with(TimingLayer)
+                .init(),
+        }
 
         debug!("parsed options: {:?}", self);
 
+        if self.check {
+            std::process::exit(if self.check().await { 0 } else { 1 });
+        }
+
+        let server_settings = self.server_settings();
+
         let Self {
+            config_file: _,
+            check: _,
             log_level: _,
+            log_format: _,
+            log_access: _,
             db_connection,
+            db_track_atime,
             client_user_agent,
             client_proxy,
             client_allow_insecure,
@@ -109,8 +875,145 @@ impl AppOptions {
             drive_upload_limit,
             server_endpoint,
             server_max_upload_size,
+            server_tls_cert,
+            server_tls_key,
+            server_tls_client_ca,
+            server_tls_client_optional,
+            server_tls_clients: _,
+            server_max_form_upload_size,
+            server_upload_buffer_path,
+            server_allow_fetch,
+            server_image_transforms,
+            server_image_cache_path,
+            server_image_cache_size,
+            server_image_max_size,
+            server_compression,
+            server_security_headers,
+            server_content_security_policy,
+            server_referrer_policy,
+            server_hsts_max_age,
+            server_headers,
+            server_client_read_limit: _,
+            server_client_write_limit: _,
+            server_client_max_downloads,
+            server_max_uploads,
+            server_upload_queue_timeout,
+            server_shed_threshold,
+            server_download_limit,
+            server_global_download_limit,
+            server_slow_request_threshold,
+            server_timing,
+            server_maintenance,
+            server_verify_credentials,
+            server_api_keys: _,
+            server_read_api_keys: _,
+            server_basic_auth: _,
+            server_s3_credentials,
+            server_authenticate_reads,
+            server_url_signing_key,
+            oidc_issuer,
+            oidc_audience,
+            oidc_role_claim,
+            oidc_roles,
+            store_deduplicate,
+            store_cipher,
+            store_encrypt_metadata,
+            store_readahead,
+            store_download_parallelism,
+            store_spool_path,
+            store_allowed_content_types,
+            store_denied_content_types,
+            store_sniff_content_type,
+            store_quota,
+            store_namespace_quota,
+            store_namespace_transfer_allowance,
+            cache_path,
+            cache_size,
+            cache_upload_window,
+            cache_media_size,
+            cache_media_chunks,
+            redis_url,
+            redis_prefix,
+            redis_metadata_ttl,
+            redis_chunk_ttl,
+            redis_max_chunk_size,
+            statsd_address,
+            statsd_prefix,
+            webhook_urls,
+            webhook_secret,
+            master_key,
+            master_key_file,
+            previous_master_keys,
+            kms_key,
+            aws_access_key_id,
+            aws_secret_access_key,
+            aws_session_token,
+            store_reconcile_interval,
+            store_reconcile_repair,
+            audit_retention,
+            command,
         } = self;
 
+        // optional features reported by "GET /version"
+        let features = [
+            ("deduplicate", store_deduplicate),
+            ("encrypt-metadata", store_encrypt_metadata),
+            (
+                "master-key",
+                master_key.is_some() || master_key_file.is_some(),
+            ),
+            ("kms", kms_key.is_some()),
+            ("spool", store_spool_path.is_some()),
+            ("chunk-cache", cache_path.is_some()),
+            ("media-cache", cache_media_size != 0),
+            ("redis", redis_url.is_some()),
+            ("fetch", server_allow_fetch),
+            ("image-transforms", server_image_transforms),
+            ("compression", server_compression),
+            ("signed-urls", server_url_signing_key.is_some()),
+            ("oidc", oidc_issuer.is_some()),
+            ("s3", !server_s3_credentials.is_empty()),
+            ("statsd", statsd_address.is_some()),
+            ("webhooks", !webhook_urls.is_empty()),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect();
+
+        // key management service client
+        let kms = kms_key.map(|key| {
+            Kms::new(
+                HttpConfig {
+                    user_agent: client_user_agent.clone(),
+                    proxy: client_proxy.clone(),
+                    compression: true,
+                    allow_insecure: client_allow_insecure,
+                },
+                key,
+                Authenticator::new(
+                    HttpConfig {
+                        user_agent: client_user_agent.clone(),
+                        proxy: client_proxy.clone(),
+                        compression: true,
+                        allow_insecure: client_allow_insecure,
+                    },
+                    oauth_client_id.clone(),
+                    oauth_client_secret.clone(),
+                    oauth_refresh_token.clone(),
+                )
+                .expect("failed to initialize oauth client"),
+                match (aws_access_key_id, aws_secret_access_key) {
+                    (Some(access_key_id), Some(secret_access_key)) => Some(AwsCredentials {
+                        access_key_id,
+                        secret_access_key,
+                        session_token: aws_session_token,
+                    }),
+                    _ => None,
+                },
+            )
+            .expect("failed to initialize kms client")
+        });
+
         // drive authenticator
         let auth = Authenticator::new(
             HttpConfig {
@@ -125,6 +1028,61 @@ impl AppOptions {
         )
         .expect("failed to initialize oauth client");
 
+        // openid connect token validator
+        let oidc = oidc_issuer.map(|issuer| {
+            OidcValidator::new(
+                HttpConfig {
+                    user_agent: client_user_agent.clone(),
+                    proxy: client_proxy.clone(),
+                    compression: true,
+                    allow_insecure: client_allow_insecure,
+                },
+                OidcConfig {
+                    issuer,
+                    audience: oidc_audience,
+                    role_claim: oidc_role_claim,
+                    roles: oidc_roles.into_iter().collect(),
+                },
+            )
+            .expect("failed to initialize oidc client")
+        });
+
+        // url fetch client
+        let fetcher = server_allow_fetch.then(|| {
+            Fetcher::new(
+                HttpConfig {
+                    user_agent: client_user_agent.clone(),
+                    proxy: client_proxy.clone(),
+                    compression: false,
+                    allow_insecure: client_allow_insecure,
+                },
+                server_max_upload_size * 1024 * 1024,
+            )
+            .expect("failed to initialize fetch client")
+        });
+
+        // webhooks, which aren't notified of changes made by commands
+        let webhooks = (!webhook_urls.is_empty() && command.is_none()).then(|| {
+            Webhooks::new(
+                HttpConfig {
+                    user_agent: client_user_agent.clone(),
+                    proxy: client_proxy.clone(),
+                    compression: true,
+                    allow_insecure: client_allow_insecure,
+                },
+                &webhook_urls,
+                webhook_secret.as_deref(),
+            )
+            .expect("failed to initialize webhooks")
+        });
+
+        // metrics
+        let metrics = match statsd_address {
+            Some(address) => Metrics::statsd(&address, statsd_prefix)
+                .expect("failed to initialize statsd client"),
+            None => Metrics::disabled(),
+        };
+
         // drive client
         let drive = Drive::new(
             HttpConfig {
@@ -136,26 +1094,1338 @@ impl AppOptions {
             auth,
             drive_request_limit,
             drive_upload_limit,
+            metrics.clone(),
         )
         .expect("failed to initialize drive client");
 
+        // tls certificate
+        let tls = match (server_tls_cert, server_tls_key) {
+            (Some(cert_path), Some(key_path)) if command.is_none() => {
+                let certs = Arc::new(
+                    CertResolver::new(cert_path, key_path).expect("failed to load tls certificate"),
+                );
+
+                {
+                    let certs = certs.clone();
+
+                    tokio::spawn(async move {
+                        let mut interval = tokio::time::interval(Duration::from_secs(10));
+
+                        loop {
+                            interval.tick().await;
+
+                            match certs.reload_if_changed() {
+                                Ok(false) => {}
+                                Ok(true) => info!("reloaded tls certificate"),
+                                Err(err) => warn!("failed to reload tls certificate: {err}"),
+                            }
+                        }
+                    });
+                }
+
+                let client_verifier = server_tls_client_ca.map(|path| {
+                    tls::client_verifier(&path, server_tls_client_optional)
+                        .expect("failed to load tls client ca")
+                });
+
+                Some(tls::server_config(certs, client_verifier))
+            }
+            _ => None,
+        };
+
+        // answer probes while starting, as applying migrations may take a while
+        let startup_server = command.is_none().then(|| {
+            let (shutdown, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
+            let server = serve(startup_routes(), server_endpoint, tls.clone(), async move {
+                let _ = shutdown_signal.await;
+            });
+
+            (shutdown, tokio::spawn(server))
+        });
+
         debug!("connecting to database");
 
         // database client
-        let db = Db::new(db_connection).expect("failed to initialize database client");
+        let mut db = Db::new(db_connection).expect("failed to initialize database client");
+        db.set_track_atime(db_track_atime);
         db.migrate().await.expect("failed to migrate database");
 
+        let master_key = match (kms, master_key, master_key_file) {
+            (Some(kms), _, _) => Some(WrappingKey::Kms(kms)),
+            (None, Some(key), _) => Some(WrappingKey::Local(key)),
+            (None, None, Some(path)) => {
+                let key = std::fs::read_to_string(path).expect("failed to read master key");
+                Some(WrappingKey::Local(
+                    key.parse().expect("failed to parse master key"),
+                ))
+            }
+            (None, None, None) => None,
+        };
+
+        let mut store = Store::new(StoreConfig {
+            db,
+            drive,
+            deduplicate: store_deduplicate,
+            cipher: store_cipher,
+            master_key,
+            previous_master_keys: previous_master_keys
+                .into_iter()
+                .map(WrappingKey::Local)
+                .collect(),
+            chunk_cache: cache_path.map(|path| {
+                ChunkCache::new(path, cache_size * 1024 * 1024)
+                    .expect("failed to initialize chunk cache")
+            }),
+            media_cache: (cache_media_size != 0)
+                .then(|| MediaCache::new(cache_media_chunks, cache_media_size * 1024 * 1024)),
+            cache_upload_window: match cache_upload_window {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            shared_cache: redis_url.map(|url| {
+                SharedCache::new(
+                    Redis::new(&url).expect("failed to initialize redis client"),
+                    redis_prefix,
+                    redis_metadata_ttl,
+                    redis_chunk_ttl.max(1),
+                    redis_max_chunk_size * 1024,
+                )
+            }),
+            readahead: store_readahead,
+            download_parallelism: store_download_parallelism,
+            spool: store_spool_path
+                .map(|path| Spool::new(path).expect("failed to initialize upload spool")),
+            allowed_content_types: store_allowed_content_types,
+            denied_content_types: store_denied_content_types,
+            sniff: store_sniff_content_type,
+            quota: store_quota.map(|size| size * 1024 * 1024),
+            namespace_quota: store_namespace_quota.map(|size| size * 1024 * 1024),
+            transfer_allowance: store_namespace_transfer_allowance.map(|size| size * 1024 * 1024),
+            webhooks,
+        });
+
+        store
+            .load_metadata_key(store_encrypt_metadata)
+            .await
+            .expect("failed to load metadata key");
+
+        let store = Arc::new(store);
+
+        if let Some(command) = command {
+            let success = match command {
+                Command::Verify(options) => options.run(&store).await,
+                Command::Rekey(options) => options.run(&store).await,
+                Command::Put(options) => options.run(&store).await,
+                Command::Get(options) => options.run(&store).await,
+                Command::ExportMetadata(options) => options.run(&store).await,
+                Command::ImportMetadata(options) => options.run(&store).await,
+                Command::Export(options) => options.run(&store).await,
+                Command::Prune(options) => options.run(&store).await,
+                Command::Reconcile(options) => options.run(&store).await,
+            };
+
+            std::process::exit(if success { 0 } else { 1 });
+        }
+
+        if server_verify_credentials {
+            // exit without a panic, as the error is expected to be a misconfiguration
+            if let Err(err) = store.ping_db().await {
+                error!("failed to verify database connection: {err}");
+                std::process::exit(1);
+            }
+
+            if let Err(err) = store.check_drive_access().await {
+                error!("failed to verify drive credentials: {err}");
+                std::process::exit(1);
+            }
+
+            info!("verified database connection and drive credentials");
+        }
+
+        // wrap secrets of files added before the master key was configured
+        match store.wrap_secrets().await {
+            Ok(0) => {}
+            Ok(count) => info!("wrapped secrets of {count} file(s) with the master key"),
+            Err(err) => warn!("failed to wrap file secrets: {err}"),
+        }
+
+        // encrypt metadata of files added before metadata encryption was enabled
+        match store.encrypt_files_metadata().await {
+            Ok(0) => {}
+            Ok(count) => info!("encrypted metadata of {count} file(s)"),
+            Err(err) => warn!("failed to encrypt file metadata: {err}"),
+        }
+
+        // audit log pruning
+        if audit_retention != 0 {
+            let store = store.clone();
+            let retention = chrono::Duration::days(audit_retention.into());
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(3600));
+
+                loop {
+                    interval.tick().await;
+
+                    match store.prune_audit_log(retention).await {
+                        Ok(count) => debug!("pruned {count} audit log entries"),
+                        Err(err) => warn!("failed to prune audit log: {err}"),
+                    }
+                }
+            });
+        }
+
+        // pruning transfers of previous days, which no longer count towards the allowance
+        if store_namespace_transfer_allowance.is_some() {
+            let store = store.clone();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(3600));
+
+                loop {
+                    interval.tick().await;
+
+                    match store.prune_transfers().await {
+                        Ok(count) => debug!("pruned {count} transfer counters"),
+                        Err(err) => warn!("failed to prune transfer counters: {err}"),
+                    }
+                }
+            });
+        }
+
+        // download statistics flushing
+        {
+            let store = store.clone();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(10));
+
+                loop {
+                    interval.tick().await;
+
+                    if let Err(err) = store.flush_file_stats().await {
+                        warn!("failed to flush download statistics: {err}");
+                    }
+                }
+            });
+        }
+
+        // uploading spooled files
+        {
+            let store = store.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    match store.upload_spooled().await {
+                        Ok(0) => {}
+                        Ok(count) => debug!("uploaded {count} spooled file(s)"),
+                        Err(err) => warn!("failed to upload spooled files: {err}"),
+                    }
+
+                    store.wait_spooled(Duration::from_secs(60)).await;
+                }
+            });
+        }
+
+        // retrying failed deletions of remote files
+        {
+            let store = store.clone();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+                loop {
+                    interval.tick().await;
+
+                    match store.process_delete_queue().await {
+                        Ok(0) => {}
+                        Ok(count) => debug!("deleted {count} queued file(s)"),
+                        Err(err) => warn!("failed to process delete queue: {err}"),
+                    }
+                }
+            });
+        }
+
+        // comparing drive with the database
+        if store_reconcile_interval != 0 {
+            let store = store.clone();
+            let metrics = metrics.clone();
+
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(store_reconcile_interval * 3600));
+
+                // skip the first tick, so that restarts don't list every drive
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+
+                    match store.reconcile(store_reconcile_repair).await {
+                        Ok(report) => {
+                            metrics.gauge("reconcile.orphaned", report.orphaned.len() as u64);
+                            metrics.gauge("reconcile.dangling", report.dangling.len() as u64);
+                            info!(
+                                "reconciled {} remote file(s), {} orphaned, {} file(s) missing",
+                                report.listed,
+                                report.orphaned.len(),
+                                report.dangling.len()
+                            );
+                        }
+                        Err(err) => warn!("failed to reconcile files: {err}"),
+                    }
+                }
+            });
+        }
+
+        // chunk cache invalidation
+        {
+            let store = store.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    if let Err(err) = store.watch_cache_invalidations().await {
+                        warn!("failed to watch cache invalidations: {err}");
+                    } else {
+                        break;
+                    }
+
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+            });
+        }
+
+        // metrics flushing
+        {
+            let metrics = metrics.clone();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+                loop {
+                    interval.tick().await;
+                    metrics.flush();
+                }
+            });
+        }
+
+        // headers added to all responses
+        let mut response_headers = HeaderMap::new();
+
+        if server_security_headers {
+            response_headers.insert(
+                "x-content-type-options",
+                HeaderValue::from_static("nosniff"),
+            );
+            response_headers.insert("content-security-policy", server_content_security_policy);
+            response_headers.insert("referrer-policy", server_referrer_policy);
+        }
+
+        if let Some(max_age) = server_hsts_max_age {
+            response_headers.insert(
+                "strict-transport-security",
+                HeaderValue::from_str(&format!("max-age={max_age}")).expect("invalid hsts max-age"),
+            );
+        }
+
+        // extra headers replace the security headers of the same name
+        for name in server_headers.iter().map(|(name, _)| name) {
+            response_headers.remove(name);
+        }
+
+        for (name, value) in server_headers {
+            response_headers.append(name, value);
+        }
+
+        if !server_settings.has_credentials()
+            && oidc.is_none()
+            && store
+                .get_users()
+                .await
+                .expect("failed to get users")
+                .is_empty()
+        {
+            info!("no api keys, users or oidc provider configured; all requests are authorized");
+        }
+
+        let settings = Arc::new(Settings::new(server_settings));
+        let fetcher = fetcher.map(Arc::new);
+
+        // reload settings on SIGHUP and through "POST /admin/reload"
+        let (reload, mut reload_requests) = mpsc::channel(1);
+        {
+            let store = store.clone();
+            let settings = settings.clone();
+            let fetcher = fetcher.clone();
+            let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+
+            tokio::spawn(async move {
+                loop {
+                    let request: Option<server::ReloadRequest> = tokio::select! {
+                        Some(_) = hangup.recv() => None,
+                        Some(request) = reload_requests.recv() => Some(request),
+                        else => break,
+                    };
+
+                    let result = reload_config(&store, &settings, fetcher.as_deref()).await;
+
+                    match result {
+                        Ok(()) => info!("reloaded configuration"),
+                        Err(ref err) => warn!("failed to reload configuration: {err}"),
+                    }
+
+                    if let Some(request) = request {
+                        let _ = request.send(result);
+                    }
+                }
+            });
+        }
+
         info!("initialization complete; starting http server");
 
+        // stop answering probes to free the endpoint
+        if let Some((shutdown, server)) = startup_server {
+            let _ = shutdown.send(());
+            let _ = server.await;
+        }
+
         // frontend server
-        warp::serve(
+        let server = serve(
             routes(ServerConfig {
-                store: Store::new(db, drive).into(),
-                max_upload_size: server_max_upload_size * 1024 * 1024, // MiB to B
-            })
-            .with(warp::log("warp")),
-        )
-        .run(server_endpoint)
+                store: store.clone(),
+                settings,
+                max_form_upload_size: server_max_form_upload_size * 1024 * 1024,
+                upload_buffer_path: server_upload_buffer_path.inspect(|path| {
+                    std::fs::create_dir_all(path).expect("failed to create upload buffer directory")
+                }),
+                fetcher,
+                images: server_image_transforms.then(|| {
+                    ImageTransformer::new(
+                        server_image_cache_path,
+                        server_image_cache_size * 1024 * 1024,
+                        server_image_max_size * 1024 * 1024,
+                    )
+                    .expect("failed to initialize image transformer")
+                }),
+                response_headers,
+                client_max_downloads: server_client_max_downloads,
+                max_uploads: server_max_uploads,
+                upload_queue_timeout: Duration::from_secs(server_upload_queue_timeout),
+                shed_threshold: server_shed_threshold,
+                download_limit: server_download_limit,
+                global_download_limit: server_global_download_limit,
+                oidc,
+                authenticate_reads: server_authenticate_reads,
+                url_signer: server_url_signing_key.map(UrlSigner::new),
+                metrics,
+                features,
+                slow_request_threshold: (server_slow_request_threshold != 0)
+                    .then(|| Duration::from_millis(server_slow_request_threshold)),
+                server_timing,
+                compression: server_compression,
+                maintenance: server_maintenance,
+                reload,
+            }),
+            server_endpoint,
+            tls,
+            futures::future::pending(),
+        );
+
+        // systemd readiness once the server listens and an access token was obtained, then watchdog pings
+        if systemd::is_notify_enabled() {
+            tokio::spawn(async move {
+                while let Err(err) = store.check_drive_auth().await {
+                    warn!("failed to obtain access token; not yet notifying systemd of readiness: {err}");
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+
+                if let Err(err) = systemd::notify("READY=1") {
+                    warn!("{err}");
+                }
+
+                if let Some(interval) = systemd::watchdog_interval() {
+                    let mut interval = tokio::time::interval(interval);
+
+                    loop {
+                        interval.tick().await;
+
+                        if let Err(err) = systemd::notify("WATCHDOG=1") {
+                            warn!("{err}");
+                        }
+                    }
+                }
+            });
+        }
+
+        server.await;
+    }
+}
+
+/// Reloads the limits, maximum upload size, credentials and cache size from the config file and the environment.
+/// Other options only take effect on restart.
+async fn reload_config(
+    store: &Store,
+    settings: &Settings,
+    fetcher: Option<&Fetcher>,
+) -> Result<(), String> {
+    // only the first line of the error, without usage
+    let options = AppOptions::load().map_err(|err| {
+        err.to_string()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    })?;
+
+    let server_settings = options.server_settings();
+
+    store.set_drive_limits(options.drive_request_limit, options.drive_upload_limit);
+    store
+        .set_cache_capacity(options.cache_size * 1024 * 1024)
         .await;
+
+    if let Some(fetcher) = fetcher {
+        fetcher.set_max_size(options.server_max_upload_size * 1024 * 1024);
+    }
+
+    settings.replace(server_settings);
+    Ok(())
+}
+
+/// Listens on the endpoint and returns the future that serves requests until the shutdown future completes,
+/// over TLS if configured.
+fn serve<R>(
+    routes: BoxedFilter<(R,)>,
+    endpoint: SocketAddr,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> BoxFuture<'static, ()>
+where
+    R: Reply + 'static,
+{
+    match tls {
+        Some(config) => tls::serve(routes, endpoint, config, shutdown).boxed(),
+        None => {
+            let service = warp::service(routes);
+
+            // served by hyper rather than warp::serve, so that requests carry their address and client cache
+            let make_service = make_service_fn(move |conn: &AddrStream| {
+                let addr = conn.remote_addr();
+                let service = service.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |mut req| {
+                        req.extensions_mut().insert(RemoteAddr(addr));
+                        req.extensions_mut().insert(ClientCache::default());
+                        service.clone().call(req)
+                    }))
+                }
+            });
+
+            let server = hyper::Server::bind(&endpoint).serve(make_service);
+            info!("listening on http://{endpoint}");
+
+            server
+                .with_graceful_shutdown(shutdown)
+                .map(|result| {
+                    if let Err(err) = result {
+                        error!("server failed: {err}");
+                    }
+                })
+                .boxed()
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct RekeyOptions {
+    /// Also re-encrypt the contents of files with new secrets.
+    #[clap(long)]
+    deep: bool,
+
+    /// Only re-encrypt files last encrypted before this time, which an interrupted run prints to resume from.
+    #[clap(long, requires = "deep")]
+    before: Option<DateTime<Utc>>,
+
+    /// Bandwidth limit for reading files, measured in MiB/s.
+    #[clap(long)]
+    limit: Option<RateLimit>,
+}
+
+impl VerifyOptions {
+    /// Verifies the selected files, returning whether all of them are intact.
+    pub async fn run(self, store: &Store) -> bool {
+        let limiter = self
+            .limit
+            .map(|limit| Arc::new(BandwidthLimiter::new(limit, 1024 * 1024)));
+
+        let mut valid = true;
+        let mut keys = self.keys;
+        let mut before = None;
+
+        loop {
+            if self.all && keys.is_empty() {
+                // page through all files
+                let files = store
+                    .get_files(&FileQuery {
+                        before,
+                        limit: Some(1000),
+                        ..Default::default()
+                    })
+                    .await
+                    .expect("failed to list files");
+
+                match files.last() {
+                    Some(file) => before = Some(file.key),
+                    None => break,
+                }
+
+                keys = files.into_iter().map(|file| file.key).collect();
+            }
+
+            if keys.is_empty() {
+                break;
+            }
+
+            for key in std::mem::take(&mut keys) {
+                match store.verify(key, limiter.clone()).await {
+                    Ok(Some(report)) if report.is_valid() => println!("{key}: ok"),
+                    Ok(Some(report)) => {
+                        valid = false;
+
+                        if report.truncated {
+                            println!("{key}: truncated");
+                        }
+
+                        if !report.corrupted.is_empty() {
+                            println!(
+                                "{key}: {count} of {total} chunk(s) corrupted: {chunks:?}",
+                                count = report.corrupted.len(),
+                                total = report.chunks,
+                                chunks = report.corrupted
+                            );
+                        }
+                    }
+                    Ok(None) => {
+                        valid = false;
+                        println!("{key}: not found");
+                    }
+                    Err(err) => {
+                        valid = false;
+                        println!("{key}: {err}");
+                    }
+                }
+            }
+        }
+
+        valid
+    }
+}
+
+impl RekeyOptions {
+    /// Rekeys all files, returning whether all of them were rekeyed successfully.
+    pub async fn run(self, store: &Store) -> bool {
+        match store.wrap_secrets().await {
+            Ok(count) => println!("rewrapped secrets of {count} file(s)"),
+            Err(err) => {
+                println!("failed to rewrap file secrets: {err}");
+                return false;
+            }
+        }
+
+        if !self.deep {
+            return true;
+        }
+
+        let limiter = self
+            .limit
+            .map(|limit| Arc::new(BandwidthLimiter::new(limit, 1024 * 1024)));
+
+        let before = self.before.unwrap_or_else(Utc::now);
+
+        println!(
+            "re-encrypting files last encrypted before {}",
+            before.to_rfc3339()
+        );
+
+        let mut success = true;
+        let mut after = 0;
+        let mut reencrypted = HashSet::new();
+
+        loop {
+            let files = store
+                .get_files_by_encrypted_time(before.naive_utc(), after, 100)
+                .await
+                .expect("failed to list files");
+
+            match files.last() {
+                Some(file) => after = file.key,
+                None => break,
+            }
+
+            for file in files {
+                // files with identical content share a remote file, which is re-encrypted only once
+                if !reencrypted.insert(file.id.clone()) {
+                    continue;
+                }
+
+                match store.reencrypt(&file, limiter.clone()).await {
+                    Ok(()) => println!("{key}: re-encrypted", key = file.key),
+                    Err(err) => {
+                        success = false;
+                        println!("{key}: {err}", key = file.key);
+                    }
+                }
+            }
+        }
+
+        success
+    }
+}
+
+#[derive(Debug, Args)]
+struct PutOptions {
+    /// Path of the file to upload.
+    path: PathBuf,
+
+    /// Content type of the file, which is sniffed from the content if "CS_STORE_SNIFF_CONTENT_TYPE" allows it.
+    #[clap(long)]
+    content_type: Option<String>,
+
+    /// Original name of the file, defaulting to the name of the uploaded file.
+    #[clap(long)]
+    filename: Option<String>,
+
+    /// Namespace to upload the file into.
+    #[clap(long, default_value = DEFAULT_NAMESPACE)]
+    namespace: String,
+
+    /// Name of the collection in the namespace to upload the file into.
+    #[clap(long)]
+    collection: Option<String>,
+
+    /// Allow the file to be downloaded without credentials.
+    #[clap(long)]
+    public: bool,
+
+    /// Store the file without encryption.
+    #[clap(long)]
+    no_encrypt: bool,
+}
+
+impl PutOptions {
+    /// Uploads the file, returning whether it was uploaded successfully.
+    pub async fn run(self, store: &Store) -> bool {
+        let file = match tokio::fs::File::open(&self.path).await {
+            Ok(file) => file,
+            Err(err) => {
+                println!("failed to open '{}': {err}", self.path.display());
+                return false;
+            }
+        };
+
+        let size = match file.metadata().await {
+            Ok(metadata) => metadata.len(),
+            Err(err) => {
+                println!("failed to read '{}': {err}", self.path.display());
+                return false;
+            }
+        };
+
+        let mut options = UploadOptions {
+            filename: self.filename.or_else(|| {
+                self.path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            }),
+            encryption: if self.no_encrypt {
+                Encryption::None
+            } else {
+                Encryption::Server
+            },
+            public: self.public.then_some(true),
+            namespace: self.namespace,
+            collection: self.collection,
+            ..Default::default()
+        };
+
+        if let Some(content_type) = self.content_type {
+            options.content_type = content_type;
+        }
+
+        match store.upload(size, options, ReaderStream::new(file)).await {
+            Ok(file) => {
+                println!("{}", file.key);
+                true
+            }
+            Err(err) => {
+                println!("failed to upload '{}': {err}", self.path.display());
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct GetOptions {
+    /// Key of the file to download.
+    key: i64,
+
+    /// Path to write the file to, instead of standard output.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl GetOptions {
+    /// Downloads the file, returning whether it was downloaded successfully.
+    pub async fn run(self, store: &Store) -> bool {
+        let result = self.download(store).await;
+
+        // counted downloads are otherwise flushed periodically by the server
+        if let Err(err) = store.flush_file_stats().await {
+            eprintln!("failed to flush download statistics: {err}");
+        }
+
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("{}: {err}", self.key);
+                false
+            }
+        }
+    }
+
+    async fn download(&self, store: &Store) -> Result<(), String> {
+        let FileData {
+            content,
+            last_download,
+            ..
+        } = store
+            .get(self.key, None)
+            .await
+            .map_err(|err| err.to_string())?
+            .ok_or("not found")?;
+
+        let mut output: Box<dyn AsyncWrite + Send + Unpin> = match self.output {
+            Some(ref path) => Box::new(
+                tokio::fs::File::create(path)
+                    .await
+                    .map_err(|err| format!("failed to create '{}': {err}", path.display()))?,
+            ),
+            None => Box::new(tokio::io::stdout()),
+        };
+
+        let result = async {
+            futures::pin_mut!(content);
+
+            while let Some(chunk) = content.next().await {
+                let chunk = chunk.map_err(|err| err.to_string())?;
+
+                output
+                    .write_all(&chunk)
+                    .await
+                    .map_err(|err| format!("failed to write: {err}"))?;
+            }
+
+            output
+                .flush()
+                .await
+                .map_err(|err| format!("failed to write: {err}"))
+        }
+        .await;
+
+        if let Err(err) = result {
+            // don't leave a truncated file behind
+            if let Some(ref path) = self.output {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+
+            return Err(err);
+        }
+
+        // the file is deleted once its last permitted download was served
+        if last_download {
+            store
+                .delete(self.key)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of rows exported in each record of a metadata archive.
+const EXPORT_BATCH_SIZE: u32 = 1000;
+
+#[derive(Debug, Args)]
+struct ExportMetadataOptions {
+    /// Path to write the archive to.
+    path: PathBuf,
+
+    /// File containing the key that encrypts the archive, as 64 hex digits.
+    #[clap(long)]
+    key_file: PathBuf,
+
+    /// File containing a key with which file secrets and the metadata key are rewrapped, as 64 hex digits,
+    /// so that they can be unwrapped by configuring it as a master key instead of the current master key.
+    #[clap(long)]
+    rewrap_key_file: Option<PathBuf>,
+}
+
+impl ExportMetadataOptions {
+    /// Exports the metadata, returning whether it was exported successfully.
+    pub async fn run(self, store: &Store) -> bool {
+        match self.export(store).await {
+            Ok(count) => {
+                println!("exported {count} row(s)");
+                true
+            }
+            Err(err) => {
+                println!("failed to export metadata: {err}");
+                false
+            }
+        }
+    }
+
+    async fn export(&self, store: &Store) -> Result<u64, String> {
+        let key = read_key_file(&self.key_file)?;
+        let rewrap_key = match self.rewrap_key_file {
+            Some(ref path) => Some(read_key_file(path)?),
+            None => None,
+        };
+
+        let metadata_key = store
+            .export_metadata_key(rewrap_key.as_ref())
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let file = tokio::fs::File::create(&self.path)
+            .await
+            .map_err(|err| format!("failed to create '{}': {err}", self.path.display()))?;
+
+        let result = async {
+            let mut archive = ArchiveWriter::new(BufWriter::new(file), key)
+                .await
+                .map_err(|err| err.to_string())?;
+
+            archive
+                .write(&Record::Header {
+                    migration_version: MIGRATION_VERSION,
+                    metadata_key,
+                })
+                .await
+                .map_err(|err| err.to_string())?;
+
+            let mut total = 0;
+
+            for table in MetadataTable::ALL {
+                let mut count = 0;
+                let mut after = 0;
+
+                loop {
+                    let rows = store
+                        .export_metadata(table, after, EXPORT_BATCH_SIZE, rewrap_key.as_ref())
+                        .await
+                        .map_err(|err| err.to_string())?;
+
+                    match rows.last() {
+                        Some(row) => {
+                            after = row.get("key").and_then(Value::as_i64).unwrap_or_default()
+                        }
+                        None => break,
+                    }
+
+                    count += rows.len() as u64;
+
+                    archive
+                        .write(&Record::Rows { table, rows })
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+
+                println!("{}: {count} row(s)", table.name());
+                total += count;
+            }
+
+            archive
+                .write(&Record::End { rows: total })
+                .await
+                .map_err(|err| err.to_string())?;
+
+            archive.finish().await.map_err(|err| err.to_string())?;
+            Ok(total)
+        }
+        .await;
+
+        // don't leave a partial archive behind
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&self.path).await;
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Args)]
+struct ImportMetadataOptions {
+    /// Path of the archive to import.
+    path: PathBuf,
+
+    /// File containing the key that encrypts the archive, as 64 hex digits.
+    #[clap(long)]
+    key_file: PathBuf,
+}
+
+impl ImportMetadataOptions {
+    /// Imports the metadata, returning whether it was imported successfully.
+    pub async fn run(self, store: &Store) -> bool {
+        match self.import(store).await {
+            Ok((inserted, total)) => {
+                println!("imported {inserted} of {total} row(s)");
+                true
+            }
+            Err(err) => {
+                println!("failed to import metadata: {err}");
+                false
+            }
+        }
+    }
+
+    async fn import(&self, store: &Store) -> Result<(u64, u64), String> {
+        let key = read_key_file(&self.key_file)?;
+
+        let file = tokio::fs::File::open(&self.path)
+            .await
+            .map_err(|err| format!("failed to open '{}': {err}", self.path.display()))?;
+
+        let mut archive = ArchiveReader::new(BufReader::new(file), key)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        match archive.read().await.map_err(|err| err.to_string())? {
+            Record::Header {
+                migration_version,
+                metadata_key,
+            } => {
+                if migration_version > MIGRATION_VERSION {
+                    return Err(format!(
+                        "archive was exported at migration {migration_version}, which is newer than {MIGRATION_VERSION}"
+                    ));
+                }
+
+                if let Some(metadata_key) = metadata_key {
+                    store
+                        .import_metadata_key(&metadata_key)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+            }
+            _ => return Err("archive does not start with a header".into()),
+        }
+
+        let mut inserted = 0;
+        let mut total = 0;
+
+        // rows are committed as they are read, and existing rows are skipped when importing again
+        loop {
+            match archive.read().await.map_err(|err| err.to_string())? {
+                Record::Rows { table, rows } => {
+                    total += rows.len() as u64;
+                    inserted += store
+                        .import_metadata(table, &rows)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+                Record::End { rows } if rows == total => break,
+                Record::End { rows } => {
+                    return Err(format!("archive has {total} of {rows} row(s)"));
+                }
+                Record::Header { .. } => return Err("archive has several headers".into()),
+            }
+        }
+
+        Ok((inserted, total))
+    }
+}
+
+/// Reads a key of 64 hex digits from a file.
+fn read_key_file(path: &Path) -> Result<MasterKey, String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read '{}': {err}", path.display()))?
+        .parse()
+        .map_err(|err| format!("invalid key in '{}': {err}", path.display()))
+}
+
+#[derive(Debug, Args)]
+struct ExportOptions {
+    /// Export all stored files, newest first.
+    #[clap(long)]
+    all: bool,
+
+    /// Keys of the files to export.
+    #[clap(required_unless_present = "all", conflicts_with = "all")]
+    keys: Vec<i64>,
+
+    /// Directory to write each file to as "<key>" along with its metadata as "<key>.json".
+    /// Files that were already exported are skipped, so that an interrupted export can be run again.
+    #[clap(long)]
+    dest: PathBuf,
+
+    /// Write the content as stored on Drive instead of decrypting it,
+    /// adding the secret and cipher needed to decrypt it to the metadata.
+    #[clap(long)]
+    raw: bool,
+
+    /// Bandwidth limit for reading files, measured in MiB/s.
+    #[clap(long)]
+    limit: Option<RateLimit>,
+}
+
+impl ExportOptions {
+    /// Exports the selected files, returning whether all of them were exported successfully.
+    pub async fn run(self, store: &Store) -> bool {
+        if let Err(err) = tokio::fs::create_dir_all(&self.dest).await {
+            println!("failed to create '{}': {err}", self.dest.display());
+            return false;
+        }
+
+        let limiter = self
+            .limit
+            .map(|limit| Arc::new(BandwidthLimiter::new(limit, 1024 * 1024)));
+
+        let mut success = true;
+        let mut keys = self.keys.clone();
+        let mut before = None;
+
+        loop {
+            if self.all && keys.is_empty() {
+                // page through all files
+                let files = store
+                    .get_files(&FileQuery {
+                        before,
+                        limit: Some(1000),
+                        ..Default::default()
+                    })
+                    .await
+                    .expect("failed to list files");
+
+                match files.last() {
+                    Some(file) => before = Some(file.key),
+                    None => break,
+                }
+
+                keys = files.into_iter().map(|file| file.key).collect();
+            }
+
+            if keys.is_empty() {
+                break;
+            }
+
+            for key in std::mem::take(&mut keys) {
+                let path = self.dest.join(key.to_string());
+
+                if tokio::fs::metadata(&path).await.is_ok() {
+                    println!("{key}: skipped");
+                    continue;
+                }
+
+                match self.export(store, key, &path, limiter.clone()).await {
+                    Ok(()) => println!("{key}: exported"),
+                    Err(err) => {
+                        success = false;
+                        println!("{key}: {err}");
+                    }
+                }
+            }
+        }
+
+        success
+    }
+
+    async fn export(
+        &self,
+        store: &Store,
+        key: i64,
+        path: &Path,
+        limiter: Option<Arc<BandwidthLimiter>>,
+    ) -> Result<(), String> {
+        let ExportData {
+            info: file,
+            content,
+            secret,
+        } = store
+            .export(key, self.raw, limiter)
+            .await
+            .map_err(|err| err.to_string())?
+            .ok_or("not found")?;
+
+        let mut metadata = json!({
+            "key": file.key,
+            "size": file.size,
+            "content_type": file.content_type,
+            "filename": file.filename,
+            "metadata": file.metadata,
+            "sha256": file.sha256.as_ref().map(format_hex),
+            "namespace": file.namespace,
+            "created_time": file.created_time,
+        });
+
+        if self.raw {
+            // the drive file id is authenticated as associated data of each chunk
+            metadata["id"] = json!(file.id);
+            metadata["cipher"] = json!(file.cipher);
+            metadata["format"] = json!(file.format);
+            metadata["secret"] = json!(secret.map(format_hex));
+        }
+
+        // content is moved into place last, marking the file as exported
+        let partial = path.with_extension("part");
+
+        let result = async {
+            let mut output = tokio::fs::File::create(&partial)
+                .await
+                .map_err(|err| format!("failed to create '{}': {err}", partial.display()))?;
+
+            futures::pin_mut!(content);
+
+            while let Some(chunk) = content.next().await {
+                let chunk = chunk.map_err(|err| err.to_string())?;
+
+                output
+                    .write_all(&chunk)
+                    .await
+                    .map_err(|err| format!("failed to write: {err}"))?;
+            }
+
+            output
+                .sync_all()
+                .await
+                .map_err(|err| format!("failed to write: {err}"))?;
+
+            tokio::fs::write(
+                path.with_extension("json"),
+                serde_json::to_vec_pretty(&metadata).unwrap(),
+            )
+            .await
+            .map_err(|err| format!("failed to write metadata: {err}"))?;
+
+            tokio::fs::rename(&partial, path)
+                .await
+                .map_err(|err| format!("failed to rename '{}': {err}", partial.display()))
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Args)]
+struct PruneOptions {
+    /// Delete files created more than this many days ago.
+    #[clap(long)]
+    older_than: Option<u32>,
+
+    /// Delete files not downloaded for this many days.
+    #[clap(long)]
+    not_accessed_for: Option<u32>,
+
+    /// Delete files larger than this size, measured in MiB.
+    #[clap(long)]
+    larger_than: Option<u64>,
+
+    /// Delete files with this content type, or with this prefix if it ends with '/'.
+    #[clap(long)]
+    content_type: Option<String>,
+
+    /// Only delete files in this namespace.
+    #[clap(long)]
+    namespace: Option<String>,
+
+    /// Print the files that would be deleted without deleting them.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+impl PruneOptions {
+    /// Deletes the matching files, returning whether all of them were deleted successfully.
+    pub async fn run(self, store: &Store) -> bool {
+        let policy = PrunePolicy {
+            older_than: self.older_than,
+            not_accessed_for: self.not_accessed_for,
+            larger_than: self.larger_than.map(|size| size * 1024 * 1024),
+            content_type: self.content_type,
+            namespace: self.namespace,
+        };
+
+        let report = match store.prune(&policy, self.dry_run).await {
+            Ok(report) => report,
+            Err(err) => {
+                println!("failed to prune files: {err}");
+                return false;
+            }
+        };
+
+        for key in &report.keys {
+            println!(
+                "{key}: {}",
+                if self.dry_run { "matched" } else { "deleted" }
+            );
+        }
+
+        for key in &report.failed {
+            println!("{key}: failed to delete");
+        }
+
+        println!(
+            "{} {count} file(s) of {size} bytes",
+            if self.dry_run { "matched" } else { "deleted" },
+            count = report.keys.len(),
+            size = report.size
+        );
+
+        report.failed.is_empty()
+    }
+}
+
+#[derive(Debug, Args)]
+struct ReconcileOptions {
+    /// Delete orphaned remote files, and delete files whose remote file is missing from Drive.
+    #[clap(long)]
+    repair: bool,
+}
+
+impl ReconcileOptions {
+    /// Compares Drive with the database, returning whether no discrepancies were found or they were repaired.
+    pub async fn run(self, store: &Store) -> bool {
+        let report = match store.reconcile(self.repair).await {
+            Ok(report) => report,
+            Err(err) => {
+                println!("failed to reconcile files: {err}");
+                return false;
+            }
+        };
+
+        for id in &report.orphaned {
+            println!("{id}: orphaned");
+        }
+
+        for key in &report.dangling {
+            println!("{key}: missing from drive");
+        }
+
+        println!(
+            "listed {} remote file(s), {} orphaned, {} file(s) missing{}",
+            report.listed,
+            report.orphaned.len(),
+            report.dangling.len(),
+            if self.repair { ", repaired" } else { "" }
+        );
+
+        self.repair || (report.orphaned.is_empty() && report.dangling.is_empty())
     }
 }
@@ -0,0 +1,492 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::access::Scope;
+use castella_core::http::HttpConfig;
+use chrono::Utc;
+use reqwest::Client;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to initialize http client: {0}")]
+    ClientInit(reqwest::Error),
+
+    #[error("failed to fetch signing keys of the provider: {0}")]
+    KeysFetch(reqwest::Error),
+
+    #[error("malformed token")]
+    TokenMalformed,
+
+    #[error("unsupported token algorithm '{0}'")]
+    AlgorithmUnsupported(String),
+
+    #[error("token is signed by an unknown key")]
+    KeyUnknown,
+
+    #[error("invalid token signature")]
+    SignatureInvalid,
+
+    #[error("token is issued by another issuer")]
+    IssuerMismatch,
+
+    #[error("token is issued for another audience")]
+    AudienceMismatch,
+
+    #[error("token is expired or not yet valid")]
+    Expired,
+
+    #[error("token does not claim any mapped role")]
+    RoleMissing,
+}
+
+#[derive(Debug)]
+pub struct OidcConfig {
+    /// Issuer url of the provider, whose discovery document lists its signing keys.
+    pub issuer: String,
+    /// Audience that tokens must be issued for, usually the client ID, or `None` to accept any audience.
+    pub audience: Option<String>,
+    /// Claim listing the roles or groups of the subject, as a string or an array of strings.
+    pub role_claim: String,
+    /// Scopes granted to subjects with each claimed role.
+    pub roles: HashMap<String, Scope>,
+}
+
+/// Validates JSON web tokens issued by an OpenID Connect provider.
+#[derive(Debug)]
+pub struct OidcValidator {
+    http: Client,
+    config: OidcConfig,
+    keys: Mutex<(Vec<Jwk>, Option<Instant>)>,
+    /// Held while fetching the keys, so that tokens signed by a new key only fetch them once.
+    refresh: tokio::sync::Mutex<()>,
+}
+
+/// Minimum interval between fetches of the signing keys,
+/// so that tokens with unknown key IDs can't flood the provider with requests.
+const KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tolerated difference between the clocks of the provider and the server in seconds.
+const CLOCK_LEEWAY: i64 = 60;
+
+/// Public key of the provider in JWK format.
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    /// RSA modulus.
+    n: Option<String>,
+    /// RSA exponent.
+    e: Option<String>,
+    crv: Option<String>,
+    /// Elliptic curve point coordinates.
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl OidcValidator {
+    pub fn new(http: HttpConfig, config: OidcConfig) -> Result<Self, Error> {
+        Ok(Self {
+            http: http.create_client().map_err(Error::ClientInit)?,
+            config,
+            keys: Mutex::new((Vec::new(), None)),
+            refresh: Default::default(),
+        })
+    }
+
+    /// Validates a token, returning the highest scope granted by the roles it claims.
+    pub async fn validate(&self, token: &str) -> Result<Scope, Error> {
+        #[derive(Deserialize)]
+        struct Header {
+            alg: String,
+            kid: Option<String>,
+        }
+
+        let (message, signature) = token.rsplit_once('.').ok_or(Error::TokenMalformed)?;
+        let (header, payload) = message.split_once('.').ok_or(Error::TokenMalformed)?;
+
+        let header: Header =
+            serde_json::from_slice(&decode(header)?).map_err(|_| Error::TokenMalformed)?;
+
+        let key = self.key(header.kid.as_deref()).await?;
+        verify(&header.alg, &key, message.as_bytes(), &decode(signature)?)?;
+
+        let claims: Map<String, Value> =
+            serde_json::from_slice(&decode(payload)?).map_err(|_| Error::TokenMalformed)?;
+
+        self.check_claims(&claims)?;
+
+        let roles = match claims.get(&self.config.role_claim) {
+            Some(Value::String(role)) => vec![role.as_str()],
+            Some(Value::Array(roles)) => roles.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+
+        roles
+            .into_iter()
+            .filter_map(|role| self.config.roles.get(role).copied())
+            .max()
+            .ok_or(Error::RoleMissing)
+    }
+
+    fn check_claims(&self, claims: &Map<String, Value>) -> Result<(), Error> {
+        if claims.get("iss").and_then(Value::as_str) != Some(self.config.issuer.as_str()) {
+            return Err(Error::IssuerMismatch);
+        }
+
+        if let Some(ref audience) = self.config.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(aud)) => aud.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+
+            if !matches {
+                return Err(Error::AudienceMismatch);
+            }
+        }
+
+        let now = Utc::now().timestamp();
+        let expires = claims
+            .get("exp")
+            .and_then(Value::as_i64)
+            .ok_or(Error::TokenMalformed)?;
+
+        let not_before = claims.get("nbf").and_then(Value::as_i64);
+
+        if expires + CLOCK_LEEWAY < now || not_before.is_some_and(|nbf| nbf - CLOCK_LEEWAY > now) {
+            return Err(Error::Expired);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the signing key with the ID, fetching the keys again if it isn't known
+    /// in case the provider rotated its keys.
+    async fn key(&self, kid: Option<&str>) -> Result<Jwk, Error> {
+        if let Ok(key) = self.find_key(kid) {
+            return Ok(key);
+        }
+
+        let _refresh = self.refresh.lock().await;
+
+        // the keys may have been fetched while waiting for another refresh
+        let fetched = match self.find_key(kid) {
+            Ok(key) => return Ok(key),
+            Err(fetched) => fetched,
+        };
+
+        if fetched.is_some_and(|fetched| fetched.elapsed() < KEYS_REFRESH_INTERVAL) {
+            return Err(Error::KeyUnknown);
+        }
+
+        // tokens signed by known keys are validated while fetching
+        let keys = self.fetch_keys().await?;
+        let key = find_key(&keys, kid).cloned();
+
+        *self.keys.lock().unwrap() = (keys, Some(Instant::now()));
+        key.ok_or(Error::KeyUnknown)
+    }
+
+    /// Returns the known signing key with the ID, or the time the keys were last fetched.
+    fn find_key(&self, kid: Option<&str>) -> Result<Jwk, Option<Instant>> {
+        let keys = self.keys.lock().unwrap();
+        find_key(&keys.0, kid).cloned().ok_or(keys.1)
+    }
+
+    async fn fetch_keys(&self) -> Result<Vec<Jwk>, Error> {
+        #[derive(Deserialize)]
+        struct Discovery {
+            jwks_uri: String,
+        }
+
+        #[derive(Deserialize)]
+        struct KeySet {
+            keys: Vec<Jwk>,
+        }
+
+        debug!("fetching signing keys of '{}'", self.config.issuer);
+
+        let discovery: Discovery = self
+            .http
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                self.config.issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(Error::KeysFetch)?
+            .json()
+            .await
+            .map_err(Error::KeysFetch)?;
+
+        let key_set: KeySet = self
+            .http
+            .get(discovery.jwks_uri)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(Error::KeysFetch)?
+            .json()
+            .await
+            .map_err(Error::KeysFetch)?;
+
+        Ok(key_set.keys)
+    }
+}
+
+/// Finds the key with the ID, or the only key if the token doesn't specify one.
+fn find_key<'a>(keys: &'a [Jwk], kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => keys.iter().find(|key| key.kid.as_deref() == Some(kid)),
+        None if keys.len() == 1 => keys.first(),
+        None => None,
+    }
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, Error> {
+    base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(|_| Error::TokenMalformed)
+}
+
+/// Verifies the signature of a token with a key of the provider.
+fn verify(alg: &str, key: &Jwk, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let result = match (alg, key.kty.as_str()) {
+        ("RS256", "RSA") => {
+            let (n, e) = key
+                .n
+                .as_deref()
+                .zip(key.e.as_deref())
+                .ok_or(Error::KeyUnknown)?;
+
+            RsaPublicKeyComponents {
+                n: decode(n)?,
+                e: decode(e)?,
+            }
+            .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+        }
+        ("ES256", "EC") if key.crv.as_deref() == Some("P-256") => {
+            let (x, y) = key
+                .x
+                .as_deref()
+                .zip(key.y.as_deref())
+                .ok_or(Error::KeyUnknown)?;
+
+            // uncompressed point encoding
+            let mut point = vec![0x04];
+            point.extend(decode(x)?);
+            point.extend(decode(y)?);
+
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, signature)
+        }
+        _ => return Err(Error::AlgorithmUnsupported(alg.into())),
+    };
+
+    result.map_err(|_| Error::SignatureInvalid)
+}
+
+/// Parses a mapping of a claimed role to a scope given as `claim=role`.
+pub fn parse_role_mapping(s: &str) -> Result<(String, Scope), &'static str> {
+    let (claim, role) = s
+        .rsplit_once('=')
+        .ok_or("role mapping must be given as 'claim=role'")?;
+
+    let scope = Scope::from_role(role.trim()).ok_or("role must be reader, writer or admin")?;
+    Ok((claim.trim().into(), scope))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use serde_json::json;
+
+    const ISSUER: &str = "https://issuer.example.com";
+    const AUDIENCE: &str = "castella";
+
+    struct Provider {
+        key_pair: EcdsaKeyPair,
+        rng: SystemRandom,
+    }
+
+    impl Provider {
+        fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+            let key_pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+
+            Self { key_pair, rng }
+        }
+
+        fn jwk(&self) -> Jwk {
+            let encode = |bytes| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+
+            // uncompressed point encoding
+            let point = self.key_pair.public_key().as_ref();
+
+            Jwk {
+                kty: "EC".into(),
+                kid: Some("key".into()),
+                n: None,
+                e: None,
+                crv: Some("P-256".into()),
+                x: Some(encode(&point[1..33])),
+                y: Some(encode(&point[33..])),
+            }
+        }
+
+        fn sign(&self, kid: &str, claims: Value) -> String {
+            let encode =
+                |value: Value| base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD);
+
+            let message = format!(
+                "{}.{}",
+                encode(json!({ "alg": "ES256", "kid": kid })),
+                encode(claims)
+            );
+
+            let signature = self.key_pair.sign(&self.rng, message.as_bytes()).unwrap();
+            let signature = base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD);
+
+            format!("{message}.{signature}")
+        }
+    }
+
+    /// Returns a validator that knows the key of the provider without fetching it.
+    fn validator(provider: &Provider) -> OidcValidator {
+        let validator = OidcValidator::new(
+            HttpConfig::default(),
+            OidcConfig {
+                issuer: ISSUER.into(),
+                audience: Some(AUDIENCE.into()),
+                role_claim: "groups".into(),
+                roles: [("uploaders".into(), Scope::Write)].into(),
+            },
+        )
+        .unwrap();
+
+        *validator.keys.lock().unwrap() = (vec![provider.jwk()], Some(Instant::now()));
+        validator
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "exp": Utc::now().timestamp() + 3600,
+            "groups": ["viewers", "uploaders"],
+        })
+    }
+
+    #[tokio::test]
+    async fn validate() {
+        let provider = Provider::new();
+        let token = provider.sign("key", claims());
+
+        assert_eq!(
+            validator(&provider).validate(&token).await.unwrap(),
+            Scope::Write
+        );
+    }
+
+    #[tokio::test]
+    async fn validate_issuer() {
+        let provider = Provider::new();
+
+        let mut claims = claims();
+        claims["iss"] = "https://other.example.com".into();
+
+        assert!(matches!(
+            validator(&provider)
+                .validate(&provider.sign("key", claims))
+                .await,
+            Err(Error::IssuerMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn validate_audience() {
+        let provider = Provider::new();
+
+        let mut claims = claims();
+        claims["aud"] = json!(["other", AUDIENCE]);
+        assert!(validator(&provider)
+            .validate(&provider.sign("key", claims.clone()))
+            .await
+            .is_ok());
+
+        claims["aud"] = "other".into();
+        assert!(matches!(
+            validator(&provider)
+                .validate(&provider.sign("key", claims))
+                .await,
+            Err(Error::AudienceMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn validate_expiry() {
+        let provider = Provider::new();
+        let now = Utc::now().timestamp();
+
+        // tolerated within the clock leeway
+        let mut claims = claims();
+        claims["exp"] = (now - CLOCK_LEEWAY / 2).into();
+        assert!(validator(&provider)
+            .validate(&provider.sign("key", claims.clone()))
+            .await
+            .is_ok());
+
+        claims["exp"] = (now - CLOCK_LEEWAY * 2).into();
+        assert!(matches!(
+            validator(&provider)
+                .validate(&provider.sign("key", claims.clone()))
+                .await,
+            Err(Error::Expired)
+        ));
+
+        claims["exp"] = (now + 3600).into();
+        claims["nbf"] = (now + CLOCK_LEEWAY * 2).into();
+        assert!(matches!(
+            validator(&provider)
+                .validate(&provider.sign("key", claims))
+                .await,
+            Err(Error::Expired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn validate_unknown_key() {
+        let provider = Provider::new();
+
+        // the keys were just fetched, so they aren't fetched again
+        assert!(matches!(
+            validator(&provider)
+                .validate(&provider.sign("other", claims()))
+                .await,
+            Err(Error::KeyUnknown)
+        ));
+
+        // signed by another key under a known id
+        let token = Provider::new().sign("key", claims());
+        assert!(matches!(
+            validator(&provider).validate(&token).await,
+            Err(Error::SignatureInvalid)
+        ));
+    }
+}
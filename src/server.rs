@@ -7,236 +7,5166 @@
 //   https://opensource.org/licenses/MIT
 //
 use crate::{
-    db::File,
-    header::parse_single_range_header,
-    store::{FileData, Store},
+    access::{generate_key, is_valid_namespace, key_digest, ApiKeys, Client, Scope, UrlSigner},
+    compress::{compress_stream, is_compressible, parse_accept_encoding, Encoding},
+    dav::{multistatus, parse_depth, Depth, FileProps, Resource},
+    fetch::Fetcher,
+    header::{format_content_disposition, parse_basic_credentials, parse_bearer_token},
+    log::Timings,
+    oidc::OidcValidator,
+    s3::{parse_bucket, parse_object_key, Verified},
+    tls::{ClientCert, RemoteAddr},
+    transform::{Fit, ImageTransformer, OutputFormat, Transform, MAX_DIMENSION},
 };
-use bytes::Buf;
-use chrono::{DateTime, Utc};
-use futures::Stream;
-use http::StatusCode;
-use serde::Serialize;
+use bytes::{Buf, Bytes};
+use castella_core::{
+    db::{
+        Alias, AuditEvent, AuditQuery, Collection, Encryption, File, FileCursor, FileQuery,
+        FileSort, NewUser, User, DEFAULT_NAMESPACE,
+    },
+    header::{
+        format_hex, format_json_header, parse_content_range_header, parse_hex, parse_range_header,
+        parse_repr_digest, ByteRange,
+    },
+    metrics::Metrics,
+    rate_limit::{
+        ConcurrencyPermit, KeyedConcurrencyLimiter, KeyedRateLimiter, RateLimit, WaitQueue,
+    },
+    store::{
+        DriveHealth, DriveReport, ExpectedDigest, FileData, PrunePolicy, RangesData, Store,
+        UploadOptions,
+    },
+    stream::{throttle_stream, BandwidthLimiter},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::{Stream, StreamExt, TryStreamExt};
+use http::{header::HeaderName, HeaderMap, HeaderValue, Method, StatusCode};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use sha2::{Digest, Sha256};
-use std::{convert::Infallible, num::NonZeroU64, sync::Arc};
+use std::{
+    collections::BTreeSet,
+    convert::Infallible,
+    future::Future,
+    io::SeekFrom,
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroU64},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_util::io::ReaderStream;
 use warp::{
-    any, body, delete, filters::BoxedFilter, get, head, header, hyper, path, post, reject, reply,
-    Filter, Rejection, Reply,
+    addr, any, body, delete,
+    filters::BoxedFilter,
+    get, head, header,
+    hyper::{self, body::HttpBody},
+    method,
+    multipart::{self, FormData},
+    options, patch, path, post, put, query, reject, reply, Filter, Rejection, Reply,
 };
 
-#[derive(Debug, thiserror::Error)]
-pub enum Error {
-    #[error("{0}")]
-    Store(#[from] crate::store::Error),
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Store(#[from] castella_core::store::Error),
+
+    #[error("no such file")]
+    FileNotExists,
+
+    #[error("invalid metadata filter")]
+    MetadataInvalid,
+
+    #[error("invalid cursor")]
+    CursorInvalid,
+
+    #[error("invalid sha256 digest")]
+    DigestInvalid,
+
+    #[error("invalid form: {0}")]
+    FormInvalid(warp::Error),
+
+    #[error("form does not contain a non-empty file")]
+    FormFileMissing,
+
+    #[error("missing content-length header")]
+    LengthRequired,
+
+    #[error("content-range header does not match the request body")]
+    ContentRangeInvalid,
+
+    #[error("failed to read request body: {0}")]
+    Body(warp::Error),
+
+    #[error("request body is empty")]
+    BodyEmpty,
+
+    #[error("request body too large")]
+    BodyTooLarge,
+
+    #[error("failed to buffer request body: {0}")]
+    BodyBuffer(std::io::Error),
+
+    #[error("fetching is disabled")]
+    FetchDisabled,
+
+    #[error("too many concurrent downloads")]
+    TooManyDownloads,
+
+    #[error("signed urls are disabled")]
+    SigningDisabled,
+
+    #[error("file is private")]
+    FilePrivate,
+
+    #[error("only keys of the default namespace can use this endpoint")]
+    DefaultNamespaceRequired,
+
+    #[error("no such user")]
+    UserNotExists,
+
+    #[error("user name or token is taken")]
+    UserExists,
+
+    #[error("invalid user: {0}")]
+    UserInvalid(&'static str),
+
+    #[error("no such alias")]
+    AliasNotExists,
+
+    #[error("alias is taken by a file of another namespace")]
+    AliasTaken,
+
+    #[error("invalid alias: {0}")]
+    AliasInvalid(&'static str),
+
+    #[error("no such collection")]
+    CollectionNotExists,
+
+    #[error("collection name is taken")]
+    CollectionExists,
+
+    #[error("invalid collection: {0}")]
+    CollectionInvalid(&'static str),
+
+    #[error("failed to reload configuration: {0}")]
+    Reload(String),
+
+    #[error("{0}")]
+    Fetch(#[from] crate::fetch::Error),
+
+    #[error("{0}")]
+    S3(#[from] crate::s3::Error),
+
+    #[error("invalid bucket name")]
+    BucketInvalid,
+
+    #[error("invalid object key")]
+    ObjectKeyInvalid,
+
+    #[error("only object operations are supported")]
+    OperationUnsupported,
+
+    #[error("object metadata too large")]
+    ObjectMetadataTooLarge,
+
+    #[error("depth must be 0 or 1")]
+    DepthUnsupported,
+
+    #[error("image transformations are disabled")]
+    TransformDisabled,
+
+    #[error("invalid image transformation: {0}")]
+    TransformInvalid(&'static str),
+
+    #[error("file is not an image")]
+    ImageUnsupported,
+
+    #[error("image too large to transform")]
+    ImageTooLarge,
+
+    #[error("{0}")]
+    Transform(#[from] crate::transform::Error),
+}
+
+impl Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::Store(castella_core::store::Error::DownloadLimitExceeded) => StatusCode::GONE,
+            Error::Store(castella_core::store::Error::DigestMismatch(_)) => StatusCode::BAD_REQUEST,
+            Error::Store(castella_core::store::Error::FileSpooled) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::Store(castella_core::store::Error::QuotaExceeded(_)) => {
+                StatusCode::INSUFFICIENT_STORAGE
+            }
+            Error::Store(castella_core::store::Error::TransferAllowanceExceeded(_)) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Store(castella_core::store::Error::RangeNotSatisfiable(_)) => {
+                StatusCode::RANGE_NOT_SATISFIABLE
+            }
+            Error::Store(
+                castella_core::store::Error::ContentTypeNotAllowed(_)
+                | castella_core::store::Error::ContentTypeMismatch(..),
+            ) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::Store(
+                castella_core::store::Error::AppendOffsetMismatch(_)
+                | castella_core::store::Error::FileChanged,
+            ) => StatusCode::CONFLICT,
+            Error::Store(castella_core::store::Error::CollectionNotExists(_)) => {
+                StatusCode::NOT_FOUND
+            }
+            Error::Store(castella_core::store::Error::PrunePolicyEmpty) => StatusCode::BAD_REQUEST,
+            Error::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::FileNotExists => StatusCode::NOT_FOUND,
+            Error::FilePrivate => StatusCode::UNAUTHORIZED,
+            Error::DefaultNamespaceRequired => StatusCode::FORBIDDEN,
+            Error::UserNotExists => StatusCode::NOT_FOUND,
+            Error::UserExists => StatusCode::CONFLICT,
+            Error::UserInvalid(_) => StatusCode::BAD_REQUEST,
+            Error::AliasNotExists => StatusCode::NOT_FOUND,
+            Error::AliasTaken => StatusCode::CONFLICT,
+            Error::AliasInvalid(_) => StatusCode::BAD_REQUEST,
+            Error::CollectionNotExists => StatusCode::NOT_FOUND,
+            Error::CollectionExists => StatusCode::CONFLICT,
+            Error::CollectionInvalid(_) => StatusCode::BAD_REQUEST,
+            Error::Reload(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::MetadataInvalid | Error::CursorInvalid | Error::DigestInvalid => {
+                StatusCode::BAD_REQUEST
+            }
+            Error::FormInvalid(_) | Error::FormFileMissing => StatusCode::BAD_REQUEST,
+            Error::LengthRequired | Error::Body(_) | Error::BodyEmpty => StatusCode::BAD_REQUEST,
+            Error::ContentRangeInvalid => StatusCode::BAD_REQUEST,
+            Error::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::BodyBuffer(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::FetchDisabled | Error::SigningDisabled => StatusCode::NOT_FOUND,
+            Error::TooManyDownloads => StatusCode::TOO_MANY_REQUESTS,
+            Error::Fetch(crate::fetch::Error::UrlInvalid) => StatusCode::BAD_REQUEST,
+            Error::Fetch(crate::fetch::Error::AddressForbidden(_)) => StatusCode::FORBIDDEN,
+            Error::Fetch(crate::fetch::Error::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Fetch(crate::fetch::Error::ClientInit(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Fetch(_) => StatusCode::BAD_GATEWAY,
+            Error::S3(err) => err.status(),
+            Error::BucketInvalid | Error::ObjectKeyInvalid | Error::ObjectMetadataTooLarge => {
+                StatusCode::BAD_REQUEST
+            }
+            Error::OperationUnsupported => StatusCode::NOT_IMPLEMENTED,
+            Error::DepthUnsupported => StatusCode::FORBIDDEN,
+            Error::TransformDisabled => StatusCode::NOT_FOUND,
+            Error::TransformInvalid(_) => StatusCode::BAD_REQUEST,
+            Error::ImageUnsupported => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::ImageTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Transform(crate::transform::Error::CropInvalid) => StatusCode::BAD_REQUEST,
+            Error::Transform(crate::transform::Error::Decode(_)) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::Transform(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Error code of the S3 api.
+    fn s3_code(&self) -> &'static str {
+        match self {
+            Error::S3(err) => err.code(),
+            Error::Store(castella_core::store::Error::DigestMismatch(_)) => "BadDigest",
+            Error::BucketInvalid => "InvalidBucketName",
+            Error::ObjectKeyInvalid => "KeyTooLongError",
+            Error::ObjectMetadataTooLarge => "MetadataTooLarge",
+            err => crate::s3::status_code(err.status()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct GetFileQuery {
+    /// Serve the file with an inline content disposition.
+    inline: Option<String>,
+    /// Width to which an image is resized.
+    w: Option<u32>,
+    /// Height to which an image is resized.
+    h: Option<u32>,
+    fit: Option<Fit>,
+    /// Region to which an image is cropped, given as `x,y,width,height`.
+    crop: Option<String>,
+    /// Format to which an image is converted.
+    format: Option<OutputFormat>,
+    /// Quality of an image converted to a lossy format.
+    q: Option<u8>,
+}
+
+impl GetFileQuery {
+    /// Returns the transformation requested for an image, or `None` if the file is downloaded as is.
+    fn transform(&self) -> Result<Option<Transform>, Error> {
+        if self.w.is_none()
+            && self.h.is_none()
+            && self.fit.is_none()
+            && self.crop.is_none()
+            && self.format.is_none()
+            && self.q.is_none()
+        {
+            return Ok(None);
+        }
+
+        let dimension = 1..=MAX_DIMENSION;
+
+        if !self.w.iter().chain(&self.h).all(|n| dimension.contains(n)) {
+            return Err(Error::TransformInvalid(
+                "width and height must be between 1 and 8192",
+            ));
+        }
+
+        if self.q.is_some_and(|q| !(1..=100).contains(&q)) {
+            return Err(Error::TransformInvalid("quality must be between 1 and 100"));
+        }
+
+        let crop = match self.crop {
+            Some(ref crop) => Some(crop.parse().map_err(|_| {
+                Error::TransformInvalid("crop must be given as 'x,y,width,height'")
+            })?),
+            None => None,
+        };
+
+        Ok(Some(Transform {
+            crop,
+            width: self.w,
+            height: self.h,
+            fit: self.fit.unwrap_or_default(),
+            format: self.format,
+            quality: self.q,
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadFileQuery {
+    filename: Option<String>,
+    encrypt: Option<EncryptQuery>,
+    public: Option<bool>,
+    collection: Option<String>,
+}
+
+/// Encryption requested for an upload.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EncryptQuery {
+    True,
+    /// Store the content without encryption.
+    False,
+    /// Store the content as is, as it was already encrypted by the client.
+    Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct FetchRequest {
+    /// Url of the content to store.
+    url: String,
+    /// Overrides the filename taken from the url.
+    filename: Option<String>,
+    /// Overrides the content type reported by the source.
+    content_type: Option<String>,
+}
+
+/// Maximum size of the JSON body of a fetch request.
+const MAX_FETCH_REQUEST_SIZE: u64 = 16 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct UpdateFileRequest {
+    /// Whether the file can be downloaded without credentials, or null to follow the server default.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    public: Option<Option<bool>>,
+}
+
+/// Maximum size of the JSON body of an update request.
+const MAX_UPDATE_REQUEST_SIZE: u64 = 4096;
+
+#[derive(Debug, Deserialize)]
+struct SetAliasRequest {
+    /// Key of the file to point the alias to.
+    key: i64,
+}
+
+/// Maximum length of an alias in bytes.
+const MAX_ALIAS_LEN: usize = 256;
+
+/// Deserializes a present value as `Some`, so that null can be told apart from a missing field.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Deserialize)]
+struct ListFilesQuery {
+    /// JSON value that the metadata of listed files must contain.
+    metadata: Option<String>,
+    before: Option<i64>,
+    limit: Option<u32>,
+    /// Content type of listed files, or its prefix if it ends with '/'.
+    content_type: Option<String>,
+    /// Prefix of the filename of listed files, ignoring case.
+    filename: Option<String>,
+    /// Comma-separated tags that the metadata of listed files must list in `tags`.
+    tags: Option<String>,
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    accessed_after: Option<DateTime<Utc>>,
+    accessed_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    sort: FileSort,
+    #[serde(default)]
+    ascending: bool,
+    /// Cursor returned with the previous page.
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddUserRequest {
+    /// Unique name of the person or application.
+    name: String,
+    role: Scope,
+    /// Namespace of the files that the user can access, or the default namespace if unspecified.
+    namespace: Option<String>,
+}
+
+/// Maximum length of a user name.
+const MAX_USER_NAME_LEN: usize = 64;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MaintenanceInfo {
+    /// Whether requests that modify files are rejected.
+    enabled: bool,
+}
+
+/// Criteria of the files to delete, which must match all of the given criteria.
+#[derive(Debug, Deserialize)]
+struct PruneRequest {
+    /// Files created more than this many days ago.
+    older_than: Option<u32>,
+    /// Files not downloaded for this many days.
+    not_accessed_for: Option<u32>,
+    /// Files larger than this many bytes.
+    larger_than: Option<u64>,
+    /// Files with this content type, or with this prefix if it ends with '/'.
+    content_type: Option<String>,
+    /// Only report the files that would be deleted.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddCollectionRequest {
+    /// Name of the collection, unique in the namespace of the client.
+    name: String,
+}
+
+/// Maximum length of a collection name.
+const MAX_COLLECTION_NAME_LEN: usize = 64;
+
+#[derive(Debug, Deserialize)]
+struct SignedUrlQuery {
+    /// Unix timestamp after which the signature is no longer valid.
+    expires: Option<i64>,
+    sig: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SignUrlQuery {
+    /// Number of seconds for which the signed url is valid.
+    ttl: Option<u64>,
+}
+
+/// Lifetime of a signed url if the client doesn't specify it.
+const DEFAULT_SIGNED_URL_TTL: u64 = 60 * 60;
+
+/// Maximum lifetime of a signed url.
+const MAX_SIGNED_URL_TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+struct FileStatsQuery {
+    /// List the least downloaded files first.
+    #[serde(default)]
+    ascending: bool,
+    limit: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct ServerConfig {
+    pub store: Arc<Store>,
+    /// Settings that are reloaded while the server is running.
+    pub settings: Arc<Settings>,
+    /// Maximum size of a `multipart/form-data` upload, which is buffered in memory.
+    pub max_form_upload_size: u64,
+    /// Directory in which upload bodies without a content length are buffered to learn their size,
+    /// or `None` to reject such uploads.
+    pub upload_buffer_path: Option<PathBuf>,
+    /// Client used to fetch content from urls, or `None` to disable fetching.
+    pub fetcher: Option<Arc<Fetcher>>,
+    /// Transformer of images downloaded with transformation parameters, or `None` to disable transformations.
+    pub images: Option<ImageTransformer>,
+    /// Headers added to all responses, replacing those set by the handlers.
+    pub response_headers: HeaderMap,
+    /// Maximum number of files that each client can download concurrently.
+    pub client_max_downloads: Option<usize>,
+    /// Maximum number of uploads handled concurrently, or `None` for no limit.
+    pub max_uploads: Option<usize>,
+    /// Duration for which uploads over the limit wait for another upload to complete before they're rejected.
+    pub upload_queue_timeout: Duration,
+    /// Number of requests waiting for a database connection, the Drive API request limit or an upload slot
+    /// beyond which further requests are rejected right away, or `None` to let them wait.
+    pub shed_threshold: Option<usize>,
+    /// Bandwidth limit of each download in MiB.
+    pub download_limit: Option<RateLimit>,
+    /// Bandwidth limit shared by all downloads in MiB.
+    pub global_download_limit: Option<RateLimit>,
+    /// Validator of tokens issued by an OpenID Connect provider that are accepted like API keys,
+    /// or `None` to accept only API keys and user tokens.
+    pub oidc: Option<OidcValidator>,
+    /// Require an API key with the read scope to download files that aren't explicitly public.
+    pub authenticate_reads: bool,
+    /// Signer of urls that authorize downloading a file without an API key, or `None` to disable signed urls.
+    pub url_signer: Option<UrlSigner>,
+    /// Metrics to which request counts, durations and response sizes are recorded.
+    pub metrics: Metrics,
+    /// Names of the optional features that are enabled, reported by `GET /version`.
+    pub features: Vec<&'static str>,
+    /// Duration beyond which requests are logged as slow with the time spent in the database, Drive and encryption,
+    /// or `None` to not log slow requests.
+    pub slow_request_threshold: Option<Duration>,
+    /// Add a `Server-Timing` header with the time spent in the database, Drive and encryption to all responses.
+    pub server_timing: bool,
+    /// Compress downloads of compressible content types with gzip or brotli if the client accepts it.
+    pub compression: bool,
+    /// Start in maintenance mode, in which requests that modify files are rejected until it is turned off
+    /// through `PUT /admin/maintenance`.
+    pub maintenance: bool,
+    /// Channel through which `POST /admin/reload` requests reloading the settings.
+    pub reload: mpsc::Sender<ReloadRequest>,
+}
+
+/// Settings of the server that are reloaded while it is running, without affecting requests in progress.
+#[derive(Debug)]
+pub struct ServerSettings {
+    /// Maximum body size of a single upload request in bytes.
+    pub max_upload_size: u64,
+    /// Rate limit of GET, HEAD and OPTIONS requests of each client.
+    pub client_read_limit: Option<RateLimit>,
+    /// Rate limit of requests of each client with any other method.
+    pub client_write_limit: Option<RateLimit>,
+    /// Keys that authorize clients to download, or upload, modify and delete files and use admin endpoints.
+    /// Tokens of users are accepted in addition to these keys.
+    pub api_keys: ApiKeys,
+    /// Credentials given as `user:password` that authorize clients like the API keys
+    /// through HTTP basic authentication.
+    pub basic_auth: ApiKeys,
+    /// Clients authorized by the subjects of their certificates.
+    pub cert_clients: ApiKeys,
+    /// Credentials with which clients of the S3-compatible api sign their requests.
+    pub s3_credentials: crate::s3::Credentials,
+}
+
+impl ServerSettings {
+    /// Returns whether any keys, basic credentials, certificates or S3 credentials authorize clients.
+    pub fn has_credentials(&self) -> bool {
+        !self.api_keys.is_empty()
+            || !self.basic_auth.is_empty()
+            || !self.cert_clients.is_empty()
+            || !self.s3_credentials.is_empty()
+    }
+}
+
+/// Current settings of the server, which requests read when they start.
+#[derive(Debug)]
+pub struct Settings {
+    current: RwLock<Arc<ActiveSettings>>,
+}
+
+#[derive(Debug)]
+struct ActiveSettings {
+    settings: ServerSettings,
+    read_limiter: Option<Arc<KeyedRateLimiter<IpAddr>>>,
+    write_limiter: Option<Arc<KeyedRateLimiter<IpAddr>>>,
+}
+
+impl Settings {
+    pub fn new(settings: ServerSettings) -> Self {
+        let current = ActiveSettings {
+            read_limiter: settings
+                .client_read_limit
+                .map(|limit| Arc::new(KeyedRateLimiter::new(limit))),
+            write_limiter: settings
+                .client_write_limit
+                .map(|limit| Arc::new(KeyedRateLimiter::new(limit))),
+            settings,
+        };
+
+        Self {
+            current: RwLock::new(Arc::new(current)),
+        }
+    }
+
+    /// Replaces the settings. Rate limiters whose limit didn't change keep the counts of clients.
+    pub fn replace(&self, settings: ServerSettings) {
+        fn limiter(
+            limit: Option<RateLimit>,
+            previous: Option<RateLimit>,
+            limiter: &Option<Arc<KeyedRateLimiter<IpAddr>>>,
+        ) -> Option<Arc<KeyedRateLimiter<IpAddr>>> {
+            if limit == previous {
+                limiter.clone()
+            } else {
+                limit.map(|limit| Arc::new(KeyedRateLimiter::new(limit)))
+            }
+        }
+
+        let mut current = self.current.write().unwrap();
+
+        *current = Arc::new(ActiveSettings {
+            read_limiter: limiter(
+                settings.client_read_limit,
+                current.settings.client_read_limit,
+                &current.read_limiter,
+            ),
+            write_limiter: limiter(
+                settings.client_write_limit,
+                current.settings.client_write_limit,
+                &current.write_limiter,
+            ),
+            settings,
+        });
+    }
+
+    fn current(&self) -> Arc<ActiveSettings> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// Request to reload the settings of the server, answered with an error message if reloading failed.
+pub type ReloadRequest = oneshot::Sender<Result<(), String>>;
+
+/// Rejection of a request that requires an API key without a valid one.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl reject::Reject for Unauthorized {}
+
+/// Rejection of a request with an API key that doesn't grant the required scope.
+#[derive(Debug)]
+struct Forbidden;
+
+impl reject::Reject for Forbidden {}
+
+/// Access of a client to files that aren't public.
+#[derive(Debug, Clone)]
+struct ReadAccess {
+    /// Namespace of the API key presented by the client, if any.
+    namespace: Option<Arc<str>>,
+    /// Client presented a signed url for the file.
+    signed: bool,
+    /// Files are public unless they are explicitly private.
+    public_by_default: bool,
+}
+
+impl ReadAccess {
+    fn check(&self, file: &File) -> Result<(), Error> {
+        if self.signed
+            || self.namespace.as_deref() == Some(file.namespace.as_str())
+            || file.public.unwrap_or(self.public_by_default)
+        {
+            Ok(())
+        } else {
+            Err(Error::FilePrivate)
+        }
+    }
+
+    /// Checks access to a file by its key, only looking it up if the url isn't signed.
+    async fn check_key(&self, store: &Store, key: i64) -> Result<(), Error> {
+        if !self.signed {
+            self.check(&store.get_info(key).await?.ok_or(Error::FileNotExists)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Rejection of a request whose API key couldn't be checked against the users.
+#[derive(Debug)]
+struct AuthenticationUnavailable;
+
+impl reject::Reject for AuthenticationUnavailable {}
+
+/// Rejection of a request with an invalid or expired url signature.
+#[derive(Debug)]
+struct SignatureInvalid;
+
+impl reject::Reject for SignatureInvalid {}
+
+/// Rejection of a request whose client exceeded its rate limit.
+#[derive(Debug)]
+struct RateLimited(Duration);
+
+impl reject::Reject for RateLimited {}
+
+/// Rejection of an upload whose body exceeds the maximum upload size.
+#[derive(Debug)]
+struct UploadTooLarge;
+
+impl reject::Reject for UploadTooLarge {}
+
+/// Rejection of an upload that found no free slot within the upload queue timeout.
+#[derive(Debug)]
+struct TooManyUploads;
+
+impl reject::Reject for TooManyUploads {}
+
+/// Duration after which clients should retry uploads rejected because of too many concurrent uploads.
+const UPLOAD_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Rejection of a request that would wait for a resource that is saturated,
+/// with the duration after which the client should retry.
+#[derive(Debug)]
+struct Overloaded(Duration);
+
+impl reject::Reject for Overloaded {}
+
+/// Maximum duration after which clients should retry requests rejected because the server is overloaded.
+const MAX_OVERLOAD_RETRY_AFTER: Duration = Duration::from_secs(120);
+
+/// Rejection of a request that modifies files while the server is in maintenance mode.
+#[derive(Debug)]
+struct UnderMaintenance;
+
+impl reject::Reject for UnderMaintenance {}
+
+/// Duration after which clients should retry requests rejected during maintenance.
+const MAINTENANCE_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+pub fn routes(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
+    let ServerConfig {
+        store,
+        settings,
+        max_form_upload_size,
+        upload_buffer_path,
+        fetcher,
+        images,
+        response_headers,
+        client_max_downloads,
+        max_uploads,
+        upload_queue_timeout,
+        shed_threshold,
+        download_limit,
+        global_download_limit,
+        oidc,
+        authenticate_reads,
+        url_signer,
+        metrics,
+        features,
+        slow_request_threshold,
+        server_timing,
+        compression,
+        maintenance,
+        reload,
+    } = config;
+
+    let cipher = store.cipher().name();
+    let url_signer = url_signer.map(Arc::new);
+    let response_headers = Arc::new(response_headers);
+    let upload_buffer_path = upload_buffer_path.map(Arc::new);
+    let download_limiter =
+        client_max_downloads.map(|limit| Arc::new(KeyedConcurrencyLimiter::new(limit)));
+
+    let store = any().map(move || store.clone());
+    let max_upload_size = {
+        let settings = settings.clone();
+        any().map(move || settings.current().settings.max_upload_size)
+    };
+    let download_limiter = any().map(move || download_limiter.clone());
+    let throttle = Arc::new(Throttle {
+        global: global_download_limit
+            .map(|limit| Arc::new(BandwidthLimiter::new(limit, 1024 * 1024))),
+        download: download_limit,
+    });
+    let throttle = any().map(move || throttle.clone());
+    let images = images.map(Arc::new);
+    let images = any().map(move || images.clone());
+    let upload_buffer_path = any().map(move || upload_buffer_path.clone());
+    let upload_slot = upload_slot(max_uploads.map(|limit| {
+        Arc::new(UploadLimiter {
+            permits: Arc::new(Semaphore::new(limit)),
+            queue: WaitQueue::default(),
+            queue_timeout: upload_queue_timeout,
+            shed_threshold,
+        })
+    }));
+
+    // encoding of downloads, which are never compressed when a range is requested
+    let encoding = any()
+        .map(move || compression)
+        .and(header::optional::<String>("accept-encoding"))
+        .and(header::optional::<String>("range"))
+        .map(
+            |enabled: bool, accept: Option<String>, range: Option<String>| {
+                enabled.then(|| match (accept, range) {
+                    (Some(accept), None) => parse_accept_encoding(&accept),
+                    _ => Encoding::Identity,
+                })
+            },
+        );
+
+    let client = client(settings.clone(), oidc.map(Arc::new), store.clone().boxed());
+    let authorize_admin = require_scope(client.clone(), Scope::Admin);
+
+    // requests that modify files are rejected during maintenance, after checking that they're authorized
+    let maintenance = Arc::new(AtomicBool::new(maintenance));
+    let authorize_write = require_scope(client.clone(), Scope::Write)
+        .and(reject_in_maintenance(maintenance.clone()))
+        .boxed();
+    let authorize_admin_write = require_scope(client.clone(), Scope::Admin)
+        .and(reject_in_maintenance(maintenance.clone()))
+        .boxed();
+    let s3_write = reject_in_maintenance(maintenance.clone());
+    let maintenance = any().map(move || maintenance.clone());
+    let authorize_read = require_scope(client.clone(), Scope::Read);
+
+    let public_by_default = !authenticate_reads;
+    let read_access = client
+        .map(move |client: Option<Client>| ReadAccess {
+            namespace: client.map(|client| client.namespace),
+            signed: false,
+            public_by_default,
+        })
+        .boxed();
+
+    // file key of a url signed for downloading it, or of any client along with its access
+    let file_key_read = signed_file_key(url_signer.clone())
+        .map(move |key| {
+            let access = ReadAccess {
+                namespace: None,
+                signed: true,
+                public_by_default,
+            };
+
+            (key, access)
+        })
+        .untuple_one()
+        .or(path!(i64).and(read_access.clone()))
+        .unify()
+        .boxed();
+
+    let get_root = get().and(path!()).map(get_root).boxed();
+
+    // GET /healthz
+    let get_health = get()
+        .and(path!("healthz"))
+        .and(store.clone())
+        .then(get_health)
+        .boxed();
+
+    // GET /version
+    let get_version = {
+        let info = Arc::new(VersionInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: option_env!("CS_BUILD_COMMIT"),
+            build_time: env!("CS_BUILD_TIME")
+                .parse()
+                .ok()
+                .and_then(|time| NaiveDateTime::from_timestamp_opt(time, 0))
+                .map(|time| DateTime::from_utc(time, Utc)),
+            cipher,
+            features,
+        });
+
+        get()
+            .and(path!("version"))
+            .map(move || reply::json(&*info))
+            .boxed()
+    };
+
+    // GET /livez
+    let get_liveness = get().and(path!("livez")).map(get_liveness).boxed();
+
+    // GET /readyz
+    let get_readiness = get()
+        .and(path!("readyz"))
+        .and(store.clone())
+        .then(get_readiness)
+        .boxed();
+
+    // HEAD /$id
+    let head_file = head()
+        .and(file_key_read.clone())
+        .and(store.clone())
+        .then(head_file)
+        .map(handle_result)
+        .boxed();
+
+    // GET /$id/info
+    let get_file_info = get()
+        .and(path!(i64 / "info"))
+        .and(read_access.clone())
+        .and(store.clone())
+        .then(get_file_info)
+        .map(handle_result)
+        .boxed();
+
+    // GET /$id/status
+    let get_upload_status = get()
+        .and(path!(i64 / "status"))
+        .and(read_access.clone())
+        .and(store.clone())
+        .then(get_upload_status)
+        .map(handle_result)
+        .boxed();
+
+    // GET /by-hash/$sha256
+    let get_file_by_hash = get()
+        .and(path!("by-hash" / String))
+        .and(read_access.clone())
+        .and(store.clone())
+        .then(get_file_by_hash)
+        .map(handle_result)
+        .boxed();
+
+    // GET /$id
+    let get_file = get()
+        .and(file_key_read)
+        .and(store.clone())
+        .and(peer())
+        .and(download_limiter.clone())
+        .and(images.clone())
+        .and(header::optional("range"))
+        .and(header::optional("if-none-match"))
+        .and(query())
+        .then(get_file)
+        .map(handle_result)
+        .and(encoding)
+        .map(compress_response)
+        .and(throttle.clone())
+        .map(throttle_response)
+        .boxed();
+
+    // GET /alias/$name
+    let get_alias_file = get()
+        .and(path("alias"))
+        .and(path::tail())
+        .and(read_access.clone())
+        .and(store.clone())
+        .and(peer())
+        .and(download_limiter.clone())
+        .and(images)
+        .and(header::optional("range"))
+        .and(header::optional("if-none-match"))
+        .and(query())
+        .then(get_alias_file)
+        .map(handle_result)
+        .and(encoding)
+        .map(compress_response)
+        .and(throttle.clone())
+        .map(throttle_response)
+        .boxed();
+
+    // HEAD /alias/$name
+    let head_alias_file = head()
+        .and(path("alias"))
+        .and(path::tail())
+        .and(read_access.clone())
+        .and(store.clone())
+        .then(head_alias_file)
+        .map(handle_result)
+        .boxed();
+
+    // POST /
+    let upload_file = post()
+        .and(path!())
+        .and(authorize_write.clone())
+        .and(form_body(false))
+        .and(upload_size_limit(settings.clone()))
+        .and(store.clone())
+        .and(peer())
+        .and(header("content-length"))
+        .and(upload_options())
+        .and(upload_slot.clone())
+        .and(body::stream())
+        .then(upload_file)
+        .map(handle_result)
+        .boxed();
+
+    // POST / (without content-length)
+    let upload_buffered = post()
+        .and(path!())
+        .and(authorize_write.clone())
+        .and(form_body(false))
+        .and(
+            header::optional::<String>("content-length")
+                .and_then(|length: Option<String>| async move {
+                    match length {
+                        Some(_) => Err(reject::not_found()),
+                        None => Ok(()),
+                    }
+                })
+                .untuple_one(),
+        )
+        .and(store.clone())
+        .and(peer())
+        .and(upload_buffer_path.clone())
+        .and(max_upload_size.clone())
+        .and(upload_options())
+        .and(upload_slot.clone())
+        .and(body::stream())
+        .then(upload_buffered)
+        .map(handle_result)
+        .boxed();
+
+    // POST / (multipart/form-data)
+    let upload_form = post()
+        .and(path!())
+        .and(authorize_write.clone())
+        .and(form_body(true))
+        .and(store.clone())
+        .and(peer())
+        .and(upload_options())
+        .and(upload_slot.clone())
+        .and(multipart::form().max_length(max_form_upload_size))
+        .then(upload_form)
+        .map(handle_result)
+        .boxed();
+
+    // POST /batch
+    let upload_batch = post()
+        .and(path!("batch"))
+        .and(authorize_write.clone())
+        .and(store.clone())
+        .and(peer())
+        .and(upload_options())
+        .and(upload_slot.clone())
+        .and(multipart::form().max_length(max_form_upload_size))
+        .then(upload_batch)
+        .map(handle_result)
+        .boxed();
+
+    // POST /fetch
+    let fetch_file = post()
+        .and(path!("fetch"))
+        .and(authorize_write.clone())
+        .and(body::content_length_limit(MAX_FETCH_REQUEST_SIZE))
+        .and(store.clone())
+        .and(peer())
+        .and(any().map(move || fetcher.clone()))
+        .and(upload_options())
+        .and(upload_slot.clone())
+        .and(body::json())
+        .then(fetch_file)
+        .map(handle_result)
+        .boxed();
+
+    // PUT /$id
+    let replace_file = put()
+        .and(path!(i64))
+        .and(authorize_write.clone())
+        .and(upload_size_limit(settings.clone()))
+        .and(store.clone())
+        .and(peer())
+        .and(header("content-length"))
+        .and(upload_options())
+        .and(upload_slot.clone())
+        .and(body::stream())
+        .then(replace_file)
+        .map(handle_result)
+        .boxed();
+
+    // PATCH /$id
+    let append_file = patch()
+        .and(path!(i64))
+        .and(authorize_write.clone())
+        .and(upload_size_limit(settings.clone()))
+        .and(store.clone())
+        .and(peer())
+        .and(header("content-length"))
+        .and(header("content-range"))
+        .and(upload_slot.clone())
+        .and(body::stream())
+        .then(append_file)
+        .map(handle_result)
+        .boxed();
+
+    // PATCH /$id (application/json)
+    let update_file = patch()
+        .and(path!(i64))
+        .and(authorize_write.clone())
+        .and(body::content_length_limit(MAX_UPDATE_REQUEST_SIZE))
+        .and(store.clone())
+        .and(peer())
+        .and(body::json())
+        .then(update_file)
+        .map(handle_result)
+        .boxed();
+
+    // DELETE /$id
+    let delete_file = delete()
+        .and(path!(i64))
+        .and(authorize_write.clone())
+        .and(store.clone())
+        .and(peer())
+        .then(delete_file)
+        .map(handle_result)
+        .boxed();
+
+    // POST /$id/verify
+    let verify_file = post()
+        .and(path!(i64 / "verify"))
+        .and(authorize_write.clone())
+        .and(store.clone())
+        .and(peer())
+        .then(verify_file)
+        .map(handle_result)
+        .boxed();
+
+    // POST /$id/sign
+    let sign_url = post()
+        .and(path!(i64 / "sign"))
+        .and(authorize_read.clone())
+        .and(store.clone())
+        .and(any().map(move || url_signer.clone()))
+        .and(query())
+        .then(sign_url)
+        .map(handle_result)
+        .boxed();
+
+    // PUT /alias/$name
+    let set_alias = put()
+        .and(path("alias"))
+        .and(path::tail())
+        .and(authorize_write.clone())
+        .and(body::content_length_limit(MAX_UPDATE_REQUEST_SIZE))
+        .and(store.clone())
+        .and(peer())
+        .and(body::json())
+        .then(set_alias)
+        .map(handle_result)
+        .boxed();
+
+    // DELETE /alias/$name
+    let delete_alias = delete()
+        .and(path("alias"))
+        .and(path::tail())
+        .and(authorize_write.clone())
+        .and(store.clone())
+        .and(peer())
+        .then(delete_alias)
+        .map(handle_result)
+        .boxed();
+
+    // requests of the S3-compatible api are authorized by their signature instead of an api key
+    let s3_client = s3_client(settings.clone());
+
+    // GET /s3/$bucket/$key
+    let s3_get_object = get()
+        .and(path!("s3" / String / ..))
+        .and(path::tail())
+        .and(s3_client.clone())
+        .and(store.clone())
+        .and(peer())
+        .and(download_limiter.clone())
+        .and(header::optional("range"))
+        .and(header::optional("if-none-match"))
+        .then(s3_get_object)
+        .map(handle_s3_result)
+        .and(throttle.clone())
+        .map(throttle_response)
+        .boxed();
+
+    // HEAD /s3/$bucket/$key
+    let s3_head_object = head()
+        .and(path!("s3" / String / ..))
+        .and(path::tail())
+        .and(s3_client.clone())
+        .and(store.clone())
+        .then(s3_head_object)
+        .map(handle_s3_result)
+        .boxed();
+
+    // PUT /s3/$bucket/$key
+    let s3_put_object = put()
+        .and(path!("s3" / String / ..))
+        .and(path::tail())
+        .and(s3_client.clone())
+        .and(s3_write.clone())
+        .and(max_upload_size.clone())
+        .and(store.clone())
+        .and(peer())
+        .and(header::optional("content-length"))
+        .and(header::headers_cloned())
+        .and(upload_slot.clone())
+        .and(body::stream())
+        .then(s3_put_object)
+        .map(handle_s3_result)
+        .boxed();
+
+    // DELETE /s3/$bucket/$key
+    let s3_delete_object = delete()
+        .and(path!("s3" / String / ..))
+        .and(path::tail())
+        .and(s3_client)
+        .and(s3_write)
+        .and(store.clone())
+        .and(peer())
+        .then(s3_delete_object)
+        .map(handle_s3_result)
+        .boxed();
+
+    // PROPFIND /dav/$path
+    let dav_propfind = method_named("PROPFIND")
+        .and(path("dav"))
+        .and(path::tail())
+        .and(authorize_read.clone())
+        .and(store.clone())
+        .and(header::optional("depth"))
+        .then(dav_propfind)
+        .map(handle_result)
+        .boxed();
+
+    // GET /dav/$path
+    let dav_get_file = get()
+        .and(path("dav"))
+        .and(path::tail())
+        .and(authorize_read.clone())
+        .and(store.clone())
+        .and(peer())
+        .and(download_limiter)
+        .and(header::optional("range"))
+        .and(header::optional("if-none-match"))
+        .then(dav_get_file)
+        .map(handle_result)
+        .and(encoding)
+        .map(compress_response)
+        .and(throttle.clone())
+        .map(throttle_response)
+        .boxed();
+
+    // HEAD /dav/$path
+    let dav_head_file = head()
+        .and(path("dav"))
+        .and(path::tail())
+        .and(authorize_read.clone())
+        .and(store.clone())
+        .then(dav_head_file)
+        .map(handle_result)
+        .boxed();
+
+    // PUT /dav/$path
+    let dav_put_file = put()
+        .and(path("dav"))
+        .and(path::tail())
+        .and(authorize_write.clone())
+        .and(max_upload_size.clone())
+        .and(store.clone())
+        .and(peer())
+        .and(upload_buffer_path)
+        .and(header::optional("content-length"))
+        .and(header::optional("content-type"))
+        .and(upload_slot.clone())
+        .and(body::stream())
+        .then(dav_put_file)
+        .map(handle_result)
+        .boxed();
+
+    // DELETE /dav/$path
+    let dav_delete = delete()
+        .and(path("dav"))
+        .and(path::tail())
+        .and(authorize_write.clone())
+        .and(store.clone())
+        .and(peer())
+        .then(dav_delete)
+        .map(handle_result)
+        .boxed();
+
+    // GET /search
+    let search_files = get()
+        .and(path!("search"))
+        .and(authorize_read.clone())
+        .and(store.clone())
+        .and(query())
+        .then(search_files)
+        .map(handle_result)
+        .boxed();
+
+    // GET /collections
+    let list_collections = get()
+        .and(path!("collections"))
+        .and(authorize_read.clone())
+        .and(store.clone())
+        .then(list_collections)
+        .map(handle_result)
+        .boxed();
+
+    // POST /collections
+    let add_collection = post()
+        .and(path!("collections"))
+        .and(authorize_write.clone())
+        .and(body::content_length_limit(MAX_UPDATE_REQUEST_SIZE))
+        .and(store.clone())
+        .and(body::json())
+        .then(add_collection)
+        .map(handle_result)
+        .boxed();
+
+    // GET /collections/$name
+    let list_collection_files = get()
+        .and(path!("collections" / String))
+        .and(authorize_read)
+        .and(store.clone())
+        .and(query())
+        .then(list_collection_files)
+        .map(handle_result)
+        .boxed();
+
+    // DELETE /collections/$name
+    let delete_collection = delete()
+        .and(path!("collections" / String))
+        .and(authorize_write.clone())
+        .and(store.clone())
+        .then(delete_collection)
+        .map(handle_result)
+        .boxed();
+
+    // GET /admin/files
+    let list_files = get()
+        .and(path!("admin" / "files"))
+        .and(authorize_admin.clone())
+        .and(store.clone())
+        .and(query())
+        .then(list_files)
+        .map(handle_result)
+        .boxed();
+
+    // GET /admin/drives
+    let list_drives = get()
+        .and(path!("admin" / "drives"))
+        .and(authorize_admin.clone())
+        .and(store.clone())
+        .then(list_drives)
+        .map(handle_result)
+        .boxed();
+
+    // GET /admin/audit
+    let get_audit_log = get()
+        .and(path!("admin" / "audit"))
+        .and(authorize_admin.clone())
+        .and(store.clone())
+        .and(query())
+        .then(get_audit_log)
+        .map(handle_result)
+        .boxed();
+
+    // GET /admin/stats
+    let get_storage_stats = get()
+        .and(path!("admin" / "stats"))
+        .and(authorize_admin.clone())
+        .and(store.clone())
+        .then(get_storage_stats)
+        .map(handle_result)
+        .boxed();
+
+    // GET /admin/stats/downloads
+    let get_file_stats = get()
+        .and(path!("admin" / "stats" / "downloads"))
+        .and(authorize_admin.clone())
+        .and(store.clone())
+        .and(query())
+        .then(get_file_stats)
+        .map(handle_result)
+        .boxed();
+
+    // GET /admin/users
+    let list_users = get()
+        .and(path!("admin" / "users"))
+        .and(authorize_admin.clone())
+        .and(store.clone())
+        .then(list_users)
+        .map(handle_result)
+        .boxed();
+
+    // POST /admin/users
+    let add_user = post()
+        .and(path!("admin" / "users"))
+        .and(authorize_admin.clone())
+        .and(body::content_length_limit(MAX_UPDATE_REQUEST_SIZE))
+        .and(store.clone())
+        .and(body::json())
+        .then(add_user)
+        .map(handle_result)
+        .boxed();
+
+    // POST /admin/users/$id/token
+    let reset_user_token = post()
+        .and(path!("admin" / "users" / i32 / "token"))
+        .and(authorize_admin.clone())
+        .and(store.clone())
+        .then(reset_user_token)
+        .map(handle_result)
+        .boxed();
+
+    // DELETE /admin/users/$id
+    let delete_user = delete()
+        .and(path!("admin" / "users" / i32))
+        .and(authorize_admin.clone())
+        .and(store.clone())
+        .then(delete_user)
+        .map(handle_result)
+        .boxed();
+
+    // GET /admin/maintenance
+    let get_maintenance = get()
+        .and(path!("admin" / "maintenance"))
+        .and(authorize_admin.clone())
+        .and(maintenance.clone())
+        .then(get_maintenance)
+        .map(handle_result)
+        .boxed();
+
+    // PUT /admin/maintenance
+    let set_maintenance = put()
+        .and(path!("admin" / "maintenance"))
+        .and(authorize_admin.clone())
+        .and(body::content_length_limit(MAX_UPDATE_REQUEST_SIZE))
+        .and(maintenance)
+        .and(body::json())
+        .then(set_maintenance)
+        .map(handle_result)
+        .boxed();
+
+    // POST /admin/reload
+    let reload_settings = post()
+        .and(path!("admin" / "reload"))
+        .and(authorize_admin.clone())
+        .and(any().map(move || reload.clone()))
+        .then(reload_settings)
+        .map(handle_result)
+        .boxed();
+
+    // POST /admin/prune
+    let prune_files = post()
+        .and(path!("admin" / "prune"))
+        .and(authorize_admin_write)
+        .and(body::content_length_limit(MAX_UPDATE_REQUEST_SIZE))
+        .and(store.clone())
+        .and(peer())
+        .and(body::json())
+        .then(prune_files)
+        .map(handle_result)
+        .boxed();
+
+    // OPTIONS /*
+    let get_options = options().and(path::full()).and_then(get_options).boxed();
+
+    // any method not allowed on a known path
+    let method_not_allowed = method()
+        .and(path::full())
+        .and_then(method_not_allowed)
+        .boxed();
+
+    let client_limit = client_limit(settings.clone());
+    let shed_load = shed_load(store.clone().boxed(), shed_threshold);
+
+    // grouped and boxed to bound the nesting of the route types
+    let probe_routes = get_health
+        .or(get_version)
+        .or(get_liveness)
+        .or(get_readiness)
+        .map(Reply::into_response)
+        .boxed();
+
+    let file_routes = get_file
+        .or(get_file_info)
+        .or(get_upload_status)
+        .or(get_file_by_hash)
+        .or(head_file)
+        .or(upload_file)
+        .or(upload_buffered)
+        .or(upload_form)
+        .or(upload_batch)
+        .or(fetch_file)
+        .or(replace_file)
+        .or(append_file)
+        .or(update_file)
+        .or(delete_file)
+        .or(verify_file)
+        .or(sign_url)
+        .map(Reply::into_response)
+        .boxed();
+
+    let admin_routes = list_files
+        .or(list_drives)
+        .or(get_audit_log)
+        .or(get_storage_stats)
+        .or(get_file_stats)
+        .or(list_users)
+        .or(add_user)
+        .or(reset_user_token)
+        .or(delete_user)
+        .or(get_maintenance)
+        .or(set_maintenance)
+        .or(reload_settings)
+        .or(prune_files)
+        .map(Reply::into_response)
+        .boxed();
+
+    let s3_routes = s3_get_object
+        .or(s3_head_object)
+        .or(s3_put_object)
+        .or(s3_delete_object)
+        .map(Reply::into_response)
+        .boxed();
+
+    let dav_routes = dav_propfind
+        .or(dav_get_file)
+        .or(dav_head_file)
+        .or(dav_put_file)
+        .or(dav_delete)
+        .map(Reply::into_response)
+        .boxed();
+
+    let routes = get_root
+        .or(probe_routes)
+        .or(file_routes)
+        .or(get_alias_file)
+        .or(head_alias_file)
+        .or(set_alias)
+        .or(delete_alias)
+        .or(search_files)
+        .or(list_collections)
+        .or(add_collection)
+        .or(list_collection_files)
+        .or(delete_collection)
+        .or(admin_routes)
+        .or(s3_routes)
+        .or(dav_routes)
+        .or(get_options)
+        .or(method_not_allowed);
+
+    let access_config = Arc::new(AccessConfig {
+        metrics,
+        slow_request_threshold,
+        server_timing,
+    });
+
+    access_entry(access_config)
+        .and(
+            client_limit
+                .and(shed_load)
+                .and(routes)
+                .map(|reply| reply::with_header(reply, "server", "castella"))
+                .recover(recover)
+                .map(move |reply| {
+                    let mut res = add_response_headers(reply, &response_headers);
+
+                    // lets browsers prompt for credentials once they're configured
+                    let basic_challenge = !settings.current().settings.basic_auth.is_empty();
+
+                    if basic_challenge && res.status() == StatusCode::UNAUTHORIZED {
+                        res.headers_mut().append(
+                            "www-authenticate",
+                            HeaderValue::from_static("Basic realm=\"castella\""),
+                        );
+                    }
+
+                    res
+                }),
+        )
+        .map(log_access)
+        .with(warp::trace(request_span))
+        .boxed()
+}
+
+/// Returns the span enclosing the handling of a request, whose fields are included in its logs.
+fn request_span(info: warp::trace::Info<'_>) -> tracing::Span {
+    // enabled at every log level so that all logs of the request carry its fields
+    let span = error_span!(
+        "request",
+        request_id = tracing::field::Empty,
+        method = %info.method(),
+        path = info.path(),
+        key = tracing::field::Empty,
+        range = tracing::field::Empty,
+    );
+
+    let key = info.path().split('/').nth(1).map(str::parse::<i64>);
+    if let Some(Ok(key)) = key {
+        span.record("key", &key);
+    }
+
+    let range = info
+        .request_headers()
+        .get("range")
+        .and_then(|value| value.to_str().ok());
+
+    if let Some(range) = range {
+        span.record("range", &range);
+    }
+
+    span
+}
+
+/// Records the key of the file that a request resolved to into its span,
+/// for requests that name the file by something other than its key such as an alias.
+fn record_key(key: i64) {
+    tracing::Span::current().record("key", &key);
+}
+
+/// Request details written to the access log when the response to the request was sent.
+struct AccessEntry {
+    id: String,
+    method: Method,
+    path: String,
+    client: Option<IpAddr>,
+    start: Instant,
+    status: Option<StatusCode>,
+    bytes: u64,
+    span: tracing::Span,
+    config: Arc<AccessConfig>,
+}
+
+/// What is recorded of every request in addition to the access log.
+struct AccessConfig {
+    metrics: Metrics,
+    slow_request_threshold: Option<Duration>,
+    server_timing: bool,
+}
+
+impl Drop for AccessEntry {
+    fn drop(&mut self) {
+        let _guard = self.span.enter();
+
+        // like nginx, 499 marks requests that were closed by the client before a response was sent
+        let status = self.status.map_or(499, |status| status.as_u16());
+        let duration = self.start.elapsed();
+
+        let metrics = &self.config.metrics;
+        metrics.count(&format!("http.responses.{}xx", status / 100), 1);
+        metrics.time("http.request_time", duration);
+        metrics.count("http.bytes_sent", self.bytes);
+
+        if let Some(threshold) = self.config.slow_request_threshold {
+            if duration >= threshold {
+                let timings = Timings::of(&self.span);
+
+                warn!(
+                    duration_ms = duration.as_millis() as u64,
+                    db_ms = timings.db.as_millis() as u64,
+                    drive_ms = timings.drive.as_millis() as u64,
+                    crypto_ms = timings.crypto.as_millis() as u64,
+                    "slow request {} {}",
+                    self.method,
+                    self.path,
+                );
+            }
+        }
+
+        info!(
+            target: "access",
+            method = %self.method,
+            path = %self.path,
+            status,
+            bytes = self.bytes,
+            duration_ms = duration.as_millis() as u64,
+            client_ip = %self.client.map_or_else(|| "-".into(), |ip| ip.to_string()),
+            "{} {} {status}",
+            self.method,
+            self.path,
+        );
+    }
+}
+
+/// Extracts the details of a request for the access log, and records the request id into the request span.
+fn access_entry(
+    config: Arc<AccessConfig>,
+) -> impl Filter<Extract = (AccessEntry,), Error = Infallible> + Clone {
+    method()
+        .and(path::full())
+        .and(remote_addr())
+        .and(header::headers_cloned())
+        .map(
+            move |method, path: path::FullPath, addr: Option<SocketAddr>, headers: HeaderMap| {
+                // reuse the id assigned by a proxy in front of the server so that its logs can be correlated
+                let id = headers
+                    .get("x-request-id")
+                    .and_then(|value| value.to_str().ok())
+                    .filter(|id| is_valid_request_id(id))
+                    .map_or_else(|| format_hex(thread_rng().gen::<[u8; 8]>()), Into::into);
+
+                let span = tracing::Span::current();
+                span.record("request_id", &id.as_str());
+
+                AccessEntry {
+                    id,
+                    method,
+                    path: path.as_str().into(),
+                    client: addr.map(|addr| addr.ip()),
+                    start: Instant::now(),
+                    status: None,
+                    bytes: 0,
+                    span,
+                    config: config.clone(),
+                }
+            },
+        )
+}
+
+/// Maximum length of request ids supplied by clients in the `x-request-id` header, longer ids are replaced.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'))
+}
+
+/// Returns the request id in the response, deferring the access log until its body was sent.
+fn log_access(mut entry: AccessEntry, mut res: reply::Response) -> reply::Response {
+    entry.status = Some(res.status());
+
+    if let Ok(value) = HeaderValue::from_str(&entry.id) {
+        res.headers_mut().insert("x-request-id", value);
+    }
+
+    if entry.config.server_timing {
+        let timings = Timings::of(&entry.span);
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+        let value = format!(
+            "db;dur={:.1}, drive;dur={:.1}, crypto;dur={:.1}, total;dur={:.1}",
+            ms(timings.db),
+            ms(timings.drive),
+            ms(timings.crypto),
+            ms(entry.start.elapsed()),
+        );
+
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            res.headers_mut().insert("server-timing", value);
+        }
+    }
+
+    let (mut parts, body) = res.into_parts();
+
+    match HttpBody::size_hint(&body).exact() {
+        // nothing to send, so the entry is logged right away
+        Some(0) => reply::Response::from_parts(parts, body),
+
+        len => {
+            // wrapping the body hides its length, so it is sent as a header instead
+            if let Some(len) = len {
+                parts.headers.entry("content-length").or_insert(len.into());
+            }
+
+            let body = AccessLogBody { body, entry };
+            reply::Response::from_parts(parts, hyper::Body::wrap_stream(body))
+        }
+    }
+}
+
+/// Counts the bytes of a response body, logging its access entry when the body is dropped.
+struct AccessLogBody {
+    body: hyper::Body,
+    entry: AccessEntry,
+}
+
+impl Stream for AccessLogBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // logs of streaming the body such as drive requests belong to the request
+        let span = this.entry.span.clone();
+        let _guard = span.enter();
+
+        let poll = Pin::new(&mut this.body).poll_data(cx);
+
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            this.entry.bytes += chunk.len() as u64;
+        }
+
+        poll
+    }
+}
+
+/// Extracts the address of the client, which is attached to requests by the TLS server when it is enabled.
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    addr::remote().and(warp::ext::optional::<RemoteAddr>()).map(
+        |addr: Option<SocketAddr>, tls: Option<RemoteAddr>| {
+            addr.or(tls.map(|RemoteAddr(addr)| addr))
+        },
+    )
+}
+
+/// Client of a request, identified by its address and the subject of the certificate it presented, if any.
+#[derive(Debug, Clone)]
+struct Peer {
+    addr: Option<SocketAddr>,
+    cert: Option<Arc<str>>,
+}
+
+fn peer() -> impl Filter<Extract = (Peer,), Error = Infallible> + Clone {
+    remote_addr()
+        .and(warp::ext::optional::<ClientCert>())
+        .map(|addr, cert: Option<ClientCert>| Peer {
+            addr,
+            cert: cert.map(|ClientCert(subject)| subject),
+        })
+}
+
+/// Rejects uploads whose content length exceeds the maximum upload size.
+fn upload_size_limit(settings: Arc<Settings>) -> BoxedFilter<()> {
+    header::<u64>("content-length")
+        .and_then(move |length: u64| {
+            let limit = settings.current().settings.max_upload_size;
+
+            async move {
+                if length <= limit {
+                    Ok(())
+                } else {
+                    Err(reject::custom(UploadTooLarge))
+                }
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Limits the number of uploads that are handled concurrently.
+struct UploadLimiter {
+    permits: Arc<Semaphore>,
+    // uploads waiting for a slot
+    queue: WaitQueue,
+    queue_timeout: Duration,
+    shed_threshold: Option<usize>,
+}
+
+/// Slot of an upload counted against the concurrent upload limit, which is freed once the upload completes.
+type UploadPermit = Option<OwnedSemaphorePermit>;
+
+/// Waits for a slot for an upload before its body is read, rejecting it if none frees up in time.
+fn upload_slot(limiter: Option<Arc<UploadLimiter>>) -> BoxedFilter<(UploadPermit,)> {
+    any()
+        .and_then(move || {
+            let limiter = limiter.clone();
+
+            async move {
+                let limiter = match limiter {
+                    Some(limiter) => limiter,
+                    None => return Ok(None),
+                };
+
+                // uploads that would only queue behind too many others are rejected right away
+                if limiter
+                    .shed_threshold
+                    .is_some_and(|threshold| limiter.queue.len() >= threshold)
+                {
+                    return Err(reject::custom(Overloaded(limiter.queue.average_wait())));
+                }
+
+                let permit = limiter.permits.clone().acquire_owned();
+                let permit = tokio::time::timeout(limiter.queue_timeout, permit);
+
+                match limiter.queue.wait(permit).await {
+                    Ok(Ok(permit)) => Ok(Some(permit)),
+                    _ => Err(reject::custom(TooManyUploads)),
+                }
+            }
+        })
+        .boxed()
+}
+
+/// Matches requests of a method that warp has no filter for, such as `PROPFIND` of WebDAV.
+fn method_named(name: &'static str) -> BoxedFilter<()> {
+    method()
+        .and_then(move |method: Method| async move {
+            if method.as_str() == name {
+                Ok(())
+            } else {
+                Err(reject::not_found())
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Rejects requests of clients that exceeded the rate limit of the method of the request.
+fn client_limit(settings: Arc<Settings>) -> BoxedFilter<()> {
+    method()
+        .and(remote_addr())
+        .and_then(move |method: Method, addr: Option<SocketAddr>| {
+            let current = settings.current();
+            let limiter = match method {
+                Method::GET | Method::HEAD | Method::OPTIONS => current.read_limiter.clone(),
+                ref method if method.as_str() == "PROPFIND" => current.read_limiter.clone(),
+                _ => current.write_limiter.clone(),
+            };
+
+            async move {
+                match (limiter, addr) {
+                    (Some(limiter), Some(addr)) => limiter
+                        .check(&addr.ip())
+                        .map_err(|wait| reject::custom(RateLimited(wait))),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Client of a request, resolved by the first route that authorizes it and reused by the routes tried after it.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCache(Arc<tokio::sync::OnceCell<Option<Client>>>);
+
+/// Extracts the client authorized by the API key, basic credentials, user token or OpenID Connect token
+/// it presented, or by its certificate if it presented none, or `None` if it presented no valid key.
+fn client(
+    settings: Arc<Settings>,
+    oidc: Option<Arc<OidcValidator>>,
+    store: BoxedFilter<(Arc<Store>,)>,
+) -> BoxedFilter<(Option<Client>,)> {
+    header::optional::<String>("authorization")
+        .and(header::optional::<String>("x-api-key"))
+        .and(warp::ext::optional::<ClientCert>())
+        .and(warp::ext::optional::<ClientCache>())
+        .and(store)
+        .and_then(
+            move |authorization: Option<String>,
+                  api_key: Option<String>,
+                  cert: Option<ClientCert>,
+                  cache: Option<ClientCache>,
+                  store: Arc<Store>| {
+                let settings = settings.clone();
+                let oidc = oidc.clone();
+
+                async move {
+                    let resolve = || {
+                        resolve_client(
+                            settings,
+                            oidc,
+                            store,
+                            authorization,
+                            api_key,
+                            cert.map(|ClientCert(subject)| subject),
+                        )
+                    };
+
+                    // failures aren't cached, so that a later route tries again
+                    match cache {
+                        Some(ClientCache(cache)) => cache.get_or_try_init(resolve).await.cloned(),
+                        None => resolve().await,
+                    }
+                }
+            },
+        )
+        .boxed()
+}
+
+async fn resolve_client(
+    settings: Arc<Settings>,
+    oidc: Option<Arc<OidcValidator>>,
+    store: Arc<Store>,
+    authorization: Option<String>,
+    api_key: Option<String>,
+    cert: Option<Arc<str>>,
+) -> Result<Option<Client>, Rejection> {
+    let current = settings.current();
+
+    let ServerSettings {
+        ref api_keys,
+        ref basic_auth,
+        ref cert_clients,
+        ..
+    } = current.settings;
+
+    // everyone has full access without keys, users or an oidc provider;
+    // users are checked on every request as they can be added while the server is running
+    if !current.settings.has_credentials() && oidc.is_none() {
+        match store.has_users().await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Ok(Some(Client {
+                    scope: Scope::Admin,
+                    namespace: DEFAULT_NAMESPACE.into(),
+                }))
+            }
+            Err(err) => {
+                warn!("failed to check for users: {err}");
+                return Err(reject::custom(AuthenticationUnavailable));
+            }
+        }
+    }
+
+    if let Some(credentials) = authorization.as_deref().and_then(parse_basic_credentials) {
+        return Ok(basic_auth.get(credentials));
+    }
+
+    let key = match authorization
+        .as_deref()
+        .and_then(parse_bearer_token)
+        .or(api_key.as_deref())
+    {
+        Some(key) => key,
+        None => return Ok(cert.and_then(|subject| cert_clients.get(&*subject))),
+    };
+
+    if let Some(client) = api_keys.get(key) {
+        return Ok(Some(client));
+    }
+
+    // generated keys never contain dots, unlike json web tokens
+    if let (Some(oidc), true) = (oidc, key.contains('.')) {
+        return match oidc.validate(key).await {
+            Ok(scope) => Ok(Some(Client {
+                scope,
+                namespace: DEFAULT_NAMESPACE.into(),
+            })),
+            Err(crate::oidc::Error::KeysFetch(err)) => {
+                warn!("failed to fetch oidc signing keys: {err}");
+                Err(reject::custom(AuthenticationUnavailable))
+            }
+            Err(err) => {
+                debug!("rejected oidc token: {err}");
+                Ok(None)
+            }
+        };
+    }
+
+    match store.get_user_by_token(&key_digest(key)).await {
+        Ok(user) => Ok(user.and_then(|user| {
+            Some(Client {
+                scope: Scope::from_role(&user.role)?,
+                namespace: user.namespace.into(),
+            })
+        })),
+        Err(err) => {
+            warn!("failed to authenticate user: {err}");
+            Err(reject::custom(AuthenticationUnavailable))
+        }
+    }
+}
+
+/// Extracts the client authorized by the signature of a request of the S3-compatible api,
+/// or the error to reply with if the signature is missing or invalid.
+fn s3_client(settings: Arc<Settings>) -> BoxedFilter<(S3Auth,)> {
+    method()
+        .and(path::full())
+        .and(query::raw().or(any().map(String::new)).unify())
+        .and(header::headers_cloned())
+        .map(
+            move |method: Method, path: path::FullPath, query: String, headers: HeaderMap| {
+                settings.current().settings.s3_credentials.verify(
+                    &method,
+                    path.as_str(),
+                    &query,
+                    &headers,
+                )
+            },
+        )
+        .boxed()
+}
+
+/// Extracts the key of a file from the path if the url is signed for downloading the file.
+fn signed_file_key(signer: Option<Arc<UrlSigner>>) -> BoxedFilter<(i64,)> {
+    path!(i64)
+        .and(query())
+        .and_then(move |key: i64, query: SignedUrlQuery| {
+            let signer = signer.clone();
+
+            async move {
+                match (signer, query.expires, query.sig) {
+                    (Some(signer), Some(expires), Some(sig)) => {
+                        if signer.verify(key, expires, &sig) {
+                            Ok(key)
+                        } else {
+                            Err(reject::custom(SignatureInvalid))
+                        }
+                    }
+                    _ => Err(reject::not_found()),
+                }
+            }
+        })
+        .boxed()
+}
+
+/// Rejects requests while more than `threshold` operations are waiting for a database connection
+/// or the Drive API request limit, except for health checks.
+fn shed_load(store: BoxedFilter<(Arc<Store>,)>, threshold: Option<usize>) -> BoxedFilter<()> {
+    path::full()
+        .and(store)
+        .and_then(move |path: path::FullPath, store: Arc<Store>| async move {
+            let threshold = match threshold {
+                Some(threshold) => threshold,
+                None => return Ok(()),
+            };
+
+            if matches!(
+                path.as_str(),
+                "/healthz" | "/livez" | "/readyz" | "/version"
+            ) {
+                return Ok(());
+            }
+
+            let load = store.load();
+            let mut retry_after = None;
+
+            if load.db_waiting > threshold {
+                retry_after = Some(load.db_wait);
+            }
+
+            if load.drive_waiting > threshold {
+                retry_after = retry_after.max(Some(load.drive_wait));
+            }
+
+            match retry_after {
+                Some(retry_after) => {
+                    debug!("shedding request with {load:?}");
+                    Err(reject::custom(Overloaded(retry_after)))
+                }
+                None => Ok(()),
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Rejects requests while the server is in maintenance mode.
+fn reject_in_maintenance(maintenance: Arc<AtomicBool>) -> BoxedFilter<()> {
+    any()
+        .and_then(move || {
+            let enabled = maintenance.load(Ordering::Relaxed);
+
+            async move {
+                if enabled {
+                    Err(reject::custom(UnderMaintenance))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Rejects requests of clients whose API key doesn't grant the scope,
+/// extracting the namespace of the key otherwise.
+fn require_scope(client: BoxedFilter<(Option<Client>,)>, scope: Scope) -> BoxedFilter<(Arc<str>,)> {
+    client
+        .and_then(move |client: Option<Client>| async move {
+            match client {
+                Some(client) if client.scope >= scope => Ok(client.namespace),
+                Some(_) => Err(reject::custom(Forbidden)),
+                None => Err(reject::custom(Unauthorized)),
+            }
+        })
+        .boxed()
+}
+
+fn add_response_headers(reply: impl Reply, headers: &HeaderMap) -> reply::Response {
+    let mut res = reply.into_response();
+    res.headers_mut().extend(headers.clone());
+    res
+}
+
+fn get_root() -> impl Reply {
+    "castella file server"
+}
+
+#[derive(Debug, Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    /// Git commit from which the server was built, if known.
+    commit: Option<&'static str>,
+    build_time: Option<DateTime<Utc>>,
+    /// Cipher of newly uploaded files.
+    cipher: &'static str,
+    features: Vec<&'static str>,
+}
+
+/// Maximum duration of each check of a health check before the check fails.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of checking a dependency of the server.
+#[derive(Debug, Serialize)]
+struct HealthCheck {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    duration_ms: u64,
+}
+
+impl HealthCheck {
+    async fn run(check: impl Future<Output = Result<(), castella_core::store::Error>>) -> Self {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check).await {
+            Ok(result) => result.map_err(|err| err.to_string()),
+            Err(_) => Err("timed out".into()),
+        };
+
+        Self {
+            ok: result.is_ok(),
+            error: result.err(),
+            duration_ms: start.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HealthInfo {
+    healthy: bool,
+    database: HealthCheck,
+    drive: HealthCheck,
+}
+
+/// Checks that the database is reachable and that an access token for the Drive API can be obtained,
+/// replying 503 if either fails so that load balancers stop routing requests to the server.
+async fn get_health(store: Arc<Store>) -> reply::Response {
+    let (database, drive) = futures::join!(
+        HealthCheck::run(store.ping_db()),
+        HealthCheck::run(store.check_drive_auth()),
+    );
+
+    for (name, check) in [("database", &database), ("drive", &drive)] {
+        if let Some(ref err) = check.error {
+            warn!("health check of {name} failed: {err}");
+        }
+    }
+
+    let healthy = database.ok && drive.ok;
+
+    reply_health(
+        &HealthInfo {
+            healthy,
+            database,
+            drive,
+        },
+        healthy,
+    )
+}
+
+/// Replies 200 as long as the process is serving requests, for liveness probes that restart hung servers.
+fn get_liveness() -> reply::Response {
+    reply_health(&serde_json::json!({ "live": true }), true)
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessInfo {
+    ready: bool,
+    database: HealthCheck,
+    migrations: HealthCheck,
+    drive: HealthCheck,
+}
+
+/// Checks that the server can handle requests, for readiness probes that decide whether to route requests to it.
+/// In addition to the checks of `GET /healthz`, all database migrations must have been applied.
+async fn get_readiness(store: Arc<Store>) -> reply::Response {
+    let (database, migrations, drive) = futures::join!(
+        HealthCheck::run(store.ping_db()),
+        HealthCheck::run(store.check_migrated()),
+        HealthCheck::run(store.check_drive_auth()),
+    );
+
+    let ready = database.ok && migrations.ok && drive.ok;
+
+    reply_health(
+        &ReadinessInfo {
+            ready,
+            database,
+            migrations,
+            drive,
+        },
+        ready,
+    )
+}
+
+/// Replies to a probe with 200 if the check succeeded, or 503 otherwise.
+fn reply_health(info: &impl Serialize, ok: bool) -> reply::Response {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    reply::with_header(
+        reply::with_status(reply::json(info), status),
+        "cache-control",
+        "no-store",
+    )
+    .into_response()
+}
+
+/// Returns the routes served while the server is starting up, such as when applying database migrations.
+/// Liveness probes succeed so that the server isn't restarted for starting slowly, while all other requests fail.
+pub fn startup_routes() -> BoxedFilter<(reply::Response,)> {
+    get()
+        .and(path!("livez"))
+        .map(get_liveness)
+        .or(any().map(|| reply_error(StatusCode::SERVICE_UNAVAILABLE, "server is starting")))
+        .unify()
+        .boxed()
+}
+
+/// Returns the methods allowed on a path, or `None` if the path matches no route.
+fn allowed_methods(path: &str) -> Option<&'static [&'static str]> {
+    let segments: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let is_id = |s: &str| s.parse::<i64>().is_ok();
+
+    Some(match segments.as_slice() {
+        [] => &["GET", "POST", "OPTIONS"],
+        [id] if is_id(id) => &["GET", "HEAD", "PUT", "PATCH", "DELETE", "OPTIONS"],
+        [id, "info" | "status"] if is_id(id) => &["GET", "OPTIONS"],
+        [id, "verify" | "sign"] if is_id(id) => &["POST", "OPTIONS"],
+        ["healthz" | "livez" | "readyz" | "version"] => &["GET", "OPTIONS"],
+        ["by-hash", _] => &["GET", "OPTIONS"],
+        ["batch" | "fetch"] => &["POST", "OPTIONS"],
+        ["alias", _, ..] => &["GET", "HEAD", "PUT", "DELETE", "OPTIONS"],
+        ["search"] => &["GET", "OPTIONS"],
+        ["collections"] => &["GET", "POST", "OPTIONS"],
+        ["collections", _] => &["GET", "DELETE", "OPTIONS"],
+        ["admin", "files" | "drives" | "audit" | "stats"] => &["GET", "OPTIONS"],
+        ["admin", "stats", "downloads"] => &["GET", "OPTIONS"],
+        ["admin", "users"] => &["GET", "POST", "OPTIONS"],
+        ["admin", "users", id] if is_id(id) => &["DELETE", "OPTIONS"],
+        ["admin", "users", id, "token"] if is_id(id) => &["POST", "OPTIONS"],
+        ["admin", "maintenance"] => &["GET", "PUT", "OPTIONS"],
+        ["admin", "reload"] | ["admin", "prune"] => &["POST", "OPTIONS"],
+        ["s3", _, ..] => &["GET", "HEAD", "PUT", "DELETE", "OPTIONS"],
+        ["dav", ..] => &["GET", "HEAD", "PUT", "DELETE", "PROPFIND", "OPTIONS"],
+        _ => return None,
+    })
+}
+
+async fn get_options(path: path::FullPath) -> Result<reply::Response, Rejection> {
+    let allowed = allowed_methods(path.as_str()).ok_or_else(reject::not_found)?;
+
+    let mut res = reply::with_header(
+        reply::with_status(reply(), StatusCode::NO_CONTENT),
+        "allow",
+        allowed.join(", "),
+    )
+    .into_response();
+
+    // webdav clients check the compliance class of the server before mounting it
+    if allowed.contains(&"PROPFIND") {
+        res.headers_mut()
+            .insert("dav", HeaderValue::from_static("1"));
+    }
+
+    Ok(res)
+}
+
+/// Replies 405 to a request on a known path with a method that no route of the path accepts.
+/// Requests with an allowed method are rejected so that the rejection of the route is recovered.
+async fn method_not_allowed(
+    method: Method,
+    path: path::FullPath,
+) -> Result<reply::Response, Rejection> {
+    let allowed = allowed_methods(path.as_str()).ok_or_else(reject::not_found)?;
+
+    if allowed.contains(&method.as_str()) {
+        return Err(reject::not_found());
+    }
+
+    Ok(reply::with_header(
+        reply_error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        "allow",
+        allowed.join(", "),
+    )
+    .into_response())
+}
+
+/// Maximum size of the serialized metadata attached to a file.
+const MAX_METADATA_SIZE: usize = 4096;
+
+/// User-defined file metadata given as a JSON object in a header.
+#[derive(Debug)]
+struct Metadata(Map<String, Value>);
+
+impl FromStr for Metadata {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > MAX_METADATA_SIZE {
+            return Err(serde::de::Error::custom("metadata too large"));
+        }
+
+        Ok(Self(serde_json::from_str(s)?))
+    }
+}
+
+/// Base64-encoded MD5 digest given in a `Content-MD5` header.
+#[derive(Debug)]
+struct ContentMd5(Vec<u8>);
+
+impl FromStr for ContentMd5 {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match base64::decode(s.trim()) {
+            Ok(digest) if digest.len() == 16 => Ok(Self(digest)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Digests given in a `Repr-Digest` header, ignoring unsupported algorithms.
+#[derive(Debug)]
+struct ReprDigest(Vec<ExpectedDigest>);
+
+impl FromStr for ReprDigest {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            parse_repr_digest(s)
+                .ok_or(())?
+                .into_iter()
+                .filter_map(|(algorithm, digest)| match algorithm.as_str() {
+                    "sha-256" => Some(ExpectedDigest::Sha256(digest)),
+                    "sha-512" => Some(ExpectedDigest::Sha512(digest)),
+                    _ => None,
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// Range of a file covered by the request body, given in a `Content-Range` header.
+#[derive(Debug)]
+struct ContentRange {
+    range: Range<u64>,
+    length: Option<u64>,
+}
+
+impl FromStr for ContentRange {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, length) = parse_content_range_header(s).ok_or(())?;
+        Ok(Self { range, length })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FileInfo {
+    key: i64,
+    size: i64,
+    content_type: String,
+    created_time: DateTime<Utc>,
+    accessed_time: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    metadata: Value,
+    download_count: i64,
+    bytes_served: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_downloads: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manifest_root: Option<String>,
+    encryption: Encryption,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public: Option<bool>,
+}
+
+impl From<File> for FileInfo {
+    fn from(file: File) -> Self {
+        let encryption = file.encryption();
+
+        Self {
+            key: file.key,
+            size: file.size,
+            content_type: file.content_type,
+            created_time: DateTime::from_utc(file.created_time, Utc),
+            accessed_time: DateTime::from_utc(file.accessed_time, Utc),
+            filename: file.filename,
+            metadata: file.metadata,
+            download_count: file.download_count,
+            bytes_served: file.bytes_served,
+            remaining_downloads: file.remaining_downloads,
+            sha256: file.sha256.map(format_hex),
+            manifest_root: file.manifest_root.map(format_hex),
+            encryption,
+            public: file.public,
+        }
+    }
+}
+
+/// Cache control of files, which caches must revalidate before reuse as a key can be replaced or appended to.
+const FILE_CACHE_CONTROL: &str = "public,no-cache";
+
+/// Cache control of files that must not be stored by shared caches.
+const PRIVATE_FILE_CACHE_CONTROL: &str = "private,no-cache";
+
+/// Bandwidth limits of the bodies of downloads.
+struct Throttle {
+    /// Limiter shared by all downloads.
+    global: Option<Arc<BandwidthLimiter>>,
+    /// Limit of each download, for which a limiter is created per response.
+    download: Option<RateLimit>,
+}
+
+/// Throttles the body of a download to the bandwidth limits, after it is compressed.
+fn throttle_response(reply: impl Reply, throttle: Arc<Throttle>) -> reply::Response {
+    let res = reply.into_response();
+
+    if throttle.global.is_none() && throttle.download.is_none() {
+        return res;
+    }
+
+    let (parts, body) = res.into_parts();
+
+    let body = match throttle.global {
+        Some(ref limiter) => throttle_stream(body, limiter.clone()).left_stream(),
+        None => body.right_stream(),
+    };
+
+    let body = match throttle.download {
+        Some(limit) => {
+            throttle_stream(body, Arc::new(BandwidthLimiter::new(limit, 1024 * 1024))).left_stream()
+        }
+        None => body.right_stream(),
+    };
+
+    reply::Response::from_parts(parts, hyper::Body::wrap_stream(body))
+}
+
+/// Minimum length of content that is compressed, below which compression saves too little to be worth it.
+const MIN_COMPRESS_LENGTH: u64 = 1024;
+
+/// Compresses the body of a complete download with the negotiated encoding if its content type is compressible.
+/// `encoding` is `None` if compression is disabled.
+fn compress_response(reply: impl Reply, encoding: Option<Encoding>) -> reply::Response {
+    let mut res = reply.into_response();
+
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return res,
+    };
+
+    let headers = res.headers();
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let compressible = res.status() == StatusCode::OK
+        && !headers.contains_key("content-encoding")
+        && header("content-type").is_some_and(is_compressible);
+
+    if !compressible {
+        return res;
+    }
+
+    let length = header("content-length").and_then(|value| value.parse::<u64>().ok());
+
+    // caches must not serve a compressed response to clients that don't accept it
+    res.headers_mut()
+        .insert("vary", HeaderValue::from_static("accept-encoding"));
+
+    let name = match encoding.name() {
+        Some(name) if length.is_none_or(|length| length >= MIN_COMPRESS_LENGTH) => name,
+        _ => return res,
+    };
+
+    let (mut parts, body) = res.into_parts();
+    parts.headers.remove("content-length");
+    parts.headers.remove("accept-ranges");
+    parts
+        .headers
+        .insert("content-encoding", HeaderValue::from_static(name));
+
+    // the compressed body differs from the stored content byte for byte
+    if let Some(etag) = parts
+        .headers
+        .get("etag")
+        .and_then(|etag| etag.to_str().ok())
+    {
+        if let Ok(etag) = format!("W/{}", etag.trim_start_matches("W/")).parse() {
+            parts.headers.insert("etag", etag);
+        }
+    }
+
+    let body = TryStreamExt::map_err(body, std::io::Error::other);
+    reply::Response::from_parts(
+        parts,
+        hyper::Body::wrap_stream(compress_stream(body, encoding)),
+    )
+}
+
+/// Returns the entity tag of a file, derived from the digest of its content if known so that
+/// identical content shares validators, or from its remote file ID otherwise.
+fn get_file_etag(file: &File) -> String {
+    match file.sha256 {
+        Some(ref sha256) => base64::encode_config(sha256, base64::URL_SAFE_NO_PAD),
+        None => base64::encode_config(Sha256::digest(&file.id), base64::URL_SAFE_NO_PAD),
+    }
+}
+
+/// Returns whether an `If-None-Match` header lists an entity tag, compared weakly as compressed responses
+/// have weak tags.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == etag
+    })
+}
+
+/// Replies that the content cached by the client is current, with the headers that a full response would have
+/// to update the cached response with.
+fn reply_not_modified(file: &File) -> reply::Response {
+    let mut res = add_file_headers(reply(), file, 0);
+    *res.status_mut() = StatusCode::NOT_MODIFIED;
+
+    let headers = res.headers_mut();
+    headers.remove("content-type");
+    headers.remove("content-length");
+    headers.remove("accept-ranges");
+    res
+}
+
+fn add_file_headers(reply: impl Reply, file: &File, length: u64) -> reply::Response {
+    let mut res = reply::with_header(
+        reply::with_header(
+            reply::with_header(
+                reply::with_header(
+                    reply::with_header(
+                        reply::with_header(reply, "content-type", &file.content_type),
+                        "content-length",
+                        length,
+                    ),
+                    "cache-control",
+                    match file.public {
+                        Some(false) => PRIVATE_FILE_CACHE_CONTROL,
+                        _ => FILE_CACHE_CONTROL,
+                    },
+                ),
+                "last-modified",
+                DateTime::<Utc>::from_utc(file.created_time, Utc).to_rfc2822(),
+            ),
+            "etag",
+            format!("\"{}\"", get_file_etag(file)),
+        ),
+        "accept-ranges",
+        "bytes",
+    )
+    .into_response();
+
+    if let Some(ref sha256) = file.sha256 {
+        if let Ok(value) = format_hex(sha256).parse() {
+            res.headers_mut().insert("x-castella-sha256", value);
+        }
+    }
+
+    res
+}
+
+/// Tells the client how many bytes its namespace can still transfer today, if there is a daily allowance.
+fn add_quota_header(res: &mut reply::Response, transfer_remaining: Option<u64>) {
+    if let Some(remaining) = transfer_remaining {
+        res.headers_mut()
+            .insert("x-quota-remaining", HeaderValue::from(remaining));
+    }
+}
+
+async fn head_file(key: i64, access: ReadAccess, store: Arc<Store>) -> Result<impl Reply, Error> {
+    let file = store.get_info(key).await?.ok_or(Error::FileNotExists)?;
+    access.check(&file)?;
+    let size = file.size as u64;
+
+    let res = reply::with_header(
+        reply::with_header(
+            reply::with_header(
+                add_file_headers(reply(), &file, size),
+                "x-castella-download-count",
+                file.download_count,
+            ),
+            "x-castella-bytes-served",
+            file.bytes_served,
+        ),
+        "x-castella-metadata",
+        format_json_header(&file.metadata),
+    );
+
+    Ok(match file.remaining_downloads {
+        Some(remaining) => {
+            reply::with_header(res, "x-castella-remaining-downloads", remaining).into_response()
+        }
+        None => res.into_response(),
+    })
+}
+
+async fn get_file_info(
+    key: i64,
+    access: ReadAccess,
+    store: Arc<Store>,
+) -> Result<impl Reply, Error> {
+    let file = store.get_info(key).await?.ok_or(Error::FileNotExists)?;
+    access.check(&file)?;
+    Ok(reply::json(&FileInfo::from(file)))
+}
+
+async fn get_upload_status(
+    key: i64,
+    access: ReadAccess,
+    store: Arc<Store>,
+) -> Result<impl Reply, Error> {
+    access.check_key(&store, key).await?;
+
+    let status = store
+        .get_upload_status(key)
+        .await?
+        .ok_or(Error::FileNotExists)?;
+
+    Ok(reply::json(&status))
+}
+
+async fn get_file_by_hash(
+    sha256: String,
+    access: ReadAccess,
+    store: Arc<Store>,
+) -> Result<impl Reply, Error> {
+    let sha256 = parse_hex(&sha256)
+        .filter(|digest| digest.len() == 32)
+        .ok_or(Error::DigestInvalid)?;
+
+    let file = store
+        .get_info_by_sha256(
+            &sha256,
+            access.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE),
+        )
+        .await?
+        .ok_or(Error::FileNotExists)?;
+
+    access.check(&file)?;
+
+    Ok(reply::json(&FileInfo::from(file)))
+}
+
+/// Maximum number of ranges served in a single `multipart/byteranges` response.
+/// Requests for more ranges are served the entire file.
+const MAX_RANGES: usize = 16;
+
+#[allow(clippy::too_many_arguments)]
+async fn get_file(
+    key: i64,
+    access: ReadAccess,
+    store: Arc<Store>,
+    client: Peer,
+    limiter: Option<Arc<KeyedConcurrencyLimiter<IpAddr>>>,
+    images: Option<Arc<ImageTransformer>>,
+    range: Option<String>,
+    if_none_match: Option<String>,
+    query: GetFileQuery,
+) -> Result<reply::Response, Error> {
+    let mut event = AuditEvent {
+        operation: "download",
+        file_key: Some(key),
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
+
+    let result = async {
+        // checked before the download counts against the download limit of the file
+        access.check_key(&store, key).await?;
+
+        // released once the response body is dropped
+        let permit = match (limiter, client.addr) {
+            (Some(limiter), Some(client)) => Some(
+                limiter
+                    .acquire(client.ip())
+                    .ok_or(Error::TooManyDownloads)?,
+            ),
+            _ => None,
+        };
+
+        // transformed images are served whole
+        if let Some(transform) = query.transform()? {
+            let images = images.ok_or(Error::TransformDisabled)?;
+            return get_transformed_file(
+                key, &store, &images, transform, &query, &mut event, permit,
+            )
+            .await;
+        }
+
+        // revalidated without reading the content or counting a download
+        if let Some(ref if_none_match) = if_none_match {
+            let file = store.get_info(key).await?.ok_or(Error::FileNotExists)?;
+
+            if etag_matches(if_none_match, &get_file_etag(&file)) {
+                event.file_id = Some(file.id.clone());
+                return Ok(reply_not_modified(&file));
+            }
+        }
+
+        let mut ranges = range.and_then(parse_range_header).unwrap_or_default();
+        let range = match ranges.len() {
+            1 => ranges.pop(),
+            2..=MAX_RANGES => {
+                return get_file_ranges(key, &store, ranges, &query, &mut event, permit).await
+            }
+            _ => None,
+        };
+
+        let data = match store.get(key, range).await {
+            Err(castella_core::store::Error::RangeNotSatisfiable(size)) => {
+                return Ok(reply_range_not_satisfiable(size))
+            }
+            result => result?.ok_or(Error::FileNotExists)?,
+        };
+
+        Ok(reply_file_range(&store, data, permit, &query, &mut event))
+    }
+    .await;
+
+    match result {
+        // recorded once the content has been sent, as reading it can still fail
+        Ok(res) if res.status().is_success() => Ok(audit_after_stream(store, event, res)),
+        result => {
+            audit(&store, event, &result).await;
+            result
+        }
+    }
+}
+
+/// Serves a single range of a file, or all of it.
+fn reply_file_range<S>(
+    store: &Arc<Store>,
+    data: FileData<S>,
+    permit: Option<ConcurrencyPermit<IpAddr>>,
+    query: &GetFileQuery,
+    event: &mut AuditEvent,
+) -> reply::Response
+where
+    S: Stream<Item = Result<Bytes, castella_core::store::Error>> + Send + 'static,
+{
+    let FileData {
+        info: file,
+        content,
+        range,
+        last_download,
+        transfer_remaining,
+    } = data;
+
+    let size = file.size as u64;
+    let range_length = range.end - range.start;
+
+    event.file_id = Some(file.id.clone());
+    event.size = Some(range_length as i64);
+    event.range_start = Some(range.start as i64);
+    event.range_end = Some(range.end as i64);
+
+    let content = if last_download {
+        delete_after_stream(store.clone(), file.key, content).left_stream()
+    } else {
+        content.right_stream()
+    };
+
+    let mut res = add_file_headers(
+        reply::Response::new(hyper::Body::wrap_stream(hold_during_stream(
+            content, permit,
+        ))),
+        &file,
+        range_length,
+    );
+
+    add_content_disposition(&mut res, &file, query);
+    add_quota_header(&mut res, transfer_remaining);
+
+    if range_length == size {
+        res
+    } else {
+        reply::with_header(
+            reply::with_status(res, StatusCode::PARTIAL_CONTENT),
+            "content-range",
+            format!(
+                "bytes {start}-{end}/{size}",
+                start = range.start,
+                end = range.end.saturating_sub(1)
+            ),
+        )
+        .into_response()
+    }
+}
+
+/// Replies that none of the requested ranges are within the content of a file of `size` bytes.
+fn reply_range_not_satisfiable(size: u64) -> reply::Response {
+    reply::with_header(
+        reply_error(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "requested range not satisfiable",
+        ),
+        "content-range",
+        format!("bytes */{size}"),
+    )
+    .into_response()
+}
+
+/// Serves an image transformed as requested, from the cache of transformed variants if possible.
+async fn get_transformed_file(
+    key: i64,
+    store: &Arc<Store>,
+    images: &ImageTransformer,
+    mut transform: Transform,
+    query: &GetFileQuery,
+    event: &mut AuditEvent,
+    permit: Option<ConcurrencyPermit<IpAddr>>,
+) -> Result<reply::Response, Error> {
+    let file = store.get_info(key).await?.ok_or(Error::FileNotExists)?;
+
+    if !file.content_type.starts_with("image/") {
+        return Err(Error::ImageUnsupported);
+    }
+
+    if file.size as u64 > images.max_size {
+        return Err(Error::ImageTooLarge);
+    }
+
+    // images keep their format unless it can't be encoded
+    let format = *transform
+        .format
+        .get_or_insert_with(|| OutputFormat::from_content_type(&file.content_type));
+
+    event.file_id = Some(file.id.clone());
+
+    // counted even if the variant is cached, like downloads of the file itself
+    let (file, last_download, transfer_remaining) = store
+        .count_download(key)
+        .await?
+        .ok_or(Error::FileNotExists)?;
+
+    // every download of a file with a download limit is counted, so its variants aren't cached
+    let cacheable = file.remaining_downloads.is_none();
+    let cached = match cacheable {
+        true => images.get(&file.id, &transform).await,
+        false => None,
+    };
+
+    let variant = match cached {
+        Some(variant) => variant,
+        None => {
+            let content = store.read_range(&file, 0..file.size as u64).await?;
+
+            let data = content
+                .try_fold(Vec::new(), |mut data, chunk| async move {
+                    data.extend_from_slice(&chunk);
+                    Ok(data)
+                })
+                .await?;
+
+            let variant = Bytes::from(images.transform(data.into(), transform.clone()).await?);
+
+            if cacheable {
+                images.put(&file.id, &transform, &variant).await;
+            }
+
+            if last_download {
+                if let Err(err) = store.expire(key).await {
+                    warn!("failed to delete file {key} after its last download: {err}");
+                }
+            }
+
+            variant
+        }
+    };
+
+    event.size = Some(variant.len() as i64);
+    drop(permit);
+
+    let mut res = add_file_headers(
+        reply::Response::new(variant.clone().into()),
+        &file,
+        variant.len() as u64,
+    );
+
+    add_content_disposition(&mut res, &file, query);
+    add_quota_header(&mut res, transfer_remaining);
+
+    let headers = res.headers_mut();
+    headers.insert(
+        "content-type",
+        HeaderValue::from_static(format.content_type()),
+    );
+    headers.remove("accept-ranges");
+    headers.remove("x-castella-sha256");
+
+    if let Ok(etag) = format!("\"{}-{}\"", get_file_etag(&file), transform.key()).parse() {
+        headers.insert("etag", etag);
+    }
+
+    Ok(res)
+}
+
+/// Serves several ranges of a file as a `multipart/byteranges` response.
+async fn get_file_ranges(
+    key: i64,
+    store: &Arc<Store>,
+    ranges: Vec<ByteRange>,
+    query: &GetFileQuery,
+    event: &mut AuditEvent,
+    permit: Option<ConcurrencyPermit<IpAddr>>,
+) -> Result<reply::Response, Error> {
+    let RangesData {
+        info: file,
+        ranges,
+        last_download,
+        transfer_remaining,
+    } = match store.get_ranges(key, ranges).await {
+        Err(castella_core::store::Error::RangeNotSatisfiable(size)) => {
+            return Ok(reply_range_not_satisfiable(size))
+        }
+        result => result?.ok_or(Error::FileNotExists)?,
+    };
+
+    // ranges that overlap or are adjacent may have been coalesced into one, which isn't sent as multipart
+    if let [ref range] = ranges[..] {
+        let range = range.clone();
+        let content = store.read_range(&file, range.clone()).await?;
+
+        let data = FileData {
+            info: file,
+            content,
+            range,
+            last_download,
+            transfer_remaining,
+        };
+
+        return Ok(reply_file_range(store, data, permit, query, event));
+    }
+
+    let size = file.size as u64;
+
+    event.file_id = Some(file.id.clone());
+    event.size = Some(
+        ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum::<u64>() as i64,
+    );
+    event.range_start = ranges.first().map(|range| range.start as i64);
+    event.range_end = ranges.last().map(|range| range.end as i64);
+
+    let boundary = format_hex(thread_rng().gen::<[u8; 16]>());
+    let parts: Vec<_> = ranges
+        .into_iter()
+        .enumerate()
+        .map(|(index, range)| {
+            let header = format!(
+                "{delimiter}--{boundary}\r\ncontent-type: {content_type}\r\ncontent-range: bytes {start}-{end}/{size}\r\n\r\n",
+                // each part is preceded by a line break
+                delimiter = if index == 0 { "" } else { "\r\n" },
+                content_type = file.content_type,
+                start = range.start,
+                end = range.end - 1,
+            );
+
+            (Bytes::from(header), range)
+        })
+        .collect();
+
+    let trailer = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+    let length = parts
+        .iter()
+        .map(|(header, range)| header.len() as u64 + (range.end - range.start))
+        .sum::<u64>()
+        + trailer.len() as u64;
+
+    let file = Arc::new(file);
+    let content = {
+        let store = store.clone();
+        let file = file.clone();
+
+        futures::stream::iter(parts)
+            .then(move |(header, range)| {
+                let store = store.clone();
+                let file = file.clone();
+
+                async move {
+                    let content = store.read_range(&file, range).await?;
+                    Ok::<_, castella_core::store::Error>(
+                        futures::stream::once(async { Ok(header) }).chain(content),
+                    )
+                }
+            })
+            .try_flatten()
+            .chain(futures::stream::once(async { Ok(trailer) }))
+    };
+
+    let content = if last_download {
+        delete_after_stream(store.clone(), key, content).left_stream()
+    } else {
+        content.right_stream()
+    };
+
+    let mut res = add_file_headers(
+        reply::Response::new(hyper::Body::wrap_stream(hold_during_stream(
+            content, permit,
+        ))),
+        &file,
+        length,
+    );
+
+    if let Ok(value) = format!("multipart/byteranges; boundary={boundary}").parse() {
+        res.headers_mut().insert("content-type", value);
+    }
+
+    add_content_disposition(&mut res, &file, query);
+    add_quota_header(&mut res, transfer_remaining);
+
+    Ok(reply::with_status(res, StatusCode::PARTIAL_CONTENT).into_response())
+}
+
+/// Deletes a file after its last permitted download is fully served.
+fn delete_after_stream<S, T>(store: Arc<Store>, key: i64, content: S) -> impl Stream<Item = T>
+where
+    S: Stream<Item = T>,
+{
+    content.chain(
+        futures::stream::once(async move {
+            if let Err(err) = store.expire(key).await {
+                warn!("failed to delete file {key} after its last download: {err}");
+            }
+        })
+        .filter_map(|_| async { None }),
+    )
+}
+
+/// Keeps a value alive until the stream is dropped.
+fn hold_during_stream<S, T>(content: S, value: T) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+{
+    content.map(move |item| {
+        let _ = &value;
+        item
+    })
+}
+
+fn add_content_disposition(res: &mut reply::Response, file: &File, query: &GetFileQuery) {
+    if let Some(ref filename) = file.filename {
+        let disposition = match query.inline.as_deref() {
+            Some("1" | "true") => "inline",
+            _ => "attachment",
+        };
+
+        if let Ok(value) = format_content_disposition(disposition, filename).parse() {
+            res.headers_mut().insert("content-disposition", value);
+        }
+    }
+}
+
+/// Extracts upload options from request headers and the query string.
+fn upload_options() -> impl Filter<Extract = (UploadOptions,), Error = Rejection> + Clone {
+    header::optional("content-type")
+        .and(header::optional("x-castella-max-downloads"))
+        .and(header::optional("x-castella-filename"))
+        .and(header::optional("x-castella-metadata"))
+        .and(header::optional("content-md5"))
+        .and(header::optional("repr-digest"))
+        .and(header::optional("x-castella-public"))
+        .and(header::optional("x-castella-collection"))
+        .and(query())
+        .map(
+            |content_type: Option<String>,
+             max_downloads: Option<NonZeroU32>,
+             filename: Option<String>,
+             metadata: Option<Metadata>,
+             content_md5: Option<ContentMd5>,
+             repr_digest: Option<ReprDigest>,
+             public: Option<bool>,
+             collection: Option<String>,
+             query: UploadFileQuery| {
+                let mut options = UploadOptions {
+                    filename: filename.or(query.filename),
+                    max_downloads: max_downloads.map(NonZeroU32::get),
+                    metadata: metadata.map(|m| m.0).unwrap_or_default(),
+                    encryption: match query.encrypt {
+                        None | Some(EncryptQuery::True) => Encryption::Server,
+                        Some(EncryptQuery::False) => Encryption::None,
+                        Some(EncryptQuery::Client) => Encryption::Client,
+                    },
+                    public: public.or(query.public),
+                    collection: collection.or(query.collection),
+                    ..Default::default()
+                };
+
+                if let Some(ContentMd5(digest)) = content_md5 {
+                    options.digests.push(ExpectedDigest::Md5(digest));
+                }
+
+                if let Some(ReprDigest(digests)) = repr_digest {
+                    options.digests.extend(digests);
+                }
+
+                if let Some(content_type) = content_type {
+                    options.content_type = content_type;
+                }
+
+                options
+            },
+        )
+}
+
+async fn upload_file<S, B, E>(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    size: NonZeroU64,
+    mut options: UploadOptions,
+    _permit: UploadPermit,
+    content: S,
+) -> Result<reply::Response, Error>
+where
+    S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    options.namespace = namespace.to_string();
+
+    let file = store_upload(&store, client, size, options, content).await?;
+    let location = format!("/{}", file.key);
+
+    let mut res = reply::with_header(
+        reply::with_status(reply::json(&FileInfo::from(file)), StatusCode::CREATED),
+        "location",
+        location,
+    )
+    .into_response();
+
+    add_quota_header(&mut res, store.get_transfer_remaining(&namespace).await?);
+    Ok(res)
+}
+
+/// Uploads content and records the upload in the audit log.
+async fn store_upload<S, B, E>(
+    store: &Store,
+    client: Peer,
+    size: NonZeroU64,
+    options: UploadOptions,
+    content: S,
+) -> Result<File, Error>
+where
+    S: Stream<Item = Result<B, E>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut event = AuditEvent {
+        operation: "upload",
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        size: Some(size.get() as i64),
+        ..Default::default()
+    };
+
+    let (content, _body) = hold_body(content);
+
+    let result = store
+        .upload(size.get(), options, content)
+        .await
+        .map_err(Error::from);
+
+    let status = match result {
+        Ok(ref file) => {
+            event.file_key = Some(file.key);
+            event.file_id = Some(file.id.clone());
+            StatusCode::CREATED
+        }
+        Err(ref err) => err.status(),
+    };
+
+    audit_status(store, event, status).await;
+    result
+}
+
+/// Content whose dropping is deferred until the guard returned by [`hold_body`] is dropped.
+type BodyGuard<S> = Arc<std::sync::Mutex<Option<Pin<Box<S>>>>>;
+
+/// Keeps the body of a request from being dropped until the returned guard is dropped.
+///
+/// hyper drains a body that is dropped before the response is written, which sends `100 Continue` to clients that
+/// are waiting for it. Holding the body until the handler returns lets an upload that is rejected before reading its
+/// content, but whose handler still awaits e.g. the audit log, be rejected without the client sending the body.
+fn hold_body<S: Stream>(content: S) -> (HeldBody<S>, BodyGuard<S>) {
+    let guard = Arc::new(std::sync::Mutex::new(None));
+
+    let body = HeldBody {
+        content: Some(Box::pin(content)),
+        guard: guard.clone(),
+    };
+
+    (body, guard)
+}
+
+struct HeldBody<S> {
+    content: Option<Pin<Box<S>>>,
+    guard: BodyGuard<S>,
+}
+
+impl<S: Stream> Stream for HeldBody<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.content {
+            Some(ref mut content) => content.as_mut().poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl<S> Drop for HeldBody<S> {
+    fn drop(&mut self) {
+        if let Some(content) = self.content.take() {
+            *self.guard.lock().unwrap() = Some(content);
+        }
+    }
+}
+
+/// Maximum number of files of a batch that are uploaded concurrently.
+const BATCH_UPLOAD_CONCURRENCY: usize = 4;
+
+/// Result of uploading one file of a batch.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchUploadResult {
+    Ok(FileInfo),
+    Err {
+        error: bool,
+        status: u16,
+        message: String,
+    },
+}
+
+/// Uploads every file part of a form, replying with the results in the order of the parts.
+async fn upload_batch(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    options: UploadOptions,
+    _permit: UploadPermit,
+    mut form: FormData,
+) -> Result<reply::Response, Error> {
+    let mut files = Vec::new();
+
+    while let Some(part) = form.next().await {
+        let mut part = part.map_err(Error::FormInvalid)?;
+
+        if part.filename().is_none() {
+            continue;
+        }
+
+        let filename = part.filename().map(Into::into);
+        let content_type = part.content_type().map(Into::into);
+        let content = match part.data().await {
+            Some(Ok(mut content)) => content.copy_to_bytes(content.remaining()),
+            Some(Err(err)) => return Err(Error::FormInvalid(err)),
+            None => Bytes::new(),
+        };
+
+        files.push((filename, content_type, content));
+    }
+
+    if files.is_empty() {
+        return Err(Error::FormFileMissing);
+    }
+
+    let results: Vec<_> = futures::stream::iter(files)
+        .map(|(filename, content_type, content)| {
+            let store = &store;
+            let client = client.clone();
+            let options = UploadOptions {
+                content_type: content_type.unwrap_or_else(|| UploadOptions::default().content_type),
+                filename,
+                max_downloads: options.max_downloads,
+                metadata: options.metadata.clone(),
+                // digests given in headers can't apply to every file
+                digests: Vec::new(),
+                encryption: options.encryption,
+                public: options.public,
+                namespace: namespace.to_string(),
+                collection: options.collection.clone(),
+            };
+
+            async move {
+                let result = async {
+                    let size =
+                        NonZeroU64::new(content.len() as u64).ok_or(Error::FormFileMissing)?;
+                    let content = futures::stream::once(async { Ok::<_, warp::Error>(content) });
+
+                    store_upload(store, client, size, options, content).await
+                }
+                .await;
+
+                match result {
+                    Ok(file) => BatchUploadResult::Ok(FileInfo::from(file)),
+                    Err(err) => BatchUploadResult::Err {
+                        error: true,
+                        status: err.status().as_u16(),
+                        message: err.to_string(),
+                    },
+                }
+            }
+        })
+        .buffered(BATCH_UPLOAD_CONCURRENCY)
+        .collect()
+        .await;
+
+    Ok(reply::json(&results).into_response())
+}
+
+/// Uploads a body of unknown length after buffering it to a temporary file.
+#[allow(clippy::too_many_arguments)]
+async fn upload_buffered<S, B>(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    buffer_path: Option<Arc<PathBuf>>,
+    max_upload_size: u64,
+    options: UploadOptions,
+    permit: UploadPermit,
+    content: S,
+) -> Result<reply::Response, Error>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+{
+    let buffer_path = buffer_path.ok_or(Error::LengthRequired)?;
+    let (size, file) = buffer_body(&buffer_path, content, max_upload_size).await?;
+
+    upload_file(
+        namespace,
+        store,
+        client,
+        size,
+        options,
+        permit,
+        ReaderStream::new(file),
+    )
+    .await
+}
+
+/// Writes a body to an anonymous temporary file, returning its size and the file rewound to the start.
+async fn buffer_body<S, B>(
+    path: &Path,
+    content: S,
+    limit: u64,
+) -> Result<(NonZeroU64, tokio::fs::File), Error>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+{
+    let temp = path.join(format!("{:016x}.tmp", thread_rng().gen::<u64>()));
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .await
+        .map_err(Error::BodyBuffer)?;
+
+    // unlinked so that the file is deleted once the handle is dropped
+    tokio::fs::remove_file(&temp)
+        .await
+        .map_err(Error::BodyBuffer)?;
+
+    let mut content = Box::pin(content);
+    let mut size = 0;
+
+    while let Some(buf) = content.next().await {
+        let mut buf = buf.map_err(Error::Body)?;
+
+        size += buf.remaining() as u64;
+        if size > limit {
+            return Err(Error::BodyTooLarge);
+        }
+
+        while buf.has_remaining() {
+            let chunk = buf.chunk();
+            let len = chunk.len();
+
+            file.write_all(chunk).await.map_err(Error::BodyBuffer)?;
+            buf.advance(len);
+        }
+    }
+
+    file.flush().await.map_err(Error::BodyBuffer)?;
+    file.seek(SeekFrom::Start(0))
+        .await
+        .map_err(Error::BodyBuffer)?;
+
+    trace!("buffered request body of size {size}");
+    Ok((NonZeroU64::new(size).ok_or(Error::BodyEmpty)?, file))
+}
+
+/// Downloads content from a url and stores it as an upload.
+async fn fetch_file(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    fetcher: Option<Arc<Fetcher>>,
+    mut options: UploadOptions,
+    permit: UploadPermit,
+    request: FetchRequest,
+) -> Result<reply::Response, Error> {
+    let fetcher = fetcher.ok_or(Error::FetchDisabled)?;
+    let source = fetcher.fetch(&request.url).await?;
+    let size = NonZeroU64::new(source.size).ok_or(crate::fetch::Error::Empty)?;
+
+    // content type of the request is that of the json body
+    options.content_type = request
+        .content_type
+        .or(source.content_type)
+        .unwrap_or_else(|| UploadOptions::default().content_type);
+
+    options.filename = request.filename.or(options.filename).or(source.filename);
+
+    upload_file(
+        namespace,
+        store,
+        client,
+        size,
+        options,
+        permit,
+        source.content,
+    )
+    .await
+}
+
+/// Matches requests depending on whether the body is `multipart/form-data`.
+fn form_body(form: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    header::optional("content-type")
+        .and_then(move |content_type: Option<String>| async move {
+            let is_form = content_type.is_some_and(|value| {
+                value
+                    .to_ascii_lowercase()
+                    .starts_with("multipart/form-data")
+            });
+
+            if is_form == form {
+                Ok(())
+            } else {
+                Err(reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Uploads the first file part of a form, using its filename and content type unless they are given by the request.
+async fn upload_form(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    mut options: UploadOptions,
+    permit: UploadPermit,
+    mut form: FormData,
+) -> Result<reply::Response, Error> {
+    let mut part = loop {
+        match form.next().await {
+            Some(Ok(part)) if part.filename().is_some() => break part,
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(Error::FormInvalid(err)),
+            None => return Err(Error::FormFileMissing),
+        }
+    };
+
+    // content type of the request is that of the form
+    options.content_type = part
+        .content_type()
+        .unwrap_or(&UploadOptions::default().content_type)
+        .into();
+
+    if options.filename.is_none() {
+        options.filename = part.filename().map(Into::into);
+    }
+
+    let mut content = match part.data().await {
+        Some(Ok(content)) => content,
+        Some(Err(err)) => return Err(Error::FormInvalid(err)),
+        None => return Err(Error::FormFileMissing),
+    };
+
+    let size = NonZeroU64::new(content.remaining() as u64).ok_or(Error::FormFileMissing)?;
+    let content = content.copy_to_bytes(content.remaining());
+
+    upload_file(
+        namespace,
+        store,
+        client,
+        size,
+        options,
+        permit,
+        futures::stream::once(async { Ok::<_, warp::Error>(content) }),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn replace_file<S, B>(
+    key: i64,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    size: NonZeroU64,
+    mut options: UploadOptions,
+    _permit: UploadPermit,
+    content: S,
+) -> Result<reply::Response, Error>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+{
+    let mut event = AuditEvent {
+        operation: "replace",
+        file_key: Some(key),
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        size: Some(size.get() as i64),
+        ..Default::default()
+    };
+
+    let (content, _body) = hold_body(content);
+
+    let result = async {
+        check_namespace(&store, key, &namespace).await?;
+        options.namespace = namespace.to_string();
+
+        let file = store
+            .replace(key, size.get(), options, content)
+            .await?
+            .ok_or(Error::FileNotExists)?;
+
+        event.file_id = Some(file.id.clone());
+
+        let mut res = reply::json(&FileInfo::from(file)).into_response();
+        add_quota_header(&mut res, store.get_transfer_remaining(&namespace).await?);
+        Ok(res)
+    }
+    .await;
+
+    audit(&store, event, &result).await;
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn append_file<S, B>(
+    key: i64,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    size: NonZeroU64,
+    content_range: ContentRange,
+    _permit: UploadPermit,
+    content: S,
+) -> Result<reply::Response, Error>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+{
+    let mut event = AuditEvent {
+        operation: "append",
+        file_key: Some(key),
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        size: Some(size.get() as i64),
+        range_start: Some(content_range.range.start as i64),
+        range_end: Some(content_range.range.end as i64),
+        ..Default::default()
+    };
+
+    let (content, _body) = hold_body(content);
+
+    let result = async {
+        let ContentRange { range, length } = content_range;
+
+        if range.end - range.start != size.get() || length.is_some_and(|n| n != range.end) {
+            return Err(Error::ContentRangeInvalid);
+        }
+
+        check_namespace(&store, key, &namespace).await?;
+
+        let file = store
+            .append(key, range.start, size.get(), content)
+            .await?
+            .ok_or(Error::FileNotExists)?;
+
+        event.file_id = Some(file.id.clone());
+
+        let mut res = reply::json(&FileInfo::from(file)).into_response();
+        add_quota_header(&mut res, store.get_transfer_remaining(&namespace).await?);
+        Ok(res)
+    }
+    .await;
+
+    audit(&store, event, &result).await;
+    result
+}
+
+async fn update_file(
+    key: i64,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    request: UpdateFileRequest,
+) -> Result<reply::Response, Error> {
+    let mut event = AuditEvent {
+        operation: "update",
+        file_key: Some(key),
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
+
+    let result = async {
+        check_namespace(&store, key, &namespace).await?;
+
+        let file = match request.public {
+            Some(public) => store.set_public(key, public).await?,
+            None => store.get_info(key).await?,
+        }
+        .ok_or(Error::FileNotExists)?;
+
+        event.file_id = Some(file.id.clone());
+
+        Ok(reply::json(&FileInfo::from(file)).into_response())
+    }
+    .await;
+
+    audit(&store, event, &result).await;
+    result
+}
+
+async fn delete_file(
+    key: i64,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+) -> Result<reply::Response, Error> {
+    let mut event = AuditEvent {
+        operation: "delete",
+        file_key: Some(key),
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
+
+    let result = async {
+        check_namespace(&store, key, &namespace).await?;
+
+        let file = store.delete(key).await?.ok_or(Error::FileNotExists)?;
+
+        event.file_id = Some(file.id);
+        event.size = Some(file.size);
+
+        #[derive(Serialize)]
+        struct Response {
+            deleted: bool,
+        }
+
+        Ok(reply::json(&Response { deleted: true }).into_response())
+    }
+    .await;
+
+    audit(&store, event, &result).await;
+    result
+}
+
+async fn verify_file(
+    key: i64,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+) -> Result<reply::Response, Error> {
+    let event = AuditEvent {
+        operation: "verify",
+        file_key: Some(key),
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
+
+    let result = async {
+        check_namespace(&store, key, &namespace).await?;
+
+        let report = store.verify(key, None).await?.ok_or(Error::FileNotExists)?;
+
+        Ok(reply::json(&report).into_response())
+    }
+    .await;
+
+    audit(&store, event, &result).await;
+    result
+}
+
+async fn sign_url(
+    key: i64,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    signer: Option<Arc<UrlSigner>>,
+    query: SignUrlQuery,
+) -> Result<impl Reply, Error> {
+    #[derive(Serialize)]
+    struct SignedUrl {
+        url: String,
+        expires: DateTime<Utc>,
+    }
+
+    let signer = signer.ok_or(Error::SigningDisabled)?;
+
+    // don't hand out urls for nonexistent files or files of other namespaces
+    check_namespace(&store, key, &namespace).await?;
+
+    let ttl = query
+        .ttl
+        .unwrap_or(DEFAULT_SIGNED_URL_TTL)
+        .min(MAX_SIGNED_URL_TTL);
+
+    let expires = Utc::now() + chrono::Duration::seconds(ttl as i64);
+    let signature = signer.sign(key, expires.timestamp());
+
+    Ok(reply::json(&SignedUrl {
+        url: format!("/{key}?expires={}&sig={signature}", expires.timestamp()),
+        expires,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct DriveInfo {
+    key: i32,
+    id: String,
+    created_time: DateTime<Utc>,
+    file_count: u64,
+    stored_size: u64,
+    health: DriveHealth,
+}
+
+impl From<DriveReport> for DriveInfo {
+    fn from(report: DriveReport) -> Self {
+        Self {
+            key: report.key,
+            id: report.id,
+            created_time: DateTime::from_utc(report.created_time, Utc),
+            file_count: report.file_count,
+            stored_size: report.stored_size,
+            health: report.health,
+        }
+    }
+}
+
+async fn list_drives(namespace: Arc<str>, store: Arc<Store>) -> Result<impl Reply, Error> {
+    // drives are shared by all namespaces
+    require_default_namespace(&namespace)?;
+
+    let drives = store.get_drive_reports().await?;
+
+    Ok(reply::json(
+        &drives.into_iter().map(DriveInfo::from).collect::<Vec<_>>(),
+    ))
+}
+
+async fn get_audit_log(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    query: AuditQuery,
+) -> Result<impl Reply, Error> {
+    // entries aren't attributed to namespaces
+    require_default_namespace(&namespace)?;
+
+    Ok(reply::json(&store.get_audit_log(&query).await?))
+}
+
+/// Lists files of a namespace, optionally only those in a collection.
+/// Full pages are returned with a cursor for the next page in the `x-castella-next-cursor` header.
+async fn query_files(
+    store: &Store,
+    namespace: &str,
+    collection_key: Option<i32>,
+    query: ListFilesQuery,
+) -> Result<reply::Response, Error> {
+    let metadata = match query.metadata {
+        Some(ref metadata) => {
+            Some(serde_json::from_str(metadata).map_err(|_| Error::MetadataInvalid)?)
+        }
+        None => None,
+    };
+
+    let cursor = match query.cursor {
+        Some(ref cursor) => Some(cursor.parse().map_err(|_| Error::CursorInvalid)?),
+        None => None,
+    };
+
+    let query = FileQuery {
+        metadata,
+        before: query.before,
+        limit: query.limit,
+        namespace: Some(namespace.into()),
+        collection_key,
+        content_type: query.content_type,
+        filename: query.filename,
+        tags: query
+            .tags
+            .iter()
+            .flat_map(|tags| tags.split(','))
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(Into::into)
+            .collect(),
+        min_size: query.min_size,
+        max_size: query.max_size,
+        created_after: query.created_after.map(|time| time.naive_utc()),
+        created_before: query.created_before.map(|time| time.naive_utc()),
+        accessed_after: query.accessed_after.map(|time| time.naive_utc()),
+        accessed_before: query.accessed_before.map(|time| time.naive_utc()),
+        sort: query.sort,
+        ascending: query.ascending,
+        cursor,
+    };
+
+    let files = store.get_files(&query).await?;
+    let next_cursor = match files.last() {
+        Some(file) if files.len() == query.limit() as usize => {
+            Some(FileCursor::new(file, query.sort).to_string())
+        }
+        _ => None,
+    };
+
+    let res = reply::json(&files.into_iter().map(FileInfo::from).collect::<Vec<_>>());
+
+    Ok(match next_cursor {
+        Some(cursor) => reply::with_header(res, "x-castella-next-cursor", cursor).into_response(),
+        None => res.into_response(),
+    })
+}
+
+async fn list_files(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    query: ListFilesQuery,
+) -> Result<impl Reply, Error> {
+    query_files(&store, &namespace, None, query).await
+}
+
+async fn get_storage_stats(namespace: Arc<str>, store: Arc<Store>) -> Result<impl Reply, Error> {
+    // statistics cover all namespaces
+    require_default_namespace(&namespace)?;
+
+    Ok(reply::json(&store.get_storage_stats().await?))
+}
+
+/// Lists the most or least downloaded files.
+async fn get_file_stats(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    query: FileStatsQuery,
+) -> Result<impl Reply, Error> {
+    let files = store
+        .get_files_by_downloads(
+            query.ascending,
+            query.limit.unwrap_or(100).min(1000),
+            Some(&namespace),
+        )
+        .await?;
+
+    Ok(reply::json(
+        &files.into_iter().map(FileInfo::from).collect::<Vec<_>>(),
+    ))
+}
+
+/// Returns the name of an alias given as the rest of the path,
+/// which consists of segments of letters, digits, '-', '_' and '.'.
+fn parse_alias(name: &str) -> Result<&str, Error> {
+    if name.is_empty() || name.len() > MAX_ALIAS_LEN {
+        return Err(Error::AliasInvalid("alias must be between 1 and 256 bytes"));
+    }
+
+    let valid = name.split('/').all(|segment| {
+        !matches!(segment, "" | "." | "..")
+            && segment
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'))
+    });
+
+    if valid {
+        Ok(name)
+    } else {
+        Err(Error::AliasInvalid(
+            "alias must consist of segments of letters, digits, '-', '_' and '.'",
+        ))
+    }
+}
+
+async fn resolve_alias(store: &Store, name: &path::Tail) -> Result<i64, Error> {
+    let alias = store
+        .get_alias(parse_alias(name.as_str())?)
+        .await?
+        .ok_or(Error::AliasNotExists)?;
+
+    record_key(alias.file_key);
+    Ok(alias.file_key)
+}
+
+/// Cache control of files downloaded by an alias, which may be pointed to another file.
+const ALIAS_CACHE_CONTROL: &str = "no-cache";
+
+fn set_alias_cache_control(mut res: reply::Response) -> reply::Response {
+    res.headers_mut().insert(
+        "cache-control",
+        HeaderValue::from_static(ALIAS_CACHE_CONTROL),
+    );
+
+    res
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn get_alias_file(
+    name: path::Tail,
+    access: ReadAccess,
+    store: Arc<Store>,
+    client: Peer,
+    limiter: Option<Arc<KeyedConcurrencyLimiter<IpAddr>>>,
+    images: Option<Arc<ImageTransformer>>,
+    range: Option<String>,
+    if_none_match: Option<String>,
+    query: GetFileQuery,
+) -> Result<reply::Response, Error> {
+    let key = resolve_alias(&store, &name).await?;
+    let res = get_file(
+        key,
+        access,
+        store,
+        client,
+        limiter,
+        images,
+        range,
+        if_none_match,
+        query,
+    )
+    .await?;
+    Ok(set_alias_cache_control(res))
+}
+
+async fn head_alias_file(
+    name: path::Tail,
+    access: ReadAccess,
+    store: Arc<Store>,
+) -> Result<reply::Response, Error> {
+    let key = resolve_alias(&store, &name).await?;
+    let res = head_file(key, access, store).await?.into_response();
+    Ok(set_alias_cache_control(res))
+}
+
+#[derive(Debug, Serialize)]
+struct AliasInfo {
+    name: String,
+    key: i64,
+    created_time: DateTime<Utc>,
+}
+
+impl From<Alias> for AliasInfo {
+    fn from(alias: Alias) -> Self {
+        Self {
+            name: alias.name,
+            key: alias.file_key,
+            created_time: DateTime::from_utc(alias.created_time, Utc),
+        }
+    }
+}
+
+/// Fails unless the alias doesn't exist or points to a file in the namespace of the client,
+/// so that clients can't repoint or delete aliases of other namespaces.
+async fn check_alias_namespace(store: &Store, name: &str, namespace: &str) -> Result<(), Error> {
+    match store.get_alias(name).await? {
+        Some(alias) => match check_namespace(store, alias.file_key, namespace).await {
+            Err(Error::FileNotExists) => Err(Error::AliasTaken),
+            result => result,
+        },
+        None => Ok(()),
+    }
+}
+
+async fn set_alias(
+    name: path::Tail,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    request: SetAliasRequest,
+) -> Result<reply::Response, Error> {
+    let mut event = AuditEvent {
+        operation: "alias",
+        file_key: Some(request.key),
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
+
+    let result = async {
+        let name = parse_alias(name.as_str())?;
+
+        check_namespace(&store, request.key, &namespace).await?;
+        check_alias_namespace(&store, name, &namespace).await?;
+
+        let alias = store.set_alias(name, request.key).await?;
+        let file = store.get_info(alias.file_key).await?;
+
+        event.file_id = file.map(|file| file.id);
+
+        Ok(reply::json(&AliasInfo::from(alias)).into_response())
+    }
+    .await;
+
+    audit(&store, event, &result).await;
+    result
+}
+
+async fn delete_alias(
+    name: path::Tail,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+) -> Result<reply::Response, Error> {
+    let mut event = AuditEvent {
+        operation: "unalias",
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
+
+    let result = async {
+        let name = parse_alias(name.as_str())?;
+
+        // aliases of other namespaces are reported as nonexistent
+        match check_alias_namespace(&store, name, &namespace).await {
+            Err(Error::AliasTaken) => return Err(Error::AliasNotExists),
+            result => result?,
+        }
+
+        let alias = store
+            .delete_alias(name)
+            .await?
+            .ok_or(Error::AliasNotExists)?;
+
+        event.file_key = Some(alias.file_key);
+
+        Ok(reply::json(&AliasInfo::from(alias)).into_response())
+    }
+    .await;
+
+    audit(&store, event, &result).await;
+    result
+}
+
+/// Outcome of verifying the signature of a request of the S3-compatible api.
+type S3Auth = Result<Verified, crate::s3::Error>;
+
+/// Fails unless the request is signed with credentials that grant the scope.
+fn s3_authorize(auth: S3Auth, scope: Scope) -> Result<Verified, Error> {
+    let verified = auth?;
+
+    if verified.client.scope >= scope {
+        Ok(verified)
+    } else {
+        Err(crate::s3::Error::AccessDenied.into())
+    }
+}
+
+/// Returns the bucket and key of an object given as the bucket segment and the rest of the path.
+fn parse_s3_object(bucket: &str, key: &path::Tail) -> Result<(String, String), Error> {
+    let bucket = parse_bucket(bucket).ok_or(Error::BucketInvalid)?;
+
+    // requests without an object key are operations on the bucket
+    if key.as_str().is_empty() {
+        return Err(Error::OperationUnsupported);
+    }
+
+    let key = parse_object_key(key.as_str()).ok_or(Error::ObjectKeyInvalid)?;
+    Ok((bucket, key))
+}
+
+/// Returns the file holding the content of an object in the namespace of the client.
+async fn resolve_s3_object(
+    store: &Store,
+    namespace: &str,
+    bucket: &str,
+    key: &path::Tail,
+) -> Result<File, Error> {
+    let (bucket, name) = parse_s3_object(bucket, key)?;
+
+    let object = store
+        .get_s3_object(namespace, &bucket, &name)
+        .await?
+        .ok_or(Error::FileNotExists)?;
+
+    store
+        .get_info(object.file_key)
+        .await?
+        .ok_or(Error::FileNotExists)
+}
+
+/// Returns the user metadata of an object given in `x-amz-meta-*` headers.
+fn parse_s3_metadata(headers: &HeaderMap) -> Result<Map<String, Value>, Error> {
+    let metadata: Map<String, Value> = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.as_str().strip_prefix("x-amz-meta-")?;
+            Some((name.into(), value.to_str().ok()?.into()))
+        })
+        .collect();
+
+    match serde_json::to_vec(&metadata) {
+        Ok(json) if json.len() <= MAX_METADATA_SIZE => Ok(metadata),
+        _ => Err(Error::ObjectMetadataTooLarge),
+    }
+}
+
+/// Adds the user metadata of an object to a response serving its file.
+fn add_s3_object_headers(mut res: reply::Response, file: &File) -> reply::Response {
+    if let Some(metadata) = file.metadata.as_object() {
+        for (name, value) in metadata {
+            let name = HeaderName::from_bytes(format!("x-amz-meta-{name}").as_bytes());
+            let value = value.as_str().map(HeaderValue::from_str);
+
+            if let (Ok(name), Some(Ok(value))) = (name, value) {
+                res.headers_mut().insert(name, value);
+            }
+        }
+    }
+
+    // objects can be overwritten like aliases
+    set_alias_cache_control(res)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn s3_get_object(
+    bucket: String,
+    key: path::Tail,
+    auth: S3Auth,
+    store: Arc<Store>,
+    client: Peer,
+    limiter: Option<Arc<KeyedConcurrencyLimiter<IpAddr>>>,
+    range: Option<String>,
+    if_none_match: Option<String>,
+) -> Result<reply::Response, Error> {
+    let namespace = s3_authorize(auth, Scope::Read)?.client.namespace;
+    let file = resolve_s3_object(&store, &namespace, &bucket, &key).await?;
+
+    let access = ReadAccess {
+        namespace: Some(namespace),
+        signed: false,
+        public_by_default: false,
+    };
+
+    let query = GetFileQuery::default();
+    record_key(file.key);
+
+    let res = get_file(
+        file.key,
+        access,
+        store,
+        client,
+        limiter,
+        None,
+        range,
+        if_none_match,
+        query,
+    )
+    .await?;
+    Ok(add_s3_object_headers(res, &file))
+}
+
+async fn s3_head_object(
+    bucket: String,
+    key: path::Tail,
+    auth: S3Auth,
+    store: Arc<Store>,
+) -> Result<reply::Response, Error> {
+    let namespace = s3_authorize(auth, Scope::Read)?.client.namespace;
+    let file = resolve_s3_object(&store, &namespace, &bucket, &key).await?;
+
+    let res = add_file_headers(reply(), &file, file.size as u64);
+    Ok(add_s3_object_headers(res, &file))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn s3_put_object<S, B>(
+    bucket: String,
+    key: path::Tail,
+    auth: S3Auth,
+    max_upload_size: u64,
+    store: Arc<Store>,
+    client: Peer,
+    length: Option<u64>,
+    headers: HeaderMap,
+    _permit: UploadPermit,
+    content: S,
+) -> Result<reply::Response, Error>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+{
+    let Verified {
+        client: Client { namespace, .. },
+        payload_sha256,
+    } = s3_authorize(auth, Scope::Write)?;
+
+    let (bucket, name) = parse_s3_object(&bucket, &key)?;
+
+    // content of files can't be empty
+    let size = NonZeroU64::new(length.ok_or(Error::LengthRequired)?).ok_or(Error::BodyEmpty)?;
+
+    if size.get() > max_upload_size {
+        return Err(Error::BodyTooLarge);
+    }
+
+    let mut options = UploadOptions {
+        metadata: parse_s3_metadata(&headers)?,
+        namespace: namespace.to_string(),
+        ..Default::default()
+    };
+
+    if let Some(content_type) = headers
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+    {
+        options.content_type = content_type.into();
+    }
+
+    if let Some(ContentMd5(digest)) = headers
+        .get("content-md5")
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+    {
+        options.digests.push(ExpectedDigest::Md5(digest));
+    }
+
+    if let Some(digest) = payload_sha256 {
+        options.digests.push(ExpectedDigest::Sha256(digest));
+    }
+
+    let file = store_upload(&store, client, size, options, content).await?;
+
+    store
+        .set_s3_object(&namespace, &bucket, &name, file.key)
+        .await?;
+
+    Ok(
+        reply::with_header(reply(), "etag", format!("\"{}\"", get_file_etag(&file)))
+            .into_response(),
+    )
+}
+
+async fn s3_delete_object(
+    bucket: String,
+    key: path::Tail,
+    auth: S3Auth,
+    store: Arc<Store>,
+    client: Peer,
+) -> Result<reply::Response, Error> {
+    let namespace = s3_authorize(auth, Scope::Write)?.client.namespace;
+    let (bucket, name) = parse_s3_object(&bucket, &key)?;
+
+    let mut event = AuditEvent {
+        operation: "delete",
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
+
+    let result = async {
+        // deleting an object that doesn't exist succeeds like in S3
+        if let Some(file) = store.delete_s3_object(&namespace, &bucket, &name).await? {
+            event.file_key = Some(file.key);
+            event.file_id = Some(file.id);
+            event.size = Some(file.size);
+        }
+
+        Ok(reply::with_status(reply(), StatusCode::NO_CONTENT).into_response())
+    }
+    .await;
+
+    audit(&store, event, &result).await;
+    result
+}
+
+/// Maximum number of aliases fetched at once to list or delete a WebDAV collection.
+const MAX_DAV_ENTRIES: u32 = 10000;
+
+/// Returns the alias named by the rest of a WebDAV path, or an empty string for the root collection.
+fn parse_dav_path(path: &path::Tail) -> Result<&str, Error> {
+    match path.as_str().trim_end_matches('/') {
+        "" => Ok(""),
+        name => parse_alias(name),
+    }
+}
+
+/// Returns the file that an alias points to, if it is in the namespace of the client.
+async fn resolve_dav_file(
+    store: &Store,
+    name: &str,
+    namespace: &str,
+) -> Result<Option<File>, Error> {
+    if name.is_empty() {
+        return Ok(None);
+    }
+
+    match store.get_alias(name).await? {
+        Some(alias) => Ok(store
+            .get_info(alias.file_key)
+            .await?
+            .filter(|file| file.namespace == namespace)),
+        None => Ok(None),
+    }
+}
+
+fn dav_file_resource(name: &str, file: &File) -> Resource {
+    Resource {
+        href: format!("/dav/{name}"),
+        created_time: Some(file.created_time),
+        file: Some(FileProps {
+            length: file.size,
+            content_type: file.content_type.clone(),
+            etag: get_file_etag(file),
+        }),
+    }
+}
+
+fn dav_collection_resource(prefix: &str) -> Resource {
+    Resource {
+        href: format!("/dav/{prefix}"),
+        created_time: None,
+        file: None,
+    }
+}
+
+async fn dav_propfind(
+    path: path::Tail,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    depth: Option<String>,
+) -> Result<reply::Response, Error> {
+    let depth = parse_depth(depth.as_deref()).ok_or(Error::DepthUnsupported)?;
+    let name = parse_dav_path(&path)?;
+
+    let resources = match resolve_dav_file(&store, name, &namespace).await? {
+        Some(file) => vec![dav_file_resource(name, &file)],
+        None => {
+            // collections are the prefixes of aliases, along with the collections of the namespace at the root
+            let prefix = match name {
+                "" => String::new(),
+                name => format!("{name}/"),
+            };
+
+            let limit = match depth {
+                Depth::Zero => 1,
+                Depth::One => MAX_DAV_ENTRIES,
+            };
+
+            let files = store.get_aliased_files(&prefix, &namespace, limit).await?;
+            let exists = name.is_empty()
+                || !files.is_empty()
+                || (!name.contains('/') && store.get_collection(name, &namespace).await?.is_some());
+
+            if !exists {
+                return Err(Error::FileNotExists);
+            }
+
+            let mut resources = vec![dav_collection_resource(&prefix)];
+
+            if depth == Depth::One {
+                let mut children = BTreeSet::new();
+
+                if name.is_empty() {
+                    for collection in store.get_collections(&namespace).await? {
+                        children.insert(collection.name);
+                    }
+                }
+
+                for (alias, file) in &files {
+                    match alias[prefix.len()..].split_once('/') {
+                        Some((child, _)) => {
+                            children.insert(child.into());
+                        }
+                        None => resources.push(dav_file_resource(alias, file)),
+                    }
+                }
+
+                for child in children {
+                    resources.push(dav_collection_resource(&format!("{prefix}{child}/")));
+                }
+            }
+
+            resources
+        }
+    };
+
+    Ok(reply::with_header(
+        reply::with_status(multistatus(&resources), StatusCode::MULTI_STATUS),
+        "content-type",
+        "application/xml; charset=utf-8",
+    )
+    .into_response())
+}
+
+async fn dav_get_file(
+    path: path::Tail,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    limiter: Option<Arc<KeyedConcurrencyLimiter<IpAddr>>>,
+    range: Option<String>,
+    if_none_match: Option<String>,
+) -> Result<reply::Response, Error> {
+    let name = parse_dav_path(&path)?;
+    let file = resolve_dav_file(&store, name, &namespace)
+        .await?
+        .ok_or(Error::FileNotExists)?;
+
+    let access = ReadAccess {
+        namespace: Some(namespace),
+        signed: false,
+        public_by_default: false,
+    };
+
+    let query = GetFileQuery::default();
+    record_key(file.key);
+
+    let res = get_file(
+        file.key,
+        access,
+        store,
+        client,
+        limiter,
+        None,
+        range,
+        if_none_match,
+        query,
+    )
+    .await?;
+    Ok(set_alias_cache_control(res))
+}
+
+async fn dav_head_file(
+    path: path::Tail,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+) -> Result<reply::Response, Error> {
+    let name = parse_dav_path(&path)?;
+    let file = resolve_dav_file(&store, name, &namespace)
+        .await?
+        .ok_or(Error::FileNotExists)?;
+
+    let access = ReadAccess {
+        namespace: Some(namespace),
+        signed: false,
+        public_by_default: false,
+    };
+
+    let res = head_file(file.key, access, store).await?.into_response();
+    Ok(set_alias_cache_control(res))
+}
+
+/// Uploads a file to an alias, replacing the file that the alias pointed to.
+#[allow(clippy::too_many_arguments)]
+async fn dav_put_file<S, B>(
+    path: path::Tail,
+    namespace: Arc<str>,
+    max_upload_size: u64,
+    store: Arc<Store>,
+    client: Peer,
+    buffer_path: Option<Arc<PathBuf>>,
+    length: Option<u64>,
+    content_type: Option<String>,
+    _permit: UploadPermit,
+    content: S,
+) -> Result<reply::Response, Error>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + Sync + 'static,
+    B: Buf + Send + Sync + 'static,
+{
+    let name = parse_alias(path.as_str())?;
+
+    let previous = match store.get_alias(name).await? {
+        Some(alias) => match check_namespace(&store, alias.file_key, &namespace).await {
+            Err(Error::FileNotExists) => return Err(Error::AliasTaken),
+            result => result.map(|_| Some(alias.file_key))?,
+        },
+        None => None,
+    };
+
+    // files under a directory named after a collection are uploaded into the collection
+    let collection = match name.split_once('/') {
+        Some((directory, _)) => store
+            .get_collection(directory, &namespace)
+            .await?
+            .map(|collection| collection.name),
+        None => None,
+    };
+
+    let mut options = UploadOptions {
+        filename: name.rsplit('/').next().map(Into::into),
+        namespace: namespace.to_string(),
+        collection,
+        ..Default::default()
+    };
+
+    if let Some(content_type) = content_type {
+        options.content_type = content_type;
+    }
+
+    // clients mounting a drive often upload without a content length
+    let file = match length {
+        Some(length) => {
+            let size = NonZeroU64::new(length).ok_or(Error::BodyEmpty)?;
 
-    #[error("no such file")]
-    FileNotExists,
+            if size.get() > max_upload_size {
+                return Err(Error::BodyTooLarge);
+            }
+
+            store_upload(&store, client.clone(), size, options, content).await?
+        }
+        None => {
+            let buffer_path = buffer_path.ok_or(Error::LengthRequired)?;
+            let (size, buffer) = buffer_body(&buffer_path, content, max_upload_size).await?;
+            let content = ReaderStream::new(buffer);
+
+            store_upload(&store, client.clone(), size, options, content).await?
+        }
+    };
+
+    store.set_alias(name, file.key).await?;
+
+    match previous {
+        Some(key) => {
+            // replaced files are no longer reachable through webdav
+            if key != file.key {
+                delete_dav_file(&store, key, &client).await?;
+            }
+
+            Ok(reply::with_status(reply(), StatusCode::NO_CONTENT).into_response())
+        }
+        None => Ok(reply::with_status(reply(), StatusCode::CREATED).into_response()),
+    }
 }
 
-#[derive(Debug)]
-pub struct ServerConfig {
-    pub store: Arc<Store>,
-    pub max_upload_size: u64,
+/// Deletes the file of an alias, or every file under a collection.
+async fn dav_delete(
+    path: path::Tail,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+) -> Result<reply::Response, Error> {
+    // the root collection can't be deleted
+    let name = parse_alias(path.as_str().trim_end_matches('/'))?;
+
+    if let Some(file) = resolve_dav_file(&store, name, &namespace).await? {
+        delete_dav_file(&store, file.key, &client).await?;
+        return Ok(reply::with_status(reply(), StatusCode::NO_CONTENT).into_response());
+    }
+
+    let prefix = format!("{name}/");
+    let mut deleted = false;
+
+    loop {
+        let files = store
+            .get_aliased_files(&prefix, &namespace, MAX_DAV_ENTRIES)
+            .await?;
+
+        if files.is_empty() {
+            break;
+        }
+
+        for (_, file) in files {
+            delete_dav_file(&store, file.key, &client).await?;
+        }
+
+        deleted = true;
+    }
+
+    if deleted {
+        Ok(reply::with_status(reply(), StatusCode::NO_CONTENT).into_response())
+    } else {
+        Err(Error::FileNotExists)
+    }
 }
 
-pub fn routes(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
-    let ServerConfig {
-        store,
-        max_upload_size,
-    } = config;
+/// Deletes a file along with its aliases and records the deletion in the audit log.
+async fn delete_dav_file(store: &Store, key: i64, client: &Peer) -> Result<(), Error> {
+    let mut event = AuditEvent {
+        operation: "delete",
+        file_key: Some(key),
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
 
-    let store = any().map(move || store.clone());
-    let get_root = get().and(path!()).map(get_root).boxed();
+    let result = store.delete(key).await.map_err(Error::from);
 
-    // HEAD /$id
-    let head_file = head()
-        .and(path!(i32))
-        .and(store.clone())
-        .then(head_file)
-        .map(handle_result)
-        .boxed();
+    let status = match result {
+        Ok(Some(ref file)) => {
+            event.file_id = Some(file.id.clone());
+            event.size = Some(file.size);
+            StatusCode::NO_CONTENT
+        }
+        Ok(None) => StatusCode::NOT_FOUND,
+        Err(ref err) => err.status(),
+    };
 
-    // GET /$id
-    let get_file = get()
-        .and(path!(i32))
-        .and(store.clone())
-        .and(header::optional("range"))
-        .then(get_file)
-        .map(handle_result)
-        .boxed();
+    audit_status(store, event, status).await;
+    result.map(|_| ())
+}
 
-    // POST /
-    let upload_file = post()
-        .and(path!())
-        .and(body::content_length_limit(max_upload_size))
-        .and(store.clone())
-        .and(header("content-length"))
-        .and(header::optional("content-type"))
-        .and(body::stream())
-        .then(upload_file)
-        .map(handle_result)
-        .boxed();
+#[derive(Debug, Serialize)]
+struct UserInfo {
+    key: i32,
+    name: String,
+    role: String,
+    namespace: String,
+    created_time: DateTime<Utc>,
+    /// Token of the user, only returned when it is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
 
-    // DELETE /$id
-    let delete_file = delete()
-        .and(path!(i32))
-        .and(store.clone())
-        .then(delete_file)
-        .map(handle_result)
-        .boxed();
+impl From<User> for UserInfo {
+    fn from(user: User) -> Self {
+        Self {
+            key: user.key,
+            name: user.name,
+            role: user.role,
+            namespace: user.namespace,
+            created_time: DateTime::from_utc(user.created_time, Utc),
+            token: None,
+        }
+    }
+}
 
-    let routes = get_root
-        .or(get_file)
-        .or(head_file)
-        .or(upload_file)
-        .or(delete_file);
+/// Finds files in the namespace of the client, for clients that can't use admin endpoints.
+async fn search_files(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    query: ListFilesQuery,
+) -> Result<impl Reply, Error> {
+    query_files(&store, &namespace, None, query).await
+}
 
-    routes
-        .map(|reply| reply::with_header(reply, "server", "castella"))
-        .recover(recover)
-        .boxed()
+#[derive(Debug, Serialize)]
+struct CollectionInfo {
+    name: String,
+    created_time: DateTime<Utc>,
 }
 
-fn get_root() -> impl Reply {
-    "castella file server"
+impl From<Collection> for CollectionInfo {
+    fn from(collection: Collection) -> Self {
+        Self {
+            name: collection.name,
+            created_time: DateTime::from_utc(collection.created_time, Utc),
+        }
+    }
 }
 
-const FILE_CACHE_CONTROL: &str = "public,max-age=31536000,immutable";
+async fn list_collections(namespace: Arc<str>, store: Arc<Store>) -> Result<impl Reply, Error> {
+    let collections = store.get_collections(&namespace).await?;
 
-fn get_file_etag(file: &File) -> String {
-    base64::encode_config(Sha256::digest(&file.id), base64::URL_SAFE_NO_PAD)
+    Ok(reply::json(
+        &collections
+            .into_iter()
+            .map(CollectionInfo::from)
+            .collect::<Vec<_>>(),
+    ))
 }
 
-fn add_file_headers(reply: impl Reply, file: &File, length: u64) -> impl Reply {
-    reply::with_header(
-        reply::with_header(
-            reply::with_header(
-                reply::with_header(
-                    reply::with_header(
-                        reply::with_header(reply, "content-type", &file.content_type),
-                        "content-length",
-                        length,
-                    ),
-                    "cache-control",
-                    FILE_CACHE_CONTROL,
-                ),
-                "last-modified",
-                DateTime::<Utc>::from_utc(file.created_time, Utc).to_rfc2822(),
-            ),
-            "etag",
-            format!("\"{}\"", get_file_etag(file)),
+async fn add_collection(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    request: AddCollectionRequest,
+) -> Result<impl Reply, Error> {
+    // names are used in urls as is
+    if request.name.is_empty()
+        || request.name.len() > MAX_COLLECTION_NAME_LEN
+        || !request
+            .name
+            .bytes()
+            .all(|c| matches!(c, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_'))
+    {
+        return Err(Error::CollectionInvalid(
+            "name must consist of 1 to 64 lowercase letters, digits, '-' and '_'",
+        ));
+    }
+
+    let collection = store
+        .add_collection(&request.name, &namespace)
+        .await?
+        .ok_or(Error::CollectionExists)?;
+
+    let location = format!("/collections/{}", collection.name);
+
+    Ok(reply::with_header(
+        reply::with_status(
+            reply::json(&CollectionInfo::from(collection)),
+            StatusCode::CREATED,
         ),
-        "accept-ranges",
-        "bytes",
-    )
+        "location",
+        location,
+    ))
 }
 
-async fn head_file(key: i32, store: Arc<Store>) -> Result<impl Reply, Error> {
-    let file = store.get_info(key).await?.ok_or(Error::FileNotExists)?;
-    let size = file.size as u64;
+async fn list_collection_files(
+    name: String,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    query: ListFilesQuery,
+) -> Result<impl Reply, Error> {
+    let collection = store
+        .get_collection(&name, &namespace)
+        .await?
+        .ok_or(Error::CollectionNotExists)?;
 
-    Ok(add_file_headers(reply(), &file, size))
+    query_files(&store, &namespace, Some(collection.key), query).await
 }
 
-async fn get_file(key: i32, store: Arc<Store>, range: Option<String>) -> Result<impl Reply, Error> {
-    let FileData {
-        info: file,
-        content,
-        range,
-    } = store
-        .get(key, range.and_then(parse_single_range_header))
+/// Deletes a collection, keeping its files outside any collection.
+async fn delete_collection(
+    name: String,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+) -> Result<impl Reply, Error> {
+    let collection = store
+        .get_collection(&name, &namespace)
         .await?
-        .ok_or(Error::FileNotExists)?;
+        .ok_or(Error::CollectionNotExists)?;
 
-    let size = file.size as u64;
-    let range_length = range.end - range.start;
+    let collection = store
+        .delete_collection(collection.key)
+        .await?
+        .ok_or(Error::CollectionNotExists)?;
 
-    let res = add_file_headers(
-        reply::Response::new(hyper::Body::wrap_stream(content)),
-        &file,
-        range_length,
-    );
+    Ok(reply::json(&CollectionInfo::from(collection)))
+}
 
-    let res = if range_length == size {
-        res.into_response()
+/// Fails unless the client is in the default namespace,
+/// for endpoints that would let clients access other namespaces.
+fn require_default_namespace(namespace: &str) -> Result<(), Error> {
+    if namespace == DEFAULT_NAMESPACE {
+        Ok(())
     } else {
-        reply::with_header(
-            reply::with_status(res, StatusCode::PARTIAL_CONTENT),
-            "content-range",
-            format!(
-                "bytes {start}-{end}/{size}",
-                start = range.start,
-                end = range.end.saturating_sub(1)
-            ),
-        )
-        .into_response()
-    };
+        Err(Error::DefaultNamespaceRequired)
+    }
+}
 
-    Ok(res)
+async fn list_users(namespace: Arc<str>, store: Arc<Store>) -> Result<impl Reply, Error> {
+    require_default_namespace(&namespace)?;
+
+    let users = store.get_users().await?;
+
+    Ok(reply::json(
+        &users.into_iter().map(UserInfo::from).collect::<Vec<_>>(),
+    ))
 }
 
-async fn upload_file<S, B>(
+async fn add_user(
+    namespace: Arc<str>,
     store: Arc<Store>,
-    size: NonZeroU64,
-    content_type: Option<String>,
-    content: S,
-) -> Result<impl Reply, Error>
-where
-    S: Stream<Item = Result<B, warp::Error>> + Send + Sync + 'static,
-    B: Buf + Send + Sync + 'static,
-{
-    let content_type = content_type
-        .as_deref()
-        .unwrap_or("application/octet-stream");
+    request: AddUserRequest,
+) -> Result<impl Reply, Error> {
+    require_default_namespace(&namespace)?;
 
-    let File {
-        key,
-        size,
-        content_type,
-        created_time,
-        ..
-    } = store.upload(size.get(), content_type, content).await?;
+    let namespace = request.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
 
-    #[derive(Serialize)]
-    struct Response {
-        key: i32,
-        size: i64,
-        content_type: String,
-        created_time: DateTime<Utc>,
+    if request.name.is_empty() || request.name.len() > MAX_USER_NAME_LEN {
+        return Err(Error::UserInvalid("name must be between 1 and 64 bytes"));
     }
 
-    Ok(reply::json(&Response {
-        key,
-        size,
-        content_type,
-        created_time: DateTime::from_utc(created_time, Utc),
+    if !is_valid_namespace(namespace) {
+        return Err(Error::UserInvalid(
+            "namespace must consist of lowercase letters, digits, '-' and '_'",
+        ));
+    }
+
+    let token = generate_key();
+    let user = store
+        .add_user(&NewUser {
+            name: &request.name,
+            role: request.role.role(),
+            namespace,
+            token_sha256: &key_digest(&token),
+        })
+        .await?
+        .ok_or(Error::UserExists)?;
+
+    let location = format!("/admin/users/{}", user.key);
+
+    Ok(reply::with_header(
+        reply::with_status(
+            reply::json(&UserInfo {
+                token: Some(token),
+                ..UserInfo::from(user)
+            }),
+            StatusCode::CREATED,
+        ),
+        "location",
+        location,
+    ))
+}
+
+/// Replaces the token of a user, so that the previous token no longer authorizes anything.
+async fn reset_user_token(
+    key: i32,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+) -> Result<impl Reply, Error> {
+    require_default_namespace(&namespace)?;
+
+    let token = generate_key();
+    let user = store
+        .set_user_token(key, &key_digest(&token))
+        .await?
+        .ok_or(Error::UserNotExists)?;
+
+    Ok(reply::json(&UserInfo {
+        token: Some(token),
+        ..UserInfo::from(user)
     }))
 }
 
-async fn delete_file(key: i32, store: Arc<Store>) -> Result<impl Reply, Error> {
-    store.delete(key).await?.ok_or(Error::FileNotExists)?;
+async fn delete_user(
+    key: i32,
+    namespace: Arc<str>,
+    store: Arc<Store>,
+) -> Result<impl Reply, Error> {
+    require_default_namespace(&namespace)?;
+
+    let user = store.delete_user(key).await?.ok_or(Error::UserNotExists)?;
+    Ok(reply::json(&UserInfo::from(user)))
+}
+
+async fn get_maintenance(
+    namespace: Arc<str>,
+    maintenance: Arc<AtomicBool>,
+) -> Result<impl Reply, Error> {
+    // maintenance applies to all namespaces
+    require_default_namespace(&namespace)?;
 
-    #[derive(Serialize)]
-    struct Response {
-        deleted: bool,
+    Ok(reply::json(&MaintenanceInfo {
+        enabled: maintenance.load(Ordering::Relaxed),
+    }))
+}
+
+async fn set_maintenance(
+    namespace: Arc<str>,
+    maintenance: Arc<AtomicBool>,
+    request: MaintenanceInfo,
+) -> Result<impl Reply, Error> {
+    require_default_namespace(&namespace)?;
+
+    if maintenance.swap(request.enabled, Ordering::Relaxed) != request.enabled {
+        if request.enabled {
+            info!("maintenance mode enabled, rejecting requests that modify files");
+        } else {
+            info!("maintenance mode disabled");
+        }
+    }
+
+    Ok(reply::json(&request))
+}
+
+/// Deletes the files of the namespace of the client that match the criteria.
+async fn prune_files(
+    namespace: Arc<str>,
+    store: Arc<Store>,
+    client: Peer,
+    request: PruneRequest,
+) -> Result<reply::Response, Error> {
+    let mut event = AuditEvent {
+        operation: "prune",
+        client_addr: client.addr.map(|addr| addr.ip().to_string()),
+        client_name: client.cert.as_deref().map(Into::into),
+        ..Default::default()
+    };
+
+    let policy = PrunePolicy {
+        older_than: request.older_than,
+        not_accessed_for: request.not_accessed_for,
+        larger_than: request.larger_than,
+        content_type: request.content_type,
+        namespace: Some(namespace.to_string()),
+    };
+
+    let result = async {
+        let report = store.prune(&policy, request.dry_run).await?;
+
+        if !request.dry_run {
+            event.size = Some(report.size as i64);
+        }
+
+        Ok(reply::json(&report).into_response())
+    }
+    .await;
+
+    // dry runs don't modify anything
+    if !request.dry_run {
+        audit(&store, event, &result).await;
+    }
+
+    result
+}
+
+async fn reload_settings(
+    namespace: Arc<str>,
+    reload: mpsc::Sender<ReloadRequest>,
+) -> Result<impl Reply, Error> {
+    require_default_namespace(&namespace)?;
+
+    let (request, result) = oneshot::channel();
+
+    reload
+        .send(request)
+        .await
+        .map_err(|_| Error::Reload("reloading is unavailable".into()))?;
+
+    match result.await {
+        Ok(Ok(())) => Ok(StatusCode::NO_CONTENT),
+        Ok(Err(err)) => Err(Error::Reload(err)),
+        Err(_) => Err(Error::Reload("reloading was interrupted".into())),
     }
+}
+
+/// Fails as if the file doesn't exist unless it is in the namespace of the client,
+/// so that clients can't tell whether files of other namespaces exist.
+async fn check_namespace(store: &Store, key: i64, namespace: &str) -> Result<(), Error> {
+    match store.get_info(key).await? {
+        Some(file) if file.namespace == namespace => Ok(()),
+        _ => Err(Error::FileNotExists),
+    }
+}
+
+/// Records the outcome of an operation in the audit log.
+async fn audit(store: &Store, event: AuditEvent, result: &Result<reply::Response, Error>) {
+    let status = match result {
+        Ok(res) => res.status(),
+        Err(err) => err.status(),
+    };
+
+    audit_status(store, event, status).await
+}
+
+/// Records the outcome of a download in the audit log once its body has been sent or failed,
+/// or with status 499 if the client closed the connection before receiving all of it.
+fn audit_after_stream(
+    store: Arc<Store>,
+    event: AuditEvent,
+    res: reply::Response,
+) -> reply::Response {
+    let (parts, body) = res.into_parts();
+
+    // the body is dropped as soon as content-length bytes are sent, without reaching its end
+    let remaining = parts
+        .headers
+        .get("content-length")
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+
+    let audit = StreamAudit {
+        store,
+        event: Some(event),
+        status: parts.status,
+        remaining,
+        result: None,
+    };
+
+    let body = futures::stream::unfold((body, audit), |(mut body, mut audit)| async move {
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                audit.sent(chunk.len() as u64);
+                Some((Ok(chunk), (body, audit)))
+            }
+            Some(Err(err)) => {
+                audit.result = Some(StatusCode::INTERNAL_SERVER_ERROR);
+                Some((Err(err), (body, audit)))
+            }
+            None => {
+                audit.result.get_or_insert(audit.status);
+                None
+            }
+        }
+    });
+
+    reply::Response::from_parts(parts, hyper::Body::wrap_stream(body))
+}
+
+/// Records an audit event with the final status of a response body when dropped.
+struct StreamAudit {
+    store: Arc<Store>,
+    event: Option<AuditEvent>,
+    status: StatusCode,
+    /// Number of bytes left to send, if the length of the body is known.
+    remaining: Option<u64>,
+    /// Status of the completed or failed body.
+    result: Option<StatusCode>,
+}
+
+impl StreamAudit {
+    /// Counts sent bytes, completing the body once all of them are sent.
+    fn sent(&mut self, length: u64) {
+        if let Some(ref mut remaining) = self.remaining {
+            *remaining = remaining.saturating_sub(length);
+
+            if *remaining == 0 {
+                self.result.get_or_insert(self.status);
+            }
+        }
+    }
+}
 
-    Ok(reply::json(&Response { deleted: true }))
+impl Drop for StreamAudit {
+    fn drop(&mut self) {
+        if let Some(mut event) = self.event.take() {
+            let store = self.store.clone();
+            event.status = self.result.map_or(499, |status| status.as_u16() as i16);
+
+            tokio::spawn(async move {
+                if let Err(err) = store.audit(&event).await {
+                    warn!("failed to record audit log: {err}");
+                }
+            });
+        }
+    }
+}
+
+async fn audit_status(store: &Store, mut event: AuditEvent, status: StatusCode) {
+    event.status = status.as_u16() as i16;
+
+    if let Err(err) = store.audit(&event).await {
+        warn!("failed to record audit log: {err}");
+    }
 }
 
 fn handle_result(result: Result<impl Reply, Error>) -> impl Reply {
     match result {
         Ok(reply) => reply.into_response(),
-        Err(err) => reply_error(
-            match err {
-                Error::Store(ref err) => {
-                    warn!("{err}");
-                    StatusCode::INTERNAL_SERVER_ERROR
-                }
-                Error::FileNotExists => StatusCode::NOT_FOUND,
-            },
-            err.to_string(),
-        )
-        .into_response(),
+        Err(err) => {
+            let status = err.status();
+
+            if status.is_server_error() {
+                warn!("{err}");
+            }
+
+            reply_error(status, err.to_string()).into_response()
+        }
     }
 }
 
@@ -253,19 +5183,79 @@ async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
             StatusCode::BAD_REQUEST,
             format!("missing {} header", err.name()),
         )
-    } else if let Some(_) = err.find::<reject::LengthRequired>() {
+    } else if err.find::<reject::LengthRequired>().is_some() {
         reply_error(StatusCode::BAD_REQUEST, "missing content-length header")
-    } else if let Some(_) = err.find::<reject::InvalidQuery>() {
+    } else if err.find::<reject::InvalidQuery>().is_some() {
         reply_error(StatusCode::BAD_REQUEST, "invalid query string")
-    } else if let Some(_) = err.find::<reject::MethodNotAllowed>() {
-        reply_error(StatusCode::BAD_REQUEST, "method not allowed")
-    } else if let Some(_) = err.find::<reject::PayloadTooLarge>() {
+    } else if err.find::<UnderMaintenance>().is_some() {
+        reply::with_header(
+            reply_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server is in maintenance, files can't be modified",
+            ),
+            "retry-after",
+            MAINTENANCE_RETRY_AFTER.as_secs(),
+        )
+        .into_response()
+    } else if err.find::<TooManyUploads>().is_some() {
+        reply::with_header(
+            reply_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too many concurrent uploads",
+            ),
+            "retry-after",
+            UPLOAD_RETRY_AFTER.as_secs(),
+        )
+        .into_response()
+    } else if let Some(Overloaded(retry_after)) = err.find() {
+        // the time for which requests have been waiting is roughly how long the resource takes to catch up
+        let retry_after = retry_after
+            .as_secs_f64()
+            .ceil()
+            .clamp(1.0, MAX_OVERLOAD_RETRY_AFTER.as_secs_f64());
+
+        reply::with_header(
+            reply_error(StatusCode::SERVICE_UNAVAILABLE, "server is overloaded"),
+            "retry-after",
+            retry_after as u64,
+        )
+        .into_response()
+    } else if err.find::<SignatureInvalid>().is_some() {
+        reply_error(StatusCode::FORBIDDEN, "invalid or expired url signature")
+    } else if err.find::<Unauthorized>().is_some() {
+        reply::with_header(
+            reply_error(StatusCode::UNAUTHORIZED, "missing or invalid api key"),
+            "www-authenticate",
+            "Bearer",
+        )
+        .into_response()
+    } else if err.find::<AuthenticationUnavailable>().is_some() {
+        reply_error(StatusCode::SERVICE_UNAVAILABLE, "failed to check api key")
+    } else if err.find::<Forbidden>().is_some() {
+        reply_error(
+            StatusCode::FORBIDDEN,
+            "api key is not authorized for this request",
+        )
+    } else if let Some(RateLimited(wait)) = err.find() {
+        reply::with_header(
+            reply_error(StatusCode::TOO_MANY_REQUESTS, "too many requests"),
+            "retry-after",
+            // round up so that the client doesn't retry too early
+            wait.as_secs() + (wait.subsec_nanos() > 0) as u64,
+        )
+        .into_response()
+    } else if err.find::<reject::PayloadTooLarge>().is_some()
+        || err.find::<UploadTooLarge>().is_some()
+    {
         reply_error(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
-    } else if let Some(_) = err.find::<reject::UnsupportedMediaType>() {
+    } else if err.find::<reject::UnsupportedMediaType>().is_some() {
         reply_error(
             StatusCode::UNSUPPORTED_MEDIA_TYPE,
             "unsupported content-type",
         )
+    } else if err.find::<reject::MethodNotAllowed>().is_some() {
+        // checked last, as every route of another method rejects with it
+        reply_error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
     } else {
         warn!("unknown rejection: {err:?}");
         reply_error(
@@ -275,6 +5265,30 @@ async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
     })
 }
 
+/// Replies with errors of the S3-compatible api as XML, as S3 clients expect.
+fn handle_s3_result(result: Result<reply::Response, Error>) -> reply::Response {
+    match result {
+        Ok(res) => res,
+        Err(err) => {
+            let status = err.status();
+
+            if status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED {
+                warn!("{err}");
+            }
+
+            reply::with_status(
+                reply::with_header(
+                    crate::s3::error_body(err.s3_code(), &err.to_string()),
+                    "content-type",
+                    "application/xml",
+                ),
+                status,
+            )
+            .into_response()
+        }
+    }
+}
+
 fn reply_error(status: StatusCode, message: impl Into<String>) -> reply::Response {
     #[derive(Serialize)]
     struct Error {
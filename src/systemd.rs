@@ -0,0 +1,64 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use std::{env, os::unix::net::UnixDatagram, time::Duration};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to open notification socket: {0}")]
+    Socket(std::io::Error),
+
+    #[error("failed to send notification to '{0}': {1}")]
+    Send(String, std::io::Error),
+}
+
+/// Returns whether the server was started by systemd as a service of `Type=notify`.
+pub fn is_notify_enabled() -> bool {
+    env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Sends a state such as `READY=1` to systemd through the socket in `NOTIFY_SOCKET`.
+/// Does nothing if the server wasn't started by systemd.
+pub fn notify(state: &str) -> Result<(), Error> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+
+    let socket = UnixDatagram::unbound().map_err(Error::Socket)?;
+
+    let result = match path.strip_prefix('@') {
+        // abstract socket
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    };
+
+    result.map(|_| ()).map_err(|err| Error::Send(path, err))
+}
+
+/// Returns the interval at which `WATCHDOG=1` should be sent to systemd,
+/// or `None` if the watchdog isn't enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    // the watchdog applies to another process if the variables were inherited
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    let timeout: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    // ping twice within the timeout, as recommended by sd_watchdog_enabled(3)
+    (timeout != 0).then(|| Duration::from_micros(timeout / 2))
+}
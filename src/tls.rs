@@ -0,0 +1,303 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use crate::server::ClientCache;
+use futures::{future::Either, Future};
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{
+        server::{
+            AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+            ClientCertVerifier, ClientHello, NoClientAuth, ResolvesServerCert,
+        },
+        sign::{self, CertifiedKey},
+        Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use warp::{
+    filters::BoxedFilter,
+    hyper::{server::conn::Http, service::Service},
+    Reply,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read '{0}': {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("no certificates in '{0}'")]
+    CertMissing(PathBuf),
+
+    #[error("no private key in '{0}'")]
+    KeyMissing(PathBuf),
+
+    #[error("unsupported private key in '{0}'")]
+    KeyInvalid(PathBuf),
+
+    #[error("invalid ca certificate in '{0}'")]
+    CaInvalid(PathBuf),
+}
+
+/// Maximum duration of a TLS handshake before the connection is closed.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address of the client of a request, as `warp::addr::remote` is only available to servers started by `warp::serve`.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Subject of the certificate presented by the client of a request, normalized by [`normalize_subject`].
+#[derive(Debug, Clone)]
+pub struct ClientCert(pub Arc<str>);
+
+/// Certificate chain and private key of the server loaded from PEM files, which are reloaded when they change.
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<LoadedCert>,
+}
+
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    // modification times of the files when they were loaded
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl CertResolver {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, Error> {
+        let current = RwLock::new(load_cert(&cert_path, &key_path)?);
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            current,
+        })
+    }
+
+    /// Reloads the certificate and key if either file was modified since they were loaded.
+    /// Returns whether they were reloaded.
+    pub fn reload_if_changed(&self) -> Result<bool, Error> {
+        let modified = (
+            modified_time(&self.cert_path),
+            modified_time(&self.key_path),
+        );
+
+        if self.current.read().unwrap().modified == modified {
+            return Ok(false);
+        }
+
+        *self.current.write().unwrap() = load_cert(&self.cert_path, &self.key_path)?;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().key.clone())
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|meta| meta.modified()).ok()
+}
+
+fn load_cert(cert_path: &Path, key_path: &Path) -> Result<LoadedCert, Error> {
+    // taken before reading so that changes while reading are picked up by the next reload
+    let modified = (modified_time(cert_path), modified_time(key_path));
+
+    let certs = read_pem(cert_path, rustls_pemfile::certs)?;
+
+    if certs.is_empty() {
+        return Err(Error::CertMissing(cert_path.into()));
+    }
+
+    let key = read_pem(key_path, rustls_pemfile::read_all)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| Error::KeyMissing(key_path.into()))?;
+
+    let key = sign::any_supported_type(&PrivateKey(key))
+        .map_err(|_| Error::KeyInvalid(key_path.into()))?;
+
+    Ok(LoadedCert {
+        key: Arc::new(CertifiedKey::new(
+            certs.into_iter().map(Certificate).collect(),
+            key,
+        )),
+        modified,
+    })
+}
+
+fn read_pem<T>(
+    path: &Path,
+    read: fn(&mut dyn std::io::BufRead) -> std::io::Result<Vec<T>>,
+) -> Result<Vec<T>, Error> {
+    File::open(path)
+        .and_then(|file| read(&mut BufReader::new(file)))
+        .map_err(|err| Error::Read(path.into(), err))
+}
+
+/// Returns a verifier of client certificates issued by the CA certificates in the PEM file.
+/// Clients without a certificate are rejected unless `optional` is set.
+pub fn client_verifier(
+    ca_path: &Path,
+    optional: bool,
+) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+    let certs = read_pem(ca_path, rustls_pemfile::certs)?;
+
+    if certs.is_empty() {
+        return Err(Error::CertMissing(ca_path.into()));
+    }
+
+    let mut roots = RootCertStore::empty();
+
+    for cert in certs {
+        roots
+            .add(&Certificate(cert))
+            .map_err(|_| Error::CaInvalid(ca_path.into()))?;
+    }
+
+    Ok(if optional {
+        AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+    } else {
+        AllowAnyAuthenticatedClient::new(roots)
+    })
+}
+
+/// Normalizes a distinguished name such as "CN=uploader, O=chiya" by removing whitespace around separators,
+/// so that names given in configuration match the subjects of certificates.
+pub fn normalize_subject(subject: &str) -> String {
+    subject
+        .split(',')
+        .map(|part| {
+            part.split('+')
+                .map(|attr| match attr.split_once('=') {
+                    Some((name, value)) => format!("{}={}", name.trim(), value.trim()),
+                    None => attr.trim().into(),
+                })
+                .collect::<Vec<_>>()
+                .join("+")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn cert_subject(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    Some(normalize_subject(&cert.subject().to_string()))
+}
+
+/// Returns the TLS configuration of the server, which negotiates HTTP/2 with clients that support it
+/// and verifies client certificates using the given verifier.
+pub fn server_config(
+    certs: Arc<CertResolver>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_verifier.unwrap_or_else(NoClientAuth::new))
+        .with_cert_resolver(certs);
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(config)
+}
+
+/// Listens on the endpoint and serves requests over TLS until the shutdown future completes.
+/// The endpoint is bound immediately, panicking if it can't be bound like `warp::Server::bind`.
+pub fn serve<R>(
+    routes: BoxedFilter<(R,)>,
+    endpoint: SocketAddr,
+    config: Arc<ServerConfig>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> impl Future<Output = ()>
+where
+    R: Reply + 'static,
+{
+    let listener = std::net::TcpListener::bind(endpoint)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .and_then(TcpListener::from_std)
+        .unwrap_or_else(|err| panic!("failed to bind to {endpoint}: {err}"));
+
+    let acceptor = TlsAcceptor::from(config);
+    let service = warp::service(routes);
+
+    info!("listening on https://{endpoint}");
+
+    async move {
+        tokio::pin!(shutdown);
+
+        loop {
+            let accept = listener.accept();
+            tokio::pin!(accept);
+
+            let (stream, addr) = match futures::future::select(accept, &mut shutdown).await {
+                Either::Left((Ok(accepted), _)) => accepted,
+                Either::Left((Err(err), _)) => {
+                    warn!("failed to accept connection: {err}");
+                    continue;
+                }
+                Either::Right(_) => break,
+            };
+
+            let acceptor = acceptor.clone();
+            let service = service.clone();
+
+            tokio::spawn(async move {
+                let stream =
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(err)) => {
+                            debug!("tls handshake with {addr} failed: {err}");
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("tls handshake with {addr} timed out");
+                            return;
+                        }
+                    };
+
+                // verified by the client verifier during the handshake
+                let client_cert = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(cert_subject)
+                    .map(|subject| ClientCert(subject.into()));
+
+                let service = warp::hyper::service::service_fn(move |mut req| {
+                    req.extensions_mut().insert(RemoteAddr(addr));
+                    req.extensions_mut().insert(ClientCache::default());
+
+                    if let Some(ref cert) = client_cert {
+                        req.extensions_mut().insert(cert.clone());
+                    }
+
+                    service.clone().call(req)
+                });
+
+                if let Err(err) = Http::new().serve_connection(stream, service).await {
+                    debug!("failed to serve connection of {addr}: {err}");
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//
+// Copyright (c) 2022 chiya.dev
+//
+// Use of this source code is governed by the MIT License
+// which can be found in the LICENSE file and at:
+//
+//   https://opensource.org/licenses/MIT
+//
+use bytes::Bytes;
+use castella_core::{
+    cache::ChunkCache,
+    cipher::{ChunkStreamCipher, CipherKind, Format},
+    stream::BufferPool,
+};
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageError, ImageReader, Limits,
+};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use std::{io::Cursor, path::PathBuf, str::FromStr};
+use tokio::sync::Semaphore;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    CacheInit(#[from] castella_core::cache::Error),
+
+    #[error("failed to decode image: {0}")]
+    Decode(ImageError),
+
+    #[error("failed to encode image: {0}")]
+    Encode(ImageError),
+
+    #[error("crop region is outside the image")]
+    CropInvalid,
+
+    #[error("image transformation was interrupted")]
+    Interrupted,
+}
+
+/// Maximum width and height of images that are decoded, and of transformed images.
+pub const MAX_DIMENSION: u32 = 8192;
+
+/// Maximum memory allocated to decode an image.
+const MAX_DECODE_ALLOC: u64 = 512 * 1024 * 1024;
+
+/// Quality of lossy formats unless requested otherwise, from 1 to 100.
+const DEFAULT_QUALITY: u8 = 80;
+
+/// Size of the XChaCha20-Poly1305 nonce that precedes each encrypted variant.
+const NONCE_SIZE: usize = 24;
+
+/// Speed of the AVIF encoder from 1 to 10, which trades compression for encoding time.
+const AVIF_SPEED: u8 = 8;
+
+/// How an image is resized when both its width and height are given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Fit inside the dimensions, preserving the aspect ratio.
+    #[default]
+    Contain,
+    /// Fill the dimensions, preserving the aspect ratio and cropping the center.
+    Cover,
+    /// Stretch to the dimensions.
+    Fill,
+}
+
+/// Format that images are converted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+impl OutputFormat {
+    /// Returns the format of images of a content type, or PNG if it can't be encoded.
+    pub fn from_content_type(content_type: &str) -> Self {
+        match content_type {
+            "image/jpeg" => Self::Jpeg,
+            "image/webp" => Self::Webp,
+            "image/avif" => Self::Avif,
+            _ => Self::Png,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+            Self::Avif => "image/avif",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+            Self::Avif => "avif",
+        }
+    }
+}
+
+/// Region of an image given as `x,y,width,height` in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Crop {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse().map_err(|_| ()))
+            .collect::<Result<Vec<u32>, _>>()?;
+
+        match values.as_slice() {
+            &[x, y, width, height] if width != 0 && height != 0 => Ok(Self {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Transformation applied to an image, in the order of cropping, resizing and converting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transform {
+    pub crop: Option<Crop>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    /// Format of the transformed image, or `None` for PNG.
+    pub format: Option<OutputFormat>,
+    /// Quality of lossy formats from 1 to 100.
+    pub quality: Option<u8>,
+}
+
+impl Transform {
+    /// Returns a string identifying the transformation, which is safe to use in file names.
+    pub fn key(&self) -> String {
+        let mut key = String::new();
+
+        if let Some(crop) = self.crop {
+            key.push_str(&format!(
+                "c{}_{}_{}_{}",
+                crop.x, crop.y, crop.width, crop.height
+            ));
+        }
+
+        if let Some(width) = self.width {
+            key.push_str(&format!("w{width}"));
+        }
+
+        if let Some(height) = self.height {
+            key.push_str(&format!("h{height}"));
+        }
+
+        match self.fit {
+            Fit::Contain => {}
+            Fit::Cover => key.push_str("cover"),
+            Fit::Fill => key.push_str("fill"),
+        }
+
+        if let Some(format) = self.format {
+            key.push_str(format.name());
+        }
+
+        if let Some(quality) = self.quality {
+            key.push_str(&format!("q{quality}"));
+        }
+
+        key
+    }
+
+    /// Decodes an image, transforms it and encodes the result.
+    pub fn apply(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut reader = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .map_err(|err| Error::Decode(err.into()))?;
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_DIMENSION);
+        limits.max_image_height = Some(MAX_DIMENSION);
+        limits.max_alloc = Some(MAX_DECODE_ALLOC);
+        reader.limits(limits);
+
+        let mut image = reader.decode().map_err(Error::Decode)?;
+
+        if let Some(crop) = self.crop {
+            let fits = |offset: u32, length: u32, max: u32| {
+                offset.checked_add(length).is_some_and(|end| end <= max)
+            };
+
+            if !fits(crop.x, crop.width, image.width())
+                || !fits(crop.y, crop.height, image.height())
+            {
+                return Err(Error::CropInvalid);
+            }
+
+            image = image.crop_imm(crop.x, crop.y, crop.width, crop.height);
+        }
+
+        image = match (self.width, self.height) {
+            (None, None) => image,
+            (Some(width), Some(height)) => match self.fit {
+                Fit::Contain => image.resize(width, height, FilterType::CatmullRom),
+                Fit::Cover => image.resize_to_fill(width, height, FilterType::CatmullRom),
+                Fit::Fill => image.resize_exact(width, height, FilterType::CatmullRom),
+            },
+            // the other dimension follows the aspect ratio
+            (width, height) => image.resize(
+                width.unwrap_or(MAX_DIMENSION),
+                height.unwrap_or(MAX_DIMENSION),
+                FilterType::CatmullRom,
+            ),
+        };
+
+        let quality = self.quality.unwrap_or(DEFAULT_QUALITY);
+        let mut output = Vec::new();
+
+        match self.format.unwrap_or(OutputFormat::Png) {
+            OutputFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality)),
+            OutputFormat::Png => image.write_with_encoder(PngEncoder::new(&mut output)),
+            // the webp encoder is lossless, so the quality doesn't apply
+            OutputFormat::Webp => DynamicImage::ImageRgba8(image.to_rgba8())
+                .write_with_encoder(WebPEncoder::new_lossless(&mut output)),
+            OutputFormat::Avif => DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(
+                AvifEncoder::new_with_speed_quality(&mut output, AVIF_SPEED, quality),
+            ),
+        }
+        .map_err(Error::Encode)?;
+
+        Ok(output)
+    }
+}
+
+/// Transforms images on the blocking thread pool and caches the transformed variants on local disk.
+///
+/// Variants are cached encrypted like cached chunks, with a key generated at startup,
+/// so variants cached by a previous run can't be read and are evicted over time.
+#[derive(Debug)]
+pub struct ImageTransformer {
+    cache: Option<ChunkCache>,
+    key: [u8; ChunkStreamCipher::KEY_SIZE],
+    pool: BufferPool,
+    /// Maximum size of the original images that are transformed.
+    pub max_size: u64,
+    // decoding and encoding is limited to one image per core
+    permits: Semaphore,
+}
+
+impl ImageTransformer {
+    /// `cache_path` is the directory in which variants are cached if given,
+    /// and `cache_capacity` is the maximum total size of cached variants in bytes.
+    pub fn new(
+        cache_path: Option<PathBuf>,
+        cache_capacity: u64,
+        max_size: u64,
+    ) -> Result<Self, Error> {
+        let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+        Ok(Self {
+            cache: match cache_path {
+                Some(path) => Some(ChunkCache::new(path, cache_capacity)?),
+                None => None,
+            },
+            key: thread_rng().gen(),
+            pool: BufferPool::new(BufferPool::STREAM_SIZE),
+            max_size,
+            permits: Semaphore::new(parallelism),
+        })
+    }
+
+    fn cipher(&self, nonce: &[u8], name: &str) -> ChunkStreamCipher {
+        let secret = [&self.key[..], nonce].concat();
+
+        ChunkStreamCipher::new(
+            CipherKind::XChaCha20Poly1305,
+            Format::LATEST,
+            &secret,
+            0,
+            name,
+        )
+        .expect("secret is of the size of the cipher")
+    }
+
+    /// Returns a cached variant of a remote file.
+    pub async fn get(&self, file_id: &str, transform: &Transform) -> Option<Bytes> {
+        let cache = self.cache.as_ref()?;
+        let name = format!("{file_id}-{}", transform.key());
+        let data = cache.get(&name, 0).await?;
+
+        match data.get(..NONCE_SIZE).map(|nonce| {
+            self.cipher(nonce, &name)
+                .decrypt(0, &data[NONCE_SIZE..], &self.pool)
+        }) {
+            Some(Ok(variant)) => Some(variant),
+            _ => {
+                debug!("discarding cached image variant {name} that can't be decrypted");
+                cache.remove_file(&name).await;
+                None
+            }
+        }
+    }
+
+    /// Caches a variant of a remote file.
+    pub async fn put(&self, file_id: &str, transform: &Transform, variant: &[u8]) {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return,
+        };
+
+        let name = format!("{file_id}-{}", transform.key());
+        let nonce: [u8; NONCE_SIZE] = thread_rng().gen();
+
+        match self.cipher(&nonce, &name).encrypt(0, variant, &self.pool) {
+            Ok(encrypted) => {
+                cache
+                    .put(&name, 0, &[&nonce[..], &encrypted].concat())
+                    .await
+            }
+            Err(err) => warn!("failed to encrypt image variant {name}: {err}"),
+        }
+    }
+
+    /// Transforms an image without blocking the reactor.
+    pub async fn transform(&self, data: Bytes, transform: Transform) -> Result<Vec<u8>, Error> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|_| Error::Interrupted)?;
+
+        tokio::task::spawn_blocking(move || transform.apply(&data))
+            .await
+            .map_err(|_| Error::Interrupted)?
+    }
+}